@@ -0,0 +1,456 @@
+//! Event bridge: chat events are published as JSON lines on a Unix socket and
+//! POSTed to local webhooks, and commands sent on the socket drive the session.
+//!
+//! ```text
+//! > {"type":"message","date":"10-19 12:00:00","from":"bob","to":null,"channel":"public","text":"hi"}
+//! < {"cmd":"pm","to":"bob","text":"hello"}
+//! > {"type":"ok"}
+//! ```
+use crate::filter::Channel;
+use crossbeam_channel::Sender;
+use serde_derive::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::Shutdown,
+    os::unix::net::UnixListener,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BridgeConfig {
+    pub enabled: bool,
+    /// Defaults to "bridge-<profile>.sock" next to the config file
+    pub socket: Option<PathBuf>,
+    /// Commands sent on the socket are run when true, it is read only
+    /// otherwise
+    pub accept_commands: bool,
+    /// Only local URLs, events must not leave the machine
+    pub webhooks: Vec<String>,
+}
+
+impl BridgeConfig {
+    pub fn check(&self) -> Result<(), String> {
+        match self.webhooks.iter().find(|url| !is_local(url)) {
+            Some(url) => Err(format!("webhook \"{}\" is not a local URL", url)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn is_local(url: &str) -> bool {
+    let url = match reqwest::Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+    let host = match url.host_str() {
+        Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => return false,
+    };
+    host == "localhost" || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Event {
+    /// PMs have the "pm" channel
+    Message {
+        date: String,
+        from: String,
+        to: Option<String>,
+        channel: Channel,
+        text: String,
+    },
+    Join {
+        user: String,
+    },
+    Leave {
+        user: String,
+    },
+    /// Kicks, bans, warnings... as announced by the chat
+    Moderation {
+        date: String,
+        text: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+pub enum Command {
+    Post {
+        text: String,
+    },
+    Pm {
+        to: String,
+        text: String,
+    },
+    /// To the members chat
+    Members {
+        text: String,
+    },
+    /// To the staff chat
+    Staff {
+        text: String,
+    },
+    Kick {
+        user: String,
+        #[serde(default)]
+        reason: String,
+    },
+}
+
+// Lines queued for one socket client. A client that falls this far behind is
+// dropped rather than slowing down the message fetch.
+#[cfg(unix)]
+const SUBSCRIBER_QUEUE: usize = 256;
+
+#[cfg(unix)]
+type Subscribers = Arc<Mutex<Vec<Sender<String>>>>;
+
+pub struct Bridge {
+    #[cfg(unix)]
+    subscribers: Subscribers,
+    webhooks: Option<Sender<String>>,
+}
+
+impl Bridge {
+    /// Commands received on the socket are given to "run".
+    pub fn start<F>(cfg: &BridgeConfig, socket: Option<PathBuf>, run: F) -> Self
+    where
+        F: Fn(Command) + Send + Sync + 'static,
+    {
+        let mut bridge = Self {
+            #[cfg(unix)]
+            subscribers: Arc::new(Mutex::new(vec![])),
+            webhooks: None,
+        };
+        if !cfg.enabled {
+            return bridge;
+        }
+        if !cfg.webhooks.is_empty() {
+            bridge.webhooks = Some(start_webhooks(cfg.webhooks.clone()));
+        }
+        #[cfg(unix)]
+        match cfg.socket.clone().or(socket) {
+            Some(path) => {
+                let run = if cfg.accept_commands {
+                    Some(Arc::new(run))
+                } else {
+                    None
+                };
+                if let Err(e) = listen(&path, Arc::clone(&bridge.subscribers), run) {
+                    log::error!("failed to listen on {}: {}", path.display(), e);
+                }
+            }
+            None => log::error!("no path for the bridge socket"),
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (socket, run);
+            log::error!("the bridge socket needs a unix system, only webhooks are used");
+        }
+        bridge
+    }
+
+    pub fn publish(&self, event: &Event) {
+        let line = match serde_json::to_string(event) {
+            Ok(line) => line,
+            Err(e) => {
+                log::error!("failed to serialize event: {}", e);
+                return;
+            }
+        };
+        #[cfg(unix)]
+        {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            subscribers.retain(|tx| tx.try_send(line.clone()).is_ok());
+        }
+        if let Some(tx) = &self.webhooks {
+            let _ = tx.send(line);
+        }
+    }
+}
+
+// Events are POSTed in order, on their own thread so a slow hook doesn't
+// hold the chat.
+fn start_webhooks(urls: Vec<String>) -> Sender<String> {
+    let (tx, rx) = crossbeam_channel::unbounded::<String>();
+    thread::spawn(move || {
+        // Local URLs, never through the proxy
+        let client = match reqwest::blocking::Client::builder()
+            .no_proxy()
+            .timeout(Duration::from_secs(5))
+            .build()
+        {
+            Ok(client) => client,
+            Err(e) => {
+                log::error!("failed to build the webhooks client: {}", e);
+                return;
+            }
+        };
+        for body in rx {
+            for url in &urls {
+                let sent = client
+                    .post(url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(body.clone())
+                    .send()
+                    .and_then(|r| r.error_for_status());
+                if let Err(e) = sent {
+                    log::error!("webhook {}: {}", url, e);
+                }
+            }
+        }
+    });
+    tx
+}
+
+#[cfg(unix)]
+fn listen<F>(path: &PathBuf, subscribers: Subscribers, run: Option<Arc<F>>) -> std::io::Result<()>
+where
+    F: Fn(Command) + Send + Sync + 'static,
+{
+    // Left by a previous session
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    // Bound in a directory only we can enter, so nobody can connect before
+    // the socket gets its permissions, then moved in place.
+    let mut dir = path.as_os_str().to_owned();
+    dir.push(".tmp");
+    let dir = PathBuf::from(dir);
+    let _ = fs::remove_dir_all(&dir);
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    }
+    let tmp = dir.join("sock");
+    let listener = UnixListener::bind(&tmp).and_then(|listener| {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&tmp, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&dir);
+    let listener = listener?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::error!("bridge: {}", e);
+                    continue;
+                }
+            };
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+            let reader = match stream.try_clone() {
+                Ok(reader) => reader,
+                Err(e) => {
+                    log::error!("bridge: {}", e);
+                    continue;
+                }
+            };
+            let (tx, rx) = crossbeam_channel::bounded::<String>(SUBSCRIBER_QUEUE);
+            subscribers.lock().unwrap().push(tx.clone());
+            thread::spawn(move || {
+                let mut stream = stream;
+                for line in rx {
+                    if writeln!(stream, "{}", line).is_err() {
+                        break;
+                    }
+                }
+                // Dropped, or gone: the reader stops too
+                let _ = stream.shutdown(Shutdown::Both);
+            });
+            let run = run.clone();
+            thread::spawn(move || {
+                for line in BufReader::new(reader).lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    };
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let reply = match (&run, serde_json::from_str::<Command>(&line)) {
+                        (None, _) => reply_error("commands are disabled"),
+                        (_, Err(e)) => reply_error(&e.to_string()),
+                        (Some(run), Ok(cmd)) => {
+                            run(cmd);
+                            r#"{"type":"ok"}"#.to_owned()
+                        }
+                    };
+                    if tx.try_send(reply).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(unix)]
+fn reply_error(message: &str) -> String {
+    serde_json::json!({ "type": "error", "message": message }).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn json_test() {
+        let event = Event::Message {
+            date: "10-19 12:00:00".to_owned(),
+            from: "bob".to_owned(),
+            to: Some("alice".to_owned()),
+            channel: Channel::Pm,
+            text: "hi".to_owned(),
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"type":"message","date":"10-19 12:00:00","from":"bob","to":"alice","channel":"pm","text":"hi"}"#
+        );
+        let event = Event::Join {
+            user: "bob".to_owned(),
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"type":"join","user":"bob"}"#
+        );
+
+        let cmd: Command = serde_json::from_str(r#"{"cmd":"kick","user":"bob"}"#).unwrap();
+        assert_eq!(
+            cmd,
+            Command::Kick {
+                user: "bob".to_owned(),
+                reason: String::new()
+            }
+        );
+        assert!(serde_json::from_str::<Command>(r#"{"cmd":"ban","user":"bob"}"#).is_err());
+    }
+
+    #[test]
+    fn webhooks_test() {
+        let mut cfg = BridgeConfig {
+            webhooks: vec![
+                "http://localhost:8080/hook".to_owned(),
+                "http://127.0.0.1/hook".to_owned(),
+                "http://[::1]:9000/".to_owned(),
+            ],
+            ..BridgeConfig::default()
+        };
+        assert!(cfg.check().is_ok());
+        cfg.webhooks.push("https://example.com/hook".to_owned());
+        assert!(cfg.check().is_err());
+        assert!(!is_local("not a url"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_test() {
+        let path = std::env::temp_dir().join(format!("bhcli-bridge-{}.sock", std::process::id()));
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let cfg = BridgeConfig {
+            enabled: true,
+            accept_commands: true,
+            ..BridgeConfig::default()
+        };
+        let bridge = Bridge::start(&cfg, Some(path.clone()), move |cmd| {
+            tx.send(cmd).unwrap();
+        });
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let mut client = UnixStream::connect(&path).unwrap();
+        let mut lines = BufReader::new(client.try_clone().unwrap()).lines();
+
+        writeln!(client, r#"{{"cmd":"post","text":"hi"}}"#).unwrap();
+        assert_eq!(lines.next().unwrap().unwrap(), r#"{"type":"ok"}"#);
+        let cmd = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            cmd,
+            Command::Post {
+                text: "hi".to_owned()
+            }
+        );
+        writeln!(client, "nope").unwrap();
+        assert!(lines.next().unwrap().unwrap().contains(r#""type":"error""#));
+
+        bridge.publish(&Event::Leave {
+            user: "bob".to_owned(),
+        });
+        assert_eq!(
+            lines.next().unwrap().unwrap(),
+            r#"{"type":"leave","user":"bob"}"#
+        );
+        drop(bridge);
+        let _ = fs::remove_file(&path);
+
+        // Read only by default
+        let cfg = BridgeConfig {
+            enabled: true,
+            ..BridgeConfig::default()
+        };
+        let bridge = Bridge::start(&cfg, Some(path.clone()), |_| {
+            panic!("commands are disabled")
+        });
+        let mut client = UnixStream::connect(&path).unwrap();
+        let mut lines = BufReader::new(client.try_clone().unwrap()).lines();
+        writeln!(client, r#"{{"cmd":"post","text":"hi"}}"#).unwrap();
+        assert!(lines
+            .next()
+            .unwrap()
+            .unwrap()
+            .contains("commands are disabled"));
+        drop(bridge);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn slow_client_test() {
+        let path =
+            std::env::temp_dir().join(format!("bhcli-bridge-slow-{}.sock", std::process::id()));
+        let cfg = BridgeConfig {
+            enabled: true,
+            ..BridgeConfig::default()
+        };
+        let bridge = Bridge::start(&cfg, Some(path.clone()), |_| {});
+        // Never reads
+        let _client = UnixStream::connect(&path).unwrap();
+        for _ in 0..100 {
+            if !bridge.subscribers.lock().unwrap().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(bridge.subscribers.lock().unwrap().len(), 1);
+
+        let event = Event::Message {
+            date: "10-19 12:00:00".to_owned(),
+            from: "bob".to_owned(),
+            to: None,
+            channel: Channel::Public,
+            text: "x".repeat(1024),
+        };
+        for _ in 0..4096 {
+            bridge.publish(&event);
+        }
+        assert!(bridge.subscribers.lock().unwrap().is_empty());
+        drop(bridge);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,685 @@
+//! The commands typed in the input box. Builtin commands and the custom
+//! "!commands" of the config live in the same table, which is used to parse
+//! their arguments, to complete them and to generate the /help.
+
+/// What an argument is, used to parse it and to pick its completions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgKind {
+    /// A username
+    User,
+    /// A username or part of one, the rest of the line. Quoted for an exact name.
+    Name,
+    /// A single word
+    Word,
+    /// The rest of the line
+    Text,
+    Number,
+    /// A file path, quoted if it contains spaces
+    Path,
+    Color,
+    /// A banned name, the rest of the line
+    BanFilter,
+    /// A filtered message term, the rest of the line
+    MsgFilter,
+    /// One of the listed words
+    Choice(&'static [&'static str]),
+    /// One of the listed flags, skipped when absent
+    Flag(&'static [&'static str]),
+    /// "@user", who to send to, skipped when absent
+    Target,
+    /// The name of a command
+    Command,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub optional: bool,
+}
+
+impl ArgSpec {
+    fn usage(&self) -> String {
+        let name = match self.kind {
+            ArgKind::Choice(c) | ArgKind::Flag(c) => c.join("|"),
+            ArgKind::Target => format!("@{}", self.name),
+            _ => self.name.to_owned(),
+        };
+        match (self.optional, self.kind) {
+            (true, _) => format!("[{}]", name),
+            (false, ArgKind::Choice(_)) => name,
+            (false, _) => format!("<{}>", name),
+        }
+    }
+}
+
+/// The builtin commands, run by the client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Builtin {
+    Pm,
+    Members,
+    Staffs,
+    Admins,
+    Me,
+    Find,
+    View,
+    Kick,
+    KickAll,
+    Warn,
+    Ban,
+    Unban,
+    BanList,
+    BanExactList,
+    Filter,
+    Unfilter,
+    FilterList,
+    Allow,
+    Revoke,
+    AllowList,
+    Ignore,
+    Unignore,
+    Mute,
+    Sound,
+    DeleteLast,
+    DeleteAll,
+    Nick,
+    Color,
+    Cycles,
+    Cycle1,
+    Cycle2,
+    Upload,
+    Paste,
+    Outbox,
+    Help,
+}
+
+/// What a command does.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Run {
+    Builtin(Builtin),
+    /// Post the text in the chat
+    Post(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    /// With its "/" or "!"
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub args: Vec<ArgSpec>,
+    pub help: String,
+    pub run: Run,
+}
+
+impl Command {
+    pub fn usage(&self) -> String {
+        let mut out = self.name.clone();
+        for arg in &self.args {
+            out.push(' ');
+            out.push_str(&arg.usage());
+        }
+        out
+    }
+}
+
+/// The parsed arguments of a command, by name. Absent optional arguments are
+/// not in there.
+#[derive(Debug, Default, PartialEq)]
+pub struct Args(Vec<(&'static str, String)>);
+
+impl Args {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn number(&self, name: &str) -> Option<usize> {
+        self.get(name).and_then(|v| v.parse().ok())
+    }
+}
+
+pub struct Registry {
+    commands: Vec<Command>,
+}
+
+fn arg(name: &'static str, kind: ArgKind) -> ArgSpec {
+    ArgSpec {
+        name,
+        kind,
+        optional: false,
+    }
+}
+
+fn opt(name: &'static str, kind: ArgKind) -> ArgSpec {
+    ArgSpec {
+        name,
+        kind,
+        optional: true,
+    }
+}
+
+impl Registry {
+    pub fn builtin() -> Self {
+        use ArgKind::{
+            BanFilter, Choice, Color, Flag, MsgFilter, Name, Number, Path, Target, Text, User, Word,
+        };
+        let mut registry = Registry { commands: vec![] };
+        let mut add =
+            |name: &str, aliases: &[&str], args: Vec<ArgSpec>, cmd: Builtin, help: &str| {
+                registry.register(Command {
+                    name: name.to_owned(),
+                    aliases: aliases.iter().map(|a| a.to_string()).collect(),
+                    args,
+                    help: help.to_owned(),
+                    run: Run::Builtin(cmd),
+                })
+            };
+        add(
+            "/pm",
+            &[],
+            vec![arg("user", User), arg("message", Text)],
+            Builtin::Pm,
+            "Send a private message.",
+        );
+        add(
+            "/m",
+            &[],
+            vec![arg("message", Text)],
+            Builtin::Members,
+            "Send a message to the members. \"/m /command\" runs the command and stays in the members channel.",
+        );
+        add(
+            "/s",
+            &[],
+            vec![arg("message", Text)],
+            Builtin::Staffs,
+            "Send a message to the staff.",
+        );
+        add(
+            "/a",
+            &[],
+            vec![arg("message", Text)],
+            Builtin::Admins,
+            "Send a message to the admins.",
+        );
+        add(
+            "/me",
+            &[],
+            vec![arg("action", Text)],
+            Builtin::Me,
+            "Send an action message.",
+        );
+        add(
+            "/f",
+            &[],
+            vec![arg("filter", Text)],
+            Builtin::Find,
+            "Only show the messages matching the filter while typing, Esc clears it.",
+        );
+        add(
+            "/view",
+            &[],
+            vec![opt("name", Word)],
+            Builtin::View,
+            "Only show the messages of a saved view, all of them without a name.",
+        );
+        add(
+            "/kick",
+            &["/k"],
+            vec![arg("user", User), opt("message", Text)],
+            Builtin::Kick,
+            "Kick a user.",
+        );
+        add(
+            "/kall",
+            &[],
+            vec![],
+            Builtin::KickAll,
+            "Kick all the guests.",
+        );
+        add(
+            "!warn",
+            &[],
+            vec![opt("user", User)],
+            Builtin::Warn,
+            "Warn a user that the next step is a kick.",
+        );
+        add(
+            "/ban",
+            &["/banname"],
+            vec![arg("name", Name)],
+            Builtin::Ban,
+            "Kick & keep kicking the users whose name contains the text. Quote the name to only ban that exact name.",
+        );
+        add(
+            "/unban",
+            &[],
+            vec![arg("name", BanFilter)],
+            Builtin::Unban,
+            "Remove a banned name, quoted for an exact name.",
+        );
+        add(
+            "/banlist",
+            &[],
+            vec![],
+            Builtin::BanList,
+            "List the banned names.",
+        );
+        add(
+            "/banexactlist",
+            &[],
+            vec![],
+            Builtin::BanExactList,
+            "List the exact banned names.",
+        );
+        add(
+            "/filter",
+            &["/banmsg"],
+            vec![arg("text", Text)],
+            Builtin::Filter,
+            "Hide the messages containing the text.",
+        );
+        add(
+            "/unfilter",
+            &[],
+            vec![arg("text", MsgFilter)],
+            Builtin::Unfilter,
+            "Remove a message filter.",
+        );
+        add(
+            "/filterlist",
+            &[],
+            vec![],
+            Builtin::FilterList,
+            "List the message filters.",
+        );
+        add(
+            "/allow",
+            &[],
+            vec![arg("user", User)],
+            Builtin::Allow,
+            "Never filter nor kick this user.",
+        );
+        add(
+            "/revoke",
+            &[],
+            vec![arg("user", User)],
+            Builtin::Revoke,
+            "Remove a user from the allowlist.",
+        );
+        add(
+            "/allowlist",
+            &[],
+            vec![],
+            Builtin::AllowList,
+            "List the allowed users.",
+        );
+        add(
+            "/mute",
+            &[],
+            vec![opt("user", User)],
+            Builtin::Mute,
+            "Toggle the notifications for a user, or list the muted users.",
+        );
+        add(
+            "/sound",
+            &[],
+            vec![
+                arg("action", Choice(&["test"])),
+                arg("event", Choice(crate::notify::EVENTS)),
+            ],
+            Builtin::Sound,
+            "Play the sound of an event.",
+        );
+        add(
+            "/ignore",
+            &[],
+            vec![arg("user", User)],
+            Builtin::Ignore,
+            "Ignore a user.",
+        );
+        add(
+            "/unignore",
+            &[],
+            vec![arg("user", User)],
+            Builtin::Unignore,
+            "Stop ignoring a user.",
+        );
+        add(
+            "/dl",
+            &[],
+            vec![opt("count", Number)],
+            Builtin::DeleteLast,
+            "Delete your last message, or the last count of them (\"/dl5\" works too).",
+        );
+        add(
+            "/dall",
+            &[],
+            vec![],
+            Builtin::DeleteAll,
+            "Delete all your messages.",
+        );
+        add(
+            "/nick",
+            &[],
+            vec![arg("name", Text)],
+            Builtin::Nick,
+            "Change your nickname.",
+        );
+        add(
+            "/color",
+            &[],
+            vec![arg("color", Color)],
+            Builtin::Color,
+            "Change your color, a name or #rrggbb.",
+        );
+        add(
+            "/cycles",
+            &[],
+            vec![],
+            Builtin::Cycles,
+            "Stop cycling colors.",
+        );
+        add("/cycle1", &[], vec![], Builtin::Cycle1, "Cycle your color.");
+        add(
+            "/cycle2",
+            &[],
+            vec![],
+            Builtin::Cycle2,
+            "Cycle your color and your nickname.",
+        );
+        add(
+            "/u",
+            &[],
+            vec![
+                opt("flag", Flag(&["-s", "-r"])),
+                opt("path", Path),
+                opt("target", Target),
+                opt("message", Text),
+            ],
+            Builtin::Upload,
+            "Upload a file, picked in a file browser without a path. -s strips the metadata, -r sends the file as is.",
+        );
+        add(
+            "/paste",
+            &[],
+            vec![
+                opt("flag", Flag(&["-s", "-r"])),
+                opt("target", Target),
+                opt("message", Text),
+            ],
+            Builtin::Paste,
+            "Upload the image in the clipboard.",
+        );
+        add(
+            "/outbox",
+            &[],
+            vec![arg("action", Choice(&["retry", "drop", "edit"]))],
+            Builtin::Outbox,
+            "Retry or drop the failed outgoing items, or edit the last failed message.",
+        );
+        add(
+            "/help",
+            &[],
+            vec![opt("command", ArgKind::Command)],
+            Builtin::Help,
+            "List the commands, or show the help of one.",
+        );
+        registry
+    }
+
+    /// Add a command, replacing the one with the same name.
+    pub fn register(&mut self, command: Command) {
+        match self.commands.iter_mut().find(|c| c.name == command.name) {
+            Some(c) => *c = command,
+            None => self.commands.push(command),
+        }
+    }
+
+    /// Add a "!name" command which posts "text".
+    pub fn register_custom(&mut self, name: &str, text: &str) {
+        self.register(Command {
+            name: format!("!{}", name),
+            aliases: vec![],
+            args: vec![],
+            help: format!("Post \"{}\".", text),
+            run: Run::Post(text.to_owned()),
+        });
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Command> {
+        self.commands
+            .iter()
+            .find(|c| c.name == name || c.aliases.iter().any(|a| a == name))
+    }
+
+    /// Names & aliases of all the commands, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        let mut out: Vec<&str> = self
+            .commands
+            .iter()
+            .flat_map(|c| std::iter::once(&c.name).chain(c.aliases.iter()))
+            .map(|n| n.as_str())
+            .collect();
+        out.sort_unstable();
+        out
+    }
+
+    /// Parse the input as a command. Input that isn't a command gives None,
+    /// unknown "/commands" & bad arguments give the error to display.
+    pub fn parse(&self, input: &str) -> Result<Option<(Run, Args)>, String> {
+        if !input.starts_with('/') && !input.starts_with('!') {
+            return Ok(None);
+        }
+        let (name, rest) = split_word(input);
+        let (cmd, rest) = match self.find(name) {
+            Some(cmd) => (cmd, rest),
+            None => match self.find_with_number(name) {
+                Some((cmd, number)) if rest.is_empty() => (cmd, number),
+                _ if name.starts_with('/') => {
+                    return Err(format!("Unknown command {}, see /help", name));
+                }
+                // Not one of ours, it's a message
+                _ => return Ok(None),
+            },
+        };
+        let args =
+            parse_args(&cmd.args, rest).map_err(|e| format!("{}, usage: {}", e, cmd.usage()))?;
+        Ok(Some((cmd.run.clone(), args)))
+    }
+
+    // "/dl5" is "/dl 5"
+    fn find_with_number<'a>(&self, name: &'a str) -> Option<(&Command, &'a str)> {
+        let base = name.trim_end_matches(|c: char| c.is_ascii_digit());
+        let cmd = self.find(base)?;
+        let takes_number = cmd.args.first().map(|a| a.kind) == Some(ArgKind::Number);
+        if base.len() == name.len() || !takes_number {
+            return None;
+        }
+        Some((cmd, &name[base.len()..]))
+    }
+
+    /// The help of a command, or the list of all of them.
+    pub fn help(&self, topic: Option<&str>) -> Result<Vec<String>, String> {
+        let topic = match topic {
+            None => {
+                let usages: Vec<String> = self.commands.iter().map(|c| c.usage()).collect();
+                let width = usages.iter().map(|u| u.len()).max().unwrap_or(0);
+                let mut lines = vec!["/help <command> for the details of a command".to_owned()];
+                lines.push(String::new());
+                for (cmd, usage) in self.commands.iter().zip(usages) {
+                    let summary = cmd.help.split(". ").next().unwrap_or("");
+                    lines.push(format!("{:width$}  {}", usage, summary, width = width));
+                }
+                return Ok(lines);
+            }
+            Some(topic) => topic,
+        };
+        let cmd = self
+            .find(topic)
+            .or_else(|| self.find(&format!("/{}", topic)))
+            .or_else(|| self.find(&format!("!{}", topic)))
+            .ok_or_else(|| format!("Unknown command {}, see /help", topic))?;
+        let mut lines = vec![format!("Usage: {}", cmd.usage())];
+        if !cmd.aliases.is_empty() {
+            lines.push(format!("Aliases: {}", cmd.aliases.join(", ")));
+        }
+        lines.push(String::new());
+        lines.push(cmd.help.clone());
+        Ok(lines)
+    }
+}
+
+fn parse_args(specs: &[ArgSpec], rest: &str) -> Result<Args, String> {
+    let mut args = Args::default();
+    let mut rest = rest;
+    for spec in specs {
+        let rest_of_line = matches!(
+            spec.kind,
+            ArgKind::Text | ArgKind::Name | ArgKind::BanFilter | ArgKind::MsgFilter
+        );
+        if !rest_of_line {
+            rest = rest.trim_start();
+        }
+        if rest.is_empty() {
+            if spec.optional {
+                continue;
+            }
+            return Err(format!("missing {}", spec.usage()));
+        }
+        if rest_of_line {
+            args.0.push((spec.name, rest.to_owned()));
+            rest = "";
+            continue;
+        }
+        let (word, after) = match spec.kind {
+            ArgKind::Path if rest.starts_with('"') => match rest[1..].split_once('"') {
+                Some((path, after)) => (path, split_word(after).1),
+                None => return Err("unclosed quote".to_owned()),
+            },
+            _ => split_word(rest),
+        };
+        let value = match spec.kind {
+            ArgKind::Flag(flags) if !flags.contains(&word) => continue,
+            ArgKind::Target => match word.strip_prefix('@') {
+                Some(target) => target,
+                None if spec.optional => continue,
+                None => return Err(format!("{} must start with @", spec.name)),
+            },
+            ArgKind::Choice(choices) if !choices.contains(&word) => {
+                return Err(format!(
+                    "{} must be one of {}",
+                    spec.name,
+                    choices.join(", ")
+                ));
+            }
+            ArgKind::Number if word.parse::<usize>().is_err() => {
+                return Err(format!("{} must be a number", spec.name));
+            }
+            _ => word,
+        };
+        args.0.push((spec.name, value.to_owned()));
+        rest = after;
+    }
+    if !rest.trim().is_empty() {
+        return Err("too many arguments".to_owned());
+    }
+    Ok(args)
+}
+
+// The first word and what follows the space after it.
+fn split_word(s: &str) -> (&str, &str) {
+    match s.char_indices().find(|(_, c)| c.is_whitespace()) {
+        Some((i, c)) => (&s[..i], &s[i + c.len_utf8()..]),
+        None => (s, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Result<Option<(Run, Args)>, String> {
+        let mut registry = Registry::builtin();
+        registry.register_custom("rules", "Read the rules");
+        registry.parse(input)
+    }
+
+    fn args(input: &str) -> Vec<(&'static str, String)> {
+        parse(input).unwrap().unwrap().1 .0
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(parse("hello"), Ok(None));
+        assert_eq!(parse("!unknown"), Ok(None));
+        assert_eq!(
+            parse("/nope"),
+            Err("Unknown command /nope, see /help".to_owned())
+        );
+        assert_eq!(
+            args("/pm bob hi there"),
+            vec![("user", "bob".into()), ("message", "hi there".into())]
+        );
+        assert_eq!(args("/nick big bob"), vec![("name", "big bob".into())]);
+        assert_eq!(
+            parse("/pm bob"),
+            Err("missing <message>, usage: /pm <user> <message>".to_owned())
+        );
+        let (action, kick) = parse("/k bob").unwrap().unwrap();
+        assert_eq!(action, Run::Builtin(Builtin::Kick));
+        assert_eq!(kick.get("user"), Some("bob"));
+        assert_eq!(kick.get("message"), None);
+        assert_eq!(args("/ban \"a b\""), vec![("name", "\"a b\"".into())]);
+        assert_eq!(args("/dl5"), vec![("count", "5".into())]);
+        assert_eq!(args("/dl"), vec![]);
+        assert!(parse("/dl x").is_err());
+        assert!(parse("/dall now").is_err());
+        assert!(parse("/outbox redo").is_err());
+        assert_eq!(
+            parse("!rules").unwrap().unwrap().0,
+            Run::Post("Read the rules".to_owned())
+        );
+    }
+
+    #[test]
+    fn parse_upload_test() {
+        assert_eq!(args("/u"), vec![]);
+        assert_eq!(
+            args("/u -s \"my cat.png\" @bob look"),
+            vec![
+                ("flag", "-s".into()),
+                ("path", "my cat.png".into()),
+                ("target", "bob".into()),
+                ("message", "look".into())
+            ]
+        );
+        assert_eq!(
+            args("/u cat.png look at this"),
+            vec![
+                ("path", "cat.png".into()),
+                ("message", "look at this".into())
+            ]
+        );
+        assert_eq!(args("/paste @members"), vec![("target", "members".into())]);
+    }
+
+    #[test]
+    fn help_test() {
+        let registry = Registry::builtin();
+        let kick = registry.help(Some("k")).unwrap();
+        assert_eq!(kick[0], "Usage: /kick <user> [message]");
+        assert_eq!(kick[1], "Aliases: /k");
+        assert_eq!(
+            registry.help(Some("outbox")).unwrap()[0],
+            "Usage: /outbox retry|drop|edit"
+        );
+        assert!(registry.help(Some("nope")).is_err());
+        let all = registry.help(None).unwrap();
+        assert!(all
+            .iter()
+            .any(|l| l.starts_with("/kick <user> [message] ") && l.ends_with("  Kick a user.")));
+    }
+}
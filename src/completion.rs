@@ -0,0 +1,263 @@
+use crate::commands::{ArgKind, Registry};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What the completion can pick from, gathered by the client.
+#[derive(Default)]
+pub struct Sources {
+    /// Users in the chat, most recently active first.
+    pub users: Vec<String>,
+    pub ban_filters: Vec<String>,
+    pub msg_filters: Vec<String>,
+    pub colors: Vec<String>,
+}
+
+/// Candidates for the text in "start..end" of the input, cycled with Tab.
+#[derive(Debug, PartialEq)]
+pub struct Completion {
+    pub start: usize,
+    pub end: usize,
+    /// What was typed before the completion started
+    pub original: String,
+    pub candidates: Vec<String>,
+    pub selected: Option<usize>,
+}
+
+impl Completion {
+    /// Select the next (or previous) candidate, returns the new input & cursor.
+    pub fn cycle(&mut self, input: &str, forward: bool) -> (String, usize) {
+        let len = self.candidates.len();
+        self.selected = Some(match (self.selected, forward) {
+            (None, true) => 0,
+            (None, false) => len - 1,
+            (Some(idx), true) => (idx + 1) % len,
+            (Some(idx), false) => (idx + len - 1) % len,
+        });
+        let candidate = self.candidates[self.selected.unwrap()].clone();
+        self.replace(input, &candidate)
+    }
+
+    /// Put back what was typed.
+    pub fn cancel(&mut self, input: &str) -> (String, usize) {
+        let original = self.original.clone();
+        self.replace(input, &original)
+    }
+
+    fn replace(&mut self, input: &str, with: &str) -> (String, usize) {
+        let out = format!("{}{}{}", &input[..self.start], with, &input[self.end..]);
+        self.end = self.start + with.len();
+        (out, self.end)
+    }
+}
+
+/// Completions for the word before the cursor.
+pub fn complete(
+    input: &str,
+    cursor: usize,
+    registry: &Registry,
+    sources: &Sources,
+) -> Option<Completion> {
+    let before = &input[..cursor];
+    // "/m /kick user" works like "/kick user"
+    let offset = ["/m ", "/s ", "/a "]
+        .iter()
+        .find(|p| before.starts_with(*p) && before[p.len()..].starts_with('/'))
+        .map_or(0, |p| p.len());
+    let line = &before[offset..];
+    let word_start = line.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+    let word = &line[word_start..];
+
+    let (start, candidates) = if word_start == 0 && (word.starts_with('/') || word.starts_with('!'))
+    {
+        let names = registry.names().into_iter().map(|n| n.to_owned());
+        (0, matching(names, word))
+    } else if word.starts_with('@') {
+        let users = sources.users.iter().map(|u| format!("@{}", u));
+        (word_start, matching(users, word))
+    } else {
+        let name = line.split(char::is_whitespace).next().unwrap_or("");
+        let cmd = registry.find(name)?;
+        // Complete the first argument, flags aside.
+        let arg = cmd
+            .args
+            .iter()
+            .find(|a| !matches!(a.kind, ArgKind::Flag(_)))?;
+        // Arguments that can contain spaces start right after the command.
+        let arg_start = name.len() + 1;
+        if line.len() < arg_start {
+            return None;
+        }
+        let first_arg = word_start == arg_start;
+        match arg.kind {
+            ArgKind::User | ArgKind::Name if first_arg => {
+                (word_start, matching(sources.users.iter().cloned(), word))
+            }
+            ArgKind::Color if first_arg => {
+                (word_start, matching(sources.colors.iter().cloned(), word))
+            }
+            ArgKind::Choice(choices) if first_arg => {
+                let choices = choices.iter().map(|c| c.to_string());
+                (word_start, matching(choices, word))
+            }
+            ArgKind::Command if first_arg => {
+                let names = registry.names().into_iter();
+                let names = names.map(|n| n.trim_start_matches('/').to_owned());
+                (word_start, matching(names, word.trim_start_matches('/')))
+            }
+            ArgKind::BanFilter => {
+                let names = sources.ban_filters.iter().cloned();
+                (arg_start, matching(names, &line[arg_start..]))
+            }
+            ArgKind::MsgFilter => {
+                let terms = sources.msg_filters.iter().cloned();
+                (arg_start, matching(terms, &line[arg_start..]))
+            }
+            ArgKind::Path => {
+                // Skip the "-s"/"-r" flag
+                let path_start = match &line[arg_start..] {
+                    rest if rest.starts_with("-s ") || rest.starts_with("-r ") => arg_start + 3,
+                    _ => arg_start,
+                };
+                if line.len() < path_start {
+                    return None;
+                }
+                let typed = &line[path_start..];
+                (path_start, complete_path(typed))
+            }
+            _ => return None,
+        }
+    };
+    if candidates.is_empty() {
+        return None;
+    }
+    Some(Completion {
+        start: offset + start,
+        end: cursor,
+        original: before[offset + start..].to_owned(),
+        candidates,
+        selected: None,
+    })
+}
+
+// Case insensitive prefix match, keeps the order of the candidates.
+fn matching<I: Iterator<Item = String>>(candidates: I, prefix: &str) -> Vec<String> {
+    let prefix = prefix.to_lowercase();
+    candidates
+        .filter(|c| !c.is_empty() && c.to_lowercase().starts_with(&prefix))
+        .collect()
+}
+
+// Files & directories matching the typed path. Paths with spaces are quoted,
+// directories are left open so the completion can go on inside them.
+fn complete_path(typed: &str) -> Vec<String> {
+    let quoted = typed.starts_with('"');
+    let typed = typed.trim_start_matches('"');
+    let (dir, prefix) = match typed.rfind('/') {
+        Some(i) => (&typed[..=i], &typed[i + 1..]),
+        None => ("", typed),
+    };
+    let read_dir: PathBuf = match dir {
+        "" => PathBuf::from("."),
+        d if d.starts_with("~/") => match std::env::var("HOME") {
+            Ok(home) => Path::new(&home).join(&d[2..]),
+            Err(_) => PathBuf::from(d),
+        },
+        d => PathBuf::from(d),
+    };
+    let mut out: Vec<String> = fs::read_dir(read_dir)
+        .map(|rd| {
+            rd.filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let name = e.file_name().to_string_lossy().into_owned();
+                    let hidden = name.starts_with('.') && !prefix.starts_with('.');
+                    if hidden || !name.starts_with(prefix) {
+                        return None;
+                    }
+                    let path = format!("{}{}", dir, name);
+                    let quote = if quoted || path.contains(' ') {
+                        "\""
+                    } else {
+                        ""
+                    };
+                    Some(if e.path().is_dir() {
+                        format!("{}{}/", quote, path)
+                    } else {
+                        format!("{}{}{}", quote, path, quote)
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    out.sort_by(|a, b| a.trim_start_matches('"').cmp(b.trim_start_matches('"')));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources() -> Sources {
+        Sources {
+            users: vec!["bob".into(), "alice".into(), "Bobby".into()],
+            ban_filters: vec!["spam bot".into()],
+            msg_filters: vec!["buy now".into()],
+            colors: vec!["red".into(), "royal-blue".into()],
+        }
+    }
+
+    fn registry() -> Registry {
+        let mut registry = Registry::builtin();
+        registry.register_custom("rules", "Read the rules");
+        registry
+    }
+
+    fn candidates(input: &str) -> Vec<String> {
+        complete(input, input.len(), &registry(), &sources())
+            .map(|c| c.candidates)
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn complete_test() {
+        assert_eq!(candidates("/unf"), vec!["/unfilter"]);
+        assert_eq!(candidates("/kick b"), vec!["bob", "Bobby"]);
+        assert_eq!(candidates("/kick bob b"), Vec::<String>::new());
+        assert_eq!(candidates("/m /pm al"), vec!["alice"]);
+        assert_eq!(candidates("hi @B"), vec!["@bob", "@Bobby"]);
+        assert_eq!(candidates("/unban spam b"), vec!["spam bot"]);
+        assert_eq!(candidates("/unfilter "), vec!["buy now"]);
+        assert_eq!(candidates("/color r"), vec!["red", "royal-blue"]);
+        assert_eq!(candidates("/outbox r"), vec!["retry"]);
+        assert_eq!(candidates("!r"), vec!["!rules"]);
+        assert_eq!(candidates("/help ki"), vec!["kick"]);
+        assert_eq!(candidates("/ban b"), vec!["bob", "Bobby"]);
+        assert_eq!(candidates("hello b"), Vec::<String>::new());
+
+        let input = "/kick b please";
+        let mut c = complete(input, 7, &registry(), &sources()).unwrap();
+        let (out, cursor) = c.cycle(input, true);
+        assert_eq!((out.as_str(), cursor), ("/kick bob please", 9));
+        let (out, _) = c.cycle(&out, true);
+        assert_eq!(out, "/kick Bobby please");
+        let (out, cursor) = c.cancel(&out);
+        assert_eq!((out.as_str(), cursor), (input, 7));
+    }
+
+    #[test]
+    fn complete_path_test() {
+        let dir = std::env::temp_dir().join(format!("bhcli-complete-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub dir")).unwrap();
+        fs::write(dir.join("cat.png"), b"").unwrap();
+        fs::write(dir.join(".hidden"), b"").unwrap();
+        let base = format!("{}/", dir.display());
+        assert_eq!(
+            complete_path(&base),
+            vec![format!("{}cat.png", base), format!("\"{}sub dir/", base)]
+        );
+        assert_eq!(
+            complete_path(&format!("\"{}c", base)),
+            vec![format!("\"{}cat.png\"", base)]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+use anyhow::anyhow;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::{thread_rng, RngCore};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// Encrypt "plaintext", the random nonce is prepended to the output.
+pub fn seal(key: &[u8; KEY_LEN], plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let mut nonce = [0u8; NONCE_LEN];
+    thread_rng().fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| anyhow!("failed to encrypt"))?;
+    let mut out = nonce.to_vec();
+    out.extend(ciphertext);
+    Ok(out)
+}
+
+/// Decrypt data produced by "seal".
+pub fn open(key: &[u8; KEY_LEN], data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if data.len() < NONCE_LEN {
+        return Err(anyhow!("encrypted data is too short"));
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("failed to decrypt (wrong key or corrupted file)"))
+}
+
+/// Compare secrets in constant time, not to give them away.
+pub fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/// Write a file that only the current user can read. The data goes to a file
+/// created with the right permissions, then renamed over "path".
+pub fn write_private(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp = PathBuf::from(tmp_name);
+    // Left by a crash, it could have other permissions
+    let _ = fs::remove_file(&tmp);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp)?;
+    if let Err(e) = file.write_all(data).and_then(|_| file.sync_all()) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Key the local state (sessions, outbox, input history) is sealed with. It
+/// is kept in the vault, so it is only known with the vault passphrase.
+#[derive(Clone)]
+pub struct StorageKey([u8; KEY_LEN]);
+
+impl fmt::Debug for StorageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StorageKey(..)")
+    }
+}
+
+impl StorageKey {
+    pub fn new(key: [u8; KEY_LEN]) -> Self {
+        Self(key)
+    }
+
+    pub fn generate() -> Self {
+        let mut key = [0u8; KEY_LEN];
+        thread_rng().fill_bytes(&mut key);
+        Self(key)
+    }
+
+    pub fn bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    pub fn file(&self, path: PathBuf) -> SealedFile {
+        SealedFile {
+            path,
+            key: self.clone(),
+        }
+    }
+}
+
+/// A file encrypted with the storage key.
+#[derive(Debug, Clone)]
+pub struct SealedFile {
+    path: PathBuf,
+    key: StorageKey,
+}
+
+impl SealedFile {
+    /// None when the file doesn't exist yet.
+    pub fn read(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        match fs::read(&self.path) {
+            Ok(data) => Ok(Some(open(self.key.bytes(), &data)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn write(&self, plaintext: &[u8]) -> anyhow::Result<()> {
+        write_private(&self.path, &seal(self.key.bytes(), plaintext)?)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_open_test() {
+        let key = [7u8; KEY_LEN];
+        let sealed = seal(&key, b"secret").unwrap();
+        assert_ne!(&sealed[NONCE_LEN..], b"secret");
+        assert_eq!(open(&key, &sealed).unwrap(), b"secret");
+        // A new nonce each time
+        assert_ne!(seal(&key, b"secret").unwrap(), sealed);
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open(&key, &tampered).is_err());
+        let mut tampered = sealed.clone();
+        tampered[0] ^= 1;
+        assert!(open(&key, &tampered).is_err());
+        assert!(open(&key, &sealed[..NONCE_LEN - 1]).is_err());
+
+        assert!(open(&[8u8; KEY_LEN], &sealed).is_err());
+    }
+
+    #[test]
+    fn same_test() {
+        assert!(same("secret", "secret"));
+        assert!(!same("secret", "secreT"));
+        assert!(!same("secret", "secret2"));
+    }
+
+    #[test]
+    fn sealed_file_test() {
+        let dir = std::env::temp_dir().join(format!("bhcli-crypto-{}", std::process::id()));
+        let key = StorageKey::generate();
+        let file = key.file(dir.join("state.bin"));
+        assert!(file.read().unwrap().is_none());
+        file.write(b"state").unwrap();
+        assert_eq!(file.read().unwrap().unwrap(), b"state");
+        assert!(!fs::read(file.path())
+            .unwrap()
+            .windows(5)
+            .any(|w| w == b"state"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(file.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(StorageKey::generate()
+            .file(dir.join("state.bin"))
+            .read()
+            .is_err());
+        assert_eq!(format!("{:?}", key), "StorageKey(..)");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
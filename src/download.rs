@@ -0,0 +1,387 @@
+use anyhow::anyhow;
+use reqwest::blocking::Client;
+use reqwest::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// How many finished downloads we keep in the history.
+const HISTORY_LEN: usize = 50;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DownloadState {
+    Downloading { received: u64, total: Option<u64> },
+    Done,
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Download {
+    pub id: u64,
+    pub url: String,
+    pub path: Option<PathBuf>,
+    pub state: DownloadState,
+}
+
+impl Download {
+    pub fn is_active(&self) -> bool {
+        matches!(self.state, DownloadState::Downloading { .. })
+    }
+
+    /// Short human readable description of the download, used by the TUI.
+    pub fn summary(&self) -> String {
+        let name = self
+            .path
+            .as_ref()
+            .and_then(|p| p.file_name())
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| self.url.clone());
+        match &self.state {
+            DownloadState::Downloading {
+                received,
+                total: Some(total),
+            } if *total > 0 => format!(
+                "{} {}% ({})",
+                name,
+                received * 100 / total,
+                human_size(*received)
+            ),
+            DownloadState::Downloading { received, .. } => {
+                format!("{} ({})", name, human_size(*received))
+            }
+            DownloadState::Done => match &self.path {
+                Some(path) => format!("{}", path.display()),
+                None => name,
+            },
+            DownloadState::Failed(err) => format!("{} ({})", name, err),
+        }
+    }
+}
+
+fn human_size(n: u64) -> String {
+    if n >= 1024 * 1024 {
+        format!("{:.1} MB", n as f64 / (1024.0 * 1024.0))
+    } else if n >= 1024 {
+        format!("{:.1} KB", n as f64 / 1024.0)
+    } else {
+        format!("{} B", n)
+    }
+}
+
+/// Downloads files in background threads, using the chat client so the
+/// proxy settings & session cookies are the same as the chat.
+pub struct DownloadManager {
+    client: Client,
+    dir: PathBuf,
+    downloads: Arc<Mutex<Vec<Download>>>,
+    next_id: u64,
+}
+
+impl DownloadManager {
+    pub fn new(client: Client, dir: PathBuf) -> Self {
+        Self {
+            client,
+            dir,
+            downloads: Arc::new(Mutex::new(Vec::new())),
+            next_id: 0,
+        }
+    }
+
+    /// Start downloading "url" in the background. Once done, the file is
+    /// opened with the "open_with" program if any.
+    pub fn start(&mut self, url: String, open_with: Option<&'static str>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        {
+            let mut downloads = self.downloads.lock().unwrap();
+            downloads.push(Download {
+                id,
+                url: url.clone(),
+                path: None,
+                state: DownloadState::Downloading {
+                    received: 0,
+                    total: None,
+                },
+            });
+            let finished = downloads.iter().filter(|d| !d.is_active()).count();
+            if finished > HISTORY_LEN {
+                if let Some(pos) = downloads.iter().position(|d| !d.is_active()) {
+                    downloads.remove(pos);
+                }
+            }
+        }
+        let client = self.client.clone();
+        let dir = self.dir.clone();
+        let downloads = Arc::clone(&self.downloads);
+        thread::spawn(move || {
+            let state = match download(&client, &url, &dir, id, &downloads) {
+                Ok(path) => {
+                    if let Some(program) = open_with {
+                        if let Err(e) = Command::new(program).arg(&path).spawn() {
+                            log::error!("failed to run {}: {}", program, e);
+                        }
+                    }
+                    DownloadState::Done
+                }
+                Err(e) => {
+                    log::error!("failed to download {}: {}", url, e);
+                    DownloadState::Failed(e.to_string())
+                }
+            };
+            update(&downloads, id, |d| d.state = state);
+        });
+    }
+
+    /// All downloads, oldest first.
+    pub fn list(&self) -> Vec<Download> {
+        self.downloads.lock().unwrap().clone()
+    }
+}
+
+fn update<F: FnOnce(&mut Download)>(downloads: &Arc<Mutex<Vec<Download>>>, id: u64, f: F) {
+    if let Some(d) = downloads.lock().unwrap().iter_mut().find(|d| d.id == id) {
+        f(d);
+    }
+}
+
+fn download(
+    client: &Client,
+    url: &str,
+    dir: &Path,
+    id: u64,
+    downloads: &Arc<Mutex<Vec<Download>>>,
+) -> anyhow::Result<PathBuf> {
+    let resp = client.get(url).send()?;
+    if !resp.status().is_success() {
+        return Err(anyhow!("{}", resp.status()));
+    }
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|v: &reqwest::header::HeaderValue| v.to_str().ok())
+            .map(|s| s.to_owned())
+    };
+    let filename = header(CONTENT_DISPOSITION)
+        .and_then(|v| filename_from_disposition(&v))
+        .or_else(|| filename_from_url(url))
+        .map(|name| match header(CONTENT_TYPE) {
+            Some(ct) if Path::new(&name).extension().is_none() => match extension_for(&ct) {
+                Some(ext) => format!("{}.{}", name, ext),
+                None => name,
+            },
+            _ => name,
+        })
+        .unwrap_or_else(|| {
+            let ext = header(CONTENT_TYPE)
+                .and_then(|ct| extension_for(&ct))
+                .unwrap_or("bin");
+            format!("download.{}", ext)
+        });
+    fs::create_dir_all(dir)?;
+    let path = unique_path(dir, &filename);
+    let total = resp.content_length();
+    update(downloads, id, |d| {
+        d.path = Some(path.clone());
+        d.state = DownloadState::Downloading { received: 0, total };
+    });
+
+    save(resp, &path, total, |received| {
+        update(downloads, id, |d| {
+            d.state = DownloadState::Downloading { received, total }
+        });
+    })?;
+    Ok(path)
+}
+
+// Write the body into "path", a partial file is not left behind.
+fn save<R: Read, F: FnMut(u64)>(
+    body: R,
+    path: &Path,
+    total: Option<u64>,
+    progress: F,
+) -> anyhow::Result<()> {
+    let res = copy_body(body, path, total, progress);
+    if res.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    res
+}
+
+fn copy_body<R: Read, F: FnMut(u64)>(
+    mut body: R,
+    path: &Path,
+    total: Option<u64>,
+    mut progress: F,
+) -> anyhow::Result<()> {
+    let mut file = File::create(path)?;
+    let mut buf = [0u8; 16 * 1024];
+    let mut received = 0;
+    loop {
+        let n = body.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        file.write_all(&buf[..n])?;
+        received += n as u64;
+        progress(received);
+    }
+    match total {
+        Some(total) if received < total => Err(anyhow!(
+            "interrupted after {} of {}",
+            human_size(received),
+            human_size(total)
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Keep only the last component of a server provided name, so it cannot
+// escape the download directory.
+fn sanitize_filename(name: &str) -> Option<String> {
+    let name = name.trim().trim_matches('"');
+    let name = name.rsplit(['/', '\\']).next()?;
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+    Some(name.to_owned())
+}
+
+fn filename_from_disposition(value: &str) -> Option<String> {
+    value.split(';').find_map(|part| {
+        let part = part.trim();
+        if let Some(name) = part.strip_prefix("filename*=") {
+            // RFC 5987: charset'lang'value
+            let name = name.rsplit('\'').next()?;
+            return sanitize_filename(&percent_decode(name));
+        }
+        part.strip_prefix("filename=").and_then(sanitize_filename)
+    })
+}
+
+fn filename_from_url(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let last = url.path_segments()?.next_back()?;
+    // Links like "chat.php?action=download..." don't carry the file name
+    if last.ends_with(".php") {
+        return None;
+    }
+    sanitize_filename(&percent_decode(last))
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = s
+            .get(i + 1..i + 3)
+            .filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit()));
+        if let (b'%', Some(hex)) = (bytes[i], hex) {
+            out.push(u8::from_str_radix(hex, 16).unwrap());
+            i += 3;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn extension_for(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_lowercase();
+    Some(match mime.as_str() {
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/bmp" => "bmp",
+        "image/svg+xml" => "svg",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        "audio/mpeg" => "mp3",
+        "audio/ogg" => "ogg",
+        "application/pdf" => "pdf",
+        "application/zip" => "zip",
+        "application/json" => "json",
+        "text/plain" => "txt",
+        "text/html" => "html",
+        _ => return None,
+    })
+}
+
+// Never overwrite an existing file, add " (n)" to the name instead.
+fn unique_path(dir: &Path, filename: &str) -> PathBuf {
+    let path = dir.join(filename);
+    if !path.exists() {
+        return path;
+    }
+    let p = Path::new(filename);
+    let stem = p
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let ext = p.extension().map(|e| e.to_string_lossy().into_owned());
+    (1..)
+        .map(|n| match &ext {
+            Some(ext) => dir.join(format!("{} ({}).{}", stem, n, ext)),
+            None => dir.join(format!("{} ({})", stem, n)),
+        })
+        .find(|p| !p.exists())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filename_test() {
+        assert_eq!(
+            filename_from_disposition(r#"attachment; filename="../../etc/passwd""#),
+            Some("passwd".to_owned())
+        );
+        assert_eq!(
+            filename_from_disposition("attachment; filename*=UTF-8''caf%C3%A9.png"),
+            Some("café.png".to_owned())
+        );
+        assert_eq!(
+            filename_from_url("http://example.onion/upload/a%20b.jpg?x=1"),
+            Some("a b.jpg".to_owned())
+        );
+        assert_eq!(filename_from_url("http://example.onion/"), None);
+        assert_eq!(
+            filename_from_url("http://example.onion/chat.php?action=download&id=1"),
+            None
+        );
+        assert_eq!(extension_for("image/png; charset=binary"), Some("png"));
+        assert_eq!(percent_decode("file%20.txt%21"), "file .txt!");
+        assert_eq!(percent_decode("%+1é%é%"), "%+1é%é%");
+    }
+
+    #[test]
+    fn save_test() {
+        let path = std::env::temp_dir().join(format!("bhcli-download-{}", std::process::id()));
+        let mut seen = 0;
+        save(&b"data"[..], &path, Some(4), |n| seen = n).unwrap();
+        assert_eq!(seen, 4);
+        assert_eq!(fs::read(&path).unwrap(), b"data");
+
+        // The connection died before the end
+        assert!(save(&b"da"[..], &path, Some(4), |_| {}).is_err());
+        assert!(!path.exists());
+        let failing = (&b"da"[..]).chain(FailingReader);
+        assert!(save(failing, &path, None, |_| {}).is_err());
+        assert!(!path.exists());
+    }
+
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("reset"))
+        }
+    }
+}
@@ -0,0 +1,641 @@
+use crate::crypto::SealedFile;
+use crate::text;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EditorConfig {
+    /// How many sent lines are remembered, 0 disables the history.
+    pub history_size: usize,
+    /// Vi like normal mode, entered with Esc.
+    pub vi_mode: bool,
+}
+
+impl Default for EditorConfig {
+    fn default() -> Self {
+        Self {
+            history_size: 500,
+            vi_mode: false,
+        }
+    }
+}
+
+/// Lines sent from the input box, oldest first. Saved, sealed, after every
+/// new line if it has a file.
+#[derive(Default)]
+pub struct History {
+    entries: Vec<String>,
+    max: usize,
+    file: Option<SealedFile>,
+}
+
+impl History {
+    pub fn load(file: Option<SealedFile>, max: usize) -> Self {
+        let entries = match &file {
+            Some(file) if max > 0 => match file.read() {
+                Ok(data) => data
+                    .and_then(|data| serde_json::from_slice(&data).ok())
+                    .unwrap_or_default(),
+                Err(e) => {
+                    log::error!("failed to load history {:?}: {}", file.path(), e);
+                    vec![]
+                }
+            },
+            _ => vec![],
+        };
+        Self { entries, max, file }
+    }
+
+    pub fn push(&mut self, line: &str) {
+        if self.max == 0 || line.trim().is_empty() {
+            return;
+        }
+        if self.entries.last().map(|l| l.as_str()) == Some(line) {
+            return;
+        }
+        self.entries.push(line.to_owned());
+        if self.entries.len() > self.max {
+            let extra = self.entries.len() - self.max;
+            self.entries.drain(..extra);
+        }
+        self.save();
+    }
+
+    fn save(&self) {
+        if let Some(file) = &self.file {
+            match serde_json::to_vec(&self.entries) {
+                Ok(data) => {
+                    if let Err(e) = file.write(&data) {
+                        log::error!("failed to save history {:?}: {}", file.path(), e);
+                    }
+                }
+                Err(e) => log::error!("failed to serialize history: {}", e),
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn get(&self, idx: usize) -> Option<&str> {
+        self.entries.get(idx).map(|s| s.as_str())
+    }
+
+    // Most recent entry older than "before" that contains "query".
+    fn find(&self, query: &str, before: usize) -> Option<usize> {
+        self.entries[..before.min(self.entries.len())]
+            .iter()
+            .rposition(|l| l.contains(query))
+    }
+}
+
+/// Ctrl+R reverse search state.
+struct Search {
+    query: String,
+    // History entry currently matched
+    found: Option<usize>,
+    // What was in the input before the search started
+    saved: (String, usize),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Edit {
+    Insert,
+    Delete,
+    Other,
+}
+
+// How many kills are remembered by the yank ring.
+const KILL_RING_LEN: usize = 20;
+// How many undo steps are kept.
+const UNDO_LEN: usize = 100;
+
+/// Content of the input box, with readline like editing: history, kill ring,
+/// undo/redo and an optional vi normal mode. The cursor is a byte index that
+/// always sits on a grapheme boundary.
+#[derive(Default)]
+pub struct LineEditor {
+    text: String,
+    cursor: usize,
+    history: History,
+    // Position in the history while browsing it with Up/Down, and the line
+    // that was being typed before.
+    history_pos: Option<usize>,
+    draft: String,
+    kill_ring: Vec<String>,
+    // Range of the text inserted by the last yank, and which kill it was.
+    last_yank: Option<(usize, usize, usize)>,
+    last_kill: bool,
+    undo: Vec<(String, usize)>,
+    redo: Vec<(String, usize)>,
+    last_edit: Option<Edit>,
+    search: Option<Search>,
+    vi_mode: bool,
+    vi_normal: bool,
+    // First key of a two keys vi command ("dd", "dw"...)
+    vi_pending: Option<char>,
+}
+
+impl LineEditor {
+    pub fn new(history: History, vi_mode: bool) -> Self {
+        Self {
+            history,
+            vi_mode,
+            ..Self::default()
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Replace the whole content, the cursor goes at the end.
+    pub fn set<S: Into<String>>(&mut self, text: S) {
+        self.checkpoint(Edit::Other);
+        self.text = text.into();
+        self.cursor = self.text.len();
+        self.history_pos = None;
+    }
+
+    pub fn set_cursor(&mut self, idx: usize) {
+        let mut idx = idx.min(self.text.len());
+        while !self.text.is_char_boundary(idx) {
+            idx -= 1;
+        }
+        self.cursor = idx;
+    }
+
+    pub fn clear(&mut self) {
+        self.set("");
+    }
+
+    /// Take the content to send it. It is added to the history.
+    pub fn take(&mut self) -> String {
+        let text = std::mem::take(&mut self.text);
+        self.history.push(&text);
+        self.cursor = 0;
+        self.history_pos = None;
+        self.undo.clear();
+        self.redo.clear();
+        self.last_edit = None;
+        self.vi_normal = false;
+        text
+    }
+
+    // Remember the current state for undo. Consecutive edits of the same
+    // kind (typing a word) are undone at once.
+    fn checkpoint(&mut self, edit: Edit) {
+        if edit == Edit::Other || self.last_edit != Some(edit) {
+            self.undo.push((self.text.clone(), self.cursor));
+            if self.undo.len() > UNDO_LEN {
+                self.undo.remove(0);
+            }
+            self.redo.clear();
+        }
+        self.last_edit = Some(edit);
+        self.last_kill = false;
+        self.last_yank = None;
+    }
+
+    pub fn insert_char(&mut self, c: char) {
+        // A space ends the current undo step
+        self.checkpoint(if c == ' ' { Edit::Other } else { Edit::Insert });
+        self.text.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    pub fn insert_str(&mut self, s: &str) {
+        self.checkpoint(Edit::Other);
+        self.text.insert_str(self.cursor, s);
+        self.cursor += s.len();
+    }
+
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.checkpoint(Edit::Delete);
+            let prev = text::prev_grapheme(&self.text, self.cursor);
+            self.text.replace_range(prev..self.cursor, "");
+            self.cursor = prev;
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if self.cursor < self.text.len() {
+            self.checkpoint(Edit::Delete);
+            let next = text::next_grapheme(&self.text, self.cursor);
+            self.text.replace_range(self.cursor..next, "");
+        }
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = text::prev_grapheme(&self.text, self.cursor);
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = text::next_grapheme(&self.text, self.cursor);
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.text.len();
+    }
+
+    pub fn word_forward(&mut self) {
+        self.cursor = text::next_word(&self.text, self.cursor);
+    }
+
+    pub fn word_backward(&mut self) {
+        self.cursor = text::prev_word(&self.text, self.cursor);
+    }
+
+    // Remove "start..end" and put it in the kill ring. Consecutive kills are
+    // joined in a single ring entry, like readline.
+    fn kill(&mut self, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+        let append = self.last_kill;
+        self.checkpoint(Edit::Other);
+        let killed: String = self.text.drain(start..end).collect();
+        match self.kill_ring.last_mut() {
+            Some(last) if append && start < self.cursor => *last = killed + last,
+            Some(last) if append => last.push_str(&killed),
+            _ => {
+                self.kill_ring.push(killed);
+                if self.kill_ring.len() > KILL_RING_LEN {
+                    self.kill_ring.remove(0);
+                }
+            }
+        }
+        self.cursor = start;
+        self.last_kill = true;
+    }
+
+    /// Ctrl+W
+    pub fn kill_word_backward(&mut self) {
+        let start = text::prev_word(&self.text, self.cursor);
+        self.kill(start, self.cursor);
+    }
+
+    /// Alt+D
+    pub fn kill_word_forward(&mut self) {
+        let end = text::next_word(&self.text, self.cursor);
+        self.kill(self.cursor, end);
+    }
+
+    /// Ctrl+U
+    pub fn kill_to_start(&mut self) {
+        self.kill(0, self.cursor);
+    }
+
+    /// Ctrl+K
+    pub fn kill_to_end(&mut self) {
+        self.kill(self.cursor, self.text.len());
+    }
+
+    /// Ctrl+Y, insert the last killed text.
+    pub fn yank(&mut self) {
+        if let Some(killed) = self.kill_ring.last().cloned() {
+            self.insert_str(&killed);
+            let idx = self.kill_ring.len() - 1;
+            self.last_yank = Some((self.cursor - killed.len(), self.cursor, idx));
+        }
+    }
+
+    /// Alt+Y, right after a yank: replace the yanked text by the previous kill.
+    pub fn yank_pop(&mut self) {
+        if let Some((start, end, idx)) = self.last_yank {
+            let idx = if idx == 0 {
+                self.kill_ring.len() - 1
+            } else {
+                idx - 1
+            };
+            let killed = self.kill_ring[idx].clone();
+            self.text.replace_range(start..end, &killed);
+            self.cursor = start + killed.len();
+            self.last_yank = Some((start, self.cursor, idx));
+        }
+    }
+
+    pub fn undo(&mut self) {
+        if let Some((text, cursor)) = self.undo.pop() {
+            let current = (std::mem::replace(&mut self.text, text), self.cursor);
+            self.redo.push(current);
+            self.cursor = cursor;
+            self.last_edit = None;
+        }
+    }
+
+    pub fn redo(&mut self) {
+        if let Some((text, cursor)) = self.redo.pop() {
+            let current = (std::mem::replace(&mut self.text, text), self.cursor);
+            self.undo.push(current);
+            self.cursor = cursor;
+            self.last_edit = None;
+        }
+    }
+
+    /// Up: replace the input by the previous history entry.
+    /// Returns false if there is none.
+    pub fn history_prev(&mut self) -> bool {
+        let pos = match self.history_pos {
+            Some(0) => return false,
+            Some(pos) => pos - 1,
+            None if self.history.len() == 0 => return false,
+            None => {
+                self.draft = self.text.clone();
+                self.history.len() - 1
+            }
+        };
+        self.load_history(Some(pos));
+        true
+    }
+
+    /// Down: next history entry, or back to the line that was being typed.
+    /// Returns false if we were not browsing the history.
+    pub fn history_next(&mut self) -> bool {
+        match self.history_pos {
+            None => false,
+            Some(pos) if pos + 1 < self.history.len() => {
+                self.load_history(Some(pos + 1));
+                true
+            }
+            Some(_) => {
+                self.load_history(None);
+                true
+            }
+        }
+    }
+
+    fn load_history(&mut self, pos: Option<usize>) {
+        let line = match pos {
+            Some(pos) => self.history.get(pos).unwrap_or("").to_owned(),
+            None => std::mem::take(&mut self.draft),
+        };
+        self.text = line;
+        self.cursor = self.text.len();
+        self.history_pos = pos;
+        self.last_edit = None;
+    }
+
+    /// Ctrl+R: start a reverse search, or look for an older match.
+    pub fn search_backward(&mut self) {
+        match &mut self.search {
+            Some(search) => {
+                let before = search.found.unwrap_or(self.history.len());
+                if let Some(found) = self.history.find(&search.query, before) {
+                    search.found = Some(found);
+                }
+            }
+            None => {
+                self.search = Some(Search {
+                    query: String::new(),
+                    found: None,
+                    saved: (self.text.clone(), self.cursor),
+                });
+            }
+        }
+        self.show_search_match();
+    }
+
+    pub fn is_searching(&self) -> bool {
+        self.search.is_some()
+    }
+
+    pub fn search_query(&self) -> Option<&str> {
+        self.search.as_ref().map(|s| s.query.as_str())
+    }
+
+    pub fn search_push(&mut self, c: char) {
+        if let Some(search) = &mut self.search {
+            search.query.push(c);
+            search.found = self.history.find(&search.query, self.history.len());
+        }
+        self.show_search_match();
+    }
+
+    pub fn search_pop(&mut self) {
+        if let Some(search) = &mut self.search {
+            search.query.pop();
+            search.found = self.history.find(&search.query, self.history.len());
+        }
+        self.show_search_match();
+    }
+
+    fn show_search_match(&mut self) {
+        if let Some(search) = &self.search {
+            let (text, cursor) = match search.found.and_then(|idx| self.history.get(idx)) {
+                Some(line) => {
+                    let cursor = line.find(&search.query).unwrap_or(0);
+                    (line.to_owned(), cursor)
+                }
+                None => search.saved.clone(),
+            };
+            self.text = text;
+            self.cursor = cursor;
+        }
+    }
+
+    /// Keep the matched line in the input.
+    pub fn search_accept(&mut self) {
+        if let Some(search) = self.search.take() {
+            self.undo.push(search.saved);
+            self.redo.clear();
+            self.last_edit = None;
+        }
+    }
+
+    /// Restore the input as it was before the search.
+    pub fn search_cancel(&mut self) {
+        if let Some(search) = self.search.take() {
+            self.text = search.saved.0;
+            self.cursor = search.saved.1;
+        }
+    }
+
+    pub fn vi_mode(&self) -> bool {
+        self.vi_mode
+    }
+
+    pub fn vi_normal(&self) -> bool {
+        self.vi_normal
+    }
+
+    pub fn set_vi_normal(&mut self, normal: bool) {
+        self.vi_normal = normal && self.vi_mode;
+        self.vi_pending = None;
+        if self.vi_normal {
+            // Like vim, leaving the insert mode moves the cursor on the last character
+            if self.cursor == self.text.len() {
+                self.left();
+            }
+        }
+    }
+
+    /// Handle a key in vi normal mode.
+    pub fn vi_key(&mut self, c: char) {
+        if let Some(pending) = self.vi_pending.take() {
+            match (pending, c) {
+                ('d', 'd') => {
+                    self.home();
+                    self.kill_to_end();
+                }
+                ('d', 'w') => self.kill_word_forward(),
+                ('d', 'b') => self.kill_word_backward(),
+                ('d', '$') => self.kill_to_end(),
+                ('d', '0') => self.kill_to_start(),
+                ('c', 'w') => {
+                    self.kill_word_forward();
+                    self.set_vi_normal(false);
+                }
+                ('c', 'c') => {
+                    self.home();
+                    self.kill_to_end();
+                    self.set_vi_normal(false);
+                }
+                _ => {}
+            }
+            return;
+        }
+        match c {
+            'h' => self.left(),
+            'l' => self.right(),
+            'w' => self.word_forward(),
+            'b' => self.word_backward(),
+            '0' | '^' => self.home(),
+            '$' => self.end(),
+            'x' => self.delete(),
+            'X' => self.backspace(),
+            'D' => self.kill_to_end(),
+            'C' => {
+                self.kill_to_end();
+                self.set_vi_normal(false);
+            }
+            'p' => {
+                self.right();
+                self.yank();
+            }
+            'P' => self.yank(),
+            'u' => self.undo(),
+            'k' => {
+                self.history_prev();
+            }
+            'j' => {
+                self.history_next();
+            }
+            'i' => self.set_vi_normal(false),
+            'a' => {
+                self.right();
+                self.set_vi_normal(false);
+            }
+            'I' => {
+                self.home();
+                self.set_vi_normal(false);
+            }
+            'A' => {
+                self.end();
+                self.set_vi_normal(false);
+            }
+            'd' | 'c' => self.vi_pending = Some(c),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor(text: &str) -> LineEditor {
+        let mut e = LineEditor::new(History::load(None, 10), true);
+        for c in text.chars() {
+            e.insert_char(c);
+        }
+        e
+    }
+
+    #[test]
+    fn kill_yank_undo_test() {
+        let mut e = editor("hello big world");
+        e.kill_word_backward();
+        assert_eq!(e.text(), "hello big ");
+        e.kill_word_backward();
+        assert_eq!(e.text(), "hello ");
+        e.yank();
+        assert_eq!(e.text(), "hello big world");
+        e.home();
+        e.kill_to_end();
+        e.yank();
+        e.yank_pop();
+        assert_eq!(e.text(), "big world");
+        // Undo the yank, then the kill
+        e.undo();
+        assert_eq!(e.text(), "");
+        e.undo();
+        assert_eq!(e.text(), "hello big world");
+        e.redo();
+        assert_eq!(e.text(), "");
+    }
+
+    #[test]
+    fn history_test() {
+        let mut e = editor("first");
+        e.take();
+        e.set("second");
+        e.take();
+        e.set("draft");
+        assert!(e.history_prev());
+        assert_eq!(e.text(), "second");
+        assert!(e.history_prev());
+        assert!(!e.history_prev());
+        assert_eq!(e.text(), "first");
+        assert!(e.history_next());
+        assert!(e.history_next());
+        assert_eq!(e.text(), "draft");
+        assert!(!e.history_next());
+
+        e.search_backward();
+        e.search_push('i');
+        assert_eq!(e.text(), "first");
+        e.search_cancel();
+        assert_eq!(e.text(), "draft");
+    }
+
+    #[test]
+    fn history_file_test() {
+        let dir = std::env::temp_dir().join(format!("bhcli-history-{}", std::process::id()));
+        let file = crate::crypto::StorageKey::generate().file(dir.join("history.bin"));
+        let mut history = History::load(Some(file.clone()), 2);
+        history.push("/pm alice secret");
+        history.push("hello");
+        history.push("world");
+        let data = std::fs::read(file.path()).unwrap();
+        assert!(!data.windows(5).any(|w| w == b"hello"));
+        let history = History::load(Some(file), 2);
+        assert_eq!(history.entries, vec!["hello", "world"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn vi_test() {
+        let mut e = editor("one two");
+        e.set_vi_normal(true);
+        assert_eq!(e.cursor(), 6);
+        e.vi_key('0');
+        e.vi_key('d');
+        e.vi_key('w');
+        assert_eq!(e.text(), "two");
+        e.vi_key('A');
+        assert!(!e.vi_normal());
+        e.insert_char('!');
+        assert_eq!(e.text(), "two!");
+    }
+}
@@ -0,0 +1,437 @@
+//! Message filters: "from:bob has:link", "channel:pm or is:mention",
+//! "not (role:guest and re:spam)"... Terms are matched against the facts
+//! extracted once per message, so filtering does not parse the messages again
+//! on every frame.
+use chrono::{Duration, NaiveDateTime, NaiveTime};
+use regex::{Regex, RegexBuilder};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A named filter from the config, selected with its hotkey or `/view`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedView {
+    pub name: String,
+    pub filter: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Public,
+    Members,
+    Staff,
+    Pm,
+    System,
+}
+
+impl Channel {
+    /// Not readable by guests.
+    pub fn is_private(self) -> bool {
+        matches!(self, Channel::Members | Channel::Staff | Channel::Pm)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Guest,
+    Member,
+    Staff,
+    Admin,
+}
+
+/// What the filters can know about a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Facts {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub channel: Channel,
+    /// Lower case text of the whole message
+    pub text: String,
+    /// The message as typed, without its sender
+    pub body: String,
+    pub has_link: bool,
+    pub has_upload: bool,
+    pub mention: bool,
+    pub time: Option<NaiveDateTime>,
+    /// The message it answers, see the replies module
+    pub reply_to: Option<u64>,
+}
+
+/// A message being filtered, its flags change after the facts are extracted.
+pub struct Item<'a> {
+    pub facts: &'a Facts,
+    pub deleted: bool,
+    pub hidden: bool,
+}
+
+pub struct Context<'a> {
+    pub username: &'a str,
+    pub roles: &'a HashMap<String, Role>,
+    /// Relative times are relative to this, the time of the newest message
+    pub now: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Time {
+    Ago(Duration),
+    Clock(NaiveTime),
+}
+
+#[derive(Debug, Clone)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    From(String),
+    To(String),
+    Channel(Channel),
+    Role(Role),
+    HasLink,
+    HasUpload,
+    Deleted,
+    Hidden,
+    Mention,
+    After(Time),
+    Before(Time),
+    Text(String),
+    Regex(Regex),
+}
+
+impl Filter {
+    pub fn matches(&self, item: &Item, ctx: &Context) -> bool {
+        let facts = item.facts;
+        // "me" stands for our own username
+        let is_user = |name: &Option<String>, wanted: &str| match name {
+            Some(name) if wanted == "me" => name == ctx.username,
+            Some(name) => name.to_lowercase() == wanted,
+            None => false,
+        };
+        match self {
+            Filter::And(a, b) => a.matches(item, ctx) && b.matches(item, ctx),
+            Filter::Or(a, b) => a.matches(item, ctx) || b.matches(item, ctx),
+            Filter::Not(a) => !a.matches(item, ctx),
+            Filter::From(name) => is_user(&facts.from, name),
+            Filter::To(name) => is_user(&facts.to, name),
+            Filter::Channel(channel) => facts.channel == *channel,
+            Filter::Role(role) => match &facts.from {
+                Some(from) => ctx.roles.get(from).unwrap_or(&Role::Guest) == role,
+                None => false,
+            },
+            Filter::HasLink => facts.has_link,
+            Filter::HasUpload => facts.has_upload,
+            Filter::Deleted => item.deleted,
+            Filter::Hidden => item.hidden,
+            Filter::Mention => facts.mention,
+            Filter::After(t) => match (facts.time, resolve(t, ctx.now)) {
+                (Some(time), Some(limit)) => time >= limit,
+                _ => false,
+            },
+            Filter::Before(t) => match (facts.time, resolve(t, ctx.now)) {
+                (Some(time), Some(limit)) => time < limit,
+                _ => false,
+            },
+            Filter::Text(text) => facts.text.contains(text.as_str()),
+            Filter::Regex(re) => re.is_match(&facts.text),
+        }
+    }
+}
+
+fn resolve(t: &Time, now: Option<NaiveDateTime>) -> Option<NaiveDateTime> {
+    let now = now?;
+    Some(match t {
+        Time::Ago(d) => now - *d,
+        Time::Clock(clock) => now.date().and_time(*clock),
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Word(String),
+    // Quoted, never a keyword
+    Quoted(String),
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            _ => {
+                // A word, "key:value" or "key:\"quoted value\""
+                let mut word = String::new();
+                let mut quoted = false;
+                while let Some(&c) = chars.peek() {
+                    if c == '"' {
+                        chars.next();
+                        // "key:\"value\"" is still a key
+                        quoted |= word.is_empty();
+                        loop {
+                            match chars.next() {
+                                Some('"') => break,
+                                Some(c) => word.push(c),
+                                None => return Err("missing closing quote".to_owned()),
+                            }
+                        }
+                    } else if c.is_whitespace() || c == '(' || c == ')' {
+                        break;
+                    } else {
+                        chars.next();
+                        word.push(c);
+                    }
+                }
+                tokens.push(if quoted {
+                    Token::Quoted(word)
+                } else {
+                    Token::Word(word)
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parse a filter expression. Terms next to each other must all match, "or"
+/// and "not" (or "-term") combine them, parentheses group them.
+pub fn parse(input: &str) -> Result<Filter, String> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let filter = parser.or()?;
+    match parser.tokens.get(parser.pos) {
+        None => Ok(filter),
+        Some(Token::Close) => Err("unexpected )".to_owned()),
+        Some(t) => Err(format!("unexpected {:?}", t)),
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.tokens.get(self.pos), Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn or(&mut self) -> Result<Filter, String> {
+        let mut filter = self.and()?;
+        while self.is_keyword("or") {
+            self.pos += 1;
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, String> {
+        let mut filter = self.unary()?;
+        loop {
+            if self.is_keyword("and") {
+                self.pos += 1;
+            } else if self.is_keyword("or")
+                || matches!(self.tokens.get(self.pos), None | Some(Token::Close))
+            {
+                return Ok(filter);
+            }
+            filter = Filter::And(Box::new(filter), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Filter, String> {
+        if self.is_keyword("not") {
+            self.pos += 1;
+            return Ok(Filter::Not(Box::new(self.unary()?)));
+        }
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        match token {
+            Some(Token::Open) => {
+                let filter = self.or()?;
+                match self.tokens.get(self.pos) {
+                    Some(Token::Close) => {
+                        self.pos += 1;
+                        Ok(filter)
+                    }
+                    _ => Err("missing )".to_owned()),
+                }
+            }
+            Some(Token::Close) => Err("unexpected )".to_owned()),
+            Some(Token::Quoted(text)) => Ok(Filter::Text(text.to_lowercase())),
+            Some(Token::Word(w)) if w.len() > 1 && w.starts_with('-') => {
+                Ok(Filter::Not(Box::new(term(&w[1..])?)))
+            }
+            Some(Token::Word(w)) => term(w),
+            None => Err("missing term".to_owned()),
+        }
+    }
+}
+
+fn term(word: &str) -> Result<Filter, String> {
+    let (key, value) = match word.split_once(':') {
+        Some((key, value)) => (key.to_lowercase(), value),
+        None => return Ok(Filter::Text(word.to_lowercase())),
+    };
+    let lower = value.to_lowercase();
+    Ok(match key.as_str() {
+        "from" => Filter::From(lower),
+        "to" => Filter::To(lower),
+        "channel" | "chan" => Filter::Channel(match lower.as_str() {
+            "public" => Channel::Public,
+            "members" => Channel::Members,
+            "staff" => Channel::Staff,
+            "pm" => Channel::Pm,
+            "system" => Channel::System,
+            _ => return Err(format!("unknown channel \"{}\"", value)),
+        }),
+        "role" => Filter::Role(match lower.as_str() {
+            "guest" => Role::Guest,
+            "member" => Role::Member,
+            "staff" => Role::Staff,
+            "admin" => Role::Admin,
+            _ => return Err(format!("unknown role \"{}\"", value)),
+        }),
+        "has" => match lower.as_str() {
+            "link" => Filter::HasLink,
+            "upload" => Filter::HasUpload,
+            _ => return Err(format!("unknown has:{}", value)),
+        },
+        "is" => match lower.as_str() {
+            "deleted" => Filter::Deleted,
+            "hidden" => Filter::Hidden,
+            "mention" => Filter::Mention,
+            _ => return Err(format!("unknown is:{}", value)),
+        },
+        "mentions" if lower == "me" => Filter::Mention,
+        "after" => Filter::After(time(value)?),
+        "before" => Filter::Before(time(value)?),
+        "text" => Filter::Text(lower),
+        "re" => Filter::Regex(
+            RegexBuilder::new(value)
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| format!("bad regex: {}", e))?,
+        ),
+        // Anything else, like an url, is text
+        _ => Filter::Text(word.to_lowercase()),
+    })
+}
+
+// "30s", "15m", "2h", "1d" ago, or a time of the day "13:45".
+fn time(value: &str) -> Result<Time, String> {
+    let err = || format!("bad time \"{}\", use 15m, 2h, 1d or 13:45", value);
+    if value.contains(':') {
+        let clock = NaiveTime::parse_from_str(value, "%H:%M")
+            .or_else(|_| NaiveTime::parse_from_str(value, "%H:%M:%S"))
+            .map_err(|_| err())?;
+        return Ok(Time::Clock(clock));
+    }
+    let split = value.len().checked_sub(1).ok_or_else(err)?;
+    let n: i64 = value[..split].parse().map_err(|_| err())?;
+    let duration = match &value[split..] {
+        "s" => Duration::seconds(n),
+        "m" => Duration::minutes(n),
+        "h" => Duration::hours(n),
+        "d" => Duration::days(n),
+        _ => return Err(err()),
+    };
+    Ok(Time::Ago(duration))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(h: u32, m: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 5, 1)
+            .unwrap()
+            .and_hms_opt(h, m, 0)
+            .unwrap()
+    }
+
+    fn facts(from: &str, to: Option<&str>, text: &str) -> Facts {
+        Facts {
+            from: Some(from.to_owned()),
+            to: to.map(|t| t.to_owned()),
+            channel: if to.is_some() {
+                Channel::Pm
+            } else {
+                Channel::Public
+            },
+            text: text.to_lowercase(),
+            body: text.to_owned(),
+            has_link: text.contains("http"),
+            has_upload: false,
+            mention: text.contains("@me"),
+            time: Some(at(12, 0)),
+            reply_to: None,
+        }
+    }
+
+    fn matches(filter: &str, facts: &Facts) -> bool {
+        let mut roles = HashMap::new();
+        roles.insert("Mod".to_owned(), Role::Staff);
+        let ctx = Context {
+            username: "me",
+            roles: &roles,
+            now: Some(at(12, 30)),
+        };
+        let item = Item {
+            facts,
+            deleted: false,
+            hidden: false,
+        };
+        parse(filter).unwrap().matches(&item, &ctx)
+    }
+
+    #[test]
+    fn parse_test() {
+        assert!(parse("from:bob has:link").is_ok());
+        assert!(parse("(channel:pm or is:mention) and not role:guest").is_ok());
+        assert_eq!(
+            parse("channel:nope").unwrap_err(),
+            "unknown channel \"nope\""
+        );
+        assert_eq!(parse("(from:bob").unwrap_err(), "missing )");
+        assert_eq!(parse("from:bob)").unwrap_err(), "unexpected )");
+        assert_eq!(parse("\"abc").unwrap_err(), "missing closing quote");
+        assert!(parse("re:(").is_err());
+        assert!(parse("after:5x").is_err());
+        assert!(parse("not").is_err());
+    }
+
+    #[test]
+    fn matches_test() {
+        let hello = facts("Bob", None, "Hello http://a.onion");
+        let pm = facts("Mod", Some("me"), "hey @me");
+        assert!(matches("from:bob", &hello));
+        assert!(matches("hello has:link", &hello));
+        assert!(matches("HELLO", &hello));
+        assert!(!matches("hello -has:link", &hello));
+        assert!(matches("\"hello http\"", &hello));
+        assert!(matches("re:^hel+o", &hello));
+        assert!(matches("to:me and role:staff", &pm));
+        assert!(!matches("role:staff", &hello));
+        assert!(matches("role:guest", &hello));
+        assert!(matches("channel:pm or from:nobody", &pm));
+        assert!(!matches("not (channel:pm or from:bob)", &pm));
+        assert!(matches("is:mention", &pm));
+        assert!(matches("mentions:me", &pm));
+        assert!(matches("after:45m before:12:01", &hello));
+        assert!(!matches("after:15m", &hello));
+        assert!(matches("http://a.onion", &hello));
+        assert!(matches("from:\"bob\"", &hello));
+    }
+}
@@ -1,4 +1,8 @@
 use regex::Regex;
+use std::collections::VecDeque;
+
+// How many automatic actions are remembered.
+const MOD_LOG_SIZE: usize = 100;
 
 /// The type of harmful content detected.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -90,9 +94,49 @@ pub fn action_from_score(score: u32) -> Option<Action> {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Action {
     Warn,
     Kick,
     Ban,
 }
+
+impl Action {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Warn => "warn",
+            Action::Kick => "kick",
+            Action::Ban => "ban",
+        }
+    }
+}
+
+/// Something done without being asked, because of a filter, a score or a
+/// remote command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModAction {
+    pub date: String,
+    pub user: String,
+    pub action: Action,
+    pub reason: String,
+}
+
+/// The last automatic actions, shared with the dashboard.
+#[derive(Debug, Default)]
+pub struct ModLog {
+    actions: VecDeque<ModAction>,
+}
+
+impl ModLog {
+    pub fn extend(&mut self, actions: Vec<ModAction>) {
+        self.actions.extend(actions);
+        while self.actions.len() > MOD_LOG_SIZE {
+            self.actions.pop_front();
+        }
+    }
+
+    /// Newest first.
+    pub fn recent(&self) -> impl Iterator<Item = &ModAction> {
+        self.actions.iter().rev()
+    }
+}
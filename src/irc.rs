@@ -0,0 +1,674 @@
+//! A local IRC server in front of the chat session, for other clients. The
+//! room is `#chat`, the members and staff chats are `#members` and `#staff`,
+//! PMs are queries, staff & admins are ops and members are voiced. What the
+//! clients send becomes bridge commands, so it goes through the same outbox.
+use crate::bridge::{Command, Event};
+use crate::crypto::same;
+use crate::filter::{Channel, Role};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const SERVER: &str = "bhcli";
+const MAIN_CHANNEL: &str = "#chat";
+// What we posted from IRC, not to echo it back
+const SENT_SIZE: usize = 50;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IrcConfig {
+    pub enabled: bool,
+    /// Must be a loopback address
+    pub listen: String,
+    /// Asked with PASS, any local process could use the session otherwise
+    pub password: Option<String>,
+}
+
+impl Default for IrcConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "127.0.0.1:6667".to_owned(),
+            password: None,
+        }
+    }
+}
+
+impl IrcConfig {
+    pub fn check(&self) -> Result<(), String> {
+        match self.listen.parse::<SocketAddr>() {
+            Ok(addr) if addr.ip().is_loopback() => {}
+            Ok(_) => return Err(format!("\"{}\" is not a local address", self.listen)),
+            Err(e) => return Err(format!("invalid address \"{}\": {}", self.listen, e)),
+        }
+        match &self.password {
+            _ if !self.enabled => Ok(()),
+            Some(password) if password.len() >= 16 => Ok(()),
+            Some(_) => Err("the password must have at least 16 characters".to_owned()),
+            None => Err("a password is needed".to_owned()),
+        }
+    }
+}
+
+fn channel_name(channel: Channel) -> Option<&'static str> {
+    match channel {
+        Channel::Public => Some(MAIN_CHANNEL),
+        Channel::Members => Some("#members"),
+        Channel::Staff => Some("#staff"),
+        Channel::Pm | Channel::System => None,
+    }
+}
+
+// Chat text as IRC lines: a "\r" or any other control character would let
+// the text inject protocol lines, or mess with the client.
+fn irc_lines(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(['\r', '\n'])
+        .map(|line| line.chars().filter(|c| !c.is_control()).collect::<String>())
+        .filter(|line| !line.trim().is_empty())
+}
+
+// Chat names can have characters IRC doesn't allow in nicks.
+fn irc_nick(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            ' ' | '!' | '@' | ':' | ',' | '*' | '?' | '#' | '&' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+fn mode_prefix(role: Role) -> &'static str {
+    match role {
+        Role::Staff | Role::Admin => "@",
+        Role::Member => "+",
+        Role::Guest => "",
+    }
+}
+
+struct Client {
+    out: TcpStream,
+    channels: HashSet<&'static str>,
+}
+
+struct State {
+    username: String,
+    roles: HashMap<String, Role>,
+    /// Only registered clients
+    clients: Vec<Client>,
+    sent: VecDeque<(Option<String>, String)>,
+}
+
+impl State {
+    fn prefix(&self, name: &str) -> String {
+        let nick = irc_nick(name);
+        format!("{}!{}@{}", nick, nick, SERVER)
+    }
+
+    // The chat name of an IRC nick
+    fn chat_name(&self, nick: &str) -> String {
+        self.roles
+            .keys()
+            .find(|name| irc_nick(name) == nick)
+            .cloned()
+            .unwrap_or_else(|| nick.to_owned())
+    }
+
+    fn broadcast<F: Fn(&mut Client) -> Vec<String>>(&mut self, lines: F) {
+        self.clients.retain_mut(|client| {
+            let lines = lines(client);
+            lines.iter().all(|l| send(&mut client.out, l).is_ok())
+        });
+    }
+
+    fn join_lines(&self, channel: &'static str) -> Vec<String> {
+        let me = irc_nick(&self.username);
+        let mut names: Vec<String> = self
+            .roles
+            .iter()
+            .map(|(name, role)| format!("{}{}", mode_prefix(*role), irc_nick(name)))
+            .collect();
+        names.sort();
+        let mut lines = vec![format!(":{} JOIN {}", self.prefix(&self.username), channel)];
+        for chunk in names.chunks(40) {
+            lines.push(format!(
+                ":{} 353 {} = {} :{}",
+                SERVER,
+                me,
+                channel,
+                chunk.join(" ")
+            ));
+        }
+        lines.push(format!(
+            ":{} 366 {} {} :End of /NAMES list.",
+            SERVER, me, channel
+        ));
+        lines
+    }
+}
+
+fn send(out: &mut TcpStream, line: &str) -> io::Result<()> {
+    out.write_all(format!("{}\r\n", line).as_bytes())
+}
+
+pub struct Irc {
+    state: Arc<Mutex<State>>,
+}
+
+impl Irc {
+    /// Commands from the clients are given to "run".
+    pub fn start<F>(cfg: &IrcConfig, username: &str, run: F) -> Self
+    where
+        F: Fn(Command) + Send + Sync + 'static,
+    {
+        let irc = Self {
+            state: Arc::new(Mutex::new(State {
+                username: username.to_owned(),
+                roles: HashMap::new(),
+                clients: vec![],
+                sent: VecDeque::new(),
+            })),
+        };
+        match (cfg.enabled, &cfg.password) {
+            (false, _) => {}
+            (true, None) => log::error!("irc: no password, the gateway is not started"),
+            (true, Some(password)) => match TcpListener::bind(&cfg.listen) {
+                Ok(listener) => irc.serve(listener, password.clone(), run),
+                Err(e) => log::error!("irc: failed to listen on {}: {}", cfg.listen, e),
+            },
+        }
+        irc
+    }
+
+    fn serve<F>(&self, listener: TcpListener, password: String, run: F)
+    where
+        F: Fn(Command) + Send + Sync + 'static,
+    {
+        let state = Arc::clone(&self.state);
+        let run = Arc::new(run);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::error!("irc: {}", e);
+                        continue;
+                    }
+                };
+                let state = Arc::clone(&state);
+                let run = Arc::clone(&run);
+                let password = password.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle_client(stream, &state, password, &*run) {
+                        log::error!("irc: {}", e);
+                    }
+                });
+            }
+        });
+    }
+
+    /// Who is in the chat, joins and leaves come with "publish".
+    pub fn set_users(&self, roles: HashMap<String, Role>) {
+        self.state.lock().unwrap().roles = roles;
+    }
+
+    pub fn publish(&self, event: &Event) {
+        let mut state = self.state.lock().unwrap();
+        if state.clients.is_empty() {
+            return;
+        }
+        let me = irc_nick(&state.username);
+        match event {
+            Event::Message {
+                from,
+                to,
+                channel,
+                text,
+                ..
+            } => {
+                if *from == state.username {
+                    let target = if *channel == Channel::Pm {
+                        to.clone()
+                    } else {
+                        None
+                    };
+                    let key = (target, text.clone());
+                    if let Some(idx) = state.sent.iter().position(|s| *s == key) {
+                        state.sent.remove(idx);
+                        return;
+                    }
+                }
+                let prefix = state.prefix(from);
+                let lines: Vec<String> = match text.strip_prefix("/me ") {
+                    Some(action) => irc_lines(action)
+                        .map(|line| format!("\x01ACTION {}\x01", line))
+                        .collect(),
+                    None => irc_lines(text).collect(),
+                };
+                let target = match channel_name(*channel) {
+                    Some(name) => name.to_owned(),
+                    // Our own PMs go in the query with the other user
+                    None if *from == state.username => match to {
+                        Some(to) => irc_nick(to),
+                        None => return,
+                    },
+                    None => me.clone(),
+                };
+                let join = channel_name(*channel).map(|c| (c, state.join_lines(c)));
+                state.broadcast(|client| {
+                    let mut out = vec![];
+                    if let Some((c, join)) = &join {
+                        if client.channels.insert(c) {
+                            out.extend(join.iter().cloned());
+                        }
+                    }
+                    for line in &lines {
+                        out.push(format!(":{} PRIVMSG {} :{}", prefix, target, line));
+                    }
+                    out
+                });
+            }
+            Event::Join { user } => {
+                let prefix = state.prefix(user);
+                let role = state.roles.get(user).copied().unwrap_or(Role::Guest);
+                let nick = irc_nick(user);
+                state.broadcast(|client| {
+                    let mut lines = vec![format!(":{} JOIN {}", prefix, MAIN_CHANNEL)];
+                    let mode = match role {
+                        Role::Staff | Role::Admin => "+o",
+                        Role::Member => "+v",
+                        Role::Guest => "",
+                    };
+                    if !mode.is_empty() && client.channels.contains(MAIN_CHANNEL) {
+                        lines.push(format!(
+                            ":{} MODE {} {} {}",
+                            SERVER, MAIN_CHANNEL, mode, nick
+                        ));
+                    }
+                    lines
+                });
+            }
+            Event::Leave { user } => {
+                let line = format!(":{} PART {}", state.prefix(user), MAIN_CHANNEL);
+                state.broadcast(|_| vec![line.clone()]);
+            }
+            Event::Moderation { text, .. } => {
+                let lines: Vec<String> = irc_lines(text)
+                    .map(|line| format!(":{} NOTICE {} :{}", SERVER, MAIN_CHANNEL, line))
+                    .collect();
+                state.broadcast(|_| lines.clone());
+            }
+        }
+    }
+}
+
+fn handle_client(
+    stream: TcpStream,
+    state: &Arc<Mutex<State>>,
+    password: String,
+    run: &dyn Fn(Command),
+) -> io::Result<()> {
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let mut out = stream.try_clone()?;
+    let mut authed = false;
+    let (mut nick, mut user) = (None, false);
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let (cmd, params) = parse_line(&line);
+        let registered = state
+            .lock()
+            .unwrap()
+            .clients
+            .iter()
+            .any(|c| c.out.peer_addr().ok() == out.peer_addr().ok());
+        if !registered {
+            match cmd.as_str() {
+                "PASS" => authed = params.first().is_some_and(|p| same(p, &password)),
+                "NICK" => nick = params.first().cloned(),
+                "USER" => user = true,
+                "CAP" if params.first().map(|s| s.as_str()) == Some("LS") => {
+                    send(&mut out, &format!(":{} CAP * LS :", SERVER))?
+                }
+                "QUIT" => return Ok(()),
+                _ => {}
+            }
+            if let (Some(given), true) = (&nick, user) {
+                if !authed {
+                    send(
+                        &mut out,
+                        &format!(":{} 464 {} :Password incorrect", SERVER, given),
+                    )?;
+                    return Ok(());
+                }
+                register(&mut out, state, given)?;
+            }
+            continue;
+        }
+        let mut state = state.lock().unwrap();
+        let me = irc_nick(&state.username);
+        let reply = |code: &str, text: &str| format!(":{} {} {} {}", SERVER, code, me, text);
+        let mut lines = vec![];
+        match (cmd.as_str(), params.as_slice()) {
+            ("PING", p) => lines.push(format!(":{} PONG {} :{}", SERVER, SERVER, p.join(" "))),
+            // Other CTCP requests (VERSION, PING...) are not for the chat
+            ("PRIVMSG", [_, text])
+                if text.starts_with('\x01') && !text.starts_with("\x01ACTION ") => {}
+            ("PRIVMSG", [target, text]) => {
+                let text = match text.strip_prefix("\x01ACTION ") {
+                    Some(action) => format!("/me {}", action.trim_end_matches('\x01')),
+                    None => text.clone(),
+                };
+                let (to, command) = match target.as_str() {
+                    MAIN_CHANNEL => (None, Command::Post { text: text.clone() }),
+                    "#members" => (None, Command::Members { text: text.clone() }),
+                    "#staff" => (None, Command::Staff { text: text.clone() }),
+                    t if t.starts_with('#') => {
+                        lines.push(reply("403", &format!("{} :No such channel", t)));
+                        (
+                            None,
+                            Command::Post {
+                                text: String::new(),
+                            },
+                        )
+                    }
+                    nick => {
+                        let to = state.chat_name(nick);
+                        (
+                            Some(to.clone()),
+                            Command::Pm {
+                                to,
+                                text: text.clone(),
+                            },
+                        )
+                    }
+                };
+                if lines.is_empty() {
+                    if state.sent.len() >= SENT_SIZE {
+                        state.sent.pop_front();
+                    }
+                    state.sent.push_back((to, text));
+                    run(command);
+                }
+            }
+            ("KICK", [_, nick, rest @ ..]) => {
+                let user = state.chat_name(nick);
+                let reason = rest.first().cloned().unwrap_or_default();
+                run(Command::Kick { user, reason });
+            }
+            ("JOIN", [channels, ..]) => {
+                for c in channels.split(',') {
+                    match [MAIN_CHANNEL, "#members", "#staff"]
+                        .iter()
+                        .find(|n| **n == c)
+                    {
+                        Some(c) => {
+                            lines.extend(state.join_lines(c));
+                            if let Some(client) = find_client(&mut state, &out) {
+                                client.channels.insert(c);
+                            }
+                        }
+                        None => lines.push(reply("403", &format!("{} :No such channel", c))),
+                    }
+                }
+            }
+            ("PART", [channels, ..]) => {
+                for c in channels.split(',') {
+                    if let Some(client) = find_client(&mut state, &out) {
+                        client.channels.retain(|n| *n != c);
+                    }
+                    lines.push(format!(":{} PART {}", state.prefix(&state.username), c));
+                }
+            }
+            ("NAMES", [channel, ..]) => match [MAIN_CHANNEL, "#members", "#staff"]
+                .iter()
+                .find(|n| *n == channel)
+            {
+                Some(c) => lines.extend(state.join_lines(c).into_iter().skip(1)),
+                None => lines.push(reply("366", &format!("{} :End of /NAMES list.", channel))),
+            },
+            ("WHO", [channel, ..]) => {
+                let mut users: Vec<(&String, &Role)> = state.roles.iter().collect();
+                users.sort_by_key(|(name, _)| *name);
+                for (name, role) in users {
+                    let nick = irc_nick(name);
+                    let flags = format!("H{}", mode_prefix(*role));
+                    let who = format!(
+                        "{} {} {} {} {} {} :0 {}",
+                        channel, nick, SERVER, SERVER, nick, flags, name
+                    );
+                    lines.push(reply("352", &who));
+                }
+                lines.push(reply("315", &format!("{} :End of /WHO list.", channel)));
+            }
+            ("MODE", [target, ..]) if target.starts_with('#') => {
+                lines.push(reply("324", &format!("{} +nt", target)));
+            }
+            ("MODE", _) => lines.push(reply("221", "+i")),
+            ("QUIT", _) => {
+                state
+                    .clients
+                    .retain(|c| c.out.peer_addr().ok() != out.peer_addr().ok());
+                return Ok(());
+            }
+            // Notices are automatic replies, never sent
+            ("PONG", _) | ("NOTICE", _) | ("USERHOST", _) | ("CAP", _) => {}
+            (cmd, _) => lines.push(reply("421", &format!("{} :Unknown command", cmd))),
+        }
+        for line in lines {
+            send(&mut out, &line)?;
+        }
+    }
+    let mut state = state.lock().unwrap();
+    state
+        .clients
+        .retain(|c| c.out.peer_addr().ok() != out.peer_addr().ok());
+    Ok(())
+}
+
+fn find_client<'a>(state: &'a mut State, out: &TcpStream) -> Option<&'a mut Client> {
+    let addr = out.peer_addr().ok();
+    state
+        .clients
+        .iter_mut()
+        .find(|c| c.out.peer_addr().ok() == addr)
+}
+
+// The client takes the name used in the chat.
+fn register(out: &mut TcpStream, state: &Arc<Mutex<State>>, given: &str) -> io::Result<()> {
+    let mut state = state.lock().unwrap();
+    let me = irc_nick(&state.username);
+    let mut lines = vec![];
+    if given != me {
+        lines.push(format!(":{} NICK {}", given, me));
+    }
+    lines.push(format!(
+        ":{} 001 {} :Welcome to the chat, {}",
+        SERVER, me, state.username
+    ));
+    lines.push(format!(":{} 002 {} :Your host is {}", SERVER, me, SERVER));
+    lines.push(format!(":{} 004 {} {} bhcli i nt", SERVER, me, SERVER));
+    lines.push(format!(
+        ":{} 005 {} CHANTYPES=# PREFIX=(ov)@+ :are supported",
+        SERVER, me
+    ));
+    lines.push(format!(":{} 422 {} :No MOTD", SERVER, me));
+    lines.extend(state.join_lines(MAIN_CHANNEL));
+    for line in &lines {
+        send(out, line)?;
+    }
+    let mut channels = HashSet::new();
+    channels.insert(MAIN_CHANNEL);
+    state.clients.push(Client {
+        out: out.try_clone()?,
+        channels,
+    });
+    Ok(())
+}
+
+// "PRIVMSG #chat :hello there" -> ("PRIVMSG", ["#chat", "hello there"])
+fn parse_line(line: &str) -> (String, Vec<String>) {
+    let mut rest = line.trim_end_matches(['\r', '\n']);
+    // A prefix from the client is ignored
+    if rest.starts_with(':') {
+        rest = rest.split_once(' ').map_or("", |(_, r)| r);
+    }
+    let (head, trailing) = match rest.split_once(" :") {
+        Some((head, trailing)) => (head, Some(trailing)),
+        None => (rest, None),
+    };
+    let mut words = head.split_whitespace();
+    let cmd = words.next().unwrap_or("").to_uppercase();
+    let mut params: Vec<String> = words.map(|w| w.to_owned()).collect();
+    if let Some(trailing) = trailing {
+        params.push(trailing.to_owned());
+    }
+    (cmd, params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+
+    #[test]
+    fn parse_line_test() {
+        assert_eq!(
+            parse_line("PRIVMSG #chat :hello there\r\n"),
+            (
+                "PRIVMSG".to_owned(),
+                vec!["#chat".to_owned(), "hello there".to_owned()]
+            )
+        );
+        assert_eq!(
+            parse_line(":me nick bob"),
+            ("NICK".to_owned(), vec!["bob".to_owned()])
+        );
+        assert_eq!(irc_nick("bob the #1"), "bob_the__1");
+        let lines: Vec<String> = irc_lines("hi\rQUIT :bye\n\n\x00x\x03y\r\n").collect();
+        assert_eq!(lines, vec!["hi", "QUIT :bye", "xy"]);
+        assert!(IrcConfig::default().check().is_ok());
+        let cfg = IrcConfig {
+            listen: "0.0.0.0:6667".to_owned(),
+            ..IrcConfig::default()
+        };
+        assert!(cfg.check().is_err());
+        let mut cfg = IrcConfig {
+            enabled: true,
+            ..IrcConfig::default()
+        };
+        assert!(cfg.check().is_err());
+        cfg.password = Some("short".to_owned());
+        assert!(cfg.check().is_err());
+        cfg.password = Some("long enough secret".to_owned());
+        assert!(cfg.check().is_ok());
+    }
+
+    struct TestClient {
+        out: TcpStream,
+        lines: std::io::Lines<BufReader<TcpStream>>,
+    }
+
+    impl TestClient {
+        fn send(&mut self, line: &str) {
+            write!(self.out, "{}\r\n", line).unwrap();
+        }
+
+        // The next line containing "text"
+        fn expect(&mut self, text: &str) -> String {
+            for line in &mut self.lines {
+                let line = line.unwrap();
+                if line.contains(text) {
+                    return line;
+                }
+            }
+            panic!("no line with {}", text);
+        }
+    }
+
+    #[test]
+    fn session_test() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let cfg = IrcConfig::default();
+        let irc = Irc::start(&cfg, "me", |_| {});
+        let mut roles = HashMap::new();
+        roles.insert("me".to_owned(), Role::Member);
+        roles.insert("boss".to_owned(), Role::Admin);
+        roles.insert("bob".to_owned(), Role::Guest);
+        irc.set_users(roles);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        irc.serve(listener, "secret".to_owned(), move |cmd| {
+            tx.send(cmd).unwrap()
+        });
+
+        let connect = || {
+            let out = TcpStream::connect(addr).unwrap();
+            out.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            let lines = BufReader::new(out.try_clone().unwrap()).lines();
+            TestClient { out, lines }
+        };
+        let mut bad = connect();
+        bad.send("NICK x");
+        bad.send("USER x 0 * :x");
+        bad.expect(" 464 ");
+
+        let mut c = connect();
+        c.send("PASS secret");
+        c.send("NICK someone");
+        c.send("USER x 0 * :x");
+        assert_eq!(c.expect("NICK"), ":someone NICK me");
+        c.expect(" 001 me ");
+        assert_eq!(c.expect(" 353 "), ":bhcli 353 me = #chat :+me @boss bob");
+
+        c.send("PRIVMSG #chat :hello");
+        c.send("PRIVMSG bob :\x01VERSION\x01");
+        c.send("NOTICE bob :\x01VERSION irssi\x01");
+        c.send("PRIVMSG bob :\x01ACTION waves\x01");
+        c.send("KICK #chat bob :spam");
+        c.send("PING x");
+        c.expect("PONG");
+        let cmds: Vec<Command> = rx.try_iter().collect();
+        assert_eq!(
+            cmds,
+            vec![
+                Command::Post {
+                    text: "hello".to_owned()
+                },
+                Command::Pm {
+                    to: "bob".to_owned(),
+                    text: "/me waves".to_owned()
+                },
+                Command::Kick {
+                    user: "bob".to_owned(),
+                    reason: "spam".to_owned()
+                },
+            ]
+        );
+
+        let message = |from: &str, to: Option<&str>, channel, text: &str| Event::Message {
+            date: String::new(),
+            from: from.to_owned(),
+            to: to.map(|t| t.to_owned()),
+            channel,
+            text: text.to_owned(),
+        };
+        // What we sent from IRC is not echoed
+        irc.publish(&message("me", None, Channel::Public, "hello"));
+        irc.publish(&message("bob", Some("me"), Channel::Pm, "psst"));
+        assert_eq!(c.expect("PRIVMSG"), ":bob!bob@bhcli PRIVMSG me :psst");
+        irc.publish(&message("boss", None, Channel::Members, "hi members"));
+        c.expect("JOIN #members");
+        assert_eq!(
+            c.expect("PRIVMSG"),
+            ":boss!boss@bhcli PRIVMSG #members :hi members"
+        );
+        irc.publish(&Event::Join {
+            user: "boss".to_owned(),
+        });
+        c.expect("JOIN #chat");
+        assert_eq!(c.expect("MODE"), ":bhcli MODE #chat +o boss");
+    }
+}
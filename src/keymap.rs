@@ -0,0 +1,667 @@
+//! Key bindings: sequences of keys ("g g", "C-x C-c") mapped to named
+//! actions, for the messages list (normal mode) and the input box (editing
+//! mode). A preset is picked in the config, its bindings can be overridden.
+use anyhow::{anyhow, bail};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeymapConfig {
+    /// "vim" or "emacs"
+    pub preset: String,
+    /// Key sequence => action name, "none" removes the binding.
+    pub normal: HashMap<String, String>,
+    pub editing: HashMap<String, String>,
+}
+
+impl Default for KeymapConfig {
+    fn default() -> Self {
+        Self {
+            preset: "vim".to_owned(),
+            normal: HashMap::new(),
+            editing: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Normal,
+    Editing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyAction {
+    // Normal mode
+    Command,
+    Down,
+    Up,
+    DownFast,
+    UpFast,
+    PageDown,
+    PageUp,
+    SelectTop,
+    /// The n-th saved view, 0 for all the messages
+    View(usize),
+    NextMention,
+    PreviousMention,
+    Unselect,
+    Open,
+    Hide,
+    Copy,
+    CopyLink,
+    DownloadLink,
+    DownloadAndView,
+    ViewImage,
+    Upload,
+    ToggleMute,
+    ToggleSys,
+    ToggleDownloads,
+    ToggleMembersView,
+    ToggleGuestView,
+    ToggleHidden,
+    ToggleFollow,
+    ToggleUsers,
+    ToggleThreads,
+    ToggleModLog,
+    ToggleSplit,
+    GrowSide,
+    ShrinkSide,
+    GrowSplit,
+    ShrinkSplit,
+    Edit,
+    Tag,
+    Pm,
+    Reply,
+    ReplyQuote,
+    Thread,
+    Kick,
+    Ban,
+    BanExact,
+    Warn,
+    Translate,
+    Logout,
+    Quit,
+    // Editing mode
+    Submit,
+    Newline,
+    Complete,
+    CompletePrevious,
+    Cancel,
+    Escape,
+    Left,
+    Right,
+    Home,
+    End,
+    WordForward,
+    WordBackward,
+    Backspace,
+    Delete,
+    Paste,
+    KillWordBackward,
+    KillWordForward,
+    KillToStart,
+    KillToEnd,
+    Yank,
+    YankPop,
+    Undo,
+    Redo,
+    SearchHistory,
+    HistoryPrevious,
+    HistoryNext,
+}
+
+const ACTIONS: &[(&str, KeyAction)] = &[
+    ("command", KeyAction::Command),
+    ("down", KeyAction::Down),
+    ("up", KeyAction::Up),
+    ("down_fast", KeyAction::DownFast),
+    ("up_fast", KeyAction::UpFast),
+    ("page_down", KeyAction::PageDown),
+    ("page_up", KeyAction::PageUp),
+    ("select_top", KeyAction::SelectTop),
+    ("view_none", KeyAction::View(0)),
+    ("view_1", KeyAction::View(1)),
+    ("view_2", KeyAction::View(2)),
+    ("view_3", KeyAction::View(3)),
+    ("view_4", KeyAction::View(4)),
+    ("view_5", KeyAction::View(5)),
+    ("view_6", KeyAction::View(6)),
+    ("view_7", KeyAction::View(7)),
+    ("view_8", KeyAction::View(8)),
+    ("view_9", KeyAction::View(9)),
+    ("next_mention", KeyAction::NextMention),
+    ("previous_mention", KeyAction::PreviousMention),
+    ("unselect", KeyAction::Unselect),
+    ("open", KeyAction::Open),
+    ("hide", KeyAction::Hide),
+    ("copy", KeyAction::Copy),
+    ("copy_link", KeyAction::CopyLink),
+    ("download_link", KeyAction::DownloadLink),
+    ("download_and_view", KeyAction::DownloadAndView),
+    ("view_image", KeyAction::ViewImage),
+    ("upload", KeyAction::Upload),
+    ("toggle_mute", KeyAction::ToggleMute),
+    ("toggle_sys", KeyAction::ToggleSys),
+    ("toggle_downloads", KeyAction::ToggleDownloads),
+    ("toggle_members_view", KeyAction::ToggleMembersView),
+    ("toggle_guest_view", KeyAction::ToggleGuestView),
+    ("toggle_hidden", KeyAction::ToggleHidden),
+    ("toggle_follow", KeyAction::ToggleFollow),
+    ("toggle_users", KeyAction::ToggleUsers),
+    ("toggle_threads", KeyAction::ToggleThreads),
+    ("toggle_modlog", KeyAction::ToggleModLog),
+    ("toggle_split", KeyAction::ToggleSplit),
+    ("grow_side", KeyAction::GrowSide),
+    ("shrink_side", KeyAction::ShrinkSide),
+    ("grow_split", KeyAction::GrowSplit),
+    ("shrink_split", KeyAction::ShrinkSplit),
+    ("edit", KeyAction::Edit),
+    ("tag", KeyAction::Tag),
+    ("pm", KeyAction::Pm),
+    ("reply", KeyAction::Reply),
+    ("reply_quote", KeyAction::ReplyQuote),
+    ("thread", KeyAction::Thread),
+    ("kick", KeyAction::Kick),
+    ("ban", KeyAction::Ban),
+    ("ban_exact", KeyAction::BanExact),
+    ("warn", KeyAction::Warn),
+    ("translate", KeyAction::Translate),
+    ("logout", KeyAction::Logout),
+    ("quit", KeyAction::Quit),
+    ("submit", KeyAction::Submit),
+    ("newline", KeyAction::Newline),
+    ("complete", KeyAction::Complete),
+    ("complete_previous", KeyAction::CompletePrevious),
+    ("cancel", KeyAction::Cancel),
+    ("escape", KeyAction::Escape),
+    ("left", KeyAction::Left),
+    ("right", KeyAction::Right),
+    ("home", KeyAction::Home),
+    ("end", KeyAction::End),
+    ("word_forward", KeyAction::WordForward),
+    ("word_backward", KeyAction::WordBackward),
+    ("backspace", KeyAction::Backspace),
+    ("delete", KeyAction::Delete),
+    ("paste", KeyAction::Paste),
+    ("kill_word_backward", KeyAction::KillWordBackward),
+    ("kill_word_forward", KeyAction::KillWordForward),
+    ("kill_to_start", KeyAction::KillToStart),
+    ("kill_to_end", KeyAction::KillToEnd),
+    ("yank", KeyAction::Yank),
+    ("yank_pop", KeyAction::YankPop),
+    ("undo", KeyAction::Undo),
+    ("redo", KeyAction::Redo),
+    ("search_history", KeyAction::SearchHistory),
+    ("history_previous", KeyAction::HistoryPrevious),
+    ("history_next", KeyAction::HistoryNext),
+];
+
+const VIM_NORMAL: &[(&str, &str)] = &[
+    ("/", "command"),
+    ("j", "down"),
+    ("Down", "down"),
+    ("k", "up"),
+    ("Up", "up"),
+    ("J", "down_fast"),
+    ("K", "up_fast"),
+    ("C-d", "page_down"),
+    ("PageDown", "page_down"),
+    ("C-u", "page_up"),
+    ("PageUp", "page_up"),
+    ("g g", "select_top"),
+    ("M-0", "view_none"),
+    ("M-1", "view_1"),
+    ("M-2", "view_2"),
+    ("M-3", "view_3"),
+    ("M-4", "view_4"),
+    ("M-5", "view_5"),
+    ("M-6", "view_6"),
+    ("M-7", "view_7"),
+    ("M-8", "view_8"),
+    ("M-9", "view_9"),
+    ("n", "next_mention"),
+    ("N", "previous_mention"),
+    ("Esc", "unselect"),
+    ("Enter", "open"),
+    ("Backspace", "hide"),
+    ("y", "copy"),
+    ("C-c", "copy"),
+    ("Y", "copy_link"),
+    ("D", "download_link"),
+    ("d", "download_and_view"),
+    ("I", "view_image"),
+    ("U", "upload"),
+    ("m", "toggle_mute"),
+    ("S", "toggle_sys"),
+    ("W", "toggle_downloads"),
+    ("M", "toggle_members_view"),
+    ("G", "toggle_guest_view"),
+    ("H", "toggle_hidden"),
+    ("F", "toggle_follow"),
+    ("F2", "toggle_users"),
+    ("F3", "toggle_threads"),
+    ("F4", "toggle_modlog"),
+    ("F5", "toggle_split"),
+    ("<", "grow_side"),
+    (">", "shrink_side"),
+    ("[", "shrink_split"),
+    ("]", "grow_split"),
+    ("i", "edit"),
+    ("t", "tag"),
+    ("p", "pm"),
+    ("r", "reply"),
+    ("R", "reply_quote"),
+    ("v", "thread"),
+    ("C-k", "kick"),
+    ("C-b", "ban"),
+    ("C-B", "ban_exact"),
+    ("C-w", "warn"),
+    ("T", "translate"),
+    ("Q", "logout"),
+    ("q", "quit"),
+];
+
+const VIM_EDITING: &[(&str, &str)] = &[
+    ("Enter", "submit"),
+    ("S-Enter", "newline"),
+    ("C-Enter", "newline"),
+    ("Tab", "complete"),
+    ("BackTab", "complete_previous"),
+    ("C-c", "cancel"),
+    ("Esc", "escape"),
+    ("Left", "left"),
+    ("Right", "right"),
+    ("C-a", "home"),
+    ("Home", "home"),
+    ("C-e", "end"),
+    ("End", "end"),
+    ("C-f", "word_forward"),
+    ("C-b", "word_backward"),
+    ("Backspace", "backspace"),
+    ("Delete", "delete"),
+    ("C-v", "paste"),
+    ("C-w", "kill_word_backward"),
+    ("M-d", "kill_word_forward"),
+    ("C-u", "kill_to_start"),
+    ("C-k", "kill_to_end"),
+    ("C-y", "yank"),
+    ("M-y", "yank_pop"),
+    ("C-z", "undo"),
+    ("M-z", "redo"),
+    ("C-r", "search_history"),
+    ("Up", "history_previous"),
+    ("Down", "history_next"),
+];
+
+// Applied on top of the vim preset.
+const EMACS_NORMAL: &[(&str, &str)] = &[
+    ("C-n", "down"),
+    ("C-p", "up"),
+    ("M-n", "down_fast"),
+    ("M-p", "up_fast"),
+    ("C-v", "page_down"),
+    ("M-v", "page_up"),
+    ("M-<", "select_top"),
+    ("C-g", "unselect"),
+    ("C-x C-c", "quit"),
+    ("C-x k", "logout"),
+    ("M-w", "copy"),
+    ("j", "none"),
+    ("k", "none"),
+    ("J", "none"),
+    ("K", "none"),
+    ("g g", "none"),
+    ("q", "none"),
+    ("Q", "none"),
+];
+
+const EMACS_EDITING: &[(&str, &str)] = &[
+    ("C-f", "right"),
+    ("C-b", "left"),
+    ("M-f", "word_forward"),
+    ("M-b", "word_backward"),
+    ("C-d", "delete"),
+    ("C-p", "history_previous"),
+    ("C-n", "history_next"),
+    ("C-g", "cancel"),
+    ("C-_", "undo"),
+];
+
+/// A key with its modifiers. Shift is implied by upper case letters and
+/// symbols, so "J" and "S-j" are the same key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Key {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl Key {
+    fn new(code: KeyCode, mut modifiers: KeyModifiers) -> Self {
+        let code = match code {
+            KeyCode::Char(c)
+                if modifiers.contains(KeyModifiers::SHIFT) && c.is_ascii_lowercase() =>
+            {
+                // Some terminals send shift+j as 'j' with shift
+                modifiers.remove(KeyModifiers::SHIFT);
+                KeyCode::Char(c.to_ascii_uppercase())
+            }
+            KeyCode::Char(c) => {
+                if !c.is_ascii_lowercase() {
+                    modifiers.remove(KeyModifiers::SHIFT);
+                }
+                KeyCode::Char(c)
+            }
+            // Shift+Tab
+            KeyCode::BackTab => {
+                modifiers.remove(KeyModifiers::SHIFT);
+                KeyCode::BackTab
+            }
+            code => code,
+        };
+        Self { code, modifiers }
+    }
+
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = s;
+        // "C-" "M-" "S-" prefixes, "-" alone is a key
+        while rest.len() > 2 && rest.as_bytes()[1] == b'-' {
+            match &rest[..2] {
+                "C-" => modifiers |= KeyModifiers::CONTROL,
+                "M-" => modifiers |= KeyModifiers::ALT,
+                "S-" => modifiers |= KeyModifiers::SHIFT,
+                _ => break,
+            }
+            rest = &rest[2..];
+        }
+        let code = match rest {
+            "Enter" => KeyCode::Enter,
+            "Esc" => KeyCode::Esc,
+            "Tab" => KeyCode::Tab,
+            "BackTab" => KeyCode::BackTab,
+            "Backspace" => KeyCode::Backspace,
+            "Delete" => KeyCode::Delete,
+            "Insert" => KeyCode::Insert,
+            "Up" => KeyCode::Up,
+            "Down" => KeyCode::Down,
+            "Left" => KeyCode::Left,
+            "Right" => KeyCode::Right,
+            "Home" => KeyCode::Home,
+            "End" => KeyCode::End,
+            "PageUp" => KeyCode::PageUp,
+            "PageDown" => KeyCode::PageDown,
+            "Space" => KeyCode::Char(' '),
+            f if f.starts_with('F') && f.len() > 1 => match f[1..].parse() {
+                Ok(n) => KeyCode::F(n),
+                Err(_) => bail!("unknown key \"{}\"", s),
+            },
+            c if c.chars().count() == 1 => KeyCode::Char(c.chars().next().unwrap()),
+            _ => bail!("unknown key \"{}\"", s),
+        };
+        Ok(Self::new(code, modifiers))
+    }
+}
+
+impl From<KeyEvent> for Key {
+    fn from(e: KeyEvent) -> Self {
+        Self::new(e.code, e.modifiers)
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.modifiers.contains(KeyModifiers::CONTROL) {
+            write!(f, "C-")?;
+        }
+        if self.modifiers.contains(KeyModifiers::ALT) {
+            write!(f, "M-")?;
+        }
+        if self.modifiers.contains(KeyModifiers::SHIFT) {
+            write!(f, "S-")?;
+        }
+        match self.code {
+            KeyCode::Char(' ') => write!(f, "Space"),
+            KeyCode::Char(c) => write!(f, "{}", c),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            KeyCode::Esc => write!(f, "Esc"),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+
+fn parse_sequence(s: &str) -> anyhow::Result<Vec<Key>> {
+    let keys = s
+        .split_whitespace()
+        .map(Key::parse)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if keys.is_empty() {
+        bail!("empty key sequence");
+    }
+    Ok(keys)
+}
+
+fn parse_action(s: &str) -> anyhow::Result<Option<KeyAction>> {
+    if s == "none" {
+        return Ok(None);
+    }
+    ACTIONS
+        .iter()
+        .find(|(name, _)| *name == s)
+        .map(|(_, action)| Some(*action))
+        .ok_or_else(|| anyhow!("unknown action \"{}\"", s))
+}
+
+/// What a key did.
+#[derive(Debug, PartialEq)]
+pub enum Lookup {
+    Action(KeyAction),
+    /// The start of a sequence, waiting for the next key
+    Pending,
+    Unbound,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Keymap {
+    normal: Vec<(Vec<Key>, KeyAction)>,
+    editing: Vec<(Vec<Key>, KeyAction)>,
+    pending: Vec<Key>,
+    pending_mode: Option<Mode>,
+    // Keys of the last sequence which didn't match anything
+    dropped: Vec<Key>,
+}
+
+impl Keymap {
+    pub fn from_config(cfg: &KeymapConfig) -> anyhow::Result<Self> {
+        let mut keymap = Self::default();
+        keymap.bind_all(Mode::Normal, VIM_NORMAL.iter().copied())?;
+        keymap.bind_all(Mode::Editing, VIM_EDITING.iter().copied())?;
+        match cfg.preset.as_str() {
+            "vim" => {}
+            "emacs" => {
+                keymap.bind_all(Mode::Normal, EMACS_NORMAL.iter().copied())?;
+                keymap.bind_all(Mode::Editing, EMACS_EDITING.iter().copied())?;
+            }
+            preset => bail!("unknown keymap preset \"{}\"", preset),
+        }
+        let normal = cfg.normal.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+        keymap.bind_all(Mode::Normal, normal)?;
+        let editing = cfg.editing.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+        keymap.bind_all(Mode::Editing, editing)?;
+        Ok(keymap)
+    }
+
+    fn bind_all<'a, I: Iterator<Item = (&'a str, &'a str)>>(
+        &mut self,
+        mode: Mode,
+        bindings: I,
+    ) -> anyhow::Result<()> {
+        for (keys, action) in bindings {
+            let seq = parse_sequence(keys)?;
+            let action = parse_action(action)?;
+            let bindings = self.bindings_mut(mode);
+            bindings.retain(|(k, _)| *k != seq);
+            if let Some(action) = action {
+                bindings.push((seq, action));
+            }
+        }
+        Ok(())
+    }
+
+    fn bindings(&self, mode: Mode) -> &[(Vec<Key>, KeyAction)] {
+        match mode {
+            Mode::Normal => &self.normal,
+            Mode::Editing => &self.editing,
+        }
+    }
+
+    fn bindings_mut(&mut self, mode: Mode) -> &mut Vec<(Vec<Key>, KeyAction)> {
+        match mode {
+            Mode::Normal => &mut self.normal,
+            Mode::Editing => &mut self.editing,
+        }
+    }
+
+    /// Feed a key, it completes a sequence, starts one or isn't bound. When a
+    /// sequence is broken, its first keys are available with `take_dropped`
+    /// and the last key is looked up on its own.
+    pub fn feed(&mut self, mode: Mode, key: KeyEvent) -> Lookup {
+        if self.pending_mode != Some(mode) {
+            self.pending.clear();
+            self.pending_mode = Some(mode);
+        }
+        self.dropped.clear();
+        let key = Key::from(key);
+        self.pending.push(key);
+        if self.lookup(mode) == Lookup::Unbound && self.pending.len() > 1 {
+            self.pending.pop();
+            self.dropped.append(&mut self.pending);
+            self.pending.push(key);
+        }
+        let lookup = self.lookup(mode);
+        if lookup != Lookup::Pending {
+            self.pending.clear();
+        }
+        lookup
+    }
+
+    fn lookup(&self, mode: Mode) -> Lookup {
+        let bindings = self.bindings(mode);
+        if let Some((_, action)) = bindings.iter().find(|(k, _)| *k == self.pending) {
+            return Lookup::Action(*action);
+        }
+        if bindings.iter().any(|(k, _)| k.starts_with(&self.pending)) {
+            return Lookup::Pending;
+        }
+        Lookup::Unbound
+    }
+
+    /// The characters typed at the start of the sequence broken by the last key.
+    pub fn take_dropped(&mut self) -> Vec<char> {
+        self.dropped
+            .drain(..)
+            .filter_map(|k| match k.code {
+                KeyCode::Char(c) if k.modifiers.is_empty() => Some(c),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The keys typed so far of an unfinished sequence.
+    pub fn pending(&self) -> Option<String> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        Some(display_sequence(&self.pending))
+    }
+
+    /// The first sequence bound to the action, for the help.
+    pub fn keys_for(&self, mode: Mode, action: KeyAction) -> Option<String> {
+        self.bindings(mode)
+            .iter()
+            .find(|(_, a)| *a == action)
+            .map(|(keys, _)| display_sequence(keys))
+    }
+}
+
+fn display_sequence(keys: &[Key]) -> String {
+    let keys: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+    keys.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(s: &str) -> KeyEvent {
+        let k = Key::parse(s).unwrap();
+        KeyEvent::new(k.code, k.modifiers)
+    }
+
+    #[test]
+    fn key_parse_test() {
+        assert_eq!(Key::parse("C-k").unwrap().to_string(), "C-k");
+        assert_eq!(Key::parse("S-j").unwrap(), Key::parse("J").unwrap());
+        assert_eq!(
+            Key::from(KeyEvent::new(KeyCode::Char('J'), KeyModifiers::SHIFT)),
+            Key::parse("J").unwrap()
+        );
+        assert_eq!(Key::parse("M-<").unwrap().to_string(), "M-<");
+        assert_eq!(Key::parse("PageDown").unwrap().to_string(), "PageDown");
+        assert!(Key::parse("Nope").is_err());
+    }
+
+    #[test]
+    fn sequence_test() {
+        let mut keymap = Keymap::from_config(&KeymapConfig::default()).unwrap();
+        assert_eq!(keymap.feed(Mode::Normal, key("g")), Lookup::Pending);
+        assert_eq!(keymap.pending(), Some("g".to_owned()));
+        assert_eq!(
+            keymap.feed(Mode::Normal, key("g")),
+            Lookup::Action(KeyAction::SelectTop)
+        );
+        // A broken sequence falls back on the last key
+        keymap.feed(Mode::Normal, key("g"));
+        assert_eq!(
+            keymap.feed(Mode::Normal, key("j")),
+            Lookup::Action(KeyAction::Down)
+        );
+        assert_eq!(keymap.take_dropped(), vec!['g']);
+        assert_eq!(keymap.feed(Mode::Editing, key("x")), Lookup::Unbound);
+    }
+
+    #[test]
+    fn config_test() {
+        let mut cfg = KeymapConfig {
+            preset: "emacs".to_owned(),
+            ..Default::default()
+        };
+        cfg.editing.insert("j k".to_owned(), "escape".to_owned());
+        cfg.normal.insert("C-d".to_owned(), "none".to_owned());
+        let mut keymap = Keymap::from_config(&cfg).unwrap();
+        assert_eq!(
+            keymap.keys_for(Mode::Normal, KeyAction::Quit),
+            Some("C-x C-c".to_owned())
+        );
+        assert_eq!(
+            keymap.keys_for(Mode::Normal, KeyAction::PageDown),
+            Some("PageDown".to_owned())
+        );
+        assert_eq!(
+            keymap.feed(Mode::Editing, key("C-f")),
+            Lookup::Action(KeyAction::Right)
+        );
+        assert_eq!(keymap.feed(Mode::Editing, key("j")), Lookup::Pending);
+        assert_eq!(
+            keymap.feed(Mode::Editing, key("k")),
+            Lookup::Action(KeyAction::Escape)
+        );
+
+        cfg.normal.insert("x".to_owned(), "fly".to_owned());
+        assert!(Keymap::from_config(&cfg).is_err());
+        cfg.preset = "nano".to_owned();
+        assert!(Keymap::from_config(&cfg).is_err());
+    }
+}
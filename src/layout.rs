@@ -0,0 +1,302 @@
+//! Screen layout: the input box, the messages (in one or two columns) and the
+//! side panes (users, PM threads, mod log). Sizes and visible panes can be
+//! changed with keys, they are saved per profile.
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use tui::layout::Rect;
+
+const SIDE_WIDTH: (u16, u16) = (12, 80);
+const SPLIT_PERCENT: (u16, u16) = (20, 80);
+
+/// What can be changed from the keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PaneState {
+    /// Width of the side panes column
+    pub side_width: u16,
+    pub users: bool,
+    pub threads: bool,
+    pub modlog: bool,
+    /// Public messages on the left, PMs & members chat on the right
+    pub split: bool,
+    /// Share of the messages width given to the public column
+    pub split_percent: u16,
+}
+
+impl Default for PaneState {
+    fn default() -> Self {
+        Self {
+            side_width: 25,
+            users: true,
+            threads: false,
+            modlog: false,
+            split: false,
+            split_percent: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LayoutConfig {
+    #[serde(flatten)]
+    pub panes: PaneState,
+    /// Terminals narrower or shorter than this get the compact layout
+    pub compact_width: u16,
+    pub compact_height: u16,
+}
+
+impl Default for LayoutConfig {
+    fn default() -> Self {
+        Self {
+            panes: PaneState::default(),
+            compact_width: 80,
+            compact_height: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pane {
+    Users,
+    Threads,
+    ModLog,
+}
+
+/// Where each part of the screen goes, panes that are hidden are None.
+#[derive(Debug, PartialEq)]
+pub struct Areas {
+    pub compact: bool,
+    pub help: Rect,
+    pub input: Rect,
+    pub messages: Rect,
+    pub private: Option<Rect>,
+    pub users: Option<Rect>,
+    pub threads: Option<Rect>,
+    pub modlog: Option<Rect>,
+}
+
+pub struct Panes {
+    state: PaneState,
+    compact_width: u16,
+    compact_height: u16,
+    path: Option<PathBuf>,
+}
+
+impl Panes {
+    /// The config gives the defaults, what was changed with keys wins.
+    pub fn load(cfg: &LayoutConfig, path: Option<PathBuf>) -> Self {
+        let saved = path
+            .as_ref()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .and_then(|s| serde_json::from_str(&s).ok());
+        let mut panes = Self {
+            state: saved.unwrap_or(cfg.panes),
+            compact_width: cfg.compact_width,
+            compact_height: cfg.compact_height,
+            path,
+        };
+        panes.clamp();
+        panes
+    }
+
+    pub fn is_shown(&self, pane: Pane) -> bool {
+        match pane {
+            Pane::Users => self.state.users,
+            Pane::Threads => self.state.threads,
+            Pane::ModLog => self.state.modlog,
+        }
+    }
+
+    pub fn toggle(&mut self, pane: Pane) {
+        let shown = match pane {
+            Pane::Users => &mut self.state.users,
+            Pane::Threads => &mut self.state.threads,
+            Pane::ModLog => &mut self.state.modlog,
+        };
+        *shown = !*shown;
+        self.save();
+    }
+
+    pub fn toggle_split(&mut self) {
+        self.state.split = !self.state.split;
+        self.save();
+    }
+
+    pub fn resize_side(&mut self, delta: i16) {
+        self.state.side_width = (self.state.side_width as i16 + delta).max(0) as u16;
+        self.clamp();
+        self.save();
+    }
+
+    pub fn resize_split(&mut self, delta: i16) {
+        self.state.split_percent = (self.state.split_percent as i16 + delta).max(0) as u16;
+        self.clamp();
+        self.save();
+    }
+
+    pub fn areas(&self, area: Rect, input_height: u16) -> Areas {
+        let s = &self.state;
+        let compact = area.width < self.compact_width || area.height < self.compact_height;
+        let side_panes: Vec<Pane> = [Pane::Users, Pane::Threads, Pane::ModLog]
+            .iter()
+            .copied()
+            .filter(|p| !compact && self.is_shown(*p))
+            .collect();
+        // The chat always keeps at least half of the screen
+        let side_width = match side_panes.len() {
+            0 => 0,
+            _ => s.side_width.min(area.width / 2),
+        };
+        let main = Rect {
+            width: area.width - side_width,
+            ..area
+        };
+        let help = Rect {
+            height: main.height.min(1),
+            ..main
+        };
+        let input_height = input_height.min(main.height - help.height);
+        let input = Rect {
+            y: help.y + help.height,
+            height: input_height,
+            ..main
+        };
+        let rest = Rect {
+            y: input.y + input.height,
+            height: main.height - help.height - input.height,
+            ..main
+        };
+        let (messages, private) = if s.split && !compact {
+            let public_width = rest.width * s.split_percent / 100;
+            let messages = Rect {
+                width: public_width,
+                ..rest
+            };
+            let private = Rect {
+                x: rest.x + public_width,
+                width: rest.width - public_width,
+                ..rest
+            };
+            (messages, Some(private))
+        } else {
+            (rest, None)
+        };
+
+        let mut areas = Areas {
+            compact,
+            help,
+            input,
+            messages,
+            private,
+            users: None,
+            threads: None,
+            modlog: None,
+        };
+        // Side panes are stacked, the first ones take the remainder
+        let count = side_panes.len() as u16;
+        let mut y = area.y;
+        for (idx, pane) in side_panes.into_iter().enumerate() {
+            let mut height = area.height / count;
+            if (idx as u16) < area.height % count {
+                height += 1;
+            }
+            let r = Some(Rect {
+                x: area.x + main.width,
+                y,
+                width: side_width,
+                height,
+            });
+            y += height;
+            match pane {
+                Pane::Users => areas.users = r,
+                Pane::Threads => areas.threads = r,
+                Pane::ModLog => areas.modlog = r,
+            }
+        }
+        areas
+    }
+
+    fn clamp(&mut self) {
+        let s = &mut self.state;
+        s.side_width = s.side_width.clamp(SIDE_WIDTH.0, SIDE_WIDTH.1);
+        s.split_percent = s.split_percent.clamp(SPLIT_PERCENT.0, SPLIT_PERCENT.1);
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.path {
+            match serde_json::to_string(&self.state) {
+                Ok(s) => {
+                    if let Err(e) = fs::write(path, s) {
+                        log::error!("failed to save layout: {}", e);
+                    }
+                }
+                Err(e) => log::error!("failed to serialize layout: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: u16, y: u16, width: u16, height: u16) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn areas_test() {
+        let mut panes = Panes::load(&LayoutConfig::default(), None);
+        let areas = panes.areas(rect(0, 0, 100, 40), 3);
+        assert!(!areas.compact);
+        assert_eq!(areas.help, rect(0, 0, 75, 1));
+        assert_eq!(areas.input, rect(0, 1, 75, 3));
+        assert_eq!(areas.messages, rect(0, 4, 75, 36));
+        assert_eq!(areas.users, Some(rect(75, 0, 25, 40)));
+        assert_eq!(areas.private, None);
+
+        panes.toggle(Pane::Threads);
+        panes.toggle(Pane::ModLog);
+        panes.toggle_split();
+        panes.resize_side(5);
+        let areas = panes.areas(rect(0, 0, 100, 40), 3);
+        assert_eq!(areas.users, Some(rect(70, 0, 30, 14)));
+        assert_eq!(areas.threads, Some(rect(70, 14, 30, 13)));
+        assert_eq!(areas.modlog, Some(rect(70, 27, 30, 13)));
+        assert_eq!(areas.messages, rect(0, 4, 42, 36));
+        assert_eq!(areas.private, Some(rect(42, 4, 28, 36)));
+
+        // Compact: no side panes, a single messages column
+        let areas = panes.areas(rect(0, 0, 60, 40), 3);
+        assert!(areas.compact);
+        assert_eq!(areas.users, None);
+        assert_eq!(areas.private, None);
+        assert_eq!(areas.messages, rect(0, 4, 60, 36));
+
+        // The input box never overflows the screen
+        let areas = panes.areas(rect(0, 0, 60, 3), 5);
+        assert_eq!(areas.input, rect(0, 1, 60, 2));
+        assert_eq!(areas.messages.height, 0);
+    }
+
+    #[test]
+    fn resize_test() {
+        let mut panes = Panes::load(&LayoutConfig::default(), None);
+        panes.resize_side(-100);
+        assert_eq!(panes.state.side_width, SIDE_WIDTH.0);
+        panes.resize_split(100);
+        assert_eq!(panes.state.split_percent, SPLIT_PERCENT.1);
+
+        let cfg: LayoutConfig = toml::from_str("side_width = 40\nsplit = true").unwrap();
+        assert_eq!(cfg.panes.side_width, 40);
+        assert!(cfg.panes.split && cfg.panes.users);
+        assert_eq!(cfg.compact_width, 80);
+    }
+}
@@ -243,6 +243,28 @@ pub fn login(
     Ok(session.to_owned())
 }
 
+// Cheap check that a session is still alive, by loading the messages view.
+pub fn session_is_valid(
+    client: &Client,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+) -> Result<bool, LoginErr> {
+    let url = format!(
+        "{}/{}?action=view&session={}&lang={}",
+        base_url, page_php, session, LANG
+    );
+    let resp = client.get(url).send()?;
+    match resp.status() {
+        StatusCode::BAD_GATEWAY => return Err(LoginErr::ServerDownErr),
+        StatusCode::INTERNAL_SERVER_ERROR => return Err(LoginErr::ServerDown500Err),
+        _ => {}
+    }
+    let resp = resp.text()?;
+    let doc = Document::from(resp.as_str());
+    Ok(doc.find(Attr("id", "messages")).next().is_some())
+}
+
 pub fn logout(
     client: &Client,
     base_url: &str,
@@ -1,15 +1,41 @@
 mod bhc;
+mod bridge;
+mod commands;
+mod completion;
+mod crypto;
+mod download;
+mod editor;
+mod filter;
+mod harm;
+mod irc;
+mod keymap;
+mod layout;
 mod lechatphp;
+mod notify;
+mod outbox;
+mod preview;
+mod replies;
+mod sanitize;
+mod session;
+mod status;
+mod text;
+mod theme;
+mod transport;
+mod upload;
 mod util;
-mod harm;
+mod vault;
+mod web;
 
 use crate::lechatphp::LoginErr;
 use anyhow::{anyhow, Context};
-use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
-use clap::Parser;
+use bridge::{Bridge, BridgeConfig, Command as BridgeCommand, Event as BridgeEvent};
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, Utc};
+use clap::{Parser, Subcommand};
 use clipboard::ClipboardContext;
 use clipboard::ClipboardProvider;
 use colors_transform::{Color, Rgb};
+use commands::{Args, Builtin, Registry, Run};
+use completion::Completion;
 use crossbeam_channel::{self, after, select};
 use crossterm::event;
 use crossterm::event::Event as CEvent;
@@ -17,33 +43,49 @@ use crossterm::event::{MouseEvent, MouseEventKind};
 use crossterm::{
     event::{DisableMouseCapture, EnableMouseCapture, KeyCode, KeyEvent, KeyModifiers},
     execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    terminal::{
+        disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen, SetTitle,
+    },
 };
+use crypto::{SealedFile, StorageKey};
+use download::{Download, DownloadManager, DownloadState};
+use editor::{EditorConfig, History, LineEditor};
+use filter::{Facts, Filter, Role, SavedView};
+use harm::{action_from_score, score_message, Action, ModAction, ModLog};
+use irc::{Irc, IrcConfig};
+use keymap::{KeyAction, Keymap, KeymapConfig, Lookup, Mode as KeymapMode};
+use layout::{LayoutConfig, Pane, Panes};
 use lazy_static::lazy_static;
 use linkify::LinkFinder;
 use log::LevelFilter;
 use log4rs::append::file::FileAppender;
 use log4rs::encode::pattern::PatternEncoder;
+use notify::{Event as Notice, Notifier, NotifyConfig};
+use outbox::{ItemState, Next, Outbox, OutboxItem, RateLimits};
+use preview::{PreviewCache, PreviewConfig};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use regex::Regex;
 use reqwest::blocking::multipart;
 use reqwest::blocking::Client;
-use reqwest::redirect::Policy;
-use rodio::{source::Source, Decoder, OutputStream};
+use reqwest::cookie::Jar;
 use select::document::Document;
 use select::predicate::{Attr, Name};
 use serde_derive::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::io::Cursor;
-use std::io::{self, Write};
+use status::{Level, StatusLine};
+use std::collections::{HashMap, HashSet};
 use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
 use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
 use std::sync::{Arc, MutexGuard};
 use std::thread;
 use std::time::Duration;
 use std::time::Instant;
+use theme::{Theme, ThemeConfig};
+use transport::TransportConfig;
 use tui::layout::Rect;
 use tui::style::Color as tuiColor;
 use tui::{
@@ -51,19 +93,19 @@ use tui::{
     layout::{Constraint, Direction, Layout},
     style::{Modifier, Style},
     text::{Span, Spans, Text},
-    widgets::{Block, Borders, List, ListItem, Paragraph},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Wrap},
     Frame, Terminal,
 };
-use unicode_width::UnicodeWidthStr;
-use util::StatefulList;
-use harm::{action_from_score, score_message, Action};
+use upload::{FileBrowser, PendingUpload, UploadConfig};
+use util::{scroll_to, StatefulList};
+use vault::Vault;
+use web::{Web, WebConfig};
 
 const LANG: &str = "en";
 const SEND_TO_ALL: &str = "s *";
 const SEND_TO_MEMBERS: &str = "s ?";
 const SEND_TO_STAFFS: &str = "s %";
 const SEND_TO_ADMINS: &str = "s _";
-const SOUND1: &[u8] = include_bytes!("sound1.mp3");
 const DKF_URL: &str = "http://dkforestseeaaq2dqz2uflmlsybvnq2irzn4ygyvu53oazyorednviid.onion";
 const SERVER_DOWN_500_ERR: &str = "500 Internal Server Error, server down";
 const SERVER_DOWN_ERR: &str = "502 Bad Gateway, server down";
@@ -75,21 +117,19 @@ const CAPTCHA_FAILED_SOLVE_ERR: &str = "Failed solve captcha";
 const CAPTCHA_USED_ERR: &str = "Captcha already used or timed out";
 const UNKNOWN_ERR: &str = "Unknown error";
 const DNMX_URL: &str = "http://hxuzjtocnzvv5g2rtg2bhwkcbupmk7rclb6lly3fo4tvqkk5oyrv3nid.onion";
+// The input box never grows taller than this many lines.
+const MAX_INPUT_LINES: usize = 5;
 
 lazy_static! {
     static ref META_REFRESH_RGX: Regex = Regex::new(r#"url='([^']+)'"#).unwrap();
     static ref SESSION_RGX: Regex = Regex::new(r#"session=([^&]+)"#).unwrap();
     static ref COLOR_RGX: Regex = Regex::new(r#"color:\s*([#\w]+)\s*;"#).unwrap();
     static ref COLOR1_RGX: Regex = Regex::new(r#"^#([0-9A-Fa-f]{6})$"#).unwrap();
-    static ref PM_RGX: Regex = Regex::new(r#"^/pm ([^\s]+) (.*)"#).unwrap();
-    static ref KICK_RGX: Regex = Regex::new(r#"^/(?:kick|k) ([^\s]+)\s?(.*)"#).unwrap();
-    static ref IGNORE_RGX: Regex = Regex::new(r#"^/ignore ([^\s]+)"#).unwrap();
-    static ref UNIGNORE_RGX: Regex = Regex::new(r#"^/unignore ([^\s]+)"#).unwrap();
-    static ref DLX_RGX: Regex = Regex::new(r#"^/dl([\d]+)$"#).unwrap();
-    static ref UPLOAD_RGX: Regex = Regex::new(r#"^/u\s([^\s]+)\s?(?:@([^\s]+)\s)?(.*)$"#).unwrap();
     static ref FIND_RGX: Regex = Regex::new(r#"^/f\s(.*)$"#).unwrap();
-    static ref NEW_NICKNAME_RGX: Regex = Regex::new(r#"^/nick\s(.*)$"#).unwrap();
-    static ref NEW_COLOR_RGX: Regex = Regex::new(r#"^/color\s(.*)$"#).unwrap();
+    // What a guest can see, without PMs & the members/staff chats
+    static ref GUEST_VIEW: Filter =
+        filter::parse("not channel:members not channel:staff not channel:pm").unwrap();
+    static ref MEMBER_VIEW: Filter = filter::parse("channel:members or channel:staff").unwrap();
 }
 
 fn default_empty_str() -> String {
@@ -99,7 +139,12 @@ fn default_empty_str() -> String {
 #[derive(Debug, Serialize, Deserialize)]
 struct Profile {
     username: String,
+    // Plaintext password, moved into the vault on startup
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     password: String,
+    // Name of the vault secret holding the password
+    #[serde(default)]
+    password_secret: Option<String>,
     #[serde(default = "default_empty_str")]
     url: String,
     #[serde(default = "default_empty_str")]
@@ -110,12 +155,22 @@ struct Profile {
     members_tag: String,
     #[serde(default = "default_empty_str")]
     keepalive_send_to: String,
+    #[serde(default)]
+    transport: TransportConfig,
+    #[serde(default)]
+    previews: PreviewConfig,
 }
 
 #[derive(Default, Debug, Serialize, Deserialize)]
 struct MyConfig {
     dkf_api_key: Option<String>,
     #[serde(default)]
+    dkf_api_key_secret: Option<String>,
+    #[serde(default)]
+    dnmx_username: Option<String>,
+    #[serde(default)]
+    dnmx_password_secret: Option<String>,
+    #[serde(default)]
     bad_usernames: Vec<String>,
     #[serde(default)]
     bad_exact_usernames: Vec<String>,
@@ -125,6 +180,30 @@ struct MyConfig {
     allowlist: Vec<String>,
     #[serde(default)]
     commands: HashMap<String, String>,
+    #[serde(default)]
+    outbox: RateLimits,
+    #[serde(default)]
+    download_dir: Option<PathBuf>,
+    #[serde(default)]
+    uploads: UploadConfig,
+    #[serde(default)]
+    editor: EditorConfig,
+    #[serde(default)]
+    keymap: KeymapConfig,
+    #[serde(default)]
+    theme: ThemeConfig,
+    #[serde(default)]
+    layout: LayoutConfig,
+    #[serde(default)]
+    views: Vec<SavedView>,
+    #[serde(default)]
+    notifications: NotifyConfig,
+    #[serde(default)]
+    bridge: BridgeConfig,
+    #[serde(default)]
+    irc: IrcConfig,
+    #[serde(default)]
+    web: WebConfig,
     profiles: HashMap<String, Profile>,
 }
 
@@ -158,13 +237,9 @@ struct Opts {
     members_tag: Option<String>,
     #[arg(short, long)]
     dan: bool,
-    #[arg(
-        short,
-        long,
-        env = "BHC_PROXY_URL",
-        default_value = "socks5h://127.0.0.1:9050"
-    )]
-    socks_proxy_url: String,
+    /// Wins over the profile proxy_url [default: socks5h://127.0.0.1:9050]
+    #[arg(short, long, env = "BHC_PROXY_URL")]
+    socks_proxy_url: Option<String>,
     #[arg(long)]
     no_proxy: bool,
     #[arg(long, env = "DNMX_USERNAME")]
@@ -175,7 +250,7 @@ struct Opts {
     profile: String,
 
     //Strange
-    #[arg(long,default_value = "0")]
+    #[arg(long, default_value = "0")]
     keepalive_send_to: Option<String>,
 
     #[arg(long)]
@@ -184,6 +259,13 @@ struct Opts {
     #[arg(long)]
     sxiv: bool,
 
+    #[arg(long, env = "BHC_DOWNLOAD_DIR")]
+    download_dir: Option<PathBuf>,
+
+    /// Do not fetch image attachments to preview them
+    #[arg(long)]
+    no_previews: bool,
+
     #[arg(skip)]
     bad_usernames: Option<Vec<String>>,
     #[arg(skip)]
@@ -192,6 +274,69 @@ struct Opts {
     bad_messages: Option<Vec<String>>,
     #[arg(skip)]
     allowlist: Option<Vec<String>>,
+    #[arg(skip)]
+    outbox: Option<RateLimits>,
+    #[arg(skip)]
+    transport: Option<TransportConfig>,
+    #[arg(skip)]
+    previews: Option<PreviewConfig>,
+    #[arg(skip)]
+    uploads: Option<UploadConfig>,
+    #[arg(skip)]
+    editor: Option<EditorConfig>,
+    #[arg(skip)]
+    keymap: Option<KeymapConfig>,
+    #[arg(skip)]
+    theme: Option<ThemeConfig>,
+    #[arg(skip)]
+    layout: Option<LayoutConfig>,
+    #[arg(skip)]
+    views: Option<Vec<SavedView>>,
+    #[arg(skip)]
+    notifications: Option<NotifyConfig>,
+    #[arg(skip)]
+    bridge: Option<BridgeConfig>,
+    #[arg(skip)]
+    irc: Option<IrcConfig>,
+    #[arg(skip)]
+    web: Option<WebConfig>,
+
+    #[command(subcommand)]
+    command: Option<Cmd>,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// Manage the sessions stored for each profile
+    Session {
+        #[command(subcommand)]
+        action: SessionCmd,
+    },
+    /// Manage the encrypted vault holding passwords and API keys
+    Vault {
+        #[command(subcommand)]
+        action: VaultCmd,
+    },
+}
+
+#[derive(Subcommand)]
+enum VaultCmd {
+    /// Add (or replace) a secret
+    Add { name: String },
+    /// Remove a secret
+    Rm { name: String },
+    /// List secret names
+    List,
+    /// Change the vault passphrase
+    Rekey,
+}
+
+#[derive(Subcommand)]
+enum SessionCmd {
+    /// List stored sessions
+    List,
+    /// Forget the stored session of a profile (defaults to --profile)
+    Drop { profile: Option<String> },
 }
 
 struct LeChatPHPConfig {
@@ -225,9 +370,24 @@ struct LeChatPHPClient {
     base_client: BaseClient,
     guest_color: String,
     client: Client,
+    cookie_jar: Arc<Jar>,
+    profile: String,
+    // Seals what is kept on disk, nothing is kept without a vault
+    storage_key: Option<StorageKey>,
+    // Plaintext outbox of older versions, moved into the sealed one
+    legacy_outbox: Option<PathBuf>,
     session: Option<String>,
     config: LeChatPHPConfig,
-    last_key_event: Option<KeyCode>,
+    keymap: Keymap,
+    theme: Theme,
+    panes: Panes,
+    saved_views: Vec<(String, Filter)>,
+    notifier: Arc<Notifier>,
+    bridge: Arc<Bridge>,
+    irc: Arc<Irc>,
+    web: Web,
+    // What the moderation did on its own
+    mod_log: Arc<Mutex<ModLog>>,
     manual_captcha: bool,
     sxiv: bool,
     refresh_rate: u64,
@@ -240,6 +400,14 @@ struct LeChatPHPClient {
     display_hidden_msgs: bool,
     tx: crossbeam_channel::Sender<PostType>,
     rx: Arc<Mutex<crossbeam_channel::Receiver<PostType>>>,
+    outbox: Arc<Mutex<Outbox>>,
+    downloads: DownloadManager,
+    show_downloads: bool,
+    previews: PreviewCache,
+    uploads: UploadConfig,
+    status: Arc<Mutex<StatusLine>>,
+    editor: EditorConfig,
+    history_file: Option<SealedFile>,
 
     color_tx: crossbeam_channel::Sender<()>,
     color_rx: Arc<Mutex<crossbeam_channel::Receiver<()>>>,
@@ -293,6 +461,7 @@ impl LeChatPHPClient {
                 Ok(()) => {
                     attempt = 0;
                     match self.get_msgs() {
+                        // Keep the session, login() will check if it is still usable.
                         Ok(ExitSignal::NeedLogin) => {}
                         Ok(ExitSignal::Terminate) => return,
                         Err(e) => log::error!("{:?}", e),
@@ -303,7 +472,6 @@ impl LeChatPHPClient {
             if max_retry > 0 && attempt > max_retry {
                 break;
             }
-            self.session = None;
             let retry_in = Duration::from_secs(2);
             let mut msg = format!("retry login in {:?}, attempt: {}", retry_in, attempt);
             if max_retry > 0 {
@@ -323,8 +491,11 @@ impl LeChatPHPClient {
         let send_to = self.config.keepalive_send_to.clone();
         thread::spawn(move || loop {
             let clb = || {
-                tx.send(PostType::Post("keep alive".to_owned(), Some(send_to.clone())))
-                    .unwrap();
+                tx.send(PostType::Post(
+                    "keep alive".to_owned(),
+                    Some(send_to.clone()),
+                ))
+                .unwrap();
                 tx.send(PostType::DeleteLast).unwrap();
             };
             let timeout = after(Duration::from_secs(60 * 55));
@@ -350,23 +521,64 @@ impl LeChatPHPClient {
         let full_url = format!("{}/{}", &self.config.url, &self.config.page_php);
         let session = self.session.clone().unwrap();
         let url = format!("{}?action=post&session={}", &full_url, &session);
+        let outbox = Arc::clone(&self.outbox);
+        let status = Arc::clone(&self.status);
+        outbox.lock().unwrap().reset_for_new_session();
         thread::spawn(move || loop {
-            // select! macro fucks all the LSP, therefore the code gymnastic here
-            let clb = |v: Result<PostType, crossbeam_channel::RecvError>| match v {
-                Ok(post_type_recv) => post_msg(
-                    &client,
-                    post_type_recv,
-                    &full_url,
-                    session.clone(),
-                    &url,
-                    &last_post_tx,
-                ),
-                Err(_) => return,
-            };
             let rx = rx.lock().unwrap();
+            // Everything posted goes through the outbox, which keeps ordering
+            // and rate limits, and remembers what could not be delivered.
+            for post_type in rx.try_iter() {
+                outbox.lock().unwrap().push(post_type);
+            }
+            let next = outbox.lock().unwrap().next(Instant::now());
+            let timeout = match next {
+                Next::Send(id, post_type) => {
+                    let upload = match &post_type {
+                        PostType::Upload(path, _, _) => Some(path.clone()),
+                        _ => None,
+                    };
+                    let res = post_msg(
+                        &client,
+                        post_type,
+                        &full_url,
+                        session.clone(),
+                        &url,
+                        &last_post_tx,
+                        &status,
+                    );
+                    if let Some(path) = upload {
+                        let mut status = status.lock().unwrap();
+                        status.set_progress(None);
+                        match &res {
+                            Ok(()) => status.info(format!("uploaded {}", path)),
+                            Err(err) => status.error(format!("upload of {} failed: {}", path, err)),
+                        }
+                    }
+                    let mut outbox = outbox.lock().unwrap();
+                    match res {
+                        Ok(()) => outbox.mark_sent(id),
+                        Err(err) => {
+                            log::error!("failed to send outbox item {}: {}", id, err);
+                            outbox.mark_failed(id, err.to_string());
+                        }
+                    }
+                    Duration::from_secs(0)
+                }
+                Next::Wait(d) => d,
+                Next::Idle => Duration::from_secs(60),
+            };
+            let timeout = after(timeout);
             select! {
-                recv(&exit_rx) -> _ => return,
-                recv(&rx) -> v => clb(v),
+                recv(&exit_rx) -> _ => {
+                    outbox.lock().unwrap().requeue_sending();
+                    return;
+                },
+                recv(&rx) -> v => match v {
+                    Ok(post_type) => { outbox.lock().unwrap().push(post_type); },
+                    Err(_) => return,
+                },
+                recv(&timeout) -> _ => {},
             }
         })
     }
@@ -389,18 +601,23 @@ impl LeChatPHPClient {
         let page_php = self.config.page_php.clone();
         let datetime_fmt = self.config.datetime_fmt.clone();
         let is_muted = Arc::clone(&self.is_muted);
+        let notifier = Arc::clone(&self.notifier);
+        let bridge = Arc::clone(&self.bridge);
+        let irc = Arc::clone(&self.irc);
+        let mod_log = Arc::clone(&self.mod_log);
         let exit_rx = sig.lock().unwrap().clone();
         let sig = Arc::clone(sig);
         let members_tag = self.config.members_tag.clone();
+        let staffs_tag = self.config.staffs_tag.clone();
         let tx = self.tx.clone();
         let bad_usernames = Arc::clone(&self.bad_username_filters);
         let bad_exact_usernames = Arc::clone(&self.bad_exact_username_filters);
         let bad_messages = Arc::clone(&self.bad_message_filters);
         let allowlist = Arc::clone(&self.allowlist);
         thread::spawn(move || loop {
-            let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-            let source = Decoder::new_mp3(Cursor::new(SOUND1)).unwrap();
-            let mut should_notify = false;
+            let mut notices = vec![];
+            let mut published = vec![];
+            let mut actions = vec![];
 
             if let Err(err) = get_msgs(
                 &client,
@@ -411,10 +628,10 @@ impl LeChatPHPClient {
                 &users,
                 &sig,
                 &messages_updated_tx,
-                &members_tag,
+                (&members_tag, &staffs_tag),
                 &datetime_fmt,
                 &messages,
-                &mut should_notify,
+                (&mut notices, &mut published, &mut actions),
                 &tx,
                 &bad_usernames,
                 &bad_exact_usernames,
@@ -424,10 +641,16 @@ impl LeChatPHPClient {
                 log::error!("{}", err);
             };
 
+            mod_log.lock().unwrap().extend(actions);
+            irc.set_users(user_roles(&users.lock().unwrap()));
+            for event in &published {
+                bridge.publish(event);
+                irc.publish(event);
+            }
             let muted = { *is_muted.lock().unwrap() };
-            if should_notify && !muted {
-                if let Err(err) = stream_handle.play_raw(source.convert_samples()) {
-                    log::error!("{}", err);
+            if !muted {
+                for notice in &notices {
+                    notifier.notify(notice);
                 }
             }
 
@@ -439,6 +662,78 @@ impl LeChatPHPClient {
         })
     }
 
+    // The dashboard reads the same state as the UI.
+    fn attach_web(&self, messages: &Arc<Mutex<Vec<Message>>>, users: &Arc<Mutex<Users>>) {
+        let messages = Arc::clone(messages);
+        let users = Arc::clone(users);
+        let username = self.base_client.username.clone();
+        let bad_usernames = Arc::clone(&self.bad_username_filters);
+        let bad_exact_usernames = Arc::clone(&self.bad_exact_username_filters);
+        let bad_messages = Arc::clone(&self.bad_message_filters);
+        let allowlist = Arc::clone(&self.allowlist);
+        let mod_log = Arc::clone(&self.mod_log);
+        self.web.attach(move || {
+            let messages = messages
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|m| !m.hide)
+                .map(|m| web::Line {
+                    date: m.date.clone(),
+                    spans: m
+                        .text
+                        .colored_text()
+                        .into_iter()
+                        .map(|(style, text)| web_span(style, text))
+                        .collect(),
+                    deleted: m.deleted,
+                })
+                .collect();
+            let users = {
+                let users = users.lock().unwrap();
+                let names = |list: &Vec<(tuiColor, String)>| -> Vec<(Option<String>, String)> {
+                    list.iter()
+                        .map(|(color, name)| (css_color(*color), name.clone()))
+                        .collect()
+                };
+                vec![
+                    ("Admins", names(&users.admin)),
+                    ("Staff", names(&users.staff)),
+                    ("Members", names(&users.members)),
+                    ("Guests", names(&users.guests)),
+                ]
+            };
+            let actions = mod_log
+                .lock()
+                .unwrap()
+                .recent()
+                .map(|a| {
+                    (
+                        a.date.clone(),
+                        a.user.clone(),
+                        a.action.name(),
+                        a.reason.clone(),
+                    )
+                })
+                .collect();
+            web::Snapshot {
+                username: username.clone(),
+                messages,
+                users,
+                filters: vec![
+                    ("Banned names", bad_usernames.lock().unwrap().clone()),
+                    (
+                        "Banned exact names",
+                        bad_exact_usernames.lock().unwrap().clone(),
+                    ),
+                    ("Message filters", bad_messages.lock().unwrap().clone()),
+                    ("Allowed guests", allowlist.lock().unwrap().clone()),
+                ],
+                actions,
+            }
+        });
+    }
+
     fn get_msgs(&mut self) -> anyhow::Result<ExitSignal> {
         let terminate_signal: ExitSignal;
 
@@ -447,6 +742,9 @@ impl LeChatPHPClient {
 
         // Create default app state
         let mut app = App::default();
+        let history = History::load(self.history_file.clone(), self.editor.history_size);
+        app.input = LineEditor::new(history, self.editor.vi_mode);
+        self.migrate_legacy_outbox();
 
         // Each threads gets a clone of the receiver.
         // When someone calls ".signal", all threads receive it,
@@ -459,6 +757,7 @@ impl LeChatPHPClient {
         let h1 = self.start_keepalive_thread(sig.lock().unwrap().clone(), last_post_rx);
         let h2 = self.start_post_msg_thread(sig.lock().unwrap().clone(), last_post_tx);
         let h3 = self.start_get_msgs_thread(&sig, &messages, &users, messages_updated_tx);
+        self.attach_web(&messages, &users);
 
         // Terminal initialization
         let mut stdout = io::stdout();
@@ -477,17 +776,45 @@ impl LeChatPHPClient {
         loop {
             app.is_muted = *self.is_muted.lock().unwrap();
             app.show_sys = self.show_sys;
+            app.show_downloads = self.show_downloads;
             app.display_guest_view = self.display_guest_view;
             app.display_member_view = self.display_member_view;
             app.display_hidden_msgs = self.display_hidden_msgs;
             app.members_tag = self.config.members_tag.clone();
             app.staffs_tag = self.config.staffs_tag.clone();
+            app.datetime_fmt = self.config.datetime_fmt.clone();
+            app.base_url = self.config.url.clone();
 
             // process()
             // Draw UI
             terminal.draw(|f| {
-                draw_terminal_frame(f, &mut app, &messages, &users, &self.base_client.username);
+                draw_terminal_frame(
+                    f,
+                    &mut app,
+                    &messages,
+                    &users,
+                    &ClientViews {
+                        keymap: &self.keymap,
+                        theme: &self.theme,
+                        panes: &self.panes,
+                        outbox: &self.outbox,
+                        downloads: &self.downloads,
+                        previews: &self.previews,
+                        status: &self.status,
+                    },
+                    &self.base_client.username,
+                );
             })?;
+            // Alerts from other threads are written here, not over a frame
+            let alerts = self.notifier.terminal_alerts();
+            if alerts.bell {
+                let backend = terminal.backend_mut();
+                backend.write_all(b"\x07")?;
+                backend.flush()?;
+            }
+            if let Some(title) = alerts.title {
+                execute!(terminal.backend_mut(), SetTitle(title))?;
+            }
 
             // Handle input
             match self.handle_input(&events, &mut app, &messages, &users) {
@@ -524,20 +851,57 @@ impl LeChatPHPClient {
         Ok(terminate_signal)
     }
 
+    // Said on the status line, as the UI starts, so it is seen.
+    fn migrate_legacy_outbox(&mut self) {
+        let mut outbox = self.outbox.lock().unwrap();
+        let mut status = self.status.lock().unwrap();
+        if !outbox.is_persisted() {
+            status.error("No vault, the outbox is not saved (see `bhcli vault`)");
+            return;
+        }
+        if let Some(path) = self.legacy_outbox.take() {
+            match outbox.migrate_legacy(&path) {
+                Ok(0) => {}
+                Ok(count) => status.info(format!("{} items moved from the old outbox", count)),
+                Err(e) => status.error(format!("failed to move the old outbox: {}", e)),
+            }
+        }
+    }
+
     fn post_msg(&self, post_type: PostType) -> anyhow::Result<()> {
         self.tx.send(post_type)?;
         Ok(())
     }
 
     fn login(&mut self) -> Result<(), LoginErr> {
-        // If we provided a session, skip login process
-        if self.session.is_some() {
-            // println!("Session in params: {:?}", self.session); 
-            return Ok(());
+        // If we have a session (provided, stored on disk, or from before a
+        // disconnection), skip login process as long as it is still valid.
+        if let (None, Some(key)) = (&self.session, &self.storage_key) {
+            if let Some(stored) = session::load(key, &self.profile) {
+                if stored.url == self.config.url && stored.username == self.base_client.username {
+                    stored.restore_cookies(&self.cookie_jar);
+                    self.session = Some(stored.session);
+                }
+            }
+        }
+        if let Some(session) = &self.session {
+            if lechatphp::session_is_valid(
+                &self.client,
+                &self.config.url,
+                &self.config.page_php,
+                session,
+            )? {
+                return Ok(());
+            }
+            log::error!("session is no longer valid, login again");
+            self.session = None;
+            if let Some(key) = &self.storage_key {
+                if let Err(e) = session::remove(key, &self.profile) {
+                    log::error!("failed to remove stored session: {}", e);
+                }
+            }
         }
-        // println!("self.session is not Some");
-        // println!("self.sxiv = {:?}", self.sxiv);
-        self.session = Some(lechatphp::login(
+        let session = lechatphp::login(
             &self.client,
             &self.config.url,
             &self.config.page_php,
@@ -546,7 +910,19 @@ impl LeChatPHPClient {
             &self.guest_color,
             self.manual_captcha,
             self.sxiv,
-        )?);
+        )?;
+        if let Some(key) = &self.storage_key {
+            let stored = session::StoredSession::new(
+                &self.config.url,
+                &self.base_client.username,
+                &session,
+                &self.cookie_jar,
+            );
+            if let Err(e) = session::save(key, &self.profile, stored) {
+                log::error!("failed to store session: {}", e);
+            }
+        }
+        self.session = Some(session);
         Ok(())
     }
 
@@ -559,6 +935,9 @@ impl LeChatPHPClient {
                 session,
             )?;
             self.session = None;
+            if let Some(key) = &self.storage_key {
+                session::remove(key, &self.profile)?;
+            }
         }
         Ok(())
     }
@@ -675,185 +1054,414 @@ impl LeChatPHPClient {
         }
     }
 
-    fn process_command(&mut self, input: &str, app: &mut App, users: &Arc<Mutex<Users>>) -> bool {
-        if input == "/dl" {
-            self.post_msg(PostType::DeleteLast).unwrap();
-        } else if let Some(captures) = DLX_RGX.captures(input) {
-            let x: usize = captures.get(1).unwrap().as_str().parse().unwrap();
-            for _ in 0..x {
-                self.post_msg(PostType::DeleteLast).unwrap();
-            }
-        } else if input == "/dall" {
-            self.post_msg(PostType::DeleteAll).unwrap();
-        } else if input == "/cycles" {
-            self.color_tx.send(()).unwrap();
-        } else if input == "/cycle1" {
-            self.start_cycle(true);
-        } else if input == "/cycle2" {
-            self.start_cycle(false);
-        } else if input == "/kall" {
-            let username = "s _".to_owned();
-            let msg = "".to_owned();
-            self.post_msg(PostType::Kick(msg, username)).unwrap();
-        } else if let Some(captures) = PM_RGX.captures(input) {
-            let username = &captures[1];
-            let msg = captures[2].to_owned();
-            let to = Some(username.to_owned());
-            self.post_msg(PostType::Post(msg, to)).unwrap();
-            app.input = format!("/pm {} ", username);
-            app.input_idx = app.input.width();
-        } else if let Some(captures) = NEW_NICKNAME_RGX.captures(input) {
-            let new_nickname = captures[1].to_owned();
-            self.post_msg(PostType::NewNickname(new_nickname)).unwrap();
-        } else if let Some(captures) = NEW_COLOR_RGX.captures(input) {
-            let new_color = captures[1].to_owned();
-            self.post_msg(PostType::NewColor(new_color)).unwrap();
-        } else if let Some(captures) = KICK_RGX.captures(input) {
-            let username = captures[1].to_owned();
-            let msg = captures[2].to_owned();
-            self.post_msg(PostType::Kick(msg, username)).unwrap();
-        } else if input.starts_with("/banname ") || input.starts_with("/ban ") {
-            let mut name = if input.starts_with("/banname ") {
-                remove_prefix(input, "/banname ")
-            } else {
-                remove_prefix(input, "/ban ")
-            };
-            let exact = name.starts_with('"') && name.ends_with('"') && name.len() >= 2;
-            if exact {
-                name = &name[1..name.len()-1];
-            }
-            let name = name.to_owned();
-            if exact {
-                let mut f = self.bad_exact_username_filters.lock().unwrap();
-                f.push(name.clone());
-            } else {
-                let mut f = self.bad_username_filters.lock().unwrap();
-                f.push(name.clone());
-            }
-            self.save_filters();
-            self.post_msg(PostType::Kick(String::new(), name.clone())).unwrap();
-            self.apply_ban_filters(users);
-            let msg = if exact {
-                format!("Banned exact user \"{}\"", name)
-            } else {
-                format!("Banned userfilter \"{}\"", name)
-            };
-            self.post_msg(PostType::Post(msg, Some("0".to_owned()))).unwrap();
-        } else if input.starts_with("/banmsg ") || input.starts_with("/filter ") {
-            let term = if input.starts_with("/banmsg ") {
-                remove_prefix(input, "/banmsg ")
-            } else {
-                remove_prefix(input, "/filter ")
-            };
-            let term = term.to_owned();
-            {
-                let mut f = self.bad_message_filters.lock().unwrap();
-                f.push(term.clone());
-            }
-            self.save_filters();
-            let msg = format!("Filtering messages including \"{}\"", term);
-            self.post_msg(PostType::Post(msg, Some("0".to_owned()))).unwrap();
-        } else if input == "/banlist" {
-            let list = self.list_filters(true);
-            let list_exact = self.list_exact_filters();
-            let msg = format!("Banned names: {}", list) +
-                &if list_exact.is_empty() {
-                    String::new()
+    fn run_command(
+        &mut self,
+        cmd: Builtin,
+        args: &Args,
+        input: &str,
+        app: &mut App,
+        users: &Arc<Mutex<Users>>,
+    ) {
+        // Arguments the registry made mandatory
+        let arg = |name: &str| args.get(name).unwrap_or("").to_owned();
+        match cmd {
+            Builtin::DeleteLast => {
+                for _ in 0..args.number("count").unwrap_or(1) {
+                    self.post_msg(PostType::DeleteLast).unwrap();
+                }
+            }
+            Builtin::Outbox => match args.get("action") {
+                Some("retry") => {
+                    let count = self.outbox.lock().unwrap().retry_failed();
+                    self.status
+                        .lock()
+                        .unwrap()
+                        .info(format!("{} outbox items queued again", count));
+                }
+                Some("drop") => {
+                    let count = self.outbox.lock().unwrap().drop_failed();
+                    self.status
+                        .lock()
+                        .unwrap()
+                        .info(format!("{} failed outbox items dropped", count));
+                }
+                _ => {
+                    // Put the last failed message back in the input box so it can be fixed & resent
+                    let mut outbox = self.outbox.lock().unwrap();
+                    let editable = match outbox.last_failed() {
+                        None => {
+                            self.status.lock().unwrap().info("no failed outbox item");
+                            return;
+                        }
+                        Some(item) => match item.post {
+                            PostType::Post(_, _) => true,
+                            _ => {
+                                let summary = item.summary();
+                                self.status.lock().unwrap().error(format!(
+                                    "\"{}\" can't be edited, use /outbox retry or /outbox drop",
+                                    summary
+                                ));
+                                false
+                            }
+                        },
+                    };
+                    if !editable {
+                        return;
+                    }
+                    match outbox.take_last_failed() {
+                        Some(PostType::Post(msg, None)) => app.input.set(msg),
+                        Some(PostType::Post(msg, Some(to))) => app.input.set(match to.as_str() {
+                            SEND_TO_MEMBERS => format!("/m {}", msg),
+                            SEND_TO_STAFFS => format!("/s {}", msg),
+                            SEND_TO_ADMINS => format!("/a {}", msg),
+                            _ => format!("/pm {} {}", to, msg),
+                        }),
+                        _ => {}
+                    }
+                }
+            },
+            Builtin::DeleteAll => {
+                self.post_msg(PostType::DeleteAll).unwrap();
+            }
+            Builtin::Cycles => {
+                self.color_tx.send(()).unwrap();
+            }
+            Builtin::Cycle1 => self.start_cycle(true),
+            Builtin::Cycle2 => self.start_cycle(false),
+            Builtin::KickAll => {
+                let username = "s _".to_owned();
+                let msg = "".to_owned();
+                self.post_msg(PostType::Kick(msg, username)).unwrap();
+            }
+            Builtin::Pm => {
+                let username = arg("user");
+                let to = Some(username.clone());
+                self.post_msg(PostType::Post(arg("message"), to)).unwrap();
+                app.input.set(format!("/pm {} ", username));
+            }
+            Builtin::Members | Builtin::Staffs | Builtin::Admins => {
+                let (prefix, to) = match cmd {
+                    Builtin::Members => ("/m ", SEND_TO_MEMBERS),
+                    Builtin::Staffs => ("/s ", SEND_TO_STAFFS),
+                    _ => ("/a ", SEND_TO_ADMINS),
+                };
+                let msg = arg("message");
+                // "/m /kick user" runs the command, and stays in the members channel.
+                match app.commands.parse(&msg) {
+                    Ok(Some((Run::Builtin(cmd), args))) if msg.starts_with('/') => {
+                        self.run_command(cmd, &args, &msg, app, users);
+                    }
+                    Err(e) if msg.starts_with('/') => {
+                        self.usage_error(app, input, e);
+                        return;
+                    }
+                    _ => {
+                        let to = Some(to.to_owned());
+                        self.post_msg(PostType::Post(msg, to)).unwrap();
+                    }
+                }
+                app.input.set(prefix.to_owned());
+            }
+            Builtin::Me => {
+                self.post_msg(PostType::Post(input.to_owned(), None))
+                    .unwrap();
+            }
+            // The messages are filtered while typing
+            Builtin::Find => {}
+            Builtin::View => match args.get("name") {
+                None => app.view = None,
+                Some(name) => match self.saved_views.iter().find(|(n, _)| n == name) {
+                    Some(view) => app.view = Some(view.clone()),
+                    None => {
+                        let names: Vec<&str> =
+                            self.saved_views.iter().map(|(n, _)| n.as_str()).collect();
+                        let err = format!("Unknown view \"{}\", views: {}", name, names.join(", "));
+                        self.usage_error(app, input, err);
+                    }
+                },
+            },
+            Builtin::Nick => {
+                self.post_msg(PostType::NewNickname(arg("name"))).unwrap();
+            }
+            Builtin::Color => {
+                self.post_msg(PostType::NewColor(arg("color"))).unwrap();
+            }
+            Builtin::Kick => {
+                let msg = args.get("message").unwrap_or("").to_owned();
+                self.post_msg(PostType::Kick(msg, arg("user"))).unwrap();
+            }
+            Builtin::Ban => {
+                let name = arg("name");
+                let exact = name.starts_with('"') && name.ends_with('"') && name.len() >= 2;
+                let name = if exact {
+                    name[1..name.len() - 1].to_owned()
                 } else {
-                    format!("\nBanned exact names: {}", list_exact)
+                    name
                 };
-            self.post_msg(PostType::Post(msg, Some("0".to_owned()))).unwrap();
-        } else if input == "/filterlist" {
-            let list = self.list_filters(false);
-            let msg = format!("Filtered messages: {}", list);
-            self.post_msg(PostType::Post(msg, Some("0".to_owned()))).unwrap();
-        } else if input.starts_with("/unban ") {
-            let mut name = remove_prefix(input, "/unban ");
-            if name.starts_with('"') && name.ends_with('"') && name.len() >= 2 {
-                name = &name[1..name.len() - 1];
-            }
-            if self.remove_filter(name, true) {
+                if exact {
+                    let mut f = self.bad_exact_username_filters.lock().unwrap();
+                    f.push(name.clone());
+                } else {
+                    let mut f = self.bad_username_filters.lock().unwrap();
+                    f.push(name.clone());
+                }
                 self.save_filters();
-                let msg = format!("Unbanned {}", name);
-                self.post_msg(PostType::Post(msg, Some("0".to_owned()))).unwrap();
+                self.post_msg(PostType::Kick(String::new(), name.clone()))
+                    .unwrap();
+                self.apply_ban_filters(users);
+                let msg = if exact {
+                    format!("Banned exact user \"{}\"", name)
+                } else {
+                    format!("Banned userfilter \"{}\"", name)
+                };
+                self.post_msg(PostType::Post(msg, Some("0".to_owned())))
+                    .unwrap();
             }
-        } else if input.starts_with("/unfilter ") {
-            let term = remove_prefix(input, "/unfilter ");
-            if self.remove_filter(term, false) {
+            Builtin::Filter => {
+                let term = arg("text");
+                {
+                    let mut f = self.bad_message_filters.lock().unwrap();
+                    f.push(term.clone());
+                }
                 self.save_filters();
-                let msg = format!("Unfiltered \"{}\"", term);
-                self.post_msg(PostType::Post(msg, Some("0".to_owned()))).unwrap();
+                let msg = format!("Filtering messages including \"{}\"", term);
+                self.post_msg(PostType::Post(msg, Some("0".to_owned())))
+                    .unwrap();
             }
-        } else if input.starts_with("/allow ") {
-            let user = remove_prefix(input, "/allow ").to_owned();
-            {
-                let mut list = self.allowlist.lock().unwrap();
-                if !list.contains(&user) {
-                    list.push(user.clone());
+            Builtin::BanList => {
+                let list = self.list_filters(true);
+                let list_exact = self.list_exact_filters();
+                let msg = format!("Banned names: {}", list)
+                    + &if list_exact.is_empty() {
+                        String::new()
+                    } else {
+                        format!("\nBanned exact names: {}", list_exact)
+                    };
+                self.post_msg(PostType::Post(msg, Some("0".to_owned())))
+                    .unwrap();
+            }
+            Builtin::BanExactList => {
+                let msg = format!("Banned exact names: {}", self.list_exact_filters());
+                self.post_msg(PostType::Post(msg, Some("0".to_owned())))
+                    .unwrap();
+            }
+            Builtin::FilterList => {
+                let list = self.list_filters(false);
+                let msg = format!("Filtered messages: {}", list);
+                self.post_msg(PostType::Post(msg, Some("0".to_owned())))
+                    .unwrap();
+            }
+            Builtin::Unban => {
+                let name = arg("name");
+                let mut name = name.as_str();
+                if name.starts_with('"') && name.ends_with('"') && name.len() >= 2 {
+                    name = &name[1..name.len() - 1];
+                }
+                if self.remove_filter(name, true) {
+                    self.save_filters();
+                    let msg = format!("Unbanned {}", name);
+                    self.post_msg(PostType::Post(msg, Some("0".to_owned())))
+                        .unwrap();
                 }
             }
-            self.save_filters();
-            let msg = format!("Allowed {}", user);
-            self.post_msg(PostType::Post(msg, Some("0".to_owned()))).unwrap();
-        } else if input.starts_with("/revoke ") {
-            let user = remove_prefix(input, "/revoke ").to_owned();
-            {
-                let mut list = self.allowlist.lock().unwrap();
-                if let Some(pos) = list.iter().position(|u| u == &user) {
-                    list.remove(pos);
-                }
-            }
-            self.save_filters();
-            let msg = format!("Revoked {}", user);
-            self.post_msg(PostType::Post(msg, Some("0".to_owned()))).unwrap();
-        } else if input == "/allowlist" {
-            let list = self.allowlist.lock().unwrap().clone();
-            let out = if list.is_empty() { String::from("(empty)") } else { list.join(", ") };
-            let msg = format!("Allowlist: {}", out);
-            self.post_msg(PostType::Post(msg, Some("0".to_owned()))).unwrap();
-        } else if let Some(captures) = IGNORE_RGX.captures(input) {
-            let username = captures[1].to_owned();
-            self.post_msg(PostType::Ignore(username)).unwrap();
-        } else if let Some(captures) = UNIGNORE_RGX.captures(input) {
-            let username = captures[1].to_owned();
-            self.post_msg(PostType::Unignore(username)).unwrap();
-        } else if let Some(captures) = UPLOAD_RGX.captures(input) {
-            let file_path = captures[1].to_owned();
-            let send_to = match captures.get(2) {
-                Some(to_match) => match to_match.as_str() {
-                    "members" => SEND_TO_MEMBERS,
-                    "staffs" => SEND_TO_STAFFS,
-                    "admins" => SEND_TO_ADMINS,
-                    _ => SEND_TO_ALL,
-                },
-                None => SEND_TO_ALL,
+            Builtin::Unfilter => {
+                let term = arg("text");
+                if self.remove_filter(&term, false) {
+                    self.save_filters();
+                    let msg = format!("Unfiltered \"{}\"", term);
+                    self.post_msg(PostType::Post(msg, Some("0".to_owned())))
+                        .unwrap();
+                }
             }
-            .to_owned();
-            let msg = match captures.get(3) {
-                Some(msg_match) => msg_match.as_str().to_owned(),
-                None => "".to_owned(),
-            };
-            self.post_msg(PostType::Upload(file_path, send_to, msg)).unwrap();
-        } else if input.starts_with("!warn") {
-            let msg = input.trim_start_matches("!warn").trim();
-            let msg = if msg.starts_with('@') {
-                msg.to_owned()
-            } else if msg.is_empty() {
-                String::new()
-            } else {
-                format!("@{}", msg)
-            };
-            let end_msg = format!(
-                "This is your warning - {}, will be kicked next. Please read the !-rules / https://4-0-4.io/bhc-rules",
-                msg
-            );
-            self
-                .post_msg(PostType::Post(end_msg, None))
-                .unwrap();
-        } else {
-            return false;
+            Builtin::Allow => {
+                let user = arg("user");
+                {
+                    let mut list = self.allowlist.lock().unwrap();
+                    if !list.contains(&user) {
+                        list.push(user.clone());
+                    }
+                }
+                self.save_filters();
+                let msg = format!("Allowed {}", user);
+                self.post_msg(PostType::Post(msg, Some("0".to_owned())))
+                    .unwrap();
+            }
+            Builtin::Revoke => {
+                let user = arg("user");
+                {
+                    let mut list = self.allowlist.lock().unwrap();
+                    if let Some(pos) = list.iter().position(|u| u == &user) {
+                        list.remove(pos);
+                    }
+                }
+                self.save_filters();
+                let msg = format!("Revoked {}", user);
+                self.post_msg(PostType::Post(msg, Some("0".to_owned())))
+                    .unwrap();
+            }
+            Builtin::AllowList => {
+                let list = self.allowlist.lock().unwrap().clone();
+                let out = if list.is_empty() {
+                    String::from("(empty)")
+                } else {
+                    list.join(", ")
+                };
+                let msg = format!("Allowlist: {}", out);
+                self.post_msg(PostType::Post(msg, Some("0".to_owned())))
+                    .unwrap();
+            }
+            Builtin::Mute => match args.get("user") {
+                Some(user) => {
+                    let msg = if self.notifier.toggle_mute(user) {
+                        format!("{} muted", user)
+                    } else {
+                        format!("{} unmuted", user)
+                    };
+                    self.status.lock().unwrap().info(msg);
+                }
+                None => {
+                    let users = self.notifier.muted_users();
+                    let msg = if users.is_empty() {
+                        "No muted users".to_owned()
+                    } else {
+                        format!("Muted users: {}", users.join(", "))
+                    };
+                    self.status.lock().unwrap().info(msg);
+                }
+            },
+            Builtin::Sound => {
+                if let Err(err) = self.notifier.test_sound(&arg("event")) {
+                    self.usage_error(app, input, err);
+                }
+            }
+            Builtin::Ignore => {
+                self.post_msg(PostType::Ignore(arg("user"))).unwrap();
+            }
+            Builtin::Unignore => {
+                self.post_msg(PostType::Unignore(arg("user"))).unwrap();
+            }
+            Builtin::Upload => {
+                let file_path = match args.get("path") {
+                    Some(path) => PathBuf::from(path),
+                    None => return self.open_file_browser(app),
+                };
+                let strip = self.should_strip(args.get("flag"));
+                let msg = args.get("message").unwrap_or("").to_owned();
+                let res = self.queue_upload(app, file_path, args.get("target"), msg, strip, users);
+                if let Err(e) = res {
+                    self.upload_failed(app, input, e);
+                }
+            }
+            Builtin::Paste => {
+                let strip = self.should_strip(args.get("flag"));
+                let msg = args.get("message").unwrap_or("").to_owned();
+                let target = args.get("target");
+                let res = upload::clipboard_image().and_then(|path| {
+                    let res = self.queue_upload(app, path.clone(), target, msg, strip, users);
+                    if res.is_err() {
+                        upload::remove_temp_file(&path);
+                    }
+                    res
+                });
+                if let Err(e) = res {
+                    self.upload_failed(app, input, e);
+                }
+            }
+            Builtin::Warn => {
+                let msg = match args.get("user") {
+                    Some(user) if user.starts_with('@') => user.to_owned(),
+                    Some(user) => format!("@{}", user),
+                    None => String::new(),
+                };
+                let end_msg = format!(
+                    "This is your warning - {}, will be kicked next. Please read the !-rules / https://4-0-4.io/bhc-rules",
+                    msg
+                );
+                self.post_msg(PostType::Post(end_msg, None)).unwrap();
+            }
+            Builtin::Help => match app.commands.help(args.get("command")) {
+                Ok(lines) => {
+                    app.help = Some(lines);
+                    app.help_scroll = 0;
+                    app.input_mode = InputMode::Help;
+                }
+                Err(e) => self.usage_error(app, input, e),
+            },
         }
-        true
+    }
+
+    // Keep the input so it can be fixed, and tell what's wrong with it.
+    fn usage_error(&mut self, app: &mut App, input: &str, err: String) {
+        self.status.lock().unwrap().error(err);
+        app.input.set(input.to_owned());
+        app.input_mode = InputMode::EditingErr;
+    }
+
+    // "-s" forces metadata stripping, "-r" sends the file as is.
+    fn should_strip(&self, flag: Option<&str>) -> bool {
+        match flag {
+            Some("-s") => true,
+            Some("-r") => false,
+            _ => self.uploads.strip_metadata,
+        }
+    }
+
+    fn queue_upload(
+        &mut self,
+        app: &mut App,
+        path: PathBuf,
+        target: Option<&str>,
+        msg: String,
+        strip: bool,
+        users: &Arc<Mutex<Users>>,
+    ) -> anyhow::Result<()> {
+        let send_to = upload_send_to(target, users)?;
+        upload::check_size(&path, self.uploads.max_size_kb)?;
+        if strip {
+            let report = sanitize::inspect(&path)?;
+            // Let the user review what was found before anything is sent
+            if !report.found.is_empty() {
+                app.pending_upload = Some(PendingUpload {
+                    path,
+                    send_to,
+                    msg,
+                    report,
+                });
+                app.input_mode = InputMode::UploadConfirm;
+                return Ok(());
+            }
+            // Images are re-encoded anyway, for the metadata we don't know of
+            let path = strip_upload(path, report.kind)?;
+            return self.send_upload(path, send_to, msg);
+        }
+        self.send_upload(path, send_to, msg)
+    }
+
+    fn send_upload(&mut self, path: PathBuf, send_to: String, msg: String) -> anyhow::Result<()> {
+        if let Err(e) = upload::check_size(&path, self.uploads.max_size_kb) {
+            upload::remove_temp_file(&path);
+            return Err(e);
+        }
+        let file_path = path.to_str().context("invalid file path")?.to_owned();
+        self.post_msg(PostType::Upload(file_path, send_to, msg))
+            .unwrap();
+        Ok(())
+    }
+
+    // Keep the command in the input box so it can be fixed.
+    fn upload_failed(&mut self, app: &mut App, input: &str, err: anyhow::Error) {
+        self.status
+            .lock()
+            .unwrap()
+            .error(format!("upload: {}", err));
+        app.input.set(input.to_owned());
+        app.input_mode = InputMode::EditingErr;
+    }
+
+    fn open_file_browser(&mut self, app: &mut App) {
+        let dir = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/"));
+        app.file_browser = Some(FileBrowser::new(dir));
+        app.input_mode = InputMode::FileBrowser;
     }
 
     fn handle_input(
@@ -895,16 +1503,18 @@ impl LeChatPHPClient {
         users: &Arc<Mutex<Users>>,
         key_event: KeyEvent,
     ) -> Result<(), ExitSignal> {
-        if app.input_mode != InputMode::Normal {
-            self.last_key_event = None;
-        }
         match app.input_mode {
             InputMode::LongMessage => {
                 self.handle_long_message_mode_key_event(app, key_event, messages)
             }
+            InputMode::Help => self.handle_help_mode_key_event(app, key_event),
+            InputMode::Thread => self.handle_thread_mode_key_event(app, key_event),
+            InputMode::ImageViewer => self.handle_image_viewer_mode_key_event(app, key_event),
+            InputMode::FileBrowser => self.handle_file_browser_mode_key_event(app, key_event),
+            InputMode::UploadConfirm => self.handle_upload_confirm_mode_key_event(app, key_event),
             InputMode::Normal => self.handle_normal_mode_key_event(app, key_event, messages),
             InputMode::Editing | InputMode::EditingErr => {
-                self.handle_editing_mode_key_event(app, key_event, users)
+                self.handle_editing_mode_key_event(app, key_event, messages, users)
             }
         }
     }
@@ -915,6 +1525,8 @@ impl LeChatPHPClient {
         key_event: KeyEvent,
         messages: &Arc<Mutex<Vec<Message>>>,
     ) -> Result<(), ExitSignal> {
+        // Any other key cancels opening the link
+        let open_link_confirm = app.open_link_confirm.take();
         match key_event {
             KeyEvent {
                 code: KeyCode::Enter,
@@ -931,316 +1543,457 @@ impl LeChatPHPClient {
                 modifiers: KeyModifiers::CONTROL,
                 ..
             } => self.handle_long_message_mode_key_event_ctrl_d(app, messages),
-            _ => {}
-        }
-        Ok(())
-    }
-
-    fn handle_normal_mode_key_event(
-        &mut self,
-        app: &mut App,
-        key_event: KeyEvent,
-        messages: &Arc<Mutex<Vec<Message>>>,
-    ) -> Result<(), ExitSignal> {
-        match key_event {
             KeyEvent {
-                code: KeyCode::Char('/'),
-                modifiers: KeyModifiers::NONE,
-                ..
-            } => self.handle_normal_mode_key_event_slash(app),
-            KeyEvent {
-                code: KeyCode::Char('j'),
-                modifiers: KeyModifiers::NONE,
-                ..
-            }
-            | KeyEvent {
-                code: KeyCode::Down,
+                code: KeyCode::Tab,
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => self.handle_normal_mode_key_event_down(app),
+            } => self.handle_long_message_mode_key_event_select_link(app, true),
             KeyEvent {
-                code: KeyCode::Char('J'),
-                modifiers: KeyModifiers::SHIFT,
+                code: KeyCode::BackTab,
                 ..
-            } => self.handle_normal_mode_key_event_j(app,5),
+            } => self.handle_long_message_mode_key_event_select_link(app, false),
             KeyEvent {
-                code: KeyCode::Char('k'),
+                code: KeyCode::Char('y'),
                 modifiers: KeyModifiers::NONE,
                 ..
+            } => {
+                if let Some(link) = self.long_message_selected_link(app) {
+                    self.copy_to_clipboard(link);
+                }
             }
-            | KeyEvent {
-                code: KeyCode::Up,
-                modifiers: KeyModifiers::NONE,
-                ..
-            } => self.handle_normal_mode_key_event_up(app),
             KeyEvent {
-                code: KeyCode::Char('K'),
-                modifiers: KeyModifiers::SHIFT,
-                ..
-            } => self.handle_normal_mode_key_event_k(app,5),
-            KeyEvent {
-                code: KeyCode::Enter,
-                modifiers: KeyModifiers::NONE,
-                ..
-            } => self.handle_normal_mode_key_event_enter(app, messages),
-            KeyEvent {
-                code: KeyCode::Backspace,
-                modifiers: KeyModifiers::NONE,
-                ..
-            } => self.handle_normal_mode_key_event_backspace(app, messages),
-            KeyEvent {
-                code: KeyCode::Char('y'),
+                code: KeyCode::Char('o'),
                 modifiers: KeyModifiers::NONE,
                 ..
+            } => {
+                if let Some(link) = self.long_message_selected_link(app) {
+                    if open_link_confirm.as_ref() == Some(&link) {
+                        if let Err(e) = Command::new("xdg-open").arg(&link).spawn() {
+                            self.status
+                                .lock()
+                                .unwrap()
+                                .error(format!("failed to run xdg-open: {}", e));
+                        }
+                    } else {
+                        // The browser doesn't go through our proxy, the link
+                        // comes from anyone in the chat.
+                        self.status.lock().unwrap().error(format!(
+                            "{} would be opened outside of Tor, press o again to open it, y to copy it",
+                            link
+                        ));
+                        app.open_link_confirm = Some(link);
+                    }
+                }
             }
-            | KeyEvent {
-                code: KeyCode::Char('c'),
-                modifiers: KeyModifiers::CONTROL,
-                ..
-            } => self.handle_normal_mode_key_event_yank(app),
-            KeyEvent {
-                code: KeyCode::Char('Y'),
-                modifiers: KeyModifiers::SHIFT,
-                ..
-            } => self.handle_normal_mode_key_event_yank_link(app),
+            _ => {}
+        }
+        Ok(())
+    }
 
-            //Strange
-            KeyEvent {
-                code: KeyCode::Char('D'),
-                modifiers: KeyModifiers::SHIFT,
-                ..
-            } => self.handle_normal_mode_key_event_download_link(app),
+    // Cycle through the links of the message displayed in the long message view.
+    fn handle_long_message_mode_key_event_select_link(&mut self, app: &mut App, forward: bool) {
+        let nb_links = match &app.long_message {
+            Some(m) => m.text.links().len(),
+            None => return,
+        };
+        if nb_links == 0 {
+            return;
+        }
+        app.long_message_link = Some(match (app.long_message_link, forward) {
+            (None, true) => 0,
+            (None, false) => nb_links - 1,
+            (Some(idx), true) => (idx + 1) % nb_links,
+            (Some(idx), false) => (idx + nb_links - 1) % nb_links,
+        });
+    }
 
-            //Strange
-            KeyEvent {
-                code: KeyCode::Char('d'),
-                modifiers: KeyModifiers::NONE,
-                ..
-            } => self.handle_normal_mode_key_event_download_and_view(app),
-
-            // KeyEvent {
-            //     code: KeyCode::Char('d'),
-            //     modifiers: KeyModifiers::NONE,
-            //     ..
-            // } => self.handle_normal_mode_key_event_debug(app),
-            // KeyEvent {
-            //     code: KeyCode::Char('D'),
-            //     modifiers: KeyModifiers::SHIFT,
-            //     ..
-            // } => self.handle_normal_mode_key_event_debug2(app),
-            KeyEvent {
-                code: KeyCode::Char('m'),
-                modifiers: KeyModifiers::NONE,
-                ..
-            } => self.handle_normal_mode_key_event_toggle_mute(),
-            KeyEvent {
-                code: KeyCode::Char('S'),
-                modifiers: KeyModifiers::SHIFT,
-                ..
-            } => self.handle_normal_mode_key_event_toggle_sys(),
-            KeyEvent {
-                code: KeyCode::Char('M'),
-                modifiers: KeyModifiers::SHIFT,
-                ..
-            } => self.handle_normal_mode_key_event_toggle_member_view(),
-            KeyEvent {
-                code: KeyCode::Char('G'),
-                modifiers: KeyModifiers::SHIFT,
-                ..
-            } => self.handle_normal_mode_key_event_toggle_guest_view(),
-            KeyEvent {
-                code: KeyCode::Char('H'),
-                modifiers: KeyModifiers::SHIFT,
-                ..
-            } => self.handle_normal_mode_key_event_toggle_hidden(),
+    fn long_message_selected_link(&self, app: &App) -> Option<String> {
+        let m = app.long_message.as_ref()?;
+        let link = m.text.links().into_iter().nth(app.long_message_link?)?;
+        Some(absolute_link(&self.config.url, &link))
+    }
+
+    fn handle_thread_mode_key_event(
+        &mut self,
+        app: &mut App,
+        key_event: KeyEvent,
+    ) -> Result<(), ExitSignal> {
+        let thread = match app.thread.as_mut() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+        match key_event {
             KeyEvent {
-                code: KeyCode::Char('i'),
+                code: KeyCode::Esc,
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => self.handle_normal_mode_key_event_input_mode(app),
-            KeyEvent {
-                code: KeyCode::Char('Q'),
-                modifiers: KeyModifiers::SHIFT,
-                ..
-            } => self.handle_normal_mode_key_event_logout()?,
-            KeyEvent {
+            }
+            | KeyEvent {
                 code: KeyCode::Char('q'),
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => self.handle_normal_mode_key_event_exit()?,
-            KeyEvent {
-                code: KeyCode::Char('t'),
-                modifiers: KeyModifiers::NONE,
-                ..
-            } => self.handle_normal_mode_key_event_tag(app),
+            } => {
+                app.thread = None;
+                app.input_mode = InputMode::Normal;
+            }
+            // Back to the chat, on the selected message
             KeyEvent {
-                code: KeyCode::Char('p'),
+                code: KeyCode::Enter,
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => self.handle_normal_mode_key_event_pm(app),
-            KeyEvent {
-                code: KeyCode::Char('k'),
-                modifiers: KeyModifiers::CONTROL,
-                ..
-            } => self.handle_normal_mode_key_event_kick(app),
-            KeyEvent {
-                code: KeyCode::Char('b'),
-                modifiers: KeyModifiers::CONTROL,
-                ..
-            } => self.handle_normal_mode_key_event_ban(app),
-            KeyEvent {
-                code: KeyCode::Char('B'),
-                modifiers,
-                ..
-            } if modifiers.contains(KeyModifiers::CONTROL) => {
-                self.handle_normal_mode_key_event_ban_exact(app)
+            } => {
+                if let Some((_, m)) = thread
+                    .state
+                    .selected()
+                    .and_then(|idx| thread.items.get(idx))
+                {
+                    app.jump_to = Some(m.uid);
+                    app.top = None;
+                }
+                app.clear_filter();
+                app.thread = None;
+                app.input_mode = InputMode::Normal;
             }
             KeyEvent {
-                code: KeyCode::Char('w'),
-                modifiers: KeyModifiers::CONTROL,
-                ..
-            } => self.handle_normal_mode_key_event_warn(app),
-            KeyEvent {
-                code: KeyCode::Char('T'),
-                modifiers: KeyModifiers::SHIFT,
-                ..
-            } => self.handle_normal_mode_key_event_translate(app, messages),
-            KeyEvent {
-                code: KeyCode::Char('u'),
-                modifiers: KeyModifiers::CONTROL,
-                ..
-            }
-            | KeyEvent {
-                code: KeyCode::PageUp,
+                code: KeyCode::Char('j'),
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => self.handle_normal_mode_key_event_page_up(app),
-            KeyEvent {
-                code: KeyCode::Char('d'),
-                modifiers: KeyModifiers::CONTROL,
-                ..
             }
             | KeyEvent {
-                code: KeyCode::PageDown,
+                code: KeyCode::Down,
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => self.handle_normal_mode_key_event_page_down(app),
+            } => thread.next(),
             KeyEvent {
-                code: KeyCode::Esc,
+                code: KeyCode::Char('k'),
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => self.handle_normal_mode_key_event_esc(app),
-            KeyEvent {
-                code: KeyCode::Char('u'),
-                modifiers: KeyModifiers::SHIFT,
-                ..
-            } => self.handle_normal_mode_key_event_shift_u(app),
-            KeyEvent {
-                code: KeyCode::Char('g'),
+            }
+            | KeyEvent {
+                code: KeyCode::Up,
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => self.handle_normal_mode_key_event_g(app),
+            } => thread.previous(),
             _ => {}
         }
-        self.last_key_event = Some(key_event.code);
         Ok(())
     }
 
-    fn handle_editing_mode_key_event(
+    fn handle_help_mode_key_event(
         &mut self,
         app: &mut App,
         key_event: KeyEvent,
-        users: &Arc<Mutex<Users>>,
     ) -> Result<(), ExitSignal> {
-        app.input_mode = InputMode::Editing;
         match key_event {
-            KeyEvent {
-                code: KeyCode::Enter,
-                modifiers,
-                ..
-            } if modifiers.contains(KeyModifiers::SHIFT)
-                || modifiers.contains(KeyModifiers::CONTROL) =>
-                self.handle_editing_mode_key_event_newline(app),
             KeyEvent {
                 code: KeyCode::Enter,
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => self.handle_editing_mode_key_event_enter(app, users)?,
-            KeyEvent {
-                code: KeyCode::Tab,
+            }
+            | KeyEvent {
+                code: KeyCode::Esc,
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => self.handle_editing_mode_key_event_tab(app, users),
-            KeyEvent {
-                code: KeyCode::Char('c'),
-                modifiers: KeyModifiers::CONTROL,
-                ..
-            } => self.handle_editing_mode_key_event_ctrl_c(app),
-            KeyEvent {
-                code: KeyCode::Char('a'),
-                modifiers: KeyModifiers::CONTROL,
-                ..
-            } => self.handle_editing_mode_key_event_ctrl_a(app),
-            KeyEvent {
-                code: KeyCode::Char('e'),
-                modifiers: KeyModifiers::CONTROL,
-                ..
-            } => self.handle_editing_mode_key_event_ctrl_e(app),
-            KeyEvent {
-                code: KeyCode::Char('f'),
-                modifiers: KeyModifiers::CONTROL,
-                ..
-            } => self.handle_editing_mode_key_event_ctrl_f(app),
-            KeyEvent {
-                code: KeyCode::Char('b'),
-                modifiers: KeyModifiers::CONTROL,
-                ..
-            } => self.handle_editing_mode_key_event_ctrl_b(app),
-            KeyEvent {
-                code: KeyCode::Char('v'),
-                modifiers: KeyModifiers::CONTROL,
-                ..
-            } => self.handle_editing_mode_key_event_ctrl_v(app),
-            KeyEvent {
-                code: KeyCode::Left,
+            }
+            | KeyEvent {
+                code: KeyCode::Char('q'),
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => self.handle_editing_mode_key_event_left(app),
+            } => {
+                app.help = None;
+                app.input_mode = InputMode::Editing;
+            }
             KeyEvent {
-                code: KeyCode::Right,
+                code: KeyCode::Char('j'),
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => self.handle_editing_mode_key_event_right(app),
-            KeyEvent {
+            }
+            | KeyEvent {
                 code: KeyCode::Down,
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => self.handle_editing_mode_key_event_down(app),
+            } => {
+                let nb_lines = app.help.as_ref().map_or(0, |h| h.len()) as u16;
+                app.help_scroll = std::cmp::min(app.help_scroll + 1, nb_lines.saturating_sub(1));
+            }
             KeyEvent {
-                code: KeyCode::Char(c),
+                code: KeyCode::Char('k'),
                 modifiers: KeyModifiers::NONE,
                 ..
             }
             | KeyEvent {
-                code: KeyCode::Char(c),
-                modifiers: KeyModifiers::SHIFT,
+                code: KeyCode::Up,
+                modifiers: KeyModifiers::NONE,
                 ..
-            } => self.handle_editing_mode_key_event_shift_c(app, c),
+            } => app.help_scroll = app.help_scroll.saturating_sub(1),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_image_viewer_mode_key_event(
+        &mut self,
+        app: &mut App,
+        key_event: KeyEvent,
+    ) -> Result<(), ExitSignal> {
+        match key_event {
             KeyEvent {
-                code: KeyCode::Backspace,
+                code: KeyCode::Enter,
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => self.handle_editing_mode_key_event_backspace(app),
-            KeyEvent {
-                code: KeyCode::Delete,
+            }
+            | KeyEvent {
+                code: KeyCode::Esc,
+                modifiers: KeyModifiers::NONE,
+                ..
+            }
+            | KeyEvent {
+                code: KeyCode::Char('q'),
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => self.handle_editing_mode_key_event_delete(app),
+            } => {
+                app.image_viewer = None;
+                app.input_mode = InputMode::Normal;
+            }
             KeyEvent {
-                code: KeyCode::Esc,
+                code: KeyCode::Char('d'),
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => self.handle_editing_mode_key_event_esc(app),
+            } => {
+                if let Some(url) = app.image_viewer.clone() {
+                    self.downloads.start(url, Some("xdg-open"));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_upload_confirm_mode_key_event(
+        &mut self,
+        app: &mut App,
+        key_event: KeyEvent,
+    ) -> Result<(), ExitSignal> {
+        let raw = match key_event.code {
+            KeyCode::Char('y') | KeyCode::Enter => false,
+            KeyCode::Char('r') => true,
+            KeyCode::Char('n') | KeyCode::Esc => {
+                if let Some(p) = app.pending_upload.take() {
+                    upload::remove_temp_file(&p.path);
+                }
+                app.input_mode = InputMode::Normal;
+                self.status.lock().unwrap().info("upload cancelled");
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        app.input_mode = InputMode::Normal;
+        if let Some(p) = app.pending_upload.take() {
+            let PendingUpload {
+                path,
+                send_to,
+                msg,
+                report,
+            } = p;
+            let res = if raw {
+                Ok(path)
+            } else {
+                strip_upload(path, report.kind)
+            };
+            if let Err(e) = res.and_then(|path| self.send_upload(path, send_to, msg)) {
+                self.status.lock().unwrap().error(format!("upload: {}", e));
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_file_browser_mode_key_event(
+        &mut self,
+        app: &mut App,
+        key_event: KeyEvent,
+    ) -> Result<(), ExitSignal> {
+        let Some(fb) = app.file_browser.as_mut() else {
+            app.input_mode = InputMode::Normal;
+            return Ok(());
+        };
+        match key_event.code {
+            KeyCode::Char('j') | KeyCode::Down => fb.entries.next(),
+            KeyCode::Char('k') | KeyCode::Up => fb.entries.previous(),
+            KeyCode::Char('h') | KeyCode::Left | KeyCode::Backspace => fb.parent(),
+            KeyCode::Enter | KeyCode::Char('l') | KeyCode::Right => {
+                if let Some(path) = fb.open_selected() {
+                    // Let the user add a target/message before sending
+                    app.file_browser = None;
+                    app.input.set(format!("/u \"{}\" ", path.display()));
+                    app.input_mode = InputMode::Editing;
+                }
+            }
+            KeyCode::Esc | KeyCode::Char('q') => {
+                app.file_browser = None;
+                app.input_mode = InputMode::Normal;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_normal_mode_key_event(
+        &mut self,
+        app: &mut App,
+        key_event: KeyEvent,
+        messages: &Arc<Mutex<Vec<Message>>>,
+    ) -> Result<(), ExitSignal> {
+        let action = match self.keymap.feed(KeymapMode::Normal, key_event) {
+            Lookup::Action(action) => action,
+            Lookup::Pending | Lookup::Unbound => return Ok(()),
+        };
+        match action {
+            KeyAction::Command => self.handle_normal_mode_key_event_slash(app),
+            KeyAction::Down => self.handle_normal_mode_key_event_down(app),
+            KeyAction::DownFast => self.handle_normal_mode_key_event_j(app, 5),
+            KeyAction::Up => self.handle_normal_mode_key_event_up(app),
+            KeyAction::UpFast => self.handle_normal_mode_key_event_k(app, 5),
+            KeyAction::Open => self.handle_normal_mode_key_event_enter(app),
+            KeyAction::Hide => self.handle_normal_mode_key_event_backspace(app, messages),
+            KeyAction::Copy => self.handle_normal_mode_key_event_yank(app),
+            KeyAction::CopyLink => self.handle_normal_mode_key_event_yank_link(app),
+            KeyAction::DownloadLink => self.handle_normal_mode_key_event_download_link(app),
+            KeyAction::DownloadAndView => self.handle_normal_mode_key_event_download_and_view(app),
+            KeyAction::ToggleMute => self.handle_normal_mode_key_event_toggle_mute(),
+            KeyAction::ToggleSys => self.handle_normal_mode_key_event_toggle_sys(),
+            KeyAction::ToggleDownloads => self.handle_normal_mode_key_event_toggle_downloads(),
+            KeyAction::ViewImage => self.handle_normal_mode_key_event_view_image(app),
+            KeyAction::Upload => self.open_file_browser(app),
+            KeyAction::ToggleMembersView => self.handle_normal_mode_key_event_toggle_member_view(),
+            KeyAction::ToggleGuestView => self.handle_normal_mode_key_event_toggle_guest_view(),
+            KeyAction::ToggleHidden => self.handle_normal_mode_key_event_toggle_hidden(),
+            KeyAction::ToggleFollow => app.follow = !app.follow,
+            KeyAction::ToggleUsers => self.panes.toggle(Pane::Users),
+            KeyAction::ToggleThreads => self.panes.toggle(Pane::Threads),
+            KeyAction::ToggleModLog => self.panes.toggle(Pane::ModLog),
+            KeyAction::ToggleSplit => self.panes.toggle_split(),
+            KeyAction::GrowSide => self.panes.resize_side(2),
+            KeyAction::ShrinkSide => self.panes.resize_side(-2),
+            KeyAction::GrowSplit => self.panes.resize_split(5),
+            KeyAction::ShrinkSplit => self.panes.resize_split(-5),
+            KeyAction::Edit => self.handle_normal_mode_key_event_input_mode(app),
+            KeyAction::Logout => self.handle_normal_mode_key_event_logout()?,
+            KeyAction::Quit => self.handle_normal_mode_key_event_exit()?,
+            KeyAction::Tag => self.handle_normal_mode_key_event_tag(app),
+            KeyAction::Pm => self.handle_normal_mode_key_event_pm(app),
+            KeyAction::Reply => self.handle_normal_mode_key_event_reply(app, false),
+            KeyAction::ReplyQuote => self.handle_normal_mode_key_event_reply(app, true),
+            KeyAction::Thread => self.handle_normal_mode_key_event_thread(app, messages),
+            KeyAction::Kick => self.handle_normal_mode_key_event_kick(app),
+            KeyAction::Ban => self.handle_normal_mode_key_event_ban(app),
+            KeyAction::BanExact => self.handle_normal_mode_key_event_ban_exact(app),
+            KeyAction::Warn => self.handle_normal_mode_key_event_warn(app),
+            KeyAction::Translate => self.handle_normal_mode_key_event_translate(app, messages),
+            KeyAction::PageUp => self.handle_normal_mode_key_event_page_up(app),
+            KeyAction::PageDown => self.handle_normal_mode_key_event_page_down(app),
+            KeyAction::Unselect => self.handle_normal_mode_key_event_esc(app),
+            KeyAction::SelectTop => app.items.select_top(),
+            KeyAction::View(n) => self.handle_normal_mode_key_event_view(app, n),
+            KeyAction::NextMention => self.handle_normal_mode_key_event_mention(app, true),
+            KeyAction::PreviousMention => self.handle_normal_mode_key_event_mention(app, false),
+            // Editing mode actions
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_editing_mode_key_event(
+        &mut self,
+        app: &mut App,
+        key_event: KeyEvent,
+        messages: &Arc<Mutex<Vec<Message>>>,
+        users: &Arc<Mutex<Users>>,
+    ) -> Result<(), ExitSignal> {
+        app.input_mode = InputMode::Editing;
+        if app.input.is_searching() && self.handle_editing_mode_key_event_search(app, key_event) {
+            return Ok(());
+        }
+        // The completion menu stays open while cycling, any other key accepts the candidate
+        if let Some(c) = app.completion.as_mut() {
+            match key_event.code {
+                KeyCode::Tab | KeyCode::BackTab => {}
+                KeyCode::Esc => {
+                    let (input, cursor) = c.cancel(app.input.text());
+                    app.input.set(input);
+                    app.input.set_cursor(cursor);
+                    app.completion = None;
+                    return Ok(());
+                }
+                _ => app.completion = None,
+            }
+        }
+        let lookup = self.keymap.feed(KeymapMode::Editing, key_event);
+        // The start of a sequence that didn't go through was typed text
+        for c in self.keymap.take_dropped() {
+            self.handle_editing_mode_key_event_shift_c(app, c);
+        }
+        match lookup {
+            Lookup::Action(action) => {
+                self.handle_editing_mode_action(app, action, messages, users)?
+            }
+            Lookup::Pending => {}
+            Lookup::Unbound => match key_event {
+                KeyEvent {
+                    code: KeyCode::Char(c),
+                    modifiers: KeyModifiers::NONE,
+                    ..
+                }
+                | KeyEvent {
+                    code: KeyCode::Char(c),
+                    modifiers: KeyModifiers::SHIFT,
+                    ..
+                } => self.handle_editing_mode_key_event_shift_c(app, c),
+                _ => {}
+            },
+        }
+        app.update_filter();
+        Ok(())
+    }
+
+    fn handle_editing_mode_action(
+        &mut self,
+        app: &mut App,
+        action: KeyAction,
+        messages: &Arc<Mutex<Vec<Message>>>,
+        users: &Arc<Mutex<Users>>,
+    ) -> Result<(), ExitSignal> {
+        match action {
+            KeyAction::Newline => app.input.insert_char('\n'),
+            KeyAction::Submit => self.handle_editing_mode_key_event_enter(app, users)?,
+            KeyAction::Complete => {
+                self.handle_editing_mode_key_event_tab(app, messages, users, true)
+            }
+            KeyAction::CompletePrevious => {
+                self.handle_editing_mode_key_event_tab(app, messages, users, false)
+            }
+            KeyAction::Cancel => self.handle_editing_mode_key_event_ctrl_c(app),
+            KeyAction::Home => app.input.home(),
+            KeyAction::End => app.input.end(),
+            KeyAction::WordForward => app.input.word_forward(),
+            KeyAction::WordBackward => app.input.word_backward(),
+            KeyAction::Paste => self.handle_editing_mode_key_event_ctrl_v(app),
+            KeyAction::KillWordBackward => app.input.kill_word_backward(),
+            KeyAction::KillWordForward => app.input.kill_word_forward(),
+            KeyAction::KillToStart => app.input.kill_to_start(),
+            KeyAction::KillToEnd => app.input.kill_to_end(),
+            KeyAction::Yank => app.input.yank(),
+            KeyAction::YankPop => app.input.yank_pop(),
+            KeyAction::Undo => app.input.undo(),
+            KeyAction::Redo => app.input.redo(),
+            KeyAction::SearchHistory => app.input.search_backward(),
+            KeyAction::Left => app.input.left(),
+            KeyAction::Right => app.input.right(),
+            KeyAction::HistoryPrevious => self.handle_editing_mode_key_event_up(app),
+            KeyAction::HistoryNext => self.handle_editing_mode_key_event_down(app),
+            KeyAction::Backspace => app.input.backspace(),
+            KeyAction::Delete => app.input.delete(),
+            KeyAction::Escape => self.handle_editing_mode_key_event_esc(app),
+            // Normal mode actions
             _ => {}
         }
         Ok(())
@@ -1248,6 +2001,7 @@ impl LeChatPHPClient {
 
     fn handle_long_message_mode_key_event_esc(&mut self, app: &mut App) {
         app.long_message = None;
+        app.long_message_link = None;
         app.input_mode = InputMode::Normal;
     }
 
@@ -1256,20 +2010,15 @@ impl LeChatPHPClient {
         app: &mut App,
         messages: &Arc<Mutex<Vec<Message>>>,
     ) {
-        if let Some(idx) = app.items.state.selected() {
-            if let Some(item) = app.items.items.get(idx) {
-                self.post_msg(PostType::Clean(item.date.to_owned(), item.text.text()))
-                    .unwrap();
-                let mut messages = messages.lock().unwrap();
-                if let Some(pos) = messages
-                    .iter()
-                    .position(|m| m.date == item.date && m.text == item.text)
-                {
-                    messages[pos].hide = !messages[pos].hide;
-                }
-                app.long_message = None;
-                app.input_mode = InputMode::Normal;
+        if let Some(item) = app.selected_message() {
+            self.post_msg(PostType::Clean(item.date.to_owned(), item.text.text()))
+                .unwrap();
+            let mut messages = messages.lock().unwrap();
+            if let Some(pos) = messages.iter().position(|m| m.uid == item.uid) {
+                messages[pos].hide = !messages[pos].hide;
             }
+            app.long_message = None;
+            app.input_mode = InputMode::Normal;
         }
     }
 
@@ -1295,34 +2044,21 @@ impl LeChatPHPClient {
 
     fn handle_normal_mode_key_event_slash(&mut self, app: &mut App) {
         app.items.unselect();
-        app.input = "/".to_owned();
-        app.input_idx = app.input.width();
+        app.input.set("/".to_owned());
         app.input_mode = InputMode::Editing;
     }
 
-    fn handle_normal_mode_key_event_enter(
-        &mut self,
-        app: &mut App,
-        messages: &Arc<Mutex<Vec<Message>>>,
-    ) {
-        if let Some(idx) = app.items.state.selected() {
-            if let Some(item) = app.items.items.get(idx) {
-                // If we have a filter, <enter> will "jump" to the message
-                if !app.filter.is_empty() {
-                    let idx = messages
-                        .lock()
-                        .unwrap()
-                        .iter()
-                        .enumerate()
-                        .find(|(_, e)| e.date == item.date)
-                        .map(|(i, _)| i);
-                    app.clear_filter();
-                    app.items.state.select(idx);
-                    return;
-                }
-                app.long_message = Some(item.clone());
-                app.input_mode = InputMode::LongMessage;
+    fn handle_normal_mode_key_event_enter(&mut self, app: &mut App) {
+        if let Some(item) = app.selected_message() {
+            // If we have a filter, <enter> will "jump" to the message
+            if !app.filter.is_empty() {
+                app.clear_filter();
+                app.jump_to = Some(item.uid);
+                return;
             }
+            app.long_message = Some(item);
+            app.long_message_link = None;
+            app.input_mode = InputMode::LongMessage;
         }
     }
 
@@ -1331,153 +2067,83 @@ impl LeChatPHPClient {
         app: &mut App,
         messages: &Arc<Mutex<Vec<Message>>>,
     ) {
-        if let Some(idx) = app.items.state.selected() {
-            if let Some(item) = app.items.items.get(idx) {
-                let mut messages = messages.lock().unwrap();
-                if let Some(pos) = messages
-                    .iter()
-                    .position(|m| m.date == item.date && m.text == item.text)
-                {
-                    if item.deleted {
-                        messages.remove(pos);
-                    } else {
-                        messages[pos].hide = !messages[pos].hide;
-                    }
+        if let Some(item) = app.selected_message() {
+            let mut messages = messages.lock().unwrap();
+            if let Some(pos) = messages.iter().position(|m| m.uid == item.uid) {
+                if item.deleted {
+                    messages.remove(pos);
+                } else {
+                    messages[pos].hide = !messages[pos].hide;
                 }
             }
         }
     }
 
     fn handle_normal_mode_key_event_yank(&mut self, app: &mut App) {
-        if let Some(idx) = app.items.state.selected() {
-            if let Some(item) = app.items.items.get(idx) {
-                if let Some(upload_link) = &item.upload_link {
-                    let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-                    let mut out = format!("{}{}", self.config.url, upload_link);
-                    if let Some((_, _, msg)) = get_message(&item.text, &self.config.members_tag) {
-                        out = format!("{} {}", msg, out);
-                    }
-                    ctx.set_contents(out).unwrap();
-                } else if let Some((_, _, msg)) = get_message(&item.text, &self.config.members_tag)
-                {
-                    let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-                    ctx.set_contents(msg).unwrap();
+        if let Some(item) = app.selected_message() {
+            if let Some(upload_link) = &item.upload_link {
+                let mut out = format!("{}{}", self.config.url, upload_link);
+                if let Some((_, _, msg)) = get_message(&item.text, &self.config.members_tag) {
+                    out = format!("{} {}", msg, out);
                 }
+                self.copy_to_clipboard(out);
+            } else if let Some((_, _, msg)) = get_message(&item.text, &self.config.members_tag) {
+                self.copy_to_clipboard(msg);
             }
         }
     }
 
     fn handle_normal_mode_key_event_yank_link(&mut self, app: &mut App) {
-        if let Some(idx) = app.items.state.selected() {
-            if let Some(item) = app.items.items.get(idx) {
-                if let Some(upload_link) = &item.upload_link {
-                    let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-                    let out = format!("{}{}", self.config.url, upload_link);
-                    ctx.set_contents(out).unwrap();
-                } else if let Some((_, _, msg)) = get_message(&item.text, &self.config.members_tag)
-                {
-                    let finder = LinkFinder::new();
-                    let links: Vec<_> = finder.links(msg.as_str()).collect();
-                    if let Some(link) = links.get(0) {
-                        let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-                        ctx.set_contents(link.as_str().to_owned()).unwrap();
-                    }
+        if let Some(item) = app.selected_message() {
+            if let Some(upload_link) = &item.upload_link {
+                let out = format!("{}{}", self.config.url, upload_link);
+                self.copy_to_clipboard(out);
+            } else if let Some((_, _, msg)) = get_message(&item.text, &self.config.members_tag) {
+                let finder = LinkFinder::new();
+                let links: Vec<_> = finder.links(msg.as_str()).collect();
+                if let Some(link) = links.get(0) {
+                    self.copy_to_clipboard(link.as_str().to_owned());
                 }
             }
         }
     }
 
+    // There is no clipboard on a headless box (eg: over ssh), say so.
+    fn copy_to_clipboard(&self, contents: String) {
+        let res = ClipboardProvider::new()
+            .and_then(|mut ctx: ClipboardContext| ctx.set_contents(contents));
+        if let Err(e) = res {
+            self.status
+                .lock()
+                .unwrap()
+                .error(format!("clipboard: {}", e));
+        }
+    }
+
+    // Url of the uploaded file, or of the first link, of the selected message.
+    fn selected_download_url(&self, app: &App) -> Option<(String, bool)> {
+        let item = app.selected_message()?;
+        if let Some(upload_link) = &item.upload_link {
+            return Some((format!("{}{}", self.config.url, upload_link), true));
+        }
+        let (_, _, msg) = get_message(&item.text, &self.config.members_tag)?;
+        let finder = LinkFinder::new();
+        let link = finder.links(msg.as_str()).next()?;
+        Some((link.as_str().to_owned(), false))
+    }
+
     //Strange
     fn handle_normal_mode_key_event_download_link(&mut self, app: &mut App) {
-        if let Some(idx) = app.items.state.selected() {
-            if let Some(item) = app.items.items.get(idx) {
-                if let Some(upload_link) = &item.upload_link {
-                    let url = format!("{}{}", self.config.url, upload_link);
-                    let _ = Command::new("curl")
-                        .args([
-                            "--socks5",
-                            "localhost:9050",
-                            "--socks5-hostname",
-                            "localhost:9050",
-                            &url,
-                        ])
-                        .arg("-o")
-                        .arg("download.img")
-                        .output()
-                        .expect("Failed to execute curl command");
-                } else if let Some((_, _, msg)) = get_message(&item.text, &self.config.members_tag)
-                {
-                    let finder = LinkFinder::new();
-                    let links: Vec<_> = finder.links(msg.as_str()).collect();
-                    if let Some(link) = links.first() {
-                        let url = link.as_str();
-                        let _ = Command::new("curl")
-                            .args([
-                                "--socks5",
-                                "localhost:9050",
-                                "--socks5-hostname",
-                                "localhost:9050",
-                                url,
-                            ])
-                            .arg("-o")
-                            .arg("download.img")
-                            .output()
-                            .expect("Failed to execute curl command");
-                    }
-                }
-            }
+        if let Some((url, _)) = self.selected_download_url(app) {
+            self.downloads.start(url, None);
         }
     }
 
     //strageEdit
     fn handle_normal_mode_key_event_download_and_view(&mut self, app: &mut App) {
-        if let Some(idx) = app.items.state.selected() {
-            if let Some(item) = app.items.items.get(idx) {
-                if let Some(upload_link) = &item.upload_link {
-                    let url = format!("{}{}", self.config.url, upload_link);
-                    let _ = Command::new("curl")
-                        .args([
-                            "--socks5",
-                            "localhost:9050",
-                            "--socks5-hostname",
-                            "localhost:9050",
-                            &url,
-                        ])
-                        .arg("-o")
-                        .arg("download.img")
-                        .output()
-                        .expect("Failed to execute curl command");
-
-                    let _ = Command::new("xdg-open")
-                        .arg("./download.img")
-                        .output()
-                        .expect("Failed to execute sxiv command");
-                } else if let Some((_, _, msg)) = get_message(&item.text, &self.config.members_tag)
-                {
-                    let finder = LinkFinder::new();
-                    let links: Vec<_> = finder.links(msg.as_str()).collect();
-                    if let Some(link) = links.first() {
-                        let url = link.as_str();
-                        let _ = Command::new("curl")
-                            .args([
-                                "--socks5",
-                                "localhost:9050",
-                                "--socks5-hostname",
-                                "localhost:9050",
-                                url,
-                            ])
-                            .arg("-o")
-                            .arg("download.img")
-                            .output()
-                            .expect("Failed to execute curl command");
-
-                        let _ = Command::new("sxiv")
-                            .arg("./download.img")
-                            .output()
-                            .expect("Failed to execute sxiv command");
-                    }
-                }
-            }
+        if let Some((url, is_upload)) = self.selected_download_url(app) {
+            let viewer = if is_upload { "xdg-open" } else { "sxiv" };
+            self.downloads.start(url, Some(viewer));
         }
     }
 
@@ -1490,6 +2156,21 @@ impl LeChatPHPClient {
         self.show_sys = !self.show_sys;
     }
 
+    fn handle_normal_mode_key_event_toggle_downloads(&mut self) {
+        self.show_downloads = !self.show_downloads;
+    }
+
+    fn handle_normal_mode_key_event_view_image(&mut self, app: &mut App) {
+        if let Some(item) = app.selected_message() {
+            if let Some(upload_link) = &item.upload_link {
+                let url = format!("{}{}", self.config.url, upload_link);
+                self.previews.request(&url);
+                app.image_viewer = Some(url);
+                app.input_mode = InputMode::ImageViewer;
+            }
+        }
+    }
+
     fn handle_normal_mode_key_event_toggle_guest_view(&mut self) {
         self.display_guest_view = !self.display_guest_view;
     }
@@ -1498,11 +2179,37 @@ impl LeChatPHPClient {
         self.display_member_view = !self.display_member_view;
     }
 
-    fn handle_normal_mode_key_event_g(&mut self, app: &mut App) {
-        // Handle "gg" key combination
-        if self.last_key_event == Some(KeyCode::Char('g')) {
-            app.items.select_top();
-            self.last_key_event = None;
+    // The n-th saved view, all the messages for 0.
+    fn handle_normal_mode_key_event_view(&mut self, app: &mut App, n: usize) {
+        if n == 0 {
+            app.view = None;
+        } else if let Some(view) = self.saved_views.get(n - 1) {
+            app.view = Some(view.clone());
+        } else {
+            self.status
+                .lock()
+                .unwrap()
+                .error(format!("No saved view {}", n));
+        }
+    }
+
+    // Select the next mention or PM to us, down the list (older) when "forward".
+    fn handle_normal_mode_key_event_mention(&mut self, app: &mut App, forward: bool) {
+        let facts = &app.facts;
+        let is_mention = |m: &Message| facts.get(&m.uid).map_or(false, |f| f.mention);
+        let items = &app.items.items;
+        let found = match (app.items.state.selected(), forward) {
+            (None, true) => items.iter().position(is_mention),
+            (Some(idx), true) => items
+                .iter()
+                .skip(idx + 1)
+                .position(is_mention)
+                .map(|pos| pos + idx + 1),
+            (None, false) => items.iter().rposition(is_mention),
+            (Some(idx), false) => items[..idx].iter().rposition(is_mention),
+        };
+        if let Some(idx) = found {
+            app.items.state.select(Some(idx));
         }
     }
 
@@ -1525,32 +2232,96 @@ impl LeChatPHPClient {
     }
 
     fn handle_normal_mode_key_event_tag(&mut self, app: &mut App) {
-        if let Some(idx) = app.items.state.selected() {
-            let text = &app.items.items.get(idx).unwrap().text;
+        if let Some(item) = app.selected_message() {
+            let text = &item.text;
             if let Some(username) =
                 get_username(&self.base_client.username, &text, &self.config.members_tag)
             {
                 if text.text().starts_with(&app.members_tag) {
-                    app.input = format!("/m @{} ", username);
+                    app.input.set(format!("/m @{} ", username));
                 } else {
-                    app.input = format!("@{} ", username);
+                    app.input.set(format!("@{} ", username));
                 }
-                app.input_idx = app.input.width();
                 app.input_mode = InputMode::Editing;
                 app.items.unselect();
             }
         }
     }
 
+    fn handle_normal_mode_key_event_reply(&mut self, app: &mut App, with_quote: bool) {
+        let item = match app.selected_message() {
+            Some(item) => item,
+            None => return,
+        };
+        let facts = match app.facts.get(&item.uid) {
+            Some(facts) => facts,
+            None => return,
+        };
+        let from = match &facts.from {
+            Some(from) => from,
+            None => {
+                self.status
+                    .lock()
+                    .unwrap()
+                    .error("nothing to reply to".to_owned());
+                return;
+            }
+        };
+        // Answer in the channel of the message
+        let prefix = match facts.channel {
+            filter::Channel::Pm => {
+                let other = if *from == self.base_client.username {
+                    facts.to.as_ref()
+                } else {
+                    Some(from)
+                };
+                other.map_or(String::new(), |u| format!("/pm {} ", u))
+            }
+            filter::Channel::Members => "/m ".to_owned(),
+            filter::Channel::Staff => "/s ".to_owned(),
+            _ => String::new(),
+        };
+        let reply = replies::reply_text(from, &facts.body, with_quote);
+        app.input.set(format!("{}{}", prefix, reply));
+        app.input_mode = InputMode::Editing;
+        app.items.unselect();
+    }
+
+    fn handle_normal_mode_key_event_thread(
+        &mut self,
+        app: &mut App,
+        messages: &Arc<Mutex<Vec<Message>>>,
+    ) {
+        let item = match app.selected_message() {
+            Some(item) => item,
+            None => return,
+        };
+        let messages = messages.lock().unwrap();
+        let links: Vec<(u64, Option<u64>)> = messages
+            .iter()
+            .map(|m| (m.uid, app.facts.get(&m.uid).and_then(|f| f.reply_to)))
+            .collect();
+        let mut list = StatefulList::new();
+        for (depth, uid) in replies::thread(item.uid, &links) {
+            if let Some(m) = messages.iter().find(|m| m.uid == uid) {
+                if uid == item.uid {
+                    list.state.select(Some(list.items.len()));
+                }
+                list.items.push((depth, m.clone()));
+            }
+        }
+        app.thread = Some(list);
+        app.input_mode = InputMode::Thread;
+    }
+
     fn handle_normal_mode_key_event_pm(&mut self, app: &mut App) {
-        if let Some(idx) = app.items.state.selected() {
+        if let Some(item) = app.selected_message() {
             if let Some(username) = get_username(
                 &self.base_client.username,
-                &app.items.items.get(idx).unwrap().text,
+                &item.text,
                 &self.config.members_tag,
             ) {
-                app.input = format!("/pm {} ", username);
-                app.input_idx = app.input.width();
+                app.input.set(format!("/pm {} ", username));
                 app.input_mode = InputMode::Editing;
                 app.items.unselect();
             }
@@ -1558,14 +2329,13 @@ impl LeChatPHPClient {
     }
 
     fn handle_normal_mode_key_event_kick(&mut self, app: &mut App) {
-        if let Some(idx) = app.items.state.selected() {
+        if let Some(item) = app.selected_message() {
             if let Some(username) = get_username(
                 &self.base_client.username,
-                &app.items.items.get(idx).unwrap().text,
+                &item.text,
                 &self.config.members_tag,
             ) {
-                app.input = format!("/kick {} ", username);
-                app.input_idx = app.input.width();
+                app.input.set(format!("/kick {} ", username));
                 app.input_mode = InputMode::Editing;
                 app.items.unselect();
             }
@@ -1573,14 +2343,13 @@ impl LeChatPHPClient {
     }
 
     fn handle_normal_mode_key_event_ban(&mut self, app: &mut App) {
-        if let Some(idx) = app.items.state.selected() {
+        if let Some(item) = app.selected_message() {
             if let Some(username) = get_username(
                 &self.base_client.username,
-                &app.items.items.get(idx).unwrap().text,
+                &item.text,
                 &self.config.members_tag,
             ) {
-                app.input = format!("/ban {} ", username);
-                app.input_idx = app.input.width();
+                app.input.set(format!("/ban {} ", username));
                 app.input_mode = InputMode::Editing;
                 app.items.unselect();
             }
@@ -1588,14 +2357,13 @@ impl LeChatPHPClient {
     }
 
     fn handle_normal_mode_key_event_ban_exact(&mut self, app: &mut App) {
-        if let Some(idx) = app.items.state.selected() {
+        if let Some(item) = app.selected_message() {
             if let Some(username) = get_username(
                 &self.base_client.username,
-                &app.items.items.get(idx).unwrap().text,
+                &item.text,
                 &self.config.members_tag,
             ) {
-                app.input = format!(r#"/ban "{}" "#, username);
-                app.input_idx = app.input.width();
+                app.input.set(format!(r#"/ban "{}" "#, username));
                 app.input_mode = InputMode::Editing;
                 app.items.unselect();
             }
@@ -1609,10 +2377,11 @@ impl LeChatPHPClient {
         messages: &Arc<Mutex<Vec<Message>>>,
     ) {
         log::error!("translate running");
-        if let Some(idx) = app.items.state.selected() {
+        if let Some(item) = app.selected_message() {
             log::error!("1353");
             let mut message_lock = messages.lock().unwrap();
-            if let Some(message) = message_lock.get_mut(idx) {
+            if let Some(message) = message_lock.iter_mut().find(|m| m.uid == item.uid) {
+                app.facts.remove(&item.uid);
                 log::error!("1356");
                 let original_text = &mut message.text;
                 let output = Command::new("trans")
@@ -1637,14 +2406,13 @@ impl LeChatPHPClient {
 
     //Strange
     fn handle_normal_mode_key_event_warn(&mut self, app: &mut App) {
-        if let Some(idx) = app.items.state.selected() {
+        if let Some(item) = app.selected_message() {
             if let Some(username) = get_username(
                 &self.base_client.username,
-                &app.items.items.get(idx).unwrap().text,
+                &item.text,
                 &self.config.members_tag,
             ) {
-                app.input = format!("!warn @{} ", username);
-                app.input_idx = app.input.width();
+                app.input.set(format!("!warn @{} ", username));
                 app.input_mode = InputMode::Editing;
                 app.items.unselect();
             }
@@ -1671,10 +2439,8 @@ impl LeChatPHPClient {
 
     fn handle_normal_mode_key_event_esc(&mut self, app: &mut App) {
         app.items.unselect();
-    }
-
-    fn handle_normal_mode_key_event_shift_u(&mut self, app: &mut App) {
-        app.items.state.select(Some(0));
+        // Back to the newest messages
+        app.top = None;
     }
 
     fn handle_editing_mode_key_event_enter(
@@ -1682,209 +2448,158 @@ impl LeChatPHPClient {
         app: &mut App,
         users: &Arc<Mutex<Users>>,
     ) -> Result<(), ExitSignal> {
-        if FIND_RGX.is_match(&app.input) {
+        if FIND_RGX.is_match(app.input.text()) {
             return Ok(());
         }
 
-        let mut input: String = app.input.drain(..).collect();
+        let mut input = app.input.take();
         input = replace_newline_escape(&input);
-        app.input_idx = 0;
-
-        // Iterate over commands and execute associated actions
-        for (command, action) in &app.commands.commands {
-            // log::error!("command :{} action :{}", command, action);
-            let expected_input = format!("!{}", command);
-            if input == expected_input {
-                // Execute the action by posting a message
-                self.post_msg(PostType::Post(action.clone(), None)).unwrap();
-                // Return Ok(()) if the action is executed successfully
-                return Ok(());
-            }
-        }
+        // Talking in the chat means we caught up
+        app.mark_read();
 
-        let mut cmd_input = input.clone();
-        let mut members_prefix = false;
-        if cmd_input.starts_with("/m ") {
-            members_prefix = true;
-            if remove_prefix(&cmd_input, "/m ").starts_with('/') {
-                cmd_input = remove_prefix(&cmd_input, "/m ").to_owned();
+        match app.commands.parse(&input) {
+            Ok(Some((Run::Builtin(cmd), args))) => {
+                self.run_command(cmd, &args, &input, app, users);
             }
-        }
-
-        if self.process_command(&cmd_input, app, users) {
-            if members_prefix {
-                app.input = "/m ".to_owned();
-                app.input_idx = app.input.width();
+            Ok(Some((Run::Post(text), _))) => {
+                self.post_msg(PostType::Post(text, None)).unwrap();
             }
-            return Ok(());
-        }
-
-        if members_prefix {
-            let msg = remove_prefix(&input, "/m ").to_owned();
-            let to = Some(SEND_TO_MEMBERS.to_owned());
-            self.post_msg(PostType::Post(msg, to)).unwrap();
-            app.input = "/m ".to_owned();
-            app.input_idx = app.input.width();
-        } else if input.starts_with("/a ") {
-            let msg = remove_prefix(&input, "/a ").to_owned();
-            let to = Some(SEND_TO_ADMINS.to_owned());
-            self.post_msg(PostType::Post(msg, to)).unwrap();
-            app.input = "/a ".to_owned();
-            app.input_idx = app.input.width();
-        } else if input.starts_with("/s ") {
-            let msg = remove_prefix(&input, "/s ").to_owned();
-            let to = Some(SEND_TO_STAFFS.to_owned());
-            self.post_msg(PostType::Post(msg, to)).unwrap();
-            app.input = "/s ".to_owned();
-            app.input_idx = app.input.width();
-        } else {
-            if input.starts_with("/") && !input.starts_with("/me ") {
-                app.input_idx = input.len();
-                app.input = input;
-                app.input_mode = InputMode::EditingErr;
-            } else {
+            Ok(None) => {
                 self.post_msg(PostType::Post(input, None)).unwrap();
             }
+            Err(e) => self.usage_error(app, &input, e),
         }
         Ok(())
     }
 
-    fn handle_editing_mode_key_event_tab(&mut self, app: &mut App, users: &Arc<Mutex<Users>>) {
-        let (p1, p2) = app.input.split_at(app.input_idx);
-        if p2 == "" || p2.chars().nth(0) == Some(' ') {
-            let mut parts: Vec<&str> = p1.split(" ").collect();
-            if let Some(user_prefix) = parts.pop() {
-                let mut should_autocomplete = false;
-                let mut prefix = "";
-                if parts.len() == 1
-                    && ((parts[0] == "/kick" || parts[0] == "/k")
-                        || parts[0] == "/pm"
-                        || parts[0] == "/ignore"
-                        || parts[0] == "/unignore"
-                        || parts[0] == "/ban")
-                {
-                    should_autocomplete = true;
-                } else if user_prefix.starts_with("@") {
-                    should_autocomplete = true;
-                    prefix = "@";
-                }
-                if should_autocomplete {
-                    let user_prefix_norm = remove_prefix(user_prefix, prefix);
-                    let user_prefix_norm_len = user_prefix_norm.len();
-                    if let Some(name) = autocomplete_username(users, user_prefix_norm) {
-                        let complete_name = format!("{}{}", prefix, name);
-                        parts.push(complete_name.as_str());
-                        let p2 = p2.trim_start();
-                        if p2 != "" {
-                            parts.push(p2);
-                        }
-                        app.input = parts.join(" ");
-                        app.input_idx += name.len() - user_prefix_norm_len;
-                    }
-                }
+    fn handle_editing_mode_key_event_tab(
+        &mut self,
+        app: &mut App,
+        messages: &Arc<Mutex<Vec<Message>>>,
+        users: &Arc<Mutex<Users>>,
+        forward: bool,
+    ) {
+        if app.completion.is_none() {
+            let sources = self.completion_sources(messages, users);
+            let (input, cursor) = (app.input.text(), app.input.cursor());
+            app.completion = completion::complete(input, cursor, &app.commands, &sources);
+        }
+        if let Some(c) = app.completion.as_mut() {
+            let (input, cursor) = c.cycle(app.input.text(), forward);
+            app.input.set(input);
+            app.input.set_cursor(cursor);
+            // Nothing to choose from
+            if c.candidates.len() == 1 {
+                app.completion = None;
             }
         }
     }
 
-    fn handle_editing_mode_key_event_ctrl_c(&mut self, app: &mut App) {
-        app.clear_filter();
-        app.input = "".to_owned();
-        app.input_idx = 0;
-        app.input_mode = InputMode::Normal;
-    }
-
-    fn handle_editing_mode_key_event_ctrl_a(&mut self, app: &mut App) {
-        app.input_idx = 0;
-    }
-
-    fn handle_editing_mode_key_event_ctrl_e(&mut self, app: &mut App) {
-        app.input_idx = app.input.width();
-    }
-
-    fn handle_editing_mode_key_event_ctrl_f(&mut self, app: &mut App) {
-        if let Some(idx) = app.input.chars().skip(app.input_idx).position(|c| c == ' ') {
-            app.input_idx = std::cmp::min(app.input_idx + idx + 1, app.input.width());
-        } else {
-            app.input_idx = app.input.width();
-        }
-    }
-
-    fn handle_editing_mode_key_event_ctrl_b(&mut self, app: &mut App) {
-        if let Some(idx) = app.input_idx.checked_sub(2) {
-            let tmp = app
-                .input
-                .chars()
-                .take(idx)
-                .collect::<String>()
-                .chars()
-                .rev()
-                .collect::<String>();
-            if let Some(idx) = tmp.chars().position(|c| c == ' ') {
-                app.input_idx = std::cmp::max(tmp.width() - idx, 0);
-            } else {
-                app.input_idx = 0;
+    fn completion_sources(
+        &self,
+        messages: &Arc<Mutex<Vec<Message>>>,
+        users: &Arc<Mutex<Users>>,
+    ) -> completion::Sources {
+        // Authors of the messages, most recent first
+        let mut active: Vec<String> = Vec::new();
+        for m in messages.lock().unwrap().iter() {
+            if let Some((from, _, _)) = get_message(&m.text, &self.config.members_tag) {
+                if !active.contains(&from) {
+                    active.push(from);
+                }
             }
         }
-    }
-
-    fn handle_editing_mode_key_event_ctrl_v(&mut self, app: &mut App) {
-        let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-        if let Ok(clipboard) = ctx.get_contents() {
-            let byte_position = byte_pos(&app.input, app.input_idx).unwrap();
-            app.input.insert_str(byte_position, &clipboard);
-            app.input_idx += clipboard.chars().count();
+        let mut users: Vec<String> = users
+            .lock()
+            .unwrap()
+            .all()
+            .iter()
+            .map(|(_, name)| name.to_owned())
+            .collect();
+        users.sort_by_key(|name| active.iter().position(|a| a == name).unwrap_or(usize::MAX));
+
+        let mut ban_filters = self.bad_username_filters.lock().unwrap().clone();
+        for name in self.bad_exact_username_filters.lock().unwrap().iter() {
+            ban_filters.push(format!("\"{}\"", name));
+        }
+        completion::Sources {
+            users,
+            ban_filters,
+            msg_filters: self.bad_message_filters.lock().unwrap().clone(),
+            colors: GUEST_COLORS
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .collect(),
         }
     }
 
-    fn handle_editing_mode_key_event_newline(&mut self, app: &mut App) {
-        let byte_position = byte_pos(&app.input, app.input_idx).unwrap();
-        app.input.insert(byte_position, '\n');
-        app.input_idx += 1;
+    fn handle_editing_mode_key_event_ctrl_c(&mut self, app: &mut App) {
+        app.clear_filter();
+        app.input.clear();
+        app.input_mode = InputMode::Normal;
     }
 
-    fn handle_editing_mode_key_event_left(&mut self, app: &mut App) {
-        if app.input_idx > 0 {
-            app.input_idx -= 1;
+    fn handle_editing_mode_key_event_ctrl_v(&mut self, app: &mut App) {
+        let res = ClipboardProvider::new().and_then(|mut ctx: ClipboardContext| ctx.get_contents());
+        match res {
+            Ok(clipboard) => app.input.insert_str(&clipboard),
+            Err(e) => self
+                .status
+                .lock()
+                .unwrap()
+                .error(format!("clipboard: {}", e)),
         }
     }
 
-    fn handle_editing_mode_key_event_right(&mut self, app: &mut App) {
-        if app.input_idx < app.input.width() {
-            app.input_idx += 1;
-        }
+    fn handle_editing_mode_key_event_up(&mut self, app: &mut App) {
+        app.input.history_prev();
     }
 
     fn handle_editing_mode_key_event_down(&mut self, app: &mut App) {
-        app.input_mode = InputMode::Normal;
-        app.items.next();
-    }
-
-    fn handle_editing_mode_key_event_shift_c(&mut self, app: &mut App, c: char) {
-        let byte_position = byte_pos(&app.input, app.input_idx).unwrap();
-        app.input.insert(byte_position, c);
-
-        app.input_idx += 1;
-        app.update_filter();
+        // Past the most recent history entry, go to the messages
+        if !app.input.history_next() {
+            app.input_mode = InputMode::Normal;
+            app.items.next();
+        }
     }
 
-    fn handle_editing_mode_key_event_backspace(&mut self, app: &mut App) {
-        if app.input_idx > 0 {
-            app.input_idx -= 1;
-            app.input = remove_at(&app.input, app.input_idx);
-            app.update_filter();
+    // Keys while searching the history with ctrl+R
+    // Any other key accepts the match, then is handled as usual (eg: Enter
+    // sends it), returns false in that case.
+    fn handle_editing_mode_key_event_search(&mut self, app: &mut App, key_event: KeyEvent) -> bool {
+        match (key_event.code, key_event.modifiers) {
+            (KeyCode::Char('r'), KeyModifiers::CONTROL) => app.input.search_backward(),
+            (KeyCode::Char('g'), KeyModifiers::CONTROL) | (KeyCode::Esc, _) => {
+                app.input.search_cancel()
+            }
+            (KeyCode::Char(c), KeyModifiers::NONE) | (KeyCode::Char(c), KeyModifiers::SHIFT) => {
+                app.input.search_push(c)
+            }
+            (KeyCode::Backspace, _) => app.input.search_pop(),
+            _ => {
+                app.input.search_accept();
+                return false;
+            }
         }
+        true
     }
 
-    fn handle_editing_mode_key_event_delete(&mut self, app: &mut App) {
-        if app.input_idx > 0 && app.input_idx == app.input.width() {
-            app.input_idx -= 1;
+    fn handle_editing_mode_key_event_shift_c(&mut self, app: &mut App, c: char) {
+        if app.input.vi_normal() {
+            app.input.vi_key(c);
+        } else {
+            app.input.insert_char(c);
         }
-        app.input = remove_at(&app.input, app.input_idx);
-        app.update_filter();
     }
 
     fn handle_editing_mode_key_event_esc(&mut self, app: &mut App) {
-        app.input_mode = InputMode::Normal;
+        // With vi mode, Esc goes to the vi normal mode first
+        if app.input.vi_mode() && !app.input.vi_normal() {
+            app.input.set_vi_normal(true);
+        } else {
+            app.input.set_vi_normal(false);
+            app.input_mode = InputMode::Normal;
+        }
     }
 
     fn handle_mouse_event(
@@ -1901,42 +2616,23 @@ impl LeChatPHPClient {
     }
 }
 
-// Give a char index, return the byte position
-fn byte_pos(v: &str, idx: usize) -> Option<usize> {
-    let mut b = 0;
-    let mut chars = v.chars();
-    for _ in 0..idx {
-        if let Some(c) = chars.next() {
-            b += c.len_utf8();
-        } else {
-            return None;
+// Upload target: a group, or a user that must be in the chat.
+fn upload_send_to(target: Option<&str>, users: &Arc<Mutex<Users>>) -> anyhow::Result<String> {
+    Ok(match target {
+        None => SEND_TO_ALL.to_owned(),
+        Some("members") => SEND_TO_MEMBERS.to_owned(),
+        Some("staffs") => SEND_TO_STAFFS.to_owned(),
+        Some("admins") => SEND_TO_ADMINS.to_owned(),
+        Some(username) => {
+            let users = users.lock().unwrap();
+            let found = users
+                .all()
+                .into_iter()
+                .find(|(_, name)| name.eq_ignore_ascii_case(username))
+                .map(|(_, name)| name.to_owned());
+            found.ok_or_else(|| anyhow!("@{} is not in the chat", username))?
         }
-    }
-    Some(b)
-}
-
-// Remove the character at idx (utf-8 aware)
-fn remove_at(v: &str, idx: usize) -> String {
-    v.chars()
-        .enumerate()
-        .flat_map(|(i, c)| {
-            if i == idx {
-                return None;
-            }
-            Some(c)
-        })
-        .collect::<String>()
-}
-
-// Autocomplete any username
-fn autocomplete_username(users: &Arc<Mutex<Users>>, prefix: &str) -> Option<String> {
-    let users = users.lock().unwrap();
-    let all_users = users.all();
-    let prefix_lower = prefix.to_lowercase();
-    let filtered = all_users
-        .iter()
-        .find(|(_, name)| name.to_lowercase().starts_with(&prefix_lower));
-    Some(filtered?.1.to_owned())
+    })
 }
 
 fn set_profile_base_info(
@@ -1971,38 +2667,19 @@ fn set_profile_base_info(
     Ok(())
 }
 
-enum RetryErr {
-    Retry,
-    Exit,
-}
-
-fn retry_fn<F>(mut clb: F)
-where
-    F: FnMut() -> anyhow::Result<RetryErr>,
-{
-    loop {
-        match clb() {
-            Ok(RetryErr::Retry) => continue,
-            Ok(RetryErr::Exit) => return,
-            Err(err) => {
-                log::error!("{}", err);
-                continue;
-            }
-        }
-    }
-}
-
+// Make a single attempt at delivering "post_type" to the chat server.
+// Retries are handled by the outbox.
 fn post_msg(
     client: &Client,
-    post_type_recv: PostType,
+    post_type: PostType,
     full_url: &str,
     session: String,
     url: &str,
     last_post_tx: &crossbeam_channel::Sender<()>,
-) {
+    status: &Arc<Mutex<StatusLine>>,
+) -> anyhow::Result<()> {
     let mut should_reset_keepalive_timer = false;
-    retry_fn(|| -> anyhow::Result<RetryErr> {
-        let post_type = post_type_recv.clone();
+    {
         let resp_text = client.get(url).send()?.text()?;
         let doc = Document::from(resp_text.as_str());
         let nc = doc
@@ -2025,11 +2702,8 @@ fn post_msg(
         ];
 
         if let PostType::Clean(date, text) = post_type {
-            if let Err(e) = delete_message(&client, full_url, &mut params, date, text) {
-                log::error!("failed to delete message: {:?}", e);
-                return Ok(RetryErr::Retry);
-            }
-            return Ok(RetryErr::Exit);
+            return delete_message(client, full_url, &mut params, date, text)
+                .context("failed to delete message");
         }
 
         let mut req = client.post(full_url);
@@ -2110,8 +2784,22 @@ fn post_msg(
                 }
             }
             PostType::Upload(file_path, send_to, msg) => {
+                let file = std::fs::File::open(&file_path)
+                    .map_err(|e| anyhow!("failed to read upload: {}", e))?;
+                let len = file.metadata()?.len();
+                let file_name = std::path::Path::new(&file_path)
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let status = Arc::clone(status);
+                let reader = upload::ProgressReader::new(file, file_name.clone(), len, status);
+                let part = multipart::Part::reader_with_length(reader, len).file_name(file_name);
+                let part = match upload::mime_type(&file_path) {
+                    Some(mime) => part.mime_str(mime)?,
+                    None => part,
+                };
                 form = Some(
-                    match multipart::Form::new()
+                    multipart::Form::new()
                         .text("lang", LANG.to_owned())
                         .text("nc", nc_value.to_owned())
                         .text("session", session.clone())
@@ -2120,14 +2808,7 @@ fn post_msg(
                         .text("message", msg)
                         .text("sendto", send_to.to_owned())
                         .text("what", "purge".to_owned())
-                        .file("file", file_path)
-                    {
-                        Ok(f) => f,
-                        Err(e) => {
-                            log::error!("{:?}", e);
-                            return Ok(RetryErr::Exit);
-                        }
-                    },
+                        .part("file", part),
                 );
             }
             PostType::Clean(_, _) => {}
@@ -2138,27 +2819,42 @@ fn post_msg(
         } else {
             req = req.form(&params);
         }
-        if let Err(err) = req.send() {
-            log::error!("{:?}", err.to_string());
-            if err.is_timeout() {
-                return Ok(RetryErr::Retry);
-            }
+        let resp = req.send()?;
+        if !resp.status().is_success() {
+            return Err(anyhow!("server replied {}", resp.status()));
         }
-        return Ok(RetryErr::Exit);
-    });
+    }
     if should_reset_keepalive_timer {
         last_post_tx.send(()).unwrap();
     }
+    Ok(())
+}
+
+// Re-encode images into a temporary file, other files are sent as they are.
+fn strip_upload(path: PathBuf, kind: sanitize::FileKind) -> anyhow::Result<PathBuf> {
+    match kind {
+        sanitize::FileKind::Image(format) => {
+            let stripped = sanitize::strip_image(&path, format);
+            // The clipboard image is not needed anymore
+            upload::remove_temp_file(&path);
+            stripped
+        }
+        _ => Ok(path),
+    }
 }
 
 fn parse_date(date: &str, datetime_fmt: &str) -> NaiveDateTime {
+    try_parse_date(date, datetime_fmt).unwrap()
+}
+
+fn try_parse_date(date: &str, datetime_fmt: &str) -> Option<NaiveDateTime> {
     let now = Utc::now();
     let date_fmt = format!("%Y-{}", datetime_fmt);
     NaiveDateTime::parse_from_str(
         format!("{}-{}", now.year(), date).as_str(),
         date_fmt.as_str(),
     )
-    .unwrap()
+    .ok()
 }
 
 fn get_msgs(
@@ -2170,10 +2866,10 @@ fn get_msgs(
     users: &Arc<Mutex<Users>>,
     sig: &Arc<Mutex<Sig>>,
     messages_updated_tx: &crossbeam_channel::Sender<()>,
-    members_tag: &str,
+    (members_tag, staffs_tag): (&str, &str),
     datetime_fmt: &str,
     messages: &Arc<Mutex<Vec<Message>>>,
-    should_notify: &mut bool,
+    (notices, published, actions): (&mut Vec<Notice>, &mut Vec<BridgeEvent>, &mut Vec<ModAction>),
     tx: &crossbeam_channel::Sender<PostType>,
     bad_usernames: &Arc<Mutex<Vec<String>>>,
     bad_exact_usernames: &Arc<Mutex<Vec<String>>>,
@@ -2198,15 +2894,43 @@ fn get_msgs(
     let current_users = extract_users(&doc);
     {
         let previous = users.lock().unwrap();
+        // Everyone is new on the first page
+        let known: HashSet<&String> = previous.all().into_iter().map(|(_, n)| n).collect();
+        let current: HashSet<&String> = current_users.all().into_iter().map(|(_, n)| n).collect();
+        if !known.is_empty() {
+            for name in current.difference(&known) {
+                notices.push(Notice::Join(name.to_string()));
+                published.push(BridgeEvent::Join {
+                    user: name.to_string(),
+                });
+            }
+            for name in known.difference(&current) {
+                published.push(BridgeEvent::Leave {
+                    user: name.to_string(),
+                });
+            }
+        }
         let filters = bad_usernames.lock().unwrap();
         let exact_filters = bad_exact_usernames.lock().unwrap();
         for (_, name) in &current_users.guests {
             if !previous.guests.iter().any(|(_, n)| n == name) {
-                if exact_filters.iter().any(|f| f == name)
-                    || filters.iter().any(|f| name.to_lowercase().contains(&f.to_lowercase()))
+                let reason = if exact_filters.iter().any(|f| f == name) {
+                    "exact name filter"
+                } else if filters
+                    .iter()
+                    .any(|f| name.to_lowercase().contains(&f.to_lowercase()))
                 {
-                    let _ = tx.send(PostType::Kick(String::new(), name.clone()));
-                }
+                    "name filter"
+                } else {
+                    continue;
+                };
+                let _ = tx.send(PostType::Kick(String::new(), name.clone()));
+                actions.push(ModAction {
+                    date: Local::now().format(datetime_fmt).to_string(),
+                    user: name.clone(),
+                    action: Action::Kick,
+                    reason: reason.to_owned(),
+                });
             }
         }
     }
@@ -2216,9 +2940,9 @@ fn get_msgs(
             &new_messages,
             &messages,
             datetime_fmt,
-            members_tag,
+            (members_tag, staffs_tag),
             username,
-            should_notify,
+            (notices, published, actions),
             &current_users,
             tx,
             bad_usernames,
@@ -2244,9 +2968,9 @@ fn process_new_messages(
     new_messages: &Vec<Message>,
     messages: &MutexGuard<Vec<Message>>,
     datetime_fmt: &str,
-    members_tag: &str,
+    (members_tag, staffs_tag): (&str, &str),
     username: &str,
-    should_notify: &mut bool,
+    (notices, published, actions): (&mut Vec<Notice>, &mut Vec<BridgeEvent>, &mut Vec<ModAction>),
     users: &Users,
     tx: &crossbeam_channel::Sender<PostType>,
     bad_usernames: &Arc<Mutex<Vec<String>>>,
@@ -2262,15 +2986,30 @@ fn process_new_messages(
         });
         for new_msg in filtered {
             log_chat_message(new_msg);
+            if is_mod_event(new_msg) {
+                notices.push(Notice::Moderation(new_msg.text.text()));
+                published.push(BridgeEvent::Moderation {
+                    date: new_msg.date.clone(),
+                    text: new_msg.text.text(),
+                });
+            }
             if let Some((from, to_opt, msg)) = get_message(&new_msg.text, members_tag) {
-                // Notify when tagged
-                if msg.contains(format!("@{}", &username).as_str()) {
-                    *should_notify = true;
-                }
-                if let Some(ref to) = to_opt {
-                    if to == username && msg != "!up" {
-                        *should_notify = true;
-                    }
+                let channel = message_channel(new_msg, &to_opt, members_tag, staffs_tag);
+                published.push(BridgeEvent::Message {
+                    date: new_msg.date.clone(),
+                    from: from.clone(),
+                    to: to_opt.clone(),
+                    channel,
+                    text: msg.clone(),
+                });
+                if from != username {
+                    notices.push(Notice::Message {
+                        from: from.clone(),
+                        text: msg.clone(),
+                        channel,
+                        to_me: to_opt.as_deref() == Some(username) && msg != "!up",
+                        mention: is_mention(&to_opt, &msg, username),
+                    });
                 }
 
                 // Remote moderation handling
@@ -2290,6 +3029,12 @@ fn process_new_messages(
                             let user = target.trim().trim_start_matches('@');
                             if !user.is_empty() {
                                 let _ = tx.send(PostType::Kick(String::new(), user.to_owned()));
+                                actions.push(ModAction {
+                                    date: new_msg.date.clone(),
+                                    user: user.to_owned(),
+                                    action: Action::Kick,
+                                    reason: format!("#kick from {}", from),
+                                });
                             }
                         } else if let Some(target) = msg.strip_prefix("#ban ") {
                             let user = target.trim().trim_start_matches('@');
@@ -2297,6 +3042,12 @@ fn process_new_messages(
                                 let _ = tx.send(PostType::Kick(String::new(), user.to_owned()));
                                 let mut f = bad_usernames.lock().unwrap();
                                 f.push(user.to_owned());
+                                actions.push(ModAction {
+                                    date: new_msg.date.clone(),
+                                    user: user.to_owned(),
+                                    action: Action::Ban,
+                                    reason: format!("#ban from {}", from),
+                                });
                             }
                         }
                     } else if directed_to_me && !has_permission {
@@ -2309,7 +3060,9 @@ fn process_new_messages(
                 if from != username && is_guest {
                     let bad_name = {
                         let filters = bad_usernames.lock().unwrap();
-                        filters.iter().any(|f| from.to_lowercase().contains(&f.to_lowercase()))
+                        filters
+                            .iter()
+                            .any(|f| from.to_lowercase().contains(&f.to_lowercase()))
                     };
                     let bad_name_exact = {
                         let filters = bad_exact_usernames.lock().unwrap();
@@ -2317,21 +3070,42 @@ fn process_new_messages(
                     };
                     let bad_msg = {
                         let filters = bad_messages.lock().unwrap();
-                        filters.iter().any(|f| msg.to_lowercase().contains(&f.to_lowercase()))
+                        filters
+                            .iter()
+                            .any(|f| msg.to_lowercase().contains(&f.to_lowercase()))
                     };
 
+                    let action = |action, reason: &str| ModAction {
+                        date: new_msg.date.clone(),
+                        user: from.clone(),
+                        action,
+                        reason: reason.to_owned(),
+                    };
                     if bad_name_exact || bad_name || bad_msg {
                         let _ = tx.send(PostType::Kick(String::new(), from.clone()));
+                        let reason = if bad_name_exact {
+                            "exact name filter"
+                        } else if bad_name {
+                            "name filter"
+                        } else {
+                            "message filter"
+                        };
+                        actions.push(action(Action::Kick, reason));
                     } else {
                         let res = score_message(&msg);
                         if let Some(act) = action_from_score(res.score) {
+                            let reason = res
+                                .reason
+                                .map(|r| r.description())
+                                .unwrap_or("breaking the rules");
+                            // Warnings are only posted for public messages
+                            if act != Action::Warn || to_opt.is_none() {
+                                actions
+                                    .push(action(act, &format!("score {}, {}", res.score, reason)));
+                            }
                             match act {
                                 Action::Warn => {
                                     if to_opt.is_none() {
-                                        let reason = res
-                                            .reason
-                                            .map(|r| r.description())
-                                            .unwrap_or("breaking the rules");
                                         let warn = format!(
                                             "@{username} - @{from}'s message was flagged for {reason}."
                                         );
@@ -2355,6 +3129,99 @@ fn process_new_messages(
     }
 }
 
+// Messages that tag us, and PMs sent to us.
+fn is_mention(to: &Option<String>, msg: &str, username: &str) -> bool {
+    msg.contains(format!("@{}", username).as_str())
+        || (to.as_deref() == Some(username) && msg != "!up")
+}
+
+// What the filters need to know about a message.
+fn message_facts(m: &Message, app: &App, username: &str) -> Facts {
+    let text = m.text.text();
+    let (from, to, msg) = match get_message(&m.text, &app.members_tag) {
+        Some((from, to, msg)) => (Some(from), to, msg),
+        None => (None, None, text.clone()),
+    };
+    let channel = message_channel(m, &to, &app.members_tag, &app.staffs_tag);
+    // Our own messages that tag us are not mentions
+    let mention = match &from {
+        Some(from) => from != username && is_mention(&to, &msg, username),
+        None => false,
+    };
+    let has_link = m
+        .text
+        .colored_text()
+        .iter()
+        .any(|(style, _)| style.link.is_some())
+        || LinkFinder::new().links(&text).next().is_some();
+    Facts {
+        from,
+        to,
+        channel,
+        has_link,
+        has_upload: m.upload_link.is_some(),
+        mention,
+        time: try_parse_date(&m.date, &app.datetime_fmt),
+        text: text.to_lowercase(),
+        body: msg,
+        reply_to: None,
+    }
+}
+
+fn message_channel(
+    m: &Message,
+    to: &Option<String>,
+    members_tag: &str,
+    staffs_tag: &str,
+) -> filter::Channel {
+    let text = m.text.text();
+    if m.typ == MessageType::SysMsg {
+        filter::Channel::System
+    } else if text.starts_with(members_tag) {
+        filter::Channel::Members
+    } else if text.starts_with(staffs_tag) {
+        filter::Channel::Staff
+    } else if to.is_some() {
+        filter::Channel::Pm
+    } else {
+        filter::Channel::Public
+    }
+}
+
+// White is the text without a colour of its own.
+fn css_color(color: tuiColor) -> Option<String> {
+    match color {
+        tuiColor::Rgb(r, g, b) => Some(format!("#{:02x}{:02x}{:02x}", r, g, b)),
+        tuiColor::Red => Some("red".to_owned()),
+        _ => None,
+    }
+}
+
+fn web_span(style: TextStyle, text: String) -> web::Span {
+    web::Span {
+        text,
+        color: css_color(style.color),
+        bold: style.modifier.contains(Modifier::BOLD),
+        italic: style.modifier.contains(Modifier::ITALIC),
+        underline: style.modifier.contains(Modifier::UNDERLINED),
+        strike: style.modifier.contains(Modifier::CROSSED_OUT),
+        link: style.link,
+    }
+}
+
+fn user_roles(users: &Users) -> HashMap<String, Role> {
+    let mut roles = HashMap::new();
+    for (list, role) in [
+        (&users.guests, Role::Guest),
+        (&users.members, Role::Member),
+        (&users.staff, Role::Staff),
+        (&users.admin, Role::Admin),
+    ] {
+        roles.extend(list.iter().map(|(_, name)| (name.clone(), role)));
+    }
+    roles
+}
+
 fn update_messages(
     new_messages: Vec<Message>,
     mut messages: MutexGuard<Vec<Message>>,
@@ -2406,6 +3273,17 @@ fn update_messages(
     messages.truncate(1000);
 }
 
+// Directory of the confy configuration file.
+fn config_dir() -> Option<PathBuf> {
+    let path = confy::get_configuration_file_path("bhcli", None).ok()?;
+    Some(path.parent()?.to_owned())
+}
+
+// Path of a file living next to the confy configuration file.
+fn config_dir_file(name: &str) -> Option<PathBuf> {
+    Some(config_dir()?.join(name))
+}
+
 fn log_chat_message(msg: &Message) {
     if let Ok(path) = confy::get_configuration_file_path("bhcli", None) {
         if let Some(dir) = path.parent() {
@@ -2475,10 +3353,43 @@ impl ChatClient {
     }
 }
 
+fn bridge_post_type(cmd: BridgeCommand) -> PostType {
+    match cmd {
+        BridgeCommand::Post { text } => PostType::Post(text, None),
+        BridgeCommand::Pm { to, text } => PostType::Post(text, Some(to)),
+        BridgeCommand::Members { text } => PostType::Post(text, Some(SEND_TO_MEMBERS.to_owned())),
+        BridgeCommand::Staff { text } => PostType::Post(text, Some(SEND_TO_STAFFS.to_owned())),
+        BridgeCommand::Kick { user, reason } => PostType::Kick(reason, user),
+    }
+}
+
 fn new_default_le_chat_php_client(params: Params) -> LeChatPHPClient {
     let (color_tx, color_rx) = crossbeam_channel::unbounded();
     let (tx, rx) = crossbeam_channel::unbounded();
     let session = params.session.clone();
+    let legacy_outbox = config_dir_file(&format!("outbox-{}.json", params.profile));
+    let outbox_file = params.storage_key.as_ref().and_then(|key| {
+        config_dir_file(&format!("outbox-{}.bin", params.profile)).map(|path| key.file(path))
+    });
+    // Older versions kept the history in plaintext
+    if let Some(path) = config_dir_file(&format!("history-{}.json", params.profile)) {
+        let _ = std::fs::remove_file(path);
+    }
+    let history_file = params.storage_key.as_ref().and_then(|key| {
+        config_dir_file(&format!("history-{}.bin", params.profile)).map(|path| key.file(path))
+    });
+    let layout_path = config_dir_file(&format!("layout-{}.json", params.profile));
+    let socket_path = config_dir_file(&format!("bridge-{}.sock", params.profile));
+    let bridge_tx = tx.clone();
+    let bridge = Bridge::start(&params.bridge, socket_path, move |cmd| {
+        let _ = bridge_tx.send(bridge_post_type(cmd));
+    });
+    let irc_tx = tx.clone();
+    let irc = Irc::start(&params.irc, &params.username, move |cmd| {
+        let _ = irc_tx.send(bridge_post_type(cmd));
+    });
+    let downloads = DownloadManager::new(params.client.clone(), params.download_dir.clone());
+    let previews = PreviewCache::new(params.client.clone(), params.previews.clone());
     // println!("session[2050] : {:?}",params.session);
     LeChatPHPClient {
         base_client: BaseClient {
@@ -2489,8 +3400,20 @@ fn new_default_le_chat_php_client(params: Params) -> LeChatPHPClient {
         guest_color: params.guest_color,
         // session: params.session,
         session,
-        last_key_event: None,
+        keymap: params.keymap,
+        theme: params.theme,
+        panes: Panes::load(&params.layout, layout_path),
+        saved_views: params.saved_views,
+        notifier: params.notifier,
+        bridge: Arc::new(bridge),
+        irc: Arc::new(irc),
+        web: Web::start(&params.web),
+        mod_log: Arc::new(Mutex::new(ModLog::default())),
         client: params.client,
+        cookie_jar: params.cookie_jar,
+        profile: params.profile.clone(),
+        storage_key: params.storage_key.clone(),
+        legacy_outbox,
         manual_captcha: params.manual_captcha,
         sxiv: params.sxiv,
         refresh_rate: params.refresh_rate,
@@ -2502,6 +3425,14 @@ fn new_default_le_chat_php_client(params: Params) -> LeChatPHPClient {
         display_hidden_msgs: false,
         tx,
         rx: Arc::new(Mutex::new(rx)),
+        outbox: Arc::new(Mutex::new(Outbox::new(params.outbox_limits, outbox_file))),
+        downloads,
+        show_downloads: false,
+        previews,
+        uploads: params.uploads,
+        editor: params.editor,
+        history_file,
+        status: Arc::new(Mutex::new(StatusLine::default())),
         color_tx,
         color_rx: Arc::new(Mutex::new(color_rx)),
         bad_username_filters: Arc::new(Mutex::new(params.bad_usernames)),
@@ -2525,6 +3456,7 @@ struct Params {
     password: String,
     guest_color: String,
     client: Client,
+    cookie_jar: Arc<Jar>,
     manual_captcha: bool,
     sxiv: bool,
     refresh_rate: u64,
@@ -2535,6 +3467,21 @@ struct Params {
     bad_exact_usernames: Vec<String>,
     bad_messages: Vec<String>,
     allowlist: Vec<String>,
+    profile: String,
+    storage_key: Option<StorageKey>,
+    outbox_limits: RateLimits,
+    download_dir: PathBuf,
+    previews: PreviewConfig,
+    uploads: UploadConfig,
+    editor: EditorConfig,
+    keymap: Keymap,
+    theme: Theme,
+    layout: LayoutConfig,
+    saved_views: Vec<(String, Filter)>,
+    notifier: Arc<Notifier>,
+    bridge: BridgeConfig,
+    irc: IrcConfig,
+    web: WebConfig,
 }
 
 #[derive(Clone)]
@@ -2580,56 +3527,50 @@ fn replace_newline_escape(s: &str) -> String {
     s.replace("\\n", "\n")
 }
 
+// Colours that can be picked by name for guests.
+const GUEST_COLORS: &[(&str, &str)] = &[
+    ("beige", "F5F5DC"),
+    ("blue-violet", "8A2BE2"),
+    ("brown", "A52A2A"),
+    ("cyan", "00FFFF"),
+    ("sky-blue", "00BFFF"),
+    ("gold", "FFD700"),
+    ("gray", "808080"),
+    ("green", "008000"),
+    ("hot-pink", "FF69B4"),
+    ("light-blue", "ADD8E6"),
+    ("light-green", "90EE90"),
+    ("lime-green", "32CD32"),
+    ("magenta", "FF00FF"),
+    ("olive", "808000"),
+    ("orange", "FFA500"),
+    ("orange-red", "FF4500"),
+    ("red", "FF0000"),
+    ("royal-blue", "4169E1"),
+    ("see-green", "2E8B57"),
+    ("sienna", "A0522D"),
+    ("silver", "C0C0C0"),
+    ("tan", "D2B48C"),
+    ("teal", "008080"),
+    ("violet", "EE82EE"),
+    ("white", "FFFFFF"),
+    ("yellow", "FFFF00"),
+    ("yellow-green", "9ACD32"),
+];
+
 fn get_guest_color(wanted: Option<String>) -> String {
     match wanted.as_deref() {
-        Some("beige") => "F5F5DC",
-        Some("blue-violet") => "8A2BE2",
-        Some("brown") => "A52A2A",
-        Some("cyan") => "00FFFF",
-        Some("sky-blue") => "00BFFF",
-        Some("gold") => "FFD700",
-        Some("gray") => "808080",
-        Some("green") => "008000",
-        Some("hot-pink") => "FF69B4",
-        Some("light-blue") => "ADD8E6",
-        Some("light-green") => "90EE90",
-        Some("lime-green") => "32CD32",
-        Some("magenta") => "FF00FF",
-        Some("olive") => "808000",
-        Some("orange") => "FFA500",
-        Some("orange-red") => "FF4500",
-        Some("red") => "FF0000",
-        Some("royal-blue") => "4169E1",
-        Some("see-green") => "2E8B57",
-        Some("sienna") => "A0522D",
-        Some("silver") => "C0C0C0",
-        Some("tan") => "D2B48C",
-        Some("teal") => "008080",
-        Some("violet") => "EE82EE",
-        Some("white") => "FFFFFF",
-        Some("yellow") => "FFFF00",
-        Some("yellow-green") => "9ACD32",
-        Some(other) => COLOR1_RGX
-            .captures(other)
-            .map_or("", |captures| captures.get(1).map_or("", |m| m.as_str())),
+        Some(name) => match GUEST_COLORS.iter().find(|(n, _)| *n == name) {
+            Some((_, hex)) => hex,
+            None => COLOR1_RGX
+                .captures(name)
+                .map_or("", |captures| captures.get(1).map_or("", |m| m.as_str())),
+        },
         None => "",
     }
     .to_owned()
 }
 
-fn get_tor_client(socks_proxy_url: &str, no_proxy: bool) -> Client {
-    let ua = "Dasho's Black Hat Chat Client v0.1";
-    let mut builder = reqwest::blocking::ClientBuilder::new()
-        .redirect(Policy::none())
-        .cookie_store(true)
-        .user_agent(ua);
-    if !no_proxy {
-        let proxy = reqwest::Proxy::all(socks_proxy_url).unwrap();
-        builder = builder.proxy(proxy);
-    }
-    builder.build().unwrap()
-}
-
 fn ask_username(username: Option<String>) -> String {
     username.unwrap_or_else(|| {
         print!("username: ");
@@ -2660,14 +3601,11 @@ pub struct DkfNotifierResp {
     pub last_message_created_at: String,
 }
 
-fn start_dkf_notifier(client: &Client, dkf_api_key: &str) {
+fn start_dkf_notifier(client: &Client, dkf_api_key: &str, notifier: Arc<Notifier>) {
     let client = client.clone();
     let dkf_api_key = dkf_api_key.to_owned();
     let mut last_known_date = Utc::now();
     thread::spawn(move || loop {
-        let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-        let source = Decoder::new_mp3(Cursor::new(SOUND1)).unwrap();
-
         let params: Vec<(&str, String)> = vec![(
             "last_known_date",
             last_known_date.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
@@ -2682,7 +3620,7 @@ fn start_dkf_notifier(client: &Client, dkf_api_key: &str) {
             if let Ok(txt) = resp.text() {
                 if let Ok(v) = serde_json::from_str::<DkfNotifierResp>(&txt) {
                     if v.pm_sound || v.tagged_sound {
-                        stream_handle.play_raw(source.convert_samples()).unwrap();
+                        notifier.notify(&Notice::Dkf);
                     }
                     last_known_date = DateTime::parse_from_rfc3339(&v.last_message_created_at)
                         .unwrap()
@@ -2695,16 +3633,18 @@ fn start_dkf_notifier(client: &Client, dkf_api_key: &str) {
 }
 
 // Start thread that looks for new emails on DNMX every minutes.
-fn start_dnmx_mail_notifier(client: &Client, username: &str, password: &str) {
+fn start_dnmx_mail_notifier(
+    client: &Client,
+    username: &str,
+    password: &str,
+    notifier: Arc<Notifier>,
+) {
     let params: Vec<(&str, &str)> = vec![("login_username", username), ("secretkey", password)];
     let login_url = format!("{}/src/redirect.php", DNMX_URL);
     client.post(login_url).form(&params).send().unwrap();
 
     let client_clone = client.clone();
     thread::spawn(move || loop {
-        let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-        let source = Decoder::new_mp3(Cursor::new(SOUND1)).unwrap();
-
         let right_url = format!("{}/src/right_main.php", DNMX_URL);
         if let Ok(resp) = client_clone.get(right_url).send() {
             let mut nb_mails = 0;
@@ -2720,7 +3660,7 @@ fn start_dnmx_mail_notifier(client: &Client, username: &str, password: &str) {
             }
             if nb_mails > 0 {
                 log::error!("{} new mails", nb_mails);
-                stream_handle.play_raw(source.convert_samples()).unwrap();
+                notifier.notify(&Notice::Mail(nb_mails));
             }
         }
         thread::sleep(Duration::from_secs(60));
@@ -2757,23 +3697,169 @@ fn read_commands_file(file_path: &str) -> Result<Commands, Box<dyn std::error::E
     Ok(commands)
 }
 
+fn run_session_command(action: SessionCmd, default_profile: &str) -> anyhow::Result<()> {
+    if !vault::exists() {
+        println!("No vault, sessions are not stored");
+        return Ok(());
+    }
+    let key = Vault::prompt_unlock()?.storage_key()?;
+    match action {
+        SessionCmd::List => {
+            let sessions = session::load_all(&key)?;
+            if sessions.is_empty() {
+                println!("No stored session");
+            }
+            let mut profiles: Vec<_> = sessions.iter().collect();
+            profiles.sort_by(|a, b| a.0.cmp(b.0));
+            for (profile, s) in profiles {
+                println!(
+                    "{}: {} on {} (saved {})",
+                    profile, s.username, s.url, s.saved_at
+                );
+            }
+        }
+        SessionCmd::Drop { profile } => {
+            let profile = profile.unwrap_or_else(|| default_profile.to_owned());
+            if session::remove(&key, &profile)? {
+                println!("Dropped session of profile {}", profile);
+            } else {
+                println!("No stored session for profile {}", profile);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_vault_command(action: VaultCmd) -> anyhow::Result<()> {
+    match action {
+        VaultCmd::Add { name } => {
+            let mut vault = Vault::prompt_open_or_create()?;
+            let secret = rpassword::prompt_password(format!("Secret for {}: ", name))?;
+            vault.set(&name, secret);
+            vault.save()?;
+            println!("Stored secret {}", name);
+        }
+        VaultCmd::Rm { name } => {
+            let mut vault = Vault::prompt_unlock()?;
+            if vault.remove(&name) {
+                vault.save()?;
+                println!("Removed secret {}", name);
+            } else {
+                println!("No secret named {}", name);
+            }
+        }
+        VaultCmd::List => {
+            let vault = Vault::prompt_unlock()?;
+            for name in vault.names() {
+                println!("{}", name);
+            }
+        }
+        VaultCmd::Rekey => {
+            let mut vault = Vault::prompt_unlock()?;
+            vault.prompt_rekey()?;
+            println!("Vault passphrase changed");
+        }
+    }
+    Ok(())
+}
+
+// Move plaintext passwords & API keys found in the config file into the vault,
+// and make the config reference them by name instead.
+fn migrate_plaintext_secrets(cfg: &mut MyConfig) -> anyhow::Result<Option<Vault>> {
+    let has_plaintext =
+        cfg.dkf_api_key.is_some() || cfg.profiles.values().any(|p| !p.password.is_empty());
+    if !has_plaintext {
+        return Ok(None);
+    }
+    println!("Moving plaintext passwords and API keys from the config file into the vault");
+    let mut vault = Vault::prompt_open_or_create()?;
+    move_secrets(cfg, &mut vault);
+    // Save the vault first, so a failure can't lose the secrets.
+    vault.save()?;
+    confy::store("bhcli", None, &*cfg)?;
+    Ok(Some(vault))
+}
+
+fn move_secrets(cfg: &mut MyConfig, vault: &mut Vault) {
+    for (name, profile) in cfg.profiles.iter_mut() {
+        if !profile.password.is_empty() {
+            let secret_name = format!("profile.{}.password", name);
+            vault.set(&secret_name, std::mem::take(&mut profile.password));
+            profile.password_secret = Some(secret_name);
+        }
+    }
+    if let Some(dkf_api_key) = cfg.dkf_api_key.take() {
+        vault.set("dkf_api_key", dkf_api_key);
+        cfg.dkf_api_key_secret = Some("dkf_api_key".to_owned());
+    }
+}
+
+// Sessions, the outbox & the input history are sealed with a key from the
+// vault, they are only kept when there is one.
+fn storage_key(vault: &mut Option<Vault>) -> anyhow::Result<Option<StorageKey>> {
+    if !vault::exists() {
+        println!(
+            "No vault, sessions, the outbox and the input history are not kept (see `bhcli vault`)"
+        );
+        return Ok(None);
+    }
+    if vault.is_none() {
+        *vault = Some(Vault::prompt_unlock()?);
+    }
+    let key = vault.as_mut().unwrap().storage_key()?;
+    session::migrate_legacy_key(&key)?;
+    Ok(Some(key))
+}
+
+// Get a secret from the vault, unlocking it the first time it is needed.
+fn vault_secret(vault: &mut Option<Vault>, name: &str) -> anyhow::Result<String> {
+    if vault.is_none() {
+        *vault = Some(Vault::prompt_unlock()?);
+    }
+    vault
+        .as_ref()
+        .and_then(|v| v.get(name).cloned())
+        .ok_or(anyhow!("secret {} not found in vault", name))
+}
+
 fn main() -> anyhow::Result<()> {
     let mut opts: Opts = Opts::parse();
-    // println!("Parsed Session: {:?}", opts.session);
 
+    match opts.command.take() {
+        Some(Cmd::Session { action }) => return run_session_command(action, &opts.profile),
+        Some(Cmd::Vault { action }) => return run_vault_command(action),
+        None => {}
+    }
+    // println!("Parsed Session: {:?}", opts.session);
 
     // Configs file
     if let Ok(config_path) = confy::get_configuration_file_path("bhcli", None) {
         println!("Config path: {:?}", config_path);
     }
-    if let Ok(cfg) = confy::load::<MyConfig>("bhcli", None) {
+    let mut vault = None;
+    if let Ok(mut cfg) = confy::load::<MyConfig>("bhcli", None) {
+        vault = migrate_plaintext_secrets(&mut cfg)?;
         if opts.dkf_api_key.is_none() {
-            opts.dkf_api_key = cfg.dkf_api_key;
+            if let Some(name) = &cfg.dkf_api_key_secret {
+                opts.dkf_api_key = Some(vault_secret(&mut vault, name)?);
+            }
         }
         if let Some(default_profile) = cfg.profiles.get(&opts.profile) {
+            opts.transport = Some(default_profile.transport.clone());
+            opts.previews = Some(default_profile.previews.clone());
             if opts.username.is_none() {
                 opts.username = Some(default_profile.username.clone());
-                opts.password = Some(default_profile.password.clone());
+                if let Some(name) = &default_profile.password_secret {
+                    opts.password = Some(vault_secret(&mut vault, name)?);
+                }
+            }
+        }
+        if opts.dnmx_username.is_none() {
+            opts.dnmx_username = cfg.dnmx_username.clone();
+        }
+        if opts.dnmx_password.is_none() {
+            if let Some(name) = &cfg.dnmx_password_secret {
+                opts.dnmx_password = Some(vault_secret(&mut vault, name)?);
             }
         }
         let bad_usernames = cfg.bad_usernames.clone();
@@ -2784,7 +3870,22 @@ fn main() -> anyhow::Result<()> {
         opts.bad_exact_usernames = Some(bad_exact_usernames);
         opts.bad_messages = Some(bad_messages);
         opts.allowlist = Some(allowlist_cfg);
+        opts.outbox = Some(cfg.outbox);
+        if opts.download_dir.is_none() {
+            opts.download_dir = cfg.download_dir;
+        }
+        opts.uploads = Some(cfg.uploads);
+        opts.editor = Some(cfg.editor);
+        opts.keymap = Some(cfg.keymap);
+        opts.theme = Some(cfg.theme);
+        opts.layout = Some(cfg.layout);
+        opts.views = Some(cfg.views);
+        opts.notifications = Some(cfg.notifications);
+        opts.bridge = Some(cfg.bridge);
+        opts.irc = Some(cfg.irc);
+        opts.web = Some(cfg.web);
     }
+    let storage_key = storage_key(&mut vault)?;
 
     let logfile = FileAppender::builder()
         .encoder(Box::new(PatternEncoder::new("{d} {l} {t} - {m}{n}")))
@@ -2800,21 +3901,70 @@ fn main() -> anyhow::Result<()> {
 
     log4rs::init_config(config)?;
 
-    let client = get_tor_client(&opts.socks_proxy_url, opts.no_proxy);
+    // The audio device is opened once, for all the notifiers
+    let muted_path = config_dir_file(&format!("muted-{}.json", opts.profile));
+    let notifier = Notifier::new(
+        &opts.notifications.take().unwrap_or_default(),
+        config_dir(),
+        muted_path,
+    )
+    .map_err(|e| anyhow!("notifications: {}", e))?;
+    let notifier = Arc::new(notifier);
+
+    let cookie_jar = Arc::new(Jar::default());
+    let transport = opts.transport.take().unwrap_or_default();
+    let new_client = |stream: &str, jar: Arc<Jar>| {
+        transport::build_client(
+            &transport,
+            opts.socks_proxy_url.as_deref(),
+            opts.no_proxy,
+            stream,
+            jar,
+        )
+    };
+    let client = new_client(&format!("{}-chat", opts.profile), Arc::clone(&cookie_jar))?;
 
+    // Notifiers get their own client, so they don't share a Tor circuit with the chat
     // If dnmx username is set, start mail notifier thread
-    if let Some(dnmx_username) = opts.dnmx_username {
-        start_dnmx_mail_notifier(&client, &dnmx_username, &opts.dnmx_password.unwrap())
+    if let Some(dnmx_username) = &opts.dnmx_username {
+        let dnmx_client = new_client("dnmx", Arc::new(Jar::default()))?;
+        let dnmx_password = opts.dnmx_password.as_ref().unwrap();
+        start_dnmx_mail_notifier(
+            &dnmx_client,
+            dnmx_username,
+            dnmx_password,
+            Arc::clone(&notifier),
+        )
     }
 
     if let Some(dkf_api_key) = &opts.dkf_api_key {
-        start_dkf_notifier(&client, dkf_api_key);
+        let dkf_client = new_client("dkf", Arc::new(Jar::default()))?;
+        start_dkf_notifier(&dkf_client, dkf_api_key, Arc::clone(&notifier));
     }
 
     let guest_color = get_guest_color(opts.guest_color);
     let username = ask_username(opts.username);
     let password = ask_password(opts.password);
 
+    let mut previews = opts.previews.unwrap_or_default();
+    if opts.no_previews {
+        previews.enabled = false;
+    }
+    let keymap = Keymap::from_config(&opts.keymap.unwrap_or_default()).context("keymap")?;
+    let theme = Theme::from_config(&opts.theme.unwrap_or_default()).context("theme")?;
+    let bridge = opts.bridge.unwrap_or_default();
+    bridge.check().map_err(|e| anyhow!("bridge: {}", e))?;
+    let irc = opts.irc.unwrap_or_default();
+    irc.check().map_err(|e| anyhow!("irc: {}", e))?;
+    let web = opts.web.unwrap_or_default();
+    web.check().map_err(|e| anyhow!("web: {}", e))?;
+    let mut saved_views = vec![];
+    for view in opts.views.unwrap_or_default() {
+        let filter =
+            filter::parse(&view.filter).map_err(|e| anyhow!("view \"{}\": {}", view.name, e))?;
+        saved_views.push((view.name, filter));
+    }
+
     let params = Params {
         url: opts.url,
         page_php: opts.page_php,
@@ -2824,6 +3974,7 @@ fn main() -> anyhow::Result<()> {
         password,
         guest_color,
         client: client.clone(),
+        cookie_jar,
         manual_captcha: opts.manual_captcha,
         sxiv: opts.sxiv,
         refresh_rate: opts.refresh_rate,
@@ -2834,16 +3985,32 @@ fn main() -> anyhow::Result<()> {
         bad_exact_usernames: opts.bad_exact_usernames.unwrap_or_default(),
         bad_messages: opts.bad_messages.unwrap_or_default(),
         allowlist: opts.allowlist.unwrap_or_default(),
+        profile: opts.profile.clone(),
+        storage_key,
+        outbox_limits: opts.outbox.unwrap_or_default(),
+        download_dir: opts
+            .download_dir
+            .unwrap_or_else(|| PathBuf::from("downloads")),
+        previews,
+        uploads: opts.uploads.unwrap_or_default(),
+        editor: opts.editor.unwrap_or_default(),
+        keymap,
+        theme,
+        layout: opts.layout.unwrap_or_default(),
+        saved_views,
+        notifier,
+        bridge,
+        irc,
+        web,
     };
     // println!("Session[2378]: {:?}", opts.session);
 
-
     ChatClient::new(params).run_forever();
 
     Ok(())
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 enum PostType {
     Post(String, Option<String>),   // Message, SendTo
     Kick(String, String),           // Message, Username
@@ -2934,6 +4101,8 @@ enum MessageType {
 
 #[derive(Debug, PartialEq, Clone)]
 struct Message {
+    // Identifies the message in the client, the chat id is only known to moderators
+    uid: u64,
     id: Option<usize>,
     typ: MessageType,
     date: String,
@@ -2951,7 +4120,9 @@ impl Message {
         upload_link: Option<String>,
         text: StyledText,
     ) -> Self {
+        static NEXT_UID: AtomicU64 = AtomicU64::new(1);
         Self {
+            uid: NEXT_UID.fetch_add(1, Ordering::Relaxed),
             id,
             typ,
             date,
@@ -2963,9 +4134,44 @@ impl Message {
     }
 }
 
+/// Style of a piece of text: colour, bold/italic..., and the link target if any.
+#[derive(Debug, PartialEq, Clone)]
+struct TextStyle {
+    color: tuiColor,
+    modifier: Modifier,
+    link: Option<String>,
+}
+
+impl TextStyle {
+    fn new(color: tuiColor) -> Self {
+        Self {
+            color,
+            modifier: Modifier::empty(),
+            link: None,
+        }
+    }
+
+    fn style(&self, theme: &Theme) -> Style {
+        // White is the text without a colour of its own
+        let color = match self.color {
+            tuiColor::White => theme.text,
+            color => theme.chat_color(color),
+        };
+        let mut style = Style::default().fg(color).add_modifier(self.modifier);
+        // Links are underlined, and highlighted when they have no colour of their own
+        if self.link.is_some() {
+            style = style.add_modifier(Modifier::UNDERLINED);
+            if self.color == tuiColor::White {
+                style = style.fg(theme.link);
+            }
+        }
+        style
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 enum StyledText {
-    Styled(tuiColor, Vec<StyledText>),
+    Styled(TextStyle, Vec<StyledText>),
     Text(String),
     None,
 }
@@ -2998,26 +4204,53 @@ impl StyledText {
         s
     }
 
-    // Return a vector of each text parts & what color it should be
-    fn colored_text(&self) -> Vec<(tuiColor, String)> {
-        let mut out: Vec<(tuiColor, String)> = vec![];
-        let mut v: Vec<(tuiColor, &StyledText)> = vec![(tuiColor::White, self)];
-        loop {
-            if let Some((el_color, e)) = v.pop() {
-                match e {
-                    StyledText::Styled(tui_color, children) => {
-                        for child in children {
-                            v.push((*tui_color, child));
+    // Return a vector of each text parts & what style it should have.
+    // Urls found in plain text are turned into links.
+    fn colored_text(&self) -> Vec<(TextStyle, String)> {
+        let mut out: Vec<(TextStyle, String)> = vec![];
+        let mut v: Vec<(&TextStyle, &StyledText)> = vec![];
+        let default_style = TextStyle::new(tuiColor::White);
+        v.push((&default_style, self));
+        while let Some((el_style, e)) = v.pop() {
+            match e {
+                StyledText::Styled(style, children) => {
+                    for child in children {
+                        v.push((style, child));
+                    }
+                }
+                StyledText::Text(t) if el_style.link.is_some() => {
+                    out.push((el_style.clone(), t.to_owned()));
+                }
+                StyledText::Text(t) => {
+                    let mut last = 0;
+                    for link in LinkFinder::new().links(t) {
+                        if link.start() > last {
+                            out.push((el_style.clone(), t[last..link.start()].to_owned()));
                         }
+                        let mut style = el_style.clone();
+                        style.link = Some(link.as_str().to_owned());
+                        out.push((style, link.as_str().to_owned()));
+                        last = link.end();
                     }
-                    StyledText::Text(t) => {
-                        out.push((el_color, t.to_owned()));
+                    if last < t.len() {
+                        out.push((el_style.clone(), t[last..].to_owned()));
                     }
-                    StyledText::None => {}
                 }
-                continue;
+                StyledText::None => {}
+            }
+        }
+        out
+    }
+
+    // All the links of the message, in order of appearance.
+    fn links(&self) -> Vec<String> {
+        let mut out: Vec<String> = vec![];
+        for (style, _) in self.colored_text() {
+            if let Some(link) = style.link {
+                if out.last() != Some(&link) {
+                    out.push(link);
+                }
             }
-            break;
         }
         out
     }
@@ -3038,26 +4271,41 @@ fn parse_color(color_str: &str) -> tuiColor {
     color
 }
 
-fn process_node(e: select::node::Node, mut color: tuiColor) -> (StyledText, Option<String>) {
+fn process_node(e: select::node::Node, mut style: TextStyle) -> (StyledText, Option<String>) {
     match e.data() {
         select::node::Data::Element(_, _) => {
             let mut upload_link: Option<String> = None;
             match e.name() {
                 Some("span") => {
-                    if let Some(style) = e.attr("style") {
-                        if let Some(captures) = COLOR_RGX.captures(style) {
+                    if let Some(css) = e.attr("style") {
+                        if let Some(captures) = COLOR_RGX.captures(css) {
                             let color_match = captures.get(1).unwrap().as_str();
-                            color = parse_color(color_match);
+                            style.color = parse_color(color_match);
+                        }
+                        let css = css.replace(' ', "");
+                        if css.contains("font-weight:bold") {
+                            style.modifier |= Modifier::BOLD;
+                        }
+                        if css.contains("font-style:italic") {
+                            style.modifier |= Modifier::ITALIC;
+                        }
+                        if css.contains("text-decoration:underline") {
+                            style.modifier |= Modifier::UNDERLINED;
                         }
                     }
                 }
                 Some("font") => {
                     if let Some(color_str) = e.attr("color") {
-                        color = parse_color(color_str);
+                        style.color = parse_color(color_str);
                     }
                 }
+                Some("b") | Some("strong") => style.modifier |= Modifier::BOLD,
+                Some("i") | Some("em") => style.modifier |= Modifier::ITALIC,
+                Some("u") => style.modifier |= Modifier::UNDERLINED,
+                Some("s") | Some("strike") | Some("del") => style.modifier |= Modifier::CROSSED_OUT,
                 Some("a") => {
-                    color = tuiColor::White;
+                    style.color = tuiColor::White;
+                    style.link = e.attr("href").map(|href| href.to_owned());
                     if let (Some("attachement"), Some(href)) = (e.attr("class"), e.attr("href")) {
                         upload_link = Some(href.to_owned());
                     }
@@ -3070,14 +4318,14 @@ fn process_node(e: select::node::Node, mut color: tuiColor) -> (StyledText, Opti
             let mut children_texts: Vec<StyledText> = vec![];
             let children = e.children();
             for child in children {
-                let (st, ul) = process_node(child, color);
+                let (st, ul) = process_node(child, style.clone());
                 if ul.is_some() {
                     upload_link = ul;
                 }
                 children_texts.push(st);
             }
             children_texts.reverse();
-            (StyledText::Styled(color, children_texts), upload_link)
+            (StyledText::Styled(style, children_texts), upload_link)
         }
         select::node::Data::Text(t) => (StyledText::Text(t.to_string()), None),
         select::node::Data::Comment(_) => (StyledText::None, None),
@@ -3158,10 +4406,6 @@ fn remove_suffix<'a>(s: &'a str, suffix: &str) -> &'a str {
     s.strip_suffix(suffix).unwrap_or(s)
 }
 
-fn remove_prefix<'a>(s: &'a str, prefix: &str) -> &'a str {
-    s.strip_prefix(prefix).unwrap_or(s)
-}
-
 fn extract_messages(doc: &Document) -> anyhow::Result<Vec<Message>> {
     let msgs = doc
         .find(Attr("id", "messages"))
@@ -3182,7 +4426,8 @@ fn extract_messages(doc: &Document) -> anyhow::Result<Vec<Message>> {
                         Some("sysmsg") => MessageType::SysMsg,
                         _ => return None,
                     };
-                    let (text, upload_link) = process_node(msg_span, tuiColor::White);
+                    let (text, upload_link) =
+                        process_node(msg_span, TextStyle::new(tuiColor::White));
                     return Some(Message::new(id, typ, date, upload_link, text));
                 }
             }
@@ -3192,127 +4437,229 @@ fn extract_messages(doc: &Document) -> anyhow::Result<Vec<Message>> {
     Ok(msgs)
 }
 
+// Client side state displayed alongside the chat.
+struct ClientViews<'a> {
+    keymap: &'a Keymap,
+    theme: &'a Theme,
+    panes: &'a Panes,
+    outbox: &'a Arc<Mutex<Outbox>>,
+    downloads: &'a DownloadManager,
+    previews: &'a PreviewCache,
+    status: &'a Arc<Mutex<StatusLine>>,
+}
+
 fn draw_terminal_frame(
     f: &mut Frame<CrosstermBackend<io::Stdout>>,
     app: &mut App,
     messages: &Arc<Mutex<Vec<Message>>>,
     users: &Arc<Mutex<Users>>,
+    views: &ClientViews,
     username: &str,
 ) {
-    if app.long_message.is_none() {
-        let hchunks = Layout::default()
-            .direction(Direction::Horizontal)
-            .constraints([Constraint::Min(1), Constraint::Length(25)].as_ref())
-            .split(f.size());
-
-        {
-            let chunks = Layout::default()
-                .direction(Direction::Vertical)
-                .constraints(
-                    [
-                        Constraint::Length(1),
-                        Constraint::Length(3),
-                        Constraint::Min(1),
-                    ]
-                    .as_ref(),
-                )
-                .split(hchunks[0]);
-
-            render_help_txt(f, app, chunks[0], username);
-            render_textbox(f, app, chunks[1]);
-            render_messages(f, app, chunks[2], messages);
-            render_users(f, hchunks[1], users);
-        }
+    if let Some(p) = &app.pending_upload {
+        render_upload_confirm(f, f.size(), p, views.theme);
+    } else if let Some(fb) = app.file_browser.as_mut() {
+        render_file_browser(f, f.size(), fb, views.theme);
+    } else if let Some(lines) = &app.help {
+        render_help(f, f.size(), lines, app.help_scroll);
+    } else if let Some(url) = &app.image_viewer {
+        render_image_viewer(f, f.size(), views, url);
     } else {
-        let hchunks = Layout::default()
-            .direction(Direction::Horizontal)
-            .constraints([Constraint::Min(1)])
-            .split(f.size());
-        {
-            render_long_message(f, app, hchunks[0]);
-        }
-    }
-}
-
-fn gen_lines(msg_txt: &StyledText, w: usize, line_prefix: &str) -> Vec<Vec<(tuiColor, String)>> {
-    let txt = msg_txt.text();
-    let wrapped = textwrap::fill(&txt, w);
-    let splits = wrapped.split("\n").collect::<Vec<&str>>();
-    let mut new_lines: Vec<Vec<(tuiColor, String)>> = Vec::new();
-    let mut ctxt = msg_txt.colored_text();
-    ctxt.reverse();
-    let mut ptr = 0;
-    let mut split_idx = 0;
-    let mut line: Vec<(tuiColor, String)> = Vec::new();
-    let mut first_in_line = true;
-    loop {
-        if let Some((color, mut txt)) = ctxt.pop() {
-            txt = txt.replace("\n", "");
-            if let Some(split) = splits.get(split_idx) {
-                if let Some(chr) = txt.chars().next() {
-                    if chr == ' ' && first_in_line {
-                        let skipped: String = txt.chars().skip(1).collect();
-                        txt = skipped;
-                    }
-                }
-
-                let remain = split.len() - ptr;
-                if txt.len() <= remain {
-                    ptr += txt.len();
-                    line.push((color, txt));
-                    first_in_line = false;
-                } else {
-                    //line.push((color, txt[0..remain].to_owned()));
-                    if let Some(valid_slice) = txt.get(0..remain) {
-                        line.push((color, valid_slice.to_owned()));
-                    } else {
-                        let valid_remain = txt.char_indices()
-                            .take_while(|&(i, _)| i < remain)
-                            .last()
-                            .map(|(i, _)| i)
-                            .unwrap_or(txt.len());  
-
-                        line.push((color, txt[..valid_remain].to_owned()));
-                    }
-
-                    new_lines.push(line.clone());
-                    line.clear();
-                    line.push((tuiColor::White, line_prefix.to_owned()));
-                    //ctxt.push((color, txt[(remain)..].to_owned()));
-                    if let Some(valid_slice) = txt.get(remain..) {
-                        ctxt.push((color, valid_slice.to_owned()));
-                    } else {
-                        let valid_remain = txt.char_indices()
-                            .skip_while(|&(i, _)| i < remain) // Find first valid boundary after remain
-                            .map(|(i, _)| i)
-                            .next()
-                            .unwrap_or(txt.len());
-
-                        ctxt.push((color, txt[valid_remain..].to_owned()));
-                    }
-
-                    ptr = 0;
-                    split_idx += 1;
-                    first_in_line = true;
-                }
+        let areas = views.panes.areas(f.size(), input_height(app.input.text()));
+        // Small screens give the whole space to the long message
+        if app.long_message.is_some() && areas.compact {
+            render_long_message(f, app, f.size(), views.theme);
+            return;
+        }
+        render_help_txt(f, app, areas.help, views, username);
+        render_textbox(f, app, areas.input, views.theme);
+        if let Some(r) = areas.users {
+            render_users(f, r, users, views.theme);
+        }
+        if let Some(r) = areas.threads {
+            render_threads(f, app, r, messages, views.theme, username);
+        }
+        if let Some(r) = areas.modlog {
+            render_modlog(f, r, messages, views.theme);
+        }
+        if let Some(thread) = app.thread.as_mut() {
+            let r = areas
+                .private
+                .map_or(areas.messages, |p| areas.messages.union(p));
+            render_thread(f, r, thread, views.theme);
+        } else if app.long_message.is_none() {
+            let pending = views.outbox.lock().unwrap().pending();
+            // Active downloads are always displayed, the history only when toggled.
+            let downloads: Vec<Download> = views
+                .downloads
+                .list()
+                .into_iter()
+                .filter(|d| app.show_downloads || d.is_active())
+                .collect();
+            // Undelivered items & downloads are displayed above the messages, at most 5 of each.
+            let mut constraints = Vec::new();
+            if !pending.is_empty() {
+                constraints.push(Constraint::Length(
+                    std::cmp::min(pending.len(), 5) as u16 + 2,
+                ));
+            }
+            if !downloads.is_empty() {
+                constraints.push(Constraint::Length(
+                    std::cmp::min(downloads.len(), 5) as u16 + 2,
+                ));
+            }
+            constraints.push(Constraint::Min(1));
+            let vchunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints(constraints)
+                .split(areas.messages);
+            let mut vchunks = vchunks.into_iter();
+            if !pending.is_empty() {
+                render_outbox(f, vchunks.next().unwrap(), &pending, views.theme);
+            }
+            if !downloads.is_empty() {
+                render_downloads(f, vchunks.next().unwrap(), &downloads, views.theme);
+            }
+            let messages_rect = vchunks.next().unwrap();
+            if let Some(r) = areas.private {
+                render_messages(
+                    f,
+                    app,
+                    messages_rect,
+                    messages,
+                    views,
+                    users,
+                    username,
+                    Column::Public,
+                );
+                render_messages(f, app, r, messages, views, users, username, Column::Private);
+            } else {
+                render_messages(
+                    f,
+                    app,
+                    messages_rect,
+                    messages,
+                    views,
+                    users,
+                    username,
+                    Column::All,
+                );
             }
         } else {
-            new_lines.push(line);
-            break;
+            let r = areas
+                .private
+                .map_or(areas.messages, |p| areas.messages.union(p));
+            render_long_message(f, app, r, views.theme);
+        }
+        if let Some(c) = &app.completion {
+            render_completion(f, areas.input, c, views.theme);
+        }
+    }
+}
+
+fn gen_lines(msg_txt: &StyledText, w: usize, line_prefix: &str) -> Vec<Vec<(TextStyle, String)>> {
+    let parts = msg_txt.colored_text();
+    let txt: String = parts.iter().map(|(_, t)| t.as_str()).collect();
+    // Byte range of each styled part in the whole text
+    let mut ranges = Vec::with_capacity(parts.len());
+    let mut offset = 0;
+    for (_, t) in &parts {
+        ranges.push(offset..offset + t.len());
+        offset += t.len();
+    }
+    let mut new_lines: Vec<Vec<(TextStyle, String)>> = Vec::new();
+    for (idx, line_range) in text::wrap(&txt, w).into_iter().enumerate() {
+        let mut line: Vec<(TextStyle, String)> = Vec::new();
+        if idx > 0 {
+            line.push((TextStyle::new(tuiColor::White), line_prefix.to_owned()));
         }
+        for ((style, _), range) in parts.iter().zip(&ranges) {
+            let start = range.start.max(line_range.start);
+            let end = range.end.min(line_range.end);
+            if start < end {
+                line.push((style.clone(), txt[start..end].to_owned()));
+            }
+        }
+        new_lines.push(line);
     }
     new_lines
 }
 
-fn render_long_message(f: &mut Frame<CrosstermBackend<io::Stdout>>, app: &mut App, r: Rect) {
+// Links in messages can be relative to the chat url.
+fn absolute_link(base_url: &str, href: &str) -> String {
+    if href.starts_with("http://") || href.starts_with("https://") {
+        return href.to_owned();
+    }
+    if href.starts_with('/') || href.starts_with('?') {
+        return format!("{}{}", base_url, href);
+    }
+    format!("{}/{}", base_url, href)
+}
+
+fn render_thread(
+    f: &mut Frame<CrosstermBackend<io::Stdout>>,
+    r: Rect,
+    thread: &mut StatefulList<(usize, Message)>,
+    theme: &Theme,
+) {
+    let items: Vec<ListItem> = thread
+        .items
+        .iter()
+        .map(|(depth, m)| {
+            let indent = "  ".repeat(*depth);
+            let width = (r.width as usize).saturating_sub(indent.len() + m.date.len() + 5);
+            let mut rows = vec![];
+            for (idx, line) in gen_lines(&m.text, width, "").into_iter().enumerate() {
+                let mut spans = vec![Span::raw(indent.clone())];
+                if idx == 0 {
+                    spans.push(Span::styled(m.date.clone(), Style::default().fg(theme.dim)));
+                    spans.push(Span::raw(" - "));
+                } else {
+                    spans.push(Span::raw(" ".repeat(m.date.len() + 3)));
+                }
+                spans.extend(
+                    line.into_iter()
+                        .map(|(style, txt)| Span::styled(txt, style.style(theme))),
+                );
+                rows.push(Spans::from(spans));
+            }
+            ListItem::new(rows)
+        })
+        .collect();
+    let title = "Thread (j/k: move, enter: go to message, esc: close)";
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .highlight_style(
+            Style::default()
+                .bg(theme.highlight_bg)
+                .add_modifier(Modifier::BOLD),
+        );
+    f.render_stateful_widget(list, r, &mut thread.state);
+}
+
+fn render_long_message(
+    f: &mut Frame<CrosstermBackend<io::Stdout>>,
+    app: &mut App,
+    r: Rect,
+    theme: &Theme,
+) {
     if let Some(m) = &app.long_message {
         let new_lines = gen_lines(&m.text, (r.width - 2) as usize, "");
+        let selected_link = app
+            .long_message_link
+            .and_then(|idx| m.text.links().into_iter().nth(idx));
 
         let mut rows = vec![];
         for line in new_lines.into_iter() {
             let spans_vec: Vec<Span> = line
                 .into_iter()
-                .map(|(color, txt)| Span::styled(txt, Style::default().fg(color)))
+                .map(|(style, txt)| {
+                    let mut tui_style = style.style(theme);
+                    if style.link.is_some() && style.link == selected_link {
+                        tui_style = tui_style.add_modifier(Modifier::REVERSED);
+                    }
+                    Span::styled(txt, tui_style)
+                })
                 .collect();
             rows.push(Spans::from(spans_vec));
         }
@@ -3323,7 +4670,7 @@ fn render_long_message(f: &mut Frame<CrosstermBackend<io::Stdout>>, app: &mut Ap
             .block(Block::default().borders(Borders::ALL).title(""))
             .highlight_style(
                 Style::default()
-                    .bg(tuiColor::Rgb(50, 50, 50))
+                    .bg(theme.highlight_bg)
                     .add_modifier(Modifier::BOLD),
             );
 
@@ -3331,105 +4678,222 @@ fn render_long_message(f: &mut Frame<CrosstermBackend<io::Stdout>>, app: &mut Ap
     }
 }
 
+// "Press q to exit, Q to logout..." for the actions bound in the keymap.
+fn key_hints(keymap: &Keymap, mode: KeymapMode, hints: &[(KeyAction, &str)]) -> Vec<Span<'static>> {
+    let mut out = vec![];
+    for (action, label) in hints {
+        if let Some(keys) = keymap.keys_for(mode, *action) {
+            out.push(Span::raw(if out.is_empty() { "Press " } else { ", " }));
+            out.push(Span::styled(
+                keys,
+                Style::default().add_modifier(Modifier::BOLD),
+            ));
+            out.push(Span::raw(format!(" to {}", label)));
+        }
+    }
+    if !out.is_empty() {
+        out.push(Span::raw("."));
+    }
+    out
+}
+
 fn render_help_txt(
     f: &mut Frame<CrosstermBackend<io::Stdout>>,
     app: &mut App,
     r: Rect,
+    views: &ClientViews,
     curr_user: &str,
 ) {
+    let keymap = views.keymap;
+    let theme = views.theme;
     let (mut msg, style) = match app.input_mode {
         InputMode::Normal => (
-            vec![
-                Span::raw("Press "),
-                Span::styled("q", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to exit, "),
-                Span::styled("Q", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to logout, "),
-                Span::styled("i", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to start editing."),
-            ],
+            key_hints(
+                keymap,
+                KeymapMode::Normal,
+                &[
+                    (KeyAction::Quit, "exit"),
+                    (KeyAction::Logout, "logout"),
+                    (KeyAction::Edit, "start editing"),
+                ],
+            ),
             Style::default(),
         ),
         InputMode::Editing | InputMode::EditingErr => (
-            vec![
-                Span::raw("Press "),
-                Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to stop editing, "),
-                Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(" to record the message"),
-            ],
+            key_hints(
+                keymap,
+                KeymapMode::Editing,
+                &[
+                    (KeyAction::Escape, "stop editing"),
+                    (KeyAction::Submit, "record the message"),
+                ],
+            ),
             Style::default(),
         ),
-        InputMode::LongMessage => (vec![], Style::default()),
+        InputMode::LongMessage
+        | InputMode::Help
+        | InputMode::Thread
+        | InputMode::ImageViewer
+        | InputMode::FileBrowser
+        | InputMode::UploadConfirm => (vec![], Style::default()),
     };
+    // The start of a key sequence
+    if let Some(keys) = keymap.pending() {
+        let style = Style::default()
+            .fg(theme.pending)
+            .add_modifier(Modifier::BOLD);
+        msg.extend(vec![Span::raw(" | "), Span::styled(keys, style)]);
+    }
     msg.extend(vec![Span::raw(format!(" | {}", curr_user))]);
+    if app.unread > 0 {
+        let style = Style::default()
+            .fg(theme.pending)
+            .add_modifier(Modifier::BOLD);
+        msg.extend(vec![
+            Span::raw(" | "),
+            Span::styled(format!("{} unread", app.unread), style),
+        ]);
+    }
+    if app.mentions > 0 {
+        let style = Style::default()
+            .fg(theme.error)
+            .add_modifier(Modifier::BOLD);
+        let label = format!(
+            "{} mention{}",
+            app.mentions,
+            if app.mentions > 1 { "s" } else { "" }
+        );
+        msg.extend(vec![Span::raw(" | "), Span::styled(label, style)]);
+    }
     if app.is_muted {
-        let fg = tuiColor::Red;
+        let fg = theme.error;
         let style = Style::default().fg(fg).add_modifier(Modifier::BOLD);
         msg.extend(vec![Span::raw(" | "), Span::styled("muted", style)]);
     } else {
-        let fg = tuiColor::LightGreen;
+        let fg = theme.info;
         let style = Style::default().fg(fg).add_modifier(Modifier::BOLD);
         msg.extend(vec![Span::raw(" | "), Span::styled("not muted", style)]);
     }
 
     //Strange
     if app.display_guest_view {
-        let fg = tuiColor::LightGreen;
+        let fg = theme.info;
         let style = Style::default().fg(fg).add_modifier(Modifier::BOLD);
         msg.extend(vec![Span::raw(" | "), Span::styled("G", style)]);
     } else {
-        let fg = tuiColor::Gray;
+        let fg = theme.inactive;
         let style = Style::default().fg(fg);
         msg.extend(vec![Span::raw(" | "), Span::styled("G", style)]);
     }
 
     //Strange
     if app.display_member_view {
-        let fg = tuiColor::LightGreen;
+        let fg = theme.info;
         let style = Style::default().fg(fg).add_modifier(Modifier::BOLD);
         msg.extend(vec![Span::raw(" | "), Span::styled("M", style)]);
     } else {
-        let fg = tuiColor::Gray;
+        let fg = theme.inactive;
         let style = Style::default().fg(fg);
         msg.extend(vec![Span::raw(" | "), Span::styled("M", style)]);
     }
 
     if app.display_hidden_msgs {
-        let fg = tuiColor::LightGreen;
+        let fg = theme.info;
         let style = Style::default().fg(fg).add_modifier(Modifier::BOLD);
         msg.extend(vec![Span::raw(" | "), Span::styled("H", style)]);
     } else {
-        let fg = tuiColor::Gray;
+        let fg = theme.inactive;
         let style = Style::default().fg(fg);
         msg.extend(vec![Span::raw(" | "), Span::styled("H", style)]);
     }
+
+    if let Some((name, _)) = &app.view {
+        let style = Style::default().fg(theme.info).add_modifier(Modifier::BOLD);
+        msg.extend(vec![
+            Span::raw(" | "),
+            Span::styled(format!("view: {}", name), style),
+        ]);
+    }
+
+    if app.follow {
+        let style = Style::default().fg(theme.info).add_modifier(Modifier::BOLD);
+        msg.extend(vec![Span::raw(" | "), Span::styled("F", style)]);
+    } else {
+        let style = Style::default().fg(theme.inactive);
+        msg.extend(vec![Span::raw(" | "), Span::styled("F", style)]);
+    }
+
+    let status = views.status.lock().unwrap();
+    if let Some(p) = status.progress() {
+        // 10 chars progress bar
+        let done = ((p.percent() / 10) as usize).min(10);
+        let bar = format!(
+            "[{}{}] {}% {}",
+            "#".repeat(done),
+            " ".repeat(10 - done),
+            p.percent(),
+            p.label
+        );
+        let style = Style::default().fg(theme.pending);
+        msg.extend(vec![Span::raw(" | "), Span::styled(bar, style)]);
+    }
+    if let Some((txt, level)) = status.message() {
+        let fg = match level {
+            Level::Info => theme.info,
+            Level::Error => theme.error,
+        };
+        msg.extend(vec![
+            Span::raw(" | "),
+            Span::styled(txt.to_owned(), Style::default().fg(fg)),
+        ]);
+    }
     let mut text = Text::from(Spans::from(msg));
     text.patch_style(style);
     let help_message = Paragraph::new(text);
     f.render_widget(help_message, r);
 }
 
-fn render_textbox(f: &mut Frame<CrosstermBackend<io::Stdout>>, app: &mut App, r: Rect) {
-    let w = (r.width - 3) as usize;
-    let str = app.input.clone();
-    let mut input_str = str.as_str();
-    let mut overflow = 0;
-    if app.input_idx >= w {
-        overflow = std::cmp::max(app.input.width() - w, 0);
-        input_str = &str[overflow..];
-    }
-    let input = Paragraph::new(input_str)
+// Height of the input box, it grows with the number of lines typed.
+fn input_height(input: &str) -> u16 {
+    std::cmp::min(input.matches('\n').count() + 1, MAX_INPUT_LINES) as u16 + 2
+}
+
+fn render_textbox(
+    f: &mut Frame<CrosstermBackend<io::Stdout>>,
+    app: &mut App,
+    r: Rect,
+    theme: &Theme,
+) {
+    let cols = r.width.saturating_sub(2) as usize;
+    let rows = r.height.saturating_sub(2) as usize;
+    let view = text::input_view(app.input.text(), app.input.cursor(), cols, rows);
+    let lines: Vec<Spans> = view.lines.into_iter().map(Spans::from).collect();
+    let title = match app.input.search_query() {
+        Some(query) => format!("(reverse-i-search)`{}'", query),
+        None if app.input.vi_normal() => "Input [NORMAL]".to_owned(),
+        None => "Input".to_owned(),
+    };
+    let input = Paragraph::new(lines)
         .style(match app.input_mode {
-            InputMode::LongMessage => Style::default(),
+            InputMode::LongMessage
+            | InputMode::Help
+            | InputMode::Thread
+            | InputMode::ImageViewer
+            | InputMode::FileBrowser
+            | InputMode::UploadConfirm => Style::default(),
             InputMode::Normal => Style::default(),
-            InputMode::Editing => Style::default().fg(tuiColor::Yellow),
-            InputMode::EditingErr => Style::default().fg(tuiColor::Red),
+            InputMode::Editing => Style::default().fg(theme.editing),
+            InputMode::EditingErr => Style::default().fg(theme.error),
         })
-        .block(Block::default().borders(Borders::ALL).title("Input"));
+        .block(Block::default().borders(Borders::ALL).title(title));
     f.render_widget(input, r);
     match app.input_mode {
-        InputMode::LongMessage => {}
+        InputMode::LongMessage
+        | InputMode::Help
+        | InputMode::Thread
+        | InputMode::ImageViewer
+        | InputMode::FileBrowser
+        | InputMode::UploadConfirm => {}
         InputMode::Normal =>
             // Hide the cursor. `Frame` does this by default, so we don't need to do anything here
             {}
@@ -3437,117 +4901,485 @@ fn render_textbox(f: &mut Frame<CrosstermBackend<io::Stdout>>, app: &mut App, r:
         InputMode::Editing | InputMode::EditingErr => {
             // Make the cursor visible and ask tui-rs to put it at the specified coordinates after rendering
             f.set_cursor(
-                // Put cursor past the end of the input text
-                r.x + app.input_idx as u16 - overflow as u16 + 1,
-                // Move one line down, from the border to the input line
-                r.y + 1,
+                // Past the border, at the cursor position in the visible text
+                r.x + view.cursor.0 + 1,
+                r.y + view.cursor.1 + 1,
             )
         }
     }
 }
 
+// Completion menu, drawn over the messages right under the input box.
+fn render_completion(
+    f: &mut Frame<CrosstermBackend<io::Stdout>>,
+    input: Rect,
+    c: &Completion,
+    theme: &Theme,
+) {
+    let size = f.size();
+    let y = input.y + input.height;
+    let longest = c
+        .candidates
+        .iter()
+        .map(|s| text::width(s))
+        .max()
+        .unwrap_or(0);
+    let width = std::cmp::min(longest as u16 + 4, input.width);
+    let height = std::cmp::min(c.candidates.len() as u16 + 2, 10);
+    let height = std::cmp::min(height, size.height.saturating_sub(y));
+    let r = Rect::new(input.x, y, width, height);
+    let items: Vec<ListItem> = c
+        .candidates
+        .iter()
+        .map(|s| ListItem::new(s.as_str()))
+        .collect();
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL))
+        .highlight_style(
+            Style::default()
+                .bg(theme.highlight_bg)
+                .add_modifier(Modifier::BOLD),
+        );
+    let mut state = ListState::default();
+    state.select(c.selected);
+    f.render_widget(Clear, r);
+    f.render_stateful_widget(list, r, &mut state);
+}
+
+fn render_outbox(
+    f: &mut Frame<CrosstermBackend<io::Stdout>>,
+    r: Rect,
+    pending: &[OutboxItem],
+    theme: &Theme,
+) {
+    let items: Vec<ListItem> = pending
+        .iter()
+        .rev()
+        .map(|item| {
+            let color = match item.state {
+                ItemState::Failed(_) => theme.error,
+                ItemState::Sending => theme.pending,
+                _ => theme.dim,
+            };
+            let mut spans = vec![
+                Span::styled(
+                    format!("{} ", item.state.marker()),
+                    Style::default().fg(color),
+                ),
+                Span::raw(item.summary()),
+            ];
+            if let ItemState::Failed(err) = &item.state {
+                spans.push(Span::styled(
+                    format!(" ({})", err),
+                    Style::default().fg(color),
+                ));
+            }
+            ListItem::new(Spans::from(spans))
+        })
+        .collect();
+    let list = List::new(items).block(Block::default().borders(Borders::ALL).title("Outbox"));
+    f.render_widget(list, r);
+}
+
+fn render_downloads(
+    f: &mut Frame<CrosstermBackend<io::Stdout>>,
+    r: Rect,
+    downloads: &[Download],
+    theme: &Theme,
+) {
+    let items: Vec<ListItem> = downloads
+        .iter()
+        .rev()
+        .map(|d| {
+            let (marker, color) = match d.state {
+                DownloadState::Downloading { .. } => ("↓", theme.pending),
+                DownloadState::Done => ("✓", theme.info),
+                DownloadState::Failed(_) => ("✗", theme.error),
+            };
+            ListItem::new(Spans::from(vec![
+                Span::styled(format!("{} ", marker), Style::default().fg(color)),
+                Span::raw(d.summary()),
+            ]))
+        })
+        .collect();
+    let list = List::new(items).block(Block::default().borders(Borders::ALL).title("Downloads"));
+    f.render_widget(list, r);
+}
+
+fn render_upload_confirm(
+    f: &mut Frame<CrosstermBackend<io::Stdout>>,
+    r: Rect,
+    p: &PendingUpload,
+    theme: &Theme,
+) {
+    let mut lines = vec![
+        Spans::from(format!("{}", p.path.display())),
+        Spans::from(""),
+    ];
+    if p.report.strippable() {
+        lines.push(Spans::from("This metadata will be removed:"));
+    } else {
+        let style = Style::default()
+            .fg(theme.error)
+            .add_modifier(Modifier::BOLD);
+        lines.push(Spans::from(Span::styled(
+            "This metadata CANNOT be removed and will be sent:",
+            style,
+        )));
+    }
+    for item in &p.report.found {
+        lines.push(Spans::from(format!("  - {}", item)));
+    }
+    lines.push(Spans::from(""));
+    lines.push(Spans::from(if p.report.strippable() {
+        "y/enter: strip & send, r: send as is, n/esc: cancel"
+    } else {
+        "y/enter: send anyway, n/esc: cancel"
+    }));
+    let title = "Metadata found in upload";
+    let popup = Paragraph::new(lines)
+        .wrap(Wrap { trim: false })
+        .block(Block::default().borders(Borders::ALL).title(title));
+    f.render_widget(popup, r);
+}
+
+fn render_help(
+    f: &mut Frame<CrosstermBackend<io::Stdout>>,
+    r: Rect,
+    lines: &[String],
+    scroll: u16,
+) {
+    let lines: Vec<Spans> = lines.iter().map(|l| Spans::from(l.as_str())).collect();
+    let title = "Help (j/k: scroll, esc: close)";
+    let help = Paragraph::new(lines)
+        .wrap(Wrap { trim: false })
+        .scroll((scroll, 0))
+        .block(Block::default().borders(Borders::ALL).title(title));
+    f.render_widget(help, r);
+}
+
+fn render_file_browser(
+    f: &mut Frame<CrosstermBackend<io::Stdout>>,
+    r: Rect,
+    fb: &mut FileBrowser,
+    theme: &Theme,
+) {
+    let items: Vec<ListItem> = fb
+        .entries
+        .items
+        .iter()
+        .map(|e| {
+            if e.is_dir {
+                let style = Style::default().fg(theme.directory);
+                ListItem::new(Span::styled(format!("{}/", e.name), style))
+            } else {
+                ListItem::new(Span::raw(e.name.clone()))
+            }
+        })
+        .collect();
+    let title = format!(
+        "Upload: {} (enter: select, backspace: parent, esc: cancel)",
+        fb.dir.display()
+    );
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .highlight_style(
+            Style::default()
+                .bg(theme.highlight_bg)
+                .add_modifier(Modifier::BOLD),
+        );
+    f.render_stateful_widget(list, r, &mut fb.entries.state);
+}
+
+// Images are drawn in truecolor, bring them down to what the terminal supports.
+fn themed_spans<'a>(line: Spans<'a>, theme: &Theme) -> Spans<'a> {
+    let spans: Vec<Span> = line
+        .0
+        .into_iter()
+        .map(|span| Span::styled(span.content, theme.style(span.style)))
+        .collect();
+    Spans::from(spans)
+}
+
+fn render_image_viewer(
+    f: &mut Frame<CrosstermBackend<io::Stdout>>,
+    r: Rect,
+    views: &ClientViews,
+    url: &str,
+) {
+    let lines: Vec<Spans> = views
+        .previews
+        .view(url, r.width.saturating_sub(2), r.height.saturating_sub(2))
+        .into_iter()
+        .map(|line| themed_spans(line, views.theme))
+        .collect();
+    let title = "Image (d: open externally, esc: close)";
+    let viewer = Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title));
+    f.render_widget(viewer, r);
+}
+
+// Which messages a messages column displays, when the layout splits them.
+#[derive(Clone, Copy, PartialEq)]
+enum Column {
+    All,
+    Public,
+    Private,
+}
+
 fn render_messages(
     f: &mut Frame<CrosstermBackend<io::Stdout>>,
     app: &mut App,
     r: Rect,
     messages: &Arc<Mutex<Vec<Message>>>,
+    views: &ClientViews,
+    users: &Arc<Mutex<Users>>,
+    username: &str,
+    column: Column,
 ) {
-    // Messages
-    app.items.items.clear();
+    let theme = views.theme;
+    // Only the main column can be navigated
+    let selectable = column != Column::Private;
+    let outbox = views.outbox.lock().unwrap();
     let messages = messages.lock().unwrap();
-    let messages_list_items: Vec<ListItem> = messages
-        .iter()
-        .filter_map(|m| {
-            if !app.display_hidden_msgs && m.hide {
-                return None;
+    let position = |uid: u64| messages.iter().position(|m| m.uid == uid);
+    // The selection follows the message, not its index
+    let jump_to = if selectable { app.jump_to.take() } else { None };
+    let selected = jump_to.or_else(|| {
+        let idx = app.items.state.selected()?;
+        app.items.items.get(idx).map(|m| m.uid)
+    });
+    // Facts are extracted once per message, oldest first so replies find what they answer
+    for (idx, m) in messages.iter().enumerate().rev() {
+        if !app.facts.contains_key(&m.uid) {
+            let mut facts = message_facts(m, app, username);
+            if let Some(from) = &facts.from {
+                let older = messages[idx + 1..].iter().filter_map(|o| {
+                    let f = app.facts.get(&o.uid)?;
+                    Some(replies::Post {
+                        uid: o.uid,
+                        from: f.from.as_deref()?,
+                        body: &f.body,
+                    })
+                });
+                facts.reply_to = replies::reply_to(from, &facts.body, older);
             }
-            // Simulate a guest view (remove "PMs" and "Members chat" messages)
-            if app.display_guest_view {
-                // TODO: this is not efficient at all
-                let text = m.text.text();
-                if text.starts_with(&app.members_tag) || text.starts_with(&app.staffs_tag) {
-                    return None;
-                }
-                if let Some((_, Some(_), _)) = get_message(&m.text, &app.members_tag) {
-                    return None;
-                }
+            app.facts.insert(m.uid, facts);
+        }
+    }
+    if app.facts.len() > 2 * messages.len() {
+        let uids: HashSet<u64> = messages.iter().map(|m| m.uid).collect();
+        app.facts.retain(|uid, _| uids.contains(uid));
+    }
+    if selectable {
+        // Messages there when we start are read, then reading a message reads the newer ones
+        if app.last_read.is_none() {
+            app.last_read = messages.first().map(|m| m.uid);
+        }
+        let read_pos = app.last_read.and_then(position).unwrap_or(messages.len());
+        if let Some(pos) = selected.and_then(position) {
+            if pos < read_pos {
+                app.last_read = Some(messages[pos].uid);
             }
-
-            // Strange
-            // Display only messages from members and staff
-            if app.display_member_view {
-                // In members mode, include only messages from members and staff
-                let text = m.text.text();
-                if !text.starts_with(&app.members_tag) && !text.starts_with(&app.staffs_tag) {
-                    return None;
-                }
-                if let Some((_, Some(_), _)) = get_message(&m.text, &app.members_tag) {
-                    return None;
-                }
+        }
+    }
+    let read_pos = app.last_read.and_then(position).unwrap_or(messages.len());
+    if selectable {
+        app.unread = read_pos;
+        let mentions = messages[..read_pos]
+            .iter()
+            .filter(|m| app.facts[&m.uid].mention)
+            .count();
+        app.mentions = mentions;
+    }
+    let roles = user_roles(&users.lock().unwrap());
+    let ctx = filter::Context {
+        username,
+        roles: &roles,
+        now: messages.first().and_then(|m| app.facts[&m.uid].time),
+    };
+    let mut active: Vec<&Filter> = vec![];
+    if app.display_guest_view {
+        active.push(&GUEST_VIEW);
+    }
+    if app.display_member_view {
+        active.push(&MEMBER_VIEW);
+    }
+    if let Some((_, view)) = &app.view {
+        active.push(view);
+    }
+    if let Some(find) = &app.find {
+        active.push(find);
+    }
+    let visible: Vec<(usize, &Message)> = messages
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| {
+            if !app.display_hidden_msgs && m.hide {
+                return false;
             }
-
-            if app.filter != "" {
-                if !m
-                    .text
-                    .text()
-                    .to_lowercase()
-                    .contains(&app.filter.to_lowercase())
-                {
-                    return None;
-                }
+            let facts = &app.facts[&m.uid];
+            if column != Column::All && facts.channel.is_private() != (column == Column::Private) {
+                return false;
             }
+            let item = filter::Item {
+                facts,
+                deleted: m.deleted,
+                hidden: m.hide,
+            };
+            active.iter().all(|f| f.matches(&item, &ctx))
+        })
+        .collect();
+    if selectable {
+        app.items.items = visible.iter().map(|(_, m)| (*m).clone()).collect();
+    }
 
-            app.items.items.push(m.clone());
-
-            let new_lines = gen_lines(&m.text, (r.width - 20) as usize, " ".repeat(17).as_str());
+    let mut marker_drawn = false;
+    // Attachments of each item, only the ones on screen are fetched
+    let mut attachments: Vec<Option<String>> = vec![];
+    let mut messages_list_items: Vec<ListItem> = visible
+        .into_iter()
+        .map(|(pos, m)| {
+            let new_lines = gen_lines(
+                &m.text,
+                r.width.saturating_sub(20) as usize,
+                " ".repeat(17).as_str(),
+            );
 
             let mut rows = vec![];
+            // Above the newest read message that is displayed
+            if read_pos > 0 && pos >= read_pos && !marker_drawn {
+                marker_drawn = true;
+                let marker = format!("{:─^1$}", " new ", r.width.saturating_sub(2) as usize);
+                rows.push(Spans::from(Span::styled(
+                    marker,
+                    Style::default().fg(theme.pending),
+                )));
+            }
+            let facts = &app.facts[&m.uid];
             let date_style = match (m.deleted, m.hide) {
-                (false, true) => Style::default().fg(tuiColor::Gray),
-                (false, _) => Style::default().fg(tuiColor::DarkGray),
-                (true, _) => Style::default().fg(tuiColor::Red),
+                (false, true) => Style::default().fg(theme.inactive),
+                (false, _) => Style::default().fg(theme.dim),
+                (true, _) => Style::default().fg(theme.error),
             };
             let mut spans_vec = vec![Span::styled(m.date.clone(), date_style)];
             let show_sys_sep = app.show_sys && m.typ == MessageType::SysMsg;
             let sep = if show_sys_sep { " * " } else { " - " };
             spans_vec.push(Span::raw(sep));
+            // Mark our own messages that went through the outbox
+            if facts.from.as_deref() == Some(username) {
+                if let Some((_, _, msg)) = get_message(&m.text, &app.members_tag) {
+                    if outbox.was_sent(&msg) {
+                        let marker = format!("{} ", ItemState::Sent.marker());
+                        spans_vec.push(Span::styled(marker, Style::default().fg(theme.dim)));
+                    }
+                }
+            }
+            if facts.reply_to.is_some() {
+                spans_vec.push(Span::styled("↪ ", Style::default().fg(theme.dim)));
+            }
             for (idx, line) in new_lines.into_iter().enumerate() {
                 // Spams can take your whole screen, so we limit to 5 lines.
                 if idx >= 5 {
                     spans_vec.push(Span::styled(
                         "                 […]",
-                        Style::default().fg(tuiColor::White),
+                        Style::default().fg(theme.text),
                     ));
                     rows.push(Spans::from(spans_vec));
                     break;
                 }
-                for (color, txt) in line {
-                    spans_vec.push(Span::styled(txt, Style::default().fg(color)));
+                for (style, txt) in line {
+                    spans_vec.push(Span::styled(txt, style.style(theme)));
                 }
                 rows.push(Spans::from(spans_vec.clone()));
                 spans_vec.clear();
             }
+            let attachment = match (&m.upload_link, m.hide) {
+                (Some(upload_link), false) => Some(format!("{}{}", app.base_url, upload_link)),
+                _ => None,
+            };
+            if let Some(url) = &attachment {
+                if let Some(lines) = views.previews.thumbnail(url, r.width.saturating_sub(20)) {
+                    for line in lines {
+                        let mut spans = vec![Span::raw(" ".repeat(17))];
+                        spans.extend(themed_spans(line, theme).0);
+                        rows.push(Spans::from(spans));
+                    }
+                }
+            }
+            attachments.push(attachment);
 
             let style = match (m.deleted, m.hide) {
-                (true, _) => Style::default().bg(tuiColor::Rgb(30, 0, 0)),
-                (_, true) => Style::default().bg(tuiColor::Rgb(20, 20, 20)),
+                (true, _) => Style::default().bg(theme.deleted_bg),
+                (_, true) => Style::default().bg(theme.hidden_bg),
+                _ if facts.mention => Style::default().bg(theme.mention_bg),
                 _ => Style::default(),
             };
-            Some(ListItem::new(rows).style(style))
+            ListItem::new(rows).style(style)
         })
         .collect();
 
-    let messages_list = List::new(messages_list_items)
-        .block(Block::default().borders(Borders::ALL).title("Messages"))
+    let title = match column {
+        Column::All => "Messages",
+        Column::Public => "Public",
+        Column::Private => "PMs & members",
+    };
+    let heights: Vec<usize> = messages_list_items.iter().map(|i| i.height()).collect();
+    let request_on_screen = |top: usize| {
+        let mut rows = 0;
+        for (url, height) in attachments.iter().zip(&heights).skip(top) {
+            if rows >= r.height.saturating_sub(2) as usize {
+                break;
+            }
+            if let Some(url) = url {
+                views.previews.request(url);
+            }
+            rows += height;
+        }
+    };
+    if !selectable {
+        request_on_screen(0);
+        let messages_list = List::new(messages_list_items)
+            .block(Block::default().borders(Borders::ALL).title(title));
+        f.render_widget(messages_list, r);
+        return;
+    }
+
+    // A selected message that is no longer displayed is unselected, not replaced by its neighbour
+    let selected = selected.and_then(|uid| app.items.items.iter().position(|m| m.uid == uid));
+    app.items.state.select(selected);
+    // The viewport stays on the same message while reading history
+    let top = app
+        .top
+        .and_then(|uid| app.items.items.iter().position(|m| m.uid == uid))
+        .unwrap_or(0);
+    let top = scroll_to(&heights, top, selected, r.height.saturating_sub(2) as usize);
+    request_on_screen(top);
+    app.top = match app.items.items.get(top) {
+        Some(m) if top > 0 || !app.follow => Some(m.uid),
+        _ => None,
+    };
+    let title = match top {
+        0 => title.to_owned(),
+        newer => format!("{} (+{} newer)", title, newer),
+    };
+    let mut state = ListState::default();
+    state.select(selected.map(|idx| idx - top));
+    let messages_list = List::new(messages_list_items.split_off(top))
+        .block(Block::default().borders(Borders::ALL).title(title))
         .highlight_style(
             Style::default()
-                .bg(tuiColor::Rgb(50, 50, 50))
+                .bg(theme.highlight_bg)
                 .add_modifier(Modifier::BOLD),
         );
-    f.render_stateful_widget(messages_list, r, &mut app.items.state)
+    f.render_stateful_widget(messages_list, r, &mut state)
 }
 
-fn render_users(f: &mut Frame<CrosstermBackend<io::Stdout>>, r: Rect, users: &Arc<Mutex<Users>>) {
+fn render_users(
+    f: &mut Frame<CrosstermBackend<io::Stdout>>,
+    r: Rect,
+    users: &Arc<Mutex<Users>>,
+    theme: &Theme,
+) {
     // Users lists
     let users = users.lock().unwrap();
     let mut users_list: Vec<ListItem> = vec![];
@@ -3559,7 +5391,7 @@ fn render_users(f: &mut Frame<CrosstermBackend<io::Stdout>>, r: Rect, users: &Ar
     for (users, label) in users_types.into_iter() {
         users_list.push(ListItem::new(Span::raw(label)));
         for (tui_color, username) in users.iter() {
-            let span = Span::styled(username, Style::default().fg(*tui_color));
+            let span = Span::styled(username, Style::default().fg(theme.chat_color(*tui_color)));
             users_list.push(ListItem::new(span));
         }
     }
@@ -3567,6 +5399,80 @@ fn render_users(f: &mut Frame<CrosstermBackend<io::Stdout>>, r: Rect, users: &Ar
     f.render_widget(users, r);
 }
 
+// Private conversations, most recently active first.
+fn render_threads(
+    f: &mut Frame<CrosstermBackend<io::Stdout>>,
+    app: &App,
+    r: Rect,
+    messages: &Arc<Mutex<Vec<Message>>>,
+    theme: &Theme,
+    username: &str,
+) {
+    let messages = messages.lock().unwrap();
+    // (other user, number of messages, last message)
+    let mut threads: Vec<(String, usize, String)> = Vec::new();
+    for m in messages.iter() {
+        if let Some((from, Some(to), msg)) = get_message(&m.text, &app.members_tag) {
+            let other = if from == username { to } else { from };
+            match threads.iter_mut().find(|(u, _, _)| *u == other) {
+                Some(thread) => thread.1 += 1,
+                None => threads.push((other, 1, msg)),
+            }
+        }
+    }
+    let items: Vec<ListItem> = threads
+        .into_iter()
+        .map(|(other, count, last)| {
+            ListItem::new(Spans::from(vec![
+                Span::styled(other, Style::default().add_modifier(Modifier::BOLD)),
+                Span::styled(format!(" ({}) ", count), Style::default().fg(theme.dim)),
+                Span::raw(last),
+            ]))
+        })
+        .collect();
+    let list = List::new(items).block(Block::default().borders(Borders::ALL).title("PM threads"));
+    f.render_widget(list, r);
+}
+
+// System messages about moderation: kicks, bans, warnings & cleanups.
+fn is_mod_event(m: &Message) -> bool {
+    if m.typ != MessageType::SysMsg {
+        return false;
+    }
+    let text = m.text.text().to_lowercase();
+    [
+        "kicked",
+        "banned",
+        "warn",
+        "logged out",
+        "purged",
+        "cleaned",
+    ]
+    .iter()
+    .any(|w| text.contains(w))
+}
+
+fn render_modlog(
+    f: &mut Frame<CrosstermBackend<io::Stdout>>,
+    r: Rect,
+    messages: &Arc<Mutex<Vec<Message>>>,
+    theme: &Theme,
+) {
+    let messages = messages.lock().unwrap();
+    let items: Vec<ListItem> = messages
+        .iter()
+        .filter(|m| is_mod_event(m))
+        .map(|m| {
+            ListItem::new(Spans::from(vec![
+                Span::styled(format!("{} ", m.date), Style::default().fg(theme.dim)),
+                Span::raw(m.text.text()),
+            ]))
+        })
+        .collect();
+    let list = List::new(items).block(Block::default().borders(Borders::ALL).title("Mod log"));
+    f.render_widget(list, r);
+}
+
 fn random_string(n: usize) -> String {
     let s: Vec<u8> = thread_rng().sample_iter(&Alphanumeric).take(n).collect();
     std::str::from_utf8(&s).unwrap().to_owned()
@@ -3575,6 +5481,11 @@ fn random_string(n: usize) -> String {
 #[derive(PartialEq)]
 enum InputMode {
     LongMessage,
+    Help,
+    Thread,
+    ImageViewer,
+    FileBrowser,
+    UploadConfirm,
     Normal,
     Editing,
     EditingErr,
@@ -3583,21 +5494,53 @@ enum InputMode {
 /// App holds the state of the application
 struct App {
     /// Current value of the input box
-    input: String,
-    input_idx: usize,
+    input: LineEditor,
     /// Current input mode
     input_mode: InputMode,
     is_muted: bool,
     show_sys: bool,
+    show_downloads: bool,
     display_guest_view: bool,
     display_member_view: bool,
     display_hidden_msgs: bool,
     items: StatefulList<Message>,
     filter: String,
+    // The "/f" filter, compiled while typing
+    find: Option<Filter>,
+    // The saved view being displayed
+    view: Option<(String, Filter)>,
+    // Facts of the messages, by uid
+    facts: HashMap<u64, Facts>,
+    datetime_fmt: String,
     members_tag: String,
     staffs_tag: String,
+    base_url: String,
     long_message: Option<Message>,
-    commands: Commands,
+    /// The thread view, messages with their depth in the conversation
+    thread: Option<StatefulList<(usize, Message)>>,
+    // Index of the link selected in the long message view
+    long_message_link: Option<usize>,
+    // Link waiting for a second "o" before being opened outside of Tor
+    open_link_confirm: Option<String>,
+    image_viewer: Option<String>,
+    file_browser: Option<FileBrowser>,
+    pending_upload: Option<PendingUpload>,
+    completion: Option<Completion>,
+    // Builtin & custom commands
+    commands: Registry,
+    // The /help overlay
+    help: Option<Vec<String>>,
+    help_scroll: u16,
+    // Message to select on the next draw
+    jump_to: Option<u64>,
+    // First message displayed when reading history, None when at the newest
+    top: Option<u64>,
+    // New messages scroll the list when at the newest
+    follow: bool,
+    // Newest message that was read, the marker line is drawn under the unread ones
+    last_read: Option<u64>,
+    unread: usize,
+    mentions: usize,
 }
 
 impl Default for App {
@@ -3632,38 +5575,83 @@ impl Default for App {
             }
         };
 
+        let mut registry = Registry::builtin();
+        let mut custom: Vec<_> = commands.commands.into_iter().collect();
+        custom.sort();
+        for (name, text) in custom {
+            registry.register_custom(&name, &text);
+        }
+
         App {
-            input: String::new(),
-            input_idx: 0,
+            input: LineEditor::default(),
             input_mode: InputMode::Normal,
             is_muted: false,
             show_sys: false,
+            show_downloads: false,
             display_guest_view: false,
             display_member_view: false,
             display_hidden_msgs: false,
             items: StatefulList::new(),
             filter: "".to_owned(),
+            find: None,
+            view: None,
+            facts: HashMap::new(),
+            datetime_fmt: String::new(),
             members_tag: "".to_owned(),
             staffs_tag: "".to_owned(),
+            base_url: "".to_owned(),
             long_message: None,
-            commands,
+            thread: None,
+            long_message_link: None,
+            open_link_confirm: None,
+            image_viewer: None,
+            file_browser: None,
+            pending_upload: None,
+            completion: None,
+            commands: registry,
+            help: None,
+            help_scroll: 0,
+            jump_to: None,
+            top: None,
+            follow: true,
+            last_read: None,
+            unread: 0,
+            mentions: 0,
         }
     }
 }
 
 impl App {
+    fn selected_message(&self) -> Option<Message> {
+        let idx = self.items.state.selected()?;
+        self.items.items.get(idx).cloned()
+    }
+
+    // The marker moves to the newest message on the next draw.
+    fn mark_read(&mut self) {
+        self.last_read = None;
+    }
+
     fn update_filter(&mut self) {
-        if let Some(captures) = FIND_RGX.captures(&self.input) {
+        if let Some(captures) = FIND_RGX.captures(self.input.text()) {
             // Find
-            self.filter = captures.get(1).map_or("", |m| m.as_str()).to_owned();
+            let filter = captures.get(1).map_or("", |m| m.as_str());
+            if filter != self.filter {
+                self.filter = filter.to_owned();
+                // Incomplete expressions are searched as text while typing
+                self.find = match filter.trim() {
+                    "" => None,
+                    f => Some(filter::parse(f).unwrap_or_else(|_| Filter::Text(f.to_lowercase()))),
+                };
+            }
         }
     }
 
     fn clear_filter(&mut self) {
-        if FIND_RGX.is_match(&self.input) {
+        if FIND_RGX.is_match(self.input.text()) {
             self.filter = "".to_owned();
-            self.input = "".to_owned();
-            self.input_idx = 0;
+            self.find = None;
+            self.input.clear();
         }
     }
 }
@@ -3760,33 +5748,71 @@ impl Events {
 mod tests {
     use super::*;
 
+    #[test]
+    fn move_secrets_test() {
+        let mut cfg: MyConfig = toml::from_str(
+            r#"
+            dkf_api_key = "api"
+            [profiles.default]
+            username = "alice"
+            password = "hunter2"
+            [profiles.other]
+            username = "bob"
+            password_secret = "bob"
+            "#,
+        )
+        .unwrap();
+        let mut vault = Vault::in_memory();
+        move_secrets(&mut cfg, &mut vault);
+        assert_eq!(
+            vault.get("profile.default.password").map(|s| s.as_str()),
+            Some("hunter2")
+        );
+        assert_eq!(vault.get("dkf_api_key").map(|s| s.as_str()), Some("api"));
+        assert_eq!(vault.names().len(), 2);
+        let default = &cfg.profiles["default"];
+        assert!(default.password.is_empty());
+        assert_eq!(
+            default.password_secret.as_deref(),
+            Some("profile.default.password")
+        );
+        assert_eq!(
+            cfg.profiles["other"].password_secret.as_deref(),
+            Some("bob")
+        );
+        assert_eq!(cfg.dkf_api_key, None);
+        assert_eq!(cfg.dkf_api_key_secret.as_deref(), Some("dkf_api_key"));
+        let saved = toml::to_string(&cfg).unwrap();
+        assert!(!saved.contains("hunter2") && !saved.contains("\"api\""));
+    }
+
     #[test]
     fn gen_lines_test() {
         let txt = StyledText::Styled(
-            tuiColor::White,
+            TextStyle::new(tuiColor::White),
             vec![
                 StyledText::Styled(
-                    tuiColor::Rgb(255, 255, 255),
+                    TextStyle::new(tuiColor::Rgb(255, 255, 255)),
                     vec![
                         StyledText::Text(" prmdbba pwuv💓".to_owned()),
                         StyledText::Styled(
-                            tuiColor::Rgb(255, 255, 255),
+                            TextStyle::new(tuiColor::Rgb(255, 255, 255)),
                             vec![StyledText::Styled(
-                                tuiColor::Rgb(0, 255, 0),
+                                TextStyle::new(tuiColor::Rgb(0, 255, 0)),
                                 vec![StyledText::Text("PMW".to_owned())],
                             )],
                         ),
                         StyledText::Styled(
-                            tuiColor::Rgb(255, 255, 255),
+                            TextStyle::new(tuiColor::Rgb(255, 255, 255)),
                             vec![StyledText::Styled(
-                                tuiColor::Rgb(255, 255, 255),
+                                TextStyle::new(tuiColor::Rgb(255, 255, 255)),
                                 vec![StyledText::Text("A".to_owned())],
                             )],
                         ),
                         StyledText::Styled(
-                            tuiColor::Rgb(255, 255, 255),
+                            TextStyle::new(tuiColor::Rgb(255, 255, 255)),
                             vec![StyledText::Styled(
-                                tuiColor::Rgb(0, 255, 0),
+                                TextStyle::new(tuiColor::Rgb(0, 255, 0)),
                                 vec![StyledText::Text("XOS".to_owned())],
                             )],
                         ),
@@ -3797,7 +5823,7 @@ mod tests {
                 ),
                 StyledText::Text(" - ".to_owned()),
                 StyledText::Styled(
-                    tuiColor::Rgb(255, 255, 255),
+                    TextStyle::new(tuiColor::Rgb(255, 255, 255)),
                     vec![StyledText::Text("rytxvgs".to_owned())],
                 ),
             ],
@@ -3805,4 +5831,32 @@ mod tests {
         let lines = gen_lines(&txt, 71, "");
         assert_eq!(lines.len(), 2);
     }
+
+    #[test]
+    fn process_node_styles_test() {
+        let doc = Document::from(
+            r#"<span><b>bold <i>both</i></b> <a href="/upload/x.png">link</a> see http://a.onion</span>"#,
+        );
+        let span = doc.find(Name("span")).next().unwrap();
+        let (txt, _) = process_node(span, TextStyle::new(tuiColor::White));
+        let parts = txt.colored_text();
+        assert_eq!(parts[0].1, "bold ");
+        assert_eq!(parts[0].0.modifier, Modifier::BOLD);
+        assert_eq!(parts[1].0.modifier, Modifier::BOLD | Modifier::ITALIC);
+        assert_eq!(txt.links(), vec!["/upload/x.png", "http://a.onion"]);
+        assert_eq!(
+            absolute_link("http://a.onion", "/upload/x.png"),
+            "http://a.onion/upload/x.png"
+        );
+    }
+
+    #[test]
+    fn is_mention_test() {
+        let to_me = Some("me".to_owned());
+        assert!(is_mention(&None, "hi @me", "me"));
+        assert!(is_mention(&to_me, "hello", "me"));
+        assert!(!is_mention(&to_me, "!up", "me"));
+        assert!(!is_mention(&Some("bob".to_owned()), "hello", "me"));
+        assert!(!is_mention(&None, "hi @bob", "me"));
+    }
 }
@@ -0,0 +1,723 @@
+//! Notifications: rules map chat events (PMs, mentions, keywords, joins,
+//! members/staff chat, moderation, DKF and DNMX mail) to alerts (a sound, the
+//! terminal bell, a desktop notification over D-Bus, a flashing window title).
+use crate::filter::Channel;
+use chrono::{Local, NaiveTime};
+use crossbeam_channel::Sender;
+use rodio::{source::Source, Decoder, OutputStream};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_SOUND: &[u8] = include_bytes!("sound1.mp3");
+const APP_NAME: &str = "bhcli";
+// The title blinks 3 times
+const FLASH_STEPS: u32 = 6;
+const FLASH_STEP: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    /// A PM sent to us
+    Pm,
+    /// A message that tags us
+    Mention,
+    /// A message containing one of the rule keywords
+    Keyword,
+    /// A user came in the chat
+    Join,
+    Members,
+    Staff,
+    /// Kicks, bans, warnings...
+    Moderation,
+    /// A PM or a tag on DKF
+    Dkf,
+    /// New DNMX mails
+    Mail,
+}
+
+/// The names of the triggers, as in the config.
+pub const EVENTS: &[&str] = &[
+    "pm",
+    "mention",
+    "keyword",
+    "join",
+    "members",
+    "staff",
+    "moderation",
+    "dkf",
+    "mail",
+];
+
+const TRIGGERS: &[Trigger] = &[
+    Trigger::Pm,
+    Trigger::Mention,
+    Trigger::Keyword,
+    Trigger::Join,
+    Trigger::Members,
+    Trigger::Staff,
+    Trigger::Moderation,
+    Trigger::Dkf,
+    Trigger::Mail,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Alert {
+    Sound,
+    Bell,
+    Desktop,
+    Flash,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    pub on: Trigger,
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    /// Only for these users (who wrote or joined), everyone when empty
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default = "default_alerts")]
+    pub alerts: Vec<Alert>,
+    /// Played instead of the sound of the event
+    #[serde(default)]
+    pub sound: Option<PathBuf>,
+}
+
+/// A sound per event, the embedded one for the others. Relative paths are in
+/// the config directory, wav, ogg, flac and mp3 files are played.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SoundConfig {
+    /// From 0 (silent) to 1
+    pub volume: f32,
+    pub pm: Option<PathBuf>,
+    pub mention: Option<PathBuf>,
+    pub keyword: Option<PathBuf>,
+    pub join: Option<PathBuf>,
+    pub members: Option<PathBuf>,
+    pub staff: Option<PathBuf>,
+    pub moderation: Option<PathBuf>,
+    pub dkf: Option<PathBuf>,
+    pub mail: Option<PathBuf>,
+}
+
+impl Default for SoundConfig {
+    fn default() -> Self {
+        Self {
+            volume: 1.0,
+            pm: None,
+            mention: None,
+            keyword: None,
+            join: None,
+            members: None,
+            staff: None,
+            moderation: None,
+            dkf: None,
+            mail: None,
+        }
+    }
+}
+
+impl SoundConfig {
+    fn file(&self, trigger: Trigger) -> Option<&PathBuf> {
+        match trigger {
+            Trigger::Pm => self.pm.as_ref(),
+            Trigger::Mention => self.mention.as_ref(),
+            Trigger::Keyword => self.keyword.as_ref(),
+            Trigger::Join => self.join.as_ref(),
+            Trigger::Members => self.members.as_ref(),
+            Trigger::Staff => self.staff.as_ref(),
+            Trigger::Moderation => self.moderation.as_ref(),
+            Trigger::Dkf => self.dkf.as_ref(),
+            Trigger::Mail => self.mail.as_ref(),
+        }
+    }
+}
+
+fn default_alerts() -> Vec<Alert> {
+    vec![Alert::Sound]
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotifyConfig {
+    /// A sound on PMs, mentions, DKF and mails when there are none
+    pub rules: Vec<Rule>,
+    /// "23:00-07:00", nothing is notified in between
+    pub quiet_hours: Option<String>,
+    pub muted_users: Vec<String>,
+    pub sounds: SoundConfig,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Message {
+        from: String,
+        text: String,
+        channel: Channel,
+        /// A PM sent to us
+        to_me: bool,
+        mention: bool,
+    },
+    Join(String),
+    Moderation(String),
+    Dkf,
+    /// How many
+    Mail(usize),
+}
+
+impl Event {
+    fn from(&self) -> Option<&str> {
+        match self {
+            Event::Message { from, .. } | Event::Join(from) => Some(from),
+            Event::Moderation(_) | Event::Dkf | Event::Mail(_) => None,
+        }
+    }
+
+    fn summary(&self) -> String {
+        match self {
+            Event::Message {
+                from, to_me: true, ..
+            } => format!("PM from {}", from),
+            Event::Message {
+                from,
+                mention: true,
+                ..
+            } => format!("{} mentioned you", from),
+            Event::Message {
+                from,
+                channel: Channel::Members,
+                ..
+            } => format!("{} in the members chat", from),
+            Event::Message {
+                from,
+                channel: Channel::Staff,
+                ..
+            } => format!("{} in the staff chat", from),
+            Event::Message { from, .. } => from.clone(),
+            Event::Join(user) => format!("{} joined", user),
+            Event::Moderation(_) => "Moderation".to_owned(),
+            Event::Dkf => "New PM or tag on DKF".to_owned(),
+            Event::Mail(n) => format!("{} new mail(s)", n),
+        }
+    }
+
+    fn body(&self) -> &str {
+        match self {
+            Event::Message { text, .. } | Event::Moderation(text) => text,
+            Event::Join(_) | Event::Dkf | Event::Mail(_) => "",
+        }
+    }
+}
+
+impl Rule {
+    fn matches(&self, event: &Event) -> bool {
+        if !self.users.is_empty() {
+            match event.from() {
+                Some(from) if self.users.iter().any(|u| u == from) => {}
+                _ => return false,
+            }
+        }
+        match (self.on, event) {
+            (Trigger::Pm, Event::Message { to_me, .. }) => *to_me,
+            (Trigger::Mention, Event::Message { mention, .. }) => *mention,
+            (Trigger::Keyword, Event::Message { text, .. }) => {
+                let text = text.to_lowercase();
+                self.keywords
+                    .iter()
+                    .any(|k| text.contains(&k.to_lowercase()))
+            }
+            (Trigger::Members, Event::Message { channel, .. }) => *channel == Channel::Members,
+            (Trigger::Staff, Event::Message { channel, .. }) => *channel == Channel::Staff,
+            (Trigger::Join, Event::Join(_)) => true,
+            (Trigger::Moderation, Event::Moderation(_)) => true,
+            (Trigger::Dkf, Event::Dkf) => true,
+            (Trigger::Mail, Event::Mail(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+/// "HH:MM-HH:MM", the end can be on the next day.
+fn parse_quiet_hours(s: &str) -> Result<(NaiveTime, NaiveTime), String> {
+    let err = || format!("invalid quiet hours \"{}\", expected HH:MM-HH:MM", s);
+    let (start, end) = s.split_once('-').ok_or_else(err)?;
+    let parse = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|_| err());
+    Ok((parse(start)?, parse(end)?))
+}
+
+fn is_quiet((start, end): (NaiveTime, NaiveTime), now: NaiveTime) -> bool {
+    if start <= end {
+        start <= now && now < end
+    } else {
+        now >= start || now < end
+    }
+}
+
+/// Plays sounds on one audio device, opened once.
+#[derive(Debug, Clone)]
+struct Player {
+    tx: Sender<Option<PathBuf>>,
+}
+
+impl Player {
+    fn start(volume: f32, dir: Option<PathBuf>) -> Self {
+        let volume = volume.clamp(0.0, 1.0);
+        let (tx, rx) = crossbeam_channel::unbounded::<Option<PathBuf>>();
+        thread::spawn(move || {
+            // The stream can't leave its thread
+            let (_stream, handle) = match OutputStream::try_default() {
+                Ok(s) => s,
+                Err(e) => {
+                    log::error!("no audio device: {}", e);
+                    for _ in rx {}
+                    return;
+                }
+            };
+            for file in rx {
+                let file = file.map(|f| match &dir {
+                    Some(dir) => dir.join(f),
+                    None => f,
+                });
+                let data = match &file {
+                    None => DEFAULT_SOUND.to_vec(),
+                    Some(path) => match fs::read(path) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            log::error!("failed to read {}: {}", path.display(), e);
+                            continue;
+                        }
+                    },
+                };
+                let played = Decoder::new(Cursor::new(data))
+                    .map_err(|e| e.to_string())
+                    .and_then(|source| {
+                        handle
+                            .play_raw(source.amplify(volume).convert_samples())
+                            .map_err(|e| e.to_string())
+                    });
+                if let Err(e) = played {
+                    log::error!("failed to play sound: {}", e);
+                }
+            }
+        });
+        Self { tx }
+    }
+
+    /// The default sound when there is no file.
+    fn play(&self, file: Option<PathBuf>) {
+        let _ = self.tx.send(file);
+    }
+}
+
+/// What the draw loop has to write to the terminal, nothing else writes to
+/// it while the UI is up.
+#[derive(Debug, Default, PartialEq)]
+pub struct TerminalAlerts {
+    pub bell: bool,
+    /// The new window title, if it changed
+    pub title: Option<String>,
+}
+
+#[derive(Debug)]
+struct TerminalState {
+    bell: bool,
+    // Summary of the event & when the title started to blink
+    flash: Option<(String, Instant)>,
+    title: String,
+}
+
+impl TerminalState {
+    fn take(&mut self, now: Instant) -> TerminalAlerts {
+        let step = self.flash.as_ref().map(|(_, start)| {
+            let elapsed = now.saturating_duration_since(*start);
+            (elapsed.as_millis() / FLASH_STEP.as_millis()) as u32
+        });
+        let title = match (&self.flash, step) {
+            (Some((summary, _)), Some(step)) if step < FLASH_STEPS && step % 2 == 0 => {
+                format!("* {} *", summary)
+            }
+            _ => APP_NAME.to_owned(),
+        };
+        if step.is_some_and(|step| step >= FLASH_STEPS) {
+            self.flash = None;
+        }
+        let title = if title != self.title {
+            self.title = title.clone();
+            Some(title)
+        } else {
+            None
+        };
+        TerminalAlerts {
+            bell: std::mem::take(&mut self.bell),
+            title,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Notifier {
+    rules: Vec<Rule>,
+    quiet_hours: Option<(NaiveTime, NaiveTime)>,
+    muted_users: Mutex<HashSet<String>>,
+    sounds: SoundConfig,
+    player: Player,
+    terminal: Mutex<TerminalState>,
+    path: Option<PathBuf>,
+}
+
+impl Notifier {
+    /// Sound files are relative to "dir". Users muted with commands, saved in
+    /// "path", win over the config.
+    pub fn new(
+        cfg: &NotifyConfig,
+        dir: Option<PathBuf>,
+        path: Option<PathBuf>,
+    ) -> Result<Self, String> {
+        let quiet_hours = match &cfg.quiet_hours {
+            Some(s) => Some(parse_quiet_hours(s)?),
+            None => None,
+        };
+        let rules = if cfg.rules.is_empty() {
+            [Trigger::Pm, Trigger::Mention, Trigger::Dkf, Trigger::Mail]
+                .iter()
+                .map(|on| Rule {
+                    on: *on,
+                    keywords: vec![],
+                    users: vec![],
+                    alerts: default_alerts(),
+                    sound: None,
+                })
+                .collect()
+        } else {
+            cfg.rules.clone()
+        };
+        let saved: Option<HashSet<String>> = path
+            .as_ref()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .and_then(|s| serde_json::from_str(&s).ok());
+        let muted_users = saved.unwrap_or_else(|| cfg.muted_users.iter().cloned().collect());
+        Ok(Self {
+            rules,
+            quiet_hours,
+            muted_users: Mutex::new(muted_users),
+            sounds: cfg.sounds.clone(),
+            player: Player::start(cfg.sounds.volume, dir),
+            terminal: Mutex::new(TerminalState {
+                bell: false,
+                flash: None,
+                title: APP_NAME.to_owned(),
+            }),
+            path,
+        })
+    }
+
+    /// Returns whether the user is now muted.
+    pub fn toggle_mute(&self, user: &str) -> bool {
+        let mut muted = self.muted_users.lock().unwrap();
+        let now_muted = muted.insert(user.to_owned()) || !muted.remove(user);
+        match serde_json::to_string(&*muted) {
+            Ok(s) => {
+                if let Some(path) = &self.path {
+                    if let Err(e) = fs::write(path, s) {
+                        log::error!("failed to save muted users: {}", e);
+                    }
+                }
+            }
+            Err(e) => log::error!("failed to serialize muted users: {}", e),
+        }
+        now_muted
+    }
+
+    pub fn muted_users(&self) -> Vec<String> {
+        let mut users: Vec<String> = self.muted_users.lock().unwrap().iter().cloned().collect();
+        users.sort();
+        users
+    }
+
+    /// The alerts of the rules matching the event, each one once.
+    fn alerts(&self, event: &Event, now: NaiveTime) -> Vec<(Alert, Option<PathBuf>)> {
+        if self.quiet_hours.is_some_and(|q| is_quiet(q, now)) {
+            return vec![];
+        }
+        if let Some(from) = event.from() {
+            if self.muted_users.lock().unwrap().contains(from) {
+                return vec![];
+            }
+        }
+        let mut alerts: Vec<(Alert, Option<PathBuf>)> = vec![];
+        for rule in self.rules.iter().filter(|r| r.matches(event)) {
+            for alert in &rule.alerts {
+                if alerts.iter().all(|(a, _)| a != alert) {
+                    let sound = rule.sound.as_ref().or_else(|| self.sounds.file(rule.on));
+                    alerts.push((*alert, sound.cloned()));
+                }
+            }
+        }
+        alerts
+    }
+
+    /// Play the sound of an event, whatever the rules, mutes and quiet hours.
+    pub fn test_sound(&self, name: &str) -> Result<(), String> {
+        let trigger = EVENTS
+            .iter()
+            .position(|e| *e == name)
+            .map(|idx| TRIGGERS[idx])
+            .ok_or_else(|| format!("unknown event \"{}\", events: {}", name, EVENTS.join(", ")))?;
+        let rule_sound = self
+            .rules
+            .iter()
+            .find(|r| r.on == trigger && r.sound.is_some())
+            .and_then(|r| r.sound.as_ref());
+        let sound = rule_sound.or_else(|| self.sounds.file(trigger));
+        self.player.play(sound.cloned());
+        Ok(())
+    }
+
+    pub fn notify(&self, event: &Event) {
+        for (alert, sound) in self.alerts(event, Local::now().time()) {
+            match alert {
+                Alert::Sound => self.player.play(sound),
+                Alert::Bell => self.terminal.lock().unwrap().bell = true,
+                Alert::Desktop => {
+                    if let Err(e) = desktop(&event.summary(), event.body()) {
+                        log::error!("failed to send desktop notification: {}", e);
+                    }
+                }
+                Alert::Flash => {
+                    let flash = (event.summary(), Instant::now());
+                    self.terminal.lock().unwrap().flash = Some(flash);
+                }
+            }
+        }
+    }
+
+    /// The bell to ring & the title to set, called by the draw loop.
+    pub fn terminal_alerts(&self) -> TerminalAlerts {
+        self.terminal.lock().unwrap().take(Instant::now())
+    }
+}
+
+// A notification through org.freedesktop.Notifications on the session bus.
+fn desktop(summary: &str, body: &str) -> Result<(), notify_rust::error::Error> {
+    notify_rust::Notification::new()
+        .appname(APP_NAME)
+        .summary(summary)
+        .body(body)
+        .show()
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(from: &str, text: &str, channel: Channel) -> Event {
+        Event::Message {
+            from: from.to_owned(),
+            text: text.to_owned(),
+            channel,
+            to_me: channel == Channel::Pm,
+            mention: text.contains("@me"),
+        }
+    }
+
+    #[test]
+    fn events_test() {
+        assert_eq!(EVENTS.len(), TRIGGERS.len());
+        for (name, trigger) in EVENTS.iter().zip(TRIGGERS) {
+            let parsed: Trigger = toml::Value::String(name.to_string()).try_into().unwrap();
+            assert_eq!(parsed, *trigger);
+        }
+    }
+
+    fn at(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn rules_test() {
+        let cfg: NotifyConfig = toml::from_str(
+            r#"
+            quiet_hours = "23:00-07:00"
+            muted_users = ["spammer"]
+            [[rules]]
+            on = "keyword"
+            keywords = ["Rust"]
+            alerts = ["bell", "desktop"]
+            [[rules]]
+            on = "join"
+            users = ["alice"]
+            alerts = ["flash"]
+            [[rules]]
+            on = "pm"
+            sound = "pm.ogg"
+            [[rules]]
+            on = "moderation"
+            alerts = ["bell"]
+            [[rules]]
+            on = "mail"
+            [sounds]
+            volume = 0.5
+            mail = "sounds/mail.wav"
+            pm = "unused.wav"
+            "#,
+        )
+        .unwrap();
+        let notifier = Notifier::new(&cfg, None, None).unwrap();
+        let alerts = |e: &Event| -> Vec<Alert> {
+            notifier
+                .alerts(e, at(12, 0))
+                .into_iter()
+                .map(|(a, _)| a)
+                .collect()
+        };
+        let e = message("bob", "I like rust", Channel::Public);
+        assert_eq!(alerts(&e), vec![Alert::Bell, Alert::Desktop]);
+        assert!(notifier.alerts(&e, at(23, 30)).is_empty());
+        assert!(notifier.alerts(&e, at(6, 59)).is_empty());
+        assert_eq!(alerts(&message("spammer", "rust", Channel::Public)), vec![]);
+        assert_eq!(alerts(&Event::Join("alice".to_owned())), vec![Alert::Flash]);
+        assert_eq!(alerts(&Event::Join("bob".to_owned())), vec![]);
+        assert_eq!(
+            alerts(&Event::Moderation("bob kicked".to_owned())),
+            vec![Alert::Bell]
+        );
+        let pm = notifier.alerts(&message("bob", "hi", Channel::Pm), at(12, 0));
+        assert_eq!(pm, vec![(Alert::Sound, Some(PathBuf::from("pm.ogg")))]);
+        let mail = notifier.alerts(&Event::Mail(2), at(12, 0));
+        assert_eq!(
+            mail,
+            vec![(Alert::Sound, Some(PathBuf::from("sounds/mail.wav")))]
+        );
+        assert_eq!(alerts(&Event::Dkf), vec![]);
+        assert!(notifier.test_sound("dkf").is_ok());
+        assert!(notifier.test_sound("boom").is_err());
+
+        assert!(notifier.toggle_mute("bob"));
+        assert_eq!(alerts(&e), vec![]);
+        assert!(!notifier.toggle_mute("bob"));
+        assert_eq!(notifier.muted_users(), vec!["spammer"]);
+    }
+
+    #[test]
+    fn terminal_alerts_test() {
+        let notifier = Notifier::new(&NotifyConfig::default(), None, None).unwrap();
+        assert_eq!(notifier.terminal_alerts(), TerminalAlerts::default());
+        let start = Instant::now();
+        let mut state = notifier.terminal.lock().unwrap();
+        state.bell = true;
+        state.flash = Some(("PM from bob".to_owned(), start));
+        let alerts = state.take(start);
+        assert!(alerts.bell);
+        assert_eq!(alerts.title.as_deref(), Some("* PM from bob *"));
+        // Only set again when it changes
+        assert_eq!(
+            state.take(start + FLASH_STEP / 2),
+            TerminalAlerts::default()
+        );
+        let title =
+            |state: &mut TerminalState, step: u32| state.take(start + FLASH_STEP * step).title;
+        assert_eq!(title(&mut state, 1).as_deref(), Some(APP_NAME));
+        assert_eq!(title(&mut state, 2).as_deref(), Some("* PM from bob *"));
+        assert_eq!(title(&mut state, FLASH_STEPS).as_deref(), Some(APP_NAME));
+        assert!(state.flash.is_none());
+        assert_eq!(title(&mut state, FLASH_STEPS + 2), None);
+    }
+
+    // Stands for the notification daemon of the desktop.
+    struct NotificationServer(Sender<(String, String, String)>);
+
+    #[zbus::interface(name = "org.freedesktop.Notifications")]
+    impl NotificationServer {
+        #[allow(clippy::too_many_arguments)]
+        fn notify(
+            &self,
+            app_name: String,
+            _replaces_id: u32,
+            _app_icon: String,
+            summary: String,
+            body: String,
+            _actions: Vec<String>,
+            _hints: std::collections::HashMap<String, zbus::zvariant::OwnedValue>,
+            _expire_timeout: i32,
+        ) -> u32 {
+            let _ = self.0.send((app_name, summary, body));
+            1
+        }
+    }
+
+    #[test]
+    fn desktop_test() {
+        use std::io::{BufRead, BufReader};
+        use std::process::{Command, Stdio};
+
+        // A session bus of our own, not the one of the desktop
+        let daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut daemon = match daemon {
+            Ok(daemon) => daemon,
+            Err(e) => {
+                eprintln!("skipped, no dbus-daemon: {}", e);
+                return;
+            }
+        };
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        std::env::set_var("DBUS_SESSION_BUS_ADDRESS", address.trim());
+
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let server = zbus::blocking::connection::Builder::session()
+            .and_then(|b| b.name("org.freedesktop.Notifications"))
+            .and_then(|b| b.serve_at("/org/freedesktop/Notifications", NotificationServer(tx)))
+            .and_then(|b| b.build());
+        let sent = server.map(|_server| desktop("PM from bob", "hi"));
+        let _ = daemon.kill();
+        let _ = daemon.wait();
+        sent.unwrap().unwrap();
+        let received = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let expected = (
+            "bhcli".to_owned(),
+            "PM from bob".to_owned(),
+            "hi".to_owned(),
+        );
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn default_rules_test() {
+        let notifier = Notifier::new(&NotifyConfig::default(), None, None).unwrap();
+        let alerts = |e: &Event| notifier.alerts(e, at(3, 0)).len();
+        assert_eq!(alerts(&message("bob", "hi @me", Channel::Public)), 1);
+        assert_eq!(alerts(&message("bob", "hi", Channel::Pm)), 1);
+        assert_eq!(alerts(&message("bob", "hi", Channel::Members)), 0);
+        assert_eq!(alerts(&Event::Dkf), 1);
+        assert_eq!(alerts(&Event::Mail(1)), 1);
+
+        let cfg = NotifyConfig {
+            quiet_hours: Some("7h".to_owned()),
+            ..NotifyConfig::default()
+        };
+        assert!(Notifier::new(&cfg, None, None).is_err());
+        assert!(is_quiet(
+            parse_quiet_hours("09:00-17:00").unwrap(),
+            at(12, 0)
+        ));
+        assert!(!is_quiet(
+            parse_quiet_hours("09:00-17:00").unwrap(),
+            at(17, 0)
+        ));
+    }
+}
@@ -0,0 +1,460 @@
+use crate::crypto::SealedFile;
+use crate::upload;
+use crate::PostType;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// How many delivered items we remember, so we can mark our own messages as sent.
+const SENT_HISTORY: usize = 50;
+// Delay before retrying an item that failed to be delivered.
+const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Delivery state of an outbound item.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ItemState {
+    Queued,
+    Sending,
+    Sent,
+    Failed(String),
+}
+
+impl ItemState {
+    pub fn marker(&self) -> &'static str {
+        match self {
+            ItemState::Queued => "…",
+            ItemState::Sending => "↑",
+            ItemState::Sent => "✓",
+            ItemState::Failed(_) => "✗",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxItem {
+    pub id: u64,
+    pub post: PostType,
+    pub state: ItemState,
+    pub attempts: u32,
+    #[serde(skip)]
+    not_before: Option<Instant>,
+}
+
+impl OutboxItem {
+    /// Short human readable description of the item, used by the TUI.
+    pub fn summary(&self) -> String {
+        match &self.post {
+            PostType::Post(msg, Some(to)) => format!("{} (to {})", msg, to),
+            PostType::Post(msg, None) => msg.clone(),
+            PostType::Kick(_, username) => format!("kick {}", username),
+            PostType::Upload(path, _, msg) => format!("upload {} {}", path, msg),
+            PostType::DeleteLast => "delete last message".to_owned(),
+            PostType::DeleteAll => "delete all messages".to_owned(),
+            PostType::NewNickname(nick) => format!("nickname {}", nick),
+            PostType::NewColor(color) => format!("color {}", color),
+            PostType::Profile(color, nick) => format!("profile {} {}", color, nick),
+            PostType::Ignore(username) => format!("ignore {}", username),
+            PostType::Unignore(username) => format!("unignore {}", username),
+            PostType::Clean(date, _) => format!("clean message {}", date),
+        }
+    }
+}
+
+/// Minimum delay between two requests of the same kind, so bulk kicks and
+/// deletes don't trip the server flood protection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub post_interval_ms: u64,
+    pub kick_interval_ms: u64,
+    pub delete_interval_ms: u64,
+    pub max_attempts: u32,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            post_interval_ms: 500,
+            kick_interval_ms: 1500,
+            delete_interval_ms: 1000,
+            max_attempts: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Post,
+    Kick,
+    Delete,
+}
+
+// The file of an upload is not needed anymore once the item is done with,
+// delete it if we made it (clipboard image, stripped copy).
+fn release(post: &PostType) {
+    if let PostType::Upload(path, _, _) = post {
+        upload::remove_temp_file(Path::new(path));
+    }
+}
+
+fn kind_of(post: &PostType) -> Kind {
+    match post {
+        PostType::Kick(_, _) => Kind::Kick,
+        PostType::DeleteLast | PostType::DeleteAll | PostType::Clean(_, _) => Kind::Delete,
+        _ => Kind::Post,
+    }
+}
+
+/// What the post thread should do next.
+pub enum Next {
+    Send(u64, PostType),
+    Wait(Duration),
+    Idle,
+}
+
+/// Ordered queue of everything we want to send to the chat server.
+/// Pending items are persisted on disk, encrypted, so they survive re-login
+/// and restarts.
+pub struct Outbox {
+    items: VecDeque<OutboxItem>,
+    sent: VecDeque<OutboxItem>,
+    next_id: u64,
+    limits: RateLimits,
+    last_sent: Vec<(Kind, Instant)>,
+    file: Option<SealedFile>,
+}
+
+impl Outbox {
+    pub fn new(limits: RateLimits, file: Option<SealedFile>) -> Self {
+        let data = match file.as_ref().map(|f| f.read()) {
+            Some(Ok(data)) => data,
+            Some(Err(e)) => {
+                log::error!("failed to load outbox: {}", e);
+                None
+            }
+            None => None,
+        };
+        let mut items: VecDeque<OutboxItem> = data
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        for item in items.iter_mut() {
+            if item.state == ItemState::Sending {
+                item.state = ItemState::Queued;
+            }
+        }
+        let next_id = items.iter().map(|i| i.id + 1).max().unwrap_or(0);
+        Self {
+            items,
+            sent: VecDeque::new(),
+            next_id,
+            limits,
+            last_sent: Vec::new(),
+            file,
+        }
+    }
+
+    pub fn push(&mut self, post: PostType) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.items.push_back(OutboxItem {
+            id,
+            post,
+            state: ItemState::Queued,
+            attempts: 0,
+            not_before: None,
+        });
+        self.save();
+        id
+    }
+
+    /// Move the items of the plaintext outbox of older versions into this one.
+    /// The old file is deleted once they are saved, it is left alone when
+    /// there is nowhere to save them. Returns how many were moved.
+    pub fn migrate_legacy(&mut self, path: &Path) -> anyhow::Result<usize> {
+        let Some(file) = &self.file else {
+            return Ok(0);
+        };
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let legacy: Vec<OutboxItem> = serde_json::from_slice(&data)?;
+        let count = legacy.len();
+        for mut item in legacy {
+            item.id = self.next_id;
+            self.next_id += 1;
+            if item.state == ItemState::Sending {
+                item.state = ItemState::Queued;
+            }
+            self.items.push_back(item);
+        }
+        file.write(&serde_json::to_vec(&self.items)?)?;
+        fs::remove_file(path)?;
+        Ok(count)
+    }
+
+    /// Either or not pending items are kept on disk.
+    pub fn is_persisted(&self) -> bool {
+        self.file.is_some()
+    }
+
+    fn interval(&self, kind: Kind) -> Duration {
+        let ms = match kind {
+            Kind::Post => self.limits.post_interval_ms,
+            Kind::Kick => self.limits.kick_interval_ms,
+            Kind::Delete => self.limits.delete_interval_ms,
+        };
+        Duration::from_millis(ms)
+    }
+
+    /// Pick the oldest queued item, respecting ordering and rate limits.
+    /// The returned item is marked as `Sending`.
+    pub fn next(&mut self, now: Instant) -> Next {
+        let Some(pos) = self.items.iter().position(|i| i.state == ItemState::Queued) else {
+            return Next::Idle;
+        };
+        let kind = kind_of(&self.items[pos].post);
+        let mut ready_at = self.items[pos].not_before.unwrap_or(now);
+        if let Some((_, last)) = self.last_sent.iter().find(|(k, _)| *k == kind) {
+            ready_at = ready_at.max(*last + self.interval(kind));
+        }
+        if ready_at > now {
+            return Next::Wait(ready_at - now);
+        }
+        let item = &mut self.items[pos];
+        item.state = ItemState::Sending;
+        Next::Send(item.id, item.post.clone())
+    }
+
+    pub fn mark_sent(&mut self, id: u64) {
+        if let Some(pos) = self.items.iter().position(|i| i.id == id) {
+            let mut item = self.items.remove(pos).unwrap();
+            release(&item.post);
+            let kind = kind_of(&item.post);
+            self.last_sent.retain(|(k, _)| *k != kind);
+            self.last_sent.push((kind, Instant::now()));
+            item.state = ItemState::Sent;
+            self.sent.push_front(item);
+            self.sent.truncate(SENT_HISTORY);
+            self.save();
+        }
+    }
+
+    pub fn mark_failed(&mut self, id: u64, err: String) {
+        let max_attempts = self.limits.max_attempts;
+        if let Some(item) = self.items.iter_mut().find(|i| i.id == id) {
+            item.attempts += 1;
+            if item.attempts >= max_attempts {
+                item.state = ItemState::Failed(err);
+                release(&item.post);
+            } else {
+                item.state = ItemState::Queued;
+                item.not_before = Some(Instant::now() + RETRY_BACKOFF);
+            }
+            self.save();
+        }
+    }
+
+    /// Called when a new session starts: anything that was in flight or that
+    /// failed with the previous session gets another chance.
+    pub fn reset_for_new_session(&mut self) {
+        for item in self.items.iter_mut() {
+            if item.state != ItemState::Queued {
+                item.state = ItemState::Queued;
+                item.attempts = 0;
+                item.not_before = None;
+            }
+        }
+        self.save();
+    }
+
+    /// Put items that were being sent back in the queue (session died mid-flight).
+    pub fn requeue_sending(&mut self) {
+        for item in self.items.iter_mut() {
+            if item.state == ItemState::Sending {
+                item.state = ItemState::Queued;
+            }
+        }
+        self.save();
+    }
+
+    /// Queue all failed items again. Returns how many were re-queued.
+    pub fn retry_failed(&mut self) -> usize {
+        let mut count = 0;
+        for item in self.items.iter_mut() {
+            if let ItemState::Failed(_) = item.state {
+                item.state = ItemState::Queued;
+                item.attempts = 0;
+                item.not_before = None;
+                count += 1;
+            }
+        }
+        self.save();
+        count
+    }
+
+    pub fn last_failed(&self) -> Option<&OutboxItem> {
+        self.items
+            .iter()
+            .rev()
+            .find(|i| matches!(i.state, ItemState::Failed(_)))
+    }
+
+    /// Remove the most recent failed item and return it, so it can be edited.
+    pub fn take_last_failed(&mut self) -> Option<PostType> {
+        let pos = self
+            .items
+            .iter()
+            .rposition(|i| matches!(i.state, ItemState::Failed(_)))?;
+        let item = self.items.remove(pos)?;
+        self.save();
+        Some(item.post)
+    }
+
+    /// Remove all failed items. Returns how many were dropped.
+    pub fn drop_failed(&mut self) -> usize {
+        let before = self.items.len();
+        self.items.retain(|i| {
+            let failed = matches!(i.state, ItemState::Failed(_));
+            if failed {
+                release(&i.post);
+            }
+            !failed
+        });
+        self.save();
+        before - self.items.len()
+    }
+
+    /// Items that are not delivered yet, oldest first.
+    pub fn pending(&self) -> Vec<OutboxItem> {
+        self.items.iter().cloned().collect()
+    }
+
+    /// Either or not we recently delivered this public/private message.
+    pub fn was_sent(&self, msg: &str) -> bool {
+        self.sent.iter().any(|i| match &i.post {
+            PostType::Post(m, _) => m == msg,
+            _ => false,
+        })
+    }
+
+    fn save(&self) {
+        if let Some(file) = &self.file {
+            match serde_json::to_vec(&self.items) {
+                Ok(data) => {
+                    if let Err(e) = file.write(&data) {
+                        log::error!("failed to save outbox: {}", e);
+                    }
+                }
+                Err(e) => log::error!("failed to serialize outbox: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit_and_retry_test() {
+        let limits = RateLimits {
+            kick_interval_ms: 60_000,
+            max_attempts: 2,
+            ..RateLimits::default()
+        };
+        let mut outbox = Outbox::new(limits, None);
+        let k1 = outbox.push(PostType::Kick(String::new(), "a".to_owned()));
+        outbox.push(PostType::Kick(String::new(), "b".to_owned()));
+        let now = Instant::now();
+        match outbox.next(now) {
+            Next::Send(id, _) => assert_eq!(id, k1),
+            _ => panic!("expected first kick to be ready"),
+        }
+        outbox.mark_sent(k1);
+        assert!(matches!(outbox.next(Instant::now()), Next::Wait(_)));
+
+        let p = outbox.push(PostType::Post("hi".to_owned(), None));
+        outbox.mark_failed(p, "boom".to_owned());
+        outbox.mark_failed(p, "boom".to_owned());
+        assert_eq!(
+            outbox
+                .take_last_failed()
+                .map(|p| matches!(p, PostType::Post(_, _))),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn persist_test() {
+        let dir = std::env::temp_dir().join(format!("bhcli-outbox-{}", std::process::id()));
+        let file = crate::crypto::StorageKey::generate().file(dir.join("outbox.bin"));
+        let mut outbox = Outbox::new(RateLimits::default(), Some(file.clone()));
+        outbox.push(PostType::Post(
+            "secret pm".to_owned(),
+            Some("bob".to_owned()),
+        ));
+        let data = std::fs::read(file.path()).unwrap();
+        assert!(!data.windows(6).any(|w| w == b"secret"));
+
+        let mut outbox = Outbox::new(RateLimits::default(), Some(file.clone()));
+        assert_eq!(outbox.pending().len(), 1);
+        assert_eq!(outbox.push(PostType::DeleteLast), 1);
+
+        // Older versions kept it in plaintext
+        let legacy = dir.join("outbox.json");
+        let mut old = Outbox::new(RateLimits::default(), None);
+        old.push(PostType::Post("queued".to_owned(), None));
+        assert_eq!(old.migrate_legacy(&legacy).unwrap(), 0);
+        std::fs::write(&legacy, serde_json::to_vec(&old.items).unwrap()).unwrap();
+        assert_eq!(old.migrate_legacy(&legacy).unwrap(), 0);
+        assert!(legacy.exists());
+        assert_eq!(outbox.migrate_legacy(&legacy).unwrap(), 1);
+        assert!(!legacy.exists());
+        let outbox = Outbox::new(RateLimits::default(), Some(file));
+        let ids: Vec<u64> = outbox.pending().iter().map(|i| i.id).collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn upload_file_test() {
+        let limits = RateLimits {
+            max_attempts: 2,
+            ..RateLimits::default()
+        };
+        let mut outbox = Outbox::new(limits, None);
+        let upload = |outbox: &mut Outbox| {
+            let path = upload::temp_file("png");
+            std::fs::write(&path, b"png").unwrap();
+            let post = PostType::Upload(
+                path.to_str().unwrap().to_owned(),
+                String::new(),
+                String::new(),
+            );
+            (outbox.push(post), path)
+        };
+        // Kept for the next attempts
+        let (id, path) = upload(&mut outbox);
+        outbox.mark_failed(id, "timeout".to_owned());
+        assert!(path.exists());
+        outbox.mark_sent(id);
+        assert!(!path.exists());
+
+        let (id, path) = upload(&mut outbox);
+        outbox.mark_failed(id, "timeout".to_owned());
+        outbox.mark_failed(id, "timeout".to_owned());
+        assert!(!path.exists());
+
+        let (id, path) = upload(&mut outbox);
+        outbox.items.iter_mut().find(|i| i.id == id).unwrap().state =
+            ItemState::Failed("timeout".to_owned());
+        assert!(path.exists());
+        assert_eq!(outbox.drop_failed(), 2);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,322 @@
+use anyhow::anyhow;
+use image::imageops::FilterType;
+use image::io::{Limits, Reader};
+use image::{DynamicImage, GenericImageView, RgbaImage};
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Cursor, Read};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tui::style::{Color as tuiColor, Style};
+use tui::text::{Span, Spans};
+
+// Images with bigger dimensions are refused, protects against decompression bombs.
+const MAX_DIMENSION: u32 = 8192;
+// Thumbnails never get wider than this many columns.
+const MAX_THUMB_WIDTH: u32 = 80;
+// Images are kept downscaled to this, enough for the viewer on a big terminal.
+const MAX_VIEW_SIZE: u32 = 512;
+// How many previews are kept in memory.
+const CACHE_SIZE: usize = 64;
+
+/// Per profile settings of the inline image previews.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PreviewConfig {
+    pub enabled: bool,
+    /// Attachments bigger than this are not fetched.
+    pub max_bytes: u64,
+    /// Height of the thumbnails, in terminal rows.
+    pub thumb_rows: u16,
+}
+
+impl Default for PreviewConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_bytes: 2 * 1024 * 1024,
+            thumb_rows: 6,
+        }
+    }
+}
+
+pub enum PreviewState {
+    Loading,
+    Ready(Preview),
+    Failed(String),
+}
+
+pub struct Preview {
+    // Downscaled, the full image is never kept
+    image: RgbaImage,
+    thumb: RgbaImage,
+    // Last thumbnail rendered, with the width it was rendered for.
+    thumb_lines: Option<(u16, Vec<Spans<'static>>)>,
+    // Last image rendered by the viewer, with the size it was rendered for.
+    fitted: Option<((u16, u16), Vec<Spans<'static>>)>,
+}
+
+// The least recently used previews are dropped first.
+struct Lru {
+    entries: HashMap<String, PreviewState>,
+    order: VecDeque<String>,
+}
+
+impl Lru {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn touch(&mut self, url: &str) {
+        if let Some(pos) = self.order.iter().position(|u| u == url) {
+            let url = self.order.remove(pos).unwrap();
+            self.order.push_back(url);
+        }
+    }
+
+    fn contains(&self, url: &str) -> bool {
+        self.entries.contains_key(url)
+    }
+
+    fn get_mut(&mut self, url: &str) -> Option<&mut PreviewState> {
+        self.touch(url);
+        self.entries.get_mut(url)
+    }
+
+    fn insert(&mut self, url: String, state: PreviewState) {
+        if self.entries.insert(url.clone(), state).is_some() {
+            self.touch(&url);
+            return;
+        }
+        self.order.push_back(url);
+        while self.order.len() > CACHE_SIZE {
+            if let Some(old) = self.order.pop_front() {
+                self.entries.remove(&old);
+            }
+        }
+    }
+}
+
+/// Fetch image attachments in the background and keep them, downscaled, in
+/// memory. Attachments are fetched one at a time, in the order they are
+/// requested.
+pub struct PreviewCache {
+    previews: Arc<Mutex<Lru>>,
+    tx: Option<crossbeam_channel::Sender<String>>,
+}
+
+impl PreviewCache {
+    pub fn new(client: Client, cfg: PreviewConfig) -> Self {
+        let previews = Arc::new(Mutex::new(Lru::new()));
+        let tx = if cfg.enabled {
+            let (tx, rx) = crossbeam_channel::unbounded::<String>();
+            let previews = Arc::clone(&previews);
+            thread::spawn(move || {
+                for url in rx.iter() {
+                    let state = match fetch(&client, &url, &cfg) {
+                        Ok(preview) => PreviewState::Ready(preview),
+                        Err(e) => PreviewState::Failed(e.to_string()),
+                    };
+                    let mut previews = previews.lock().unwrap();
+                    // Dropped from the cache while loading, nobody looks at it anymore
+                    if previews.contains(&url) {
+                        previews.insert(url, state);
+                    }
+                }
+            });
+            Some(tx)
+        } else {
+            None
+        };
+        Self { previews, tx }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    /// Queue the attachment for fetching, unless we already did.
+    pub fn request(&self, url: &str) {
+        if let Some(tx) = &self.tx {
+            let mut previews = self.previews.lock().unwrap();
+            if !previews.contains(url) {
+                previews.insert(url.to_owned(), PreviewState::Loading);
+                let _ = tx.send(url.to_owned());
+            }
+        }
+    }
+
+    /// Thumbnail lines to display under a message, if the attachment is an image.
+    /// Nothing is fetched, see "request".
+    pub fn thumbnail(&self, url: &str, max_width: u16) -> Option<Vec<Spans<'static>>> {
+        let mut previews = self.previews.lock().unwrap();
+        match previews.get_mut(url)? {
+            PreviewState::Ready(p) => {
+                if p.thumb_lines.as_ref().map(|(w, _)| *w) != Some(max_width) {
+                    let lines = if p.thumb.width() > max_width as u32 {
+                        let (w, h) = fit(p.thumb.dimensions(), max_width as u32, p.thumb.height());
+                        half_blocks(&image::imageops::resize(
+                            &p.thumb,
+                            w,
+                            h,
+                            FilterType::Triangle,
+                        ))
+                    } else {
+                        half_blocks(&p.thumb)
+                    };
+                    p.thumb_lines = Some((max_width, lines));
+                }
+                p.thumb_lines.as_ref().map(|(_, lines)| lines.clone())
+            }
+            PreviewState::Loading => Some(vec![Spans::from(Span::styled(
+                "[loading preview…]",
+                Style::default().fg(tuiColor::DarkGray),
+            ))]),
+            PreviewState::Failed(_) => None,
+        }
+    }
+
+    /// Lines of the full size viewer, the image fits in "width" x "height" cells.
+    pub fn view(&self, url: &str, width: u16, height: u16) -> Vec<Spans<'static>> {
+        let mut previews = self.previews.lock().unwrap();
+        match previews.get_mut(url) {
+            Some(PreviewState::Ready(p)) => {
+                let size = (width, height);
+                if p.fitted.as_ref().map(|(s, _)| *s) != Some(size) {
+                    let (w, h) = fit(p.image.dimensions(), width as u32, height as u32 * 2);
+                    let img = image::imageops::resize(&p.image, w, h, FilterType::Triangle);
+                    p.fitted = Some((size, half_blocks(&img)));
+                }
+                p.fitted.as_ref().unwrap().1.clone()
+            }
+            Some(PreviewState::Loading) => vec![Spans::from("Loading…")],
+            Some(PreviewState::Failed(err)) => vec![Spans::from(format!("No preview: {}", err))],
+            None if !self.enabled() => vec![Spans::from("Previews are disabled")],
+            None => vec![Spans::from("No preview")],
+        }
+    }
+}
+
+fn fetch(client: &Client, url: &str, cfg: &PreviewConfig) -> anyhow::Result<Preview> {
+    let resp = client.get(url).send()?;
+    if !resp.status().is_success() {
+        return Err(anyhow!("{}", resp.status()));
+    }
+    let content_type = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_owned();
+    if !content_type.starts_with("image/") {
+        return Err(anyhow!("not an image"));
+    }
+    if resp.content_length().unwrap_or(0) > cfg.max_bytes {
+        return Err(anyhow!("too big"));
+    }
+    // Do not trust the content-length, stop reading past the cap.
+    let mut data = Vec::new();
+    resp.take(cfg.max_bytes + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > cfg.max_bytes {
+        return Err(anyhow!("too big"));
+    }
+
+    let mut reader = Reader::new(Cursor::new(data)).with_guessed_format()?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    reader.limits(limits);
+    let image = reader.decode()?;
+
+    let (w, h) = fit(
+        image.dimensions(),
+        MAX_THUMB_WIDTH,
+        cfg.thumb_rows as u32 * 2,
+    );
+    let thumb = image.resize_exact(w, h, FilterType::Triangle).to_rgba8();
+    Ok(Preview {
+        image: downscale(image),
+        thumb,
+        thumb_lines: None,
+        fitted: None,
+    })
+}
+
+fn downscale(image: DynamicImage) -> RgbaImage {
+    let (w, h) = image.dimensions();
+    if w <= MAX_VIEW_SIZE && h <= MAX_VIEW_SIZE {
+        return image.to_rgba8();
+    }
+    let (w, h) = fit((w, h), MAX_VIEW_SIZE, MAX_VIEW_SIZE);
+    image.resize_exact(w, h, FilterType::Triangle).to_rgba8()
+}
+
+// Biggest size that fits in "max_w" x "max_h" and keeps the aspect ratio.
+fn fit((w, h): (u32, u32), max_w: u32, max_h: u32) -> (u32, u32) {
+    if w == 0 || h == 0 {
+        return (1, 1);
+    }
+    let ratio = f64::min(max_w as f64 / w as f64, max_h as f64 / h as f64);
+    let nw = ((w as f64 * ratio) as u32).max(1);
+    let nh = ((h as f64 * ratio) as u32).max(1);
+    (nw, nh)
+}
+
+// Render an image using "▀", each cell displays two pixels (fg top, bg bottom).
+fn half_blocks(img: &RgbaImage) -> Vec<Spans<'static>> {
+    let (w, h) = img.dimensions();
+    let color = |x, y| {
+        if y >= h {
+            return tuiColor::Reset;
+        }
+        let p = img.get_pixel(x, y);
+        tuiColor::Rgb(p[0], p[1], p[2])
+    };
+    (0..h)
+        .step_by(2)
+        .map(|y| {
+            let spans: Vec<Span> = (0..w)
+                .map(|x| Span::styled("▀", Style::default().fg(color(x, y)).bg(color(x, y + 1))))
+                .collect();
+            Spans::from(spans)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fit_test() {
+        assert_eq!(fit((800, 600), 80, 12), (16, 12));
+        assert_eq!(fit((100, 10), 80, 12), (80, 8));
+        assert_eq!(fit((0, 10), 80, 12), (1, 1));
+        let img = RgbaImage::new(3, 3);
+        let lines = half_blocks(&img);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].0.len(), 3);
+        let big = DynamicImage::new_rgba8(2000, 1000);
+        assert_eq!(downscale(big).dimensions(), (512, 256));
+    }
+
+    #[test]
+    fn lru_test() {
+        let mut lru = Lru::new();
+        for i in 0..CACHE_SIZE {
+            lru.insert(i.to_string(), PreviewState::Loading);
+        }
+        // "0" is used again, "1" is the oldest now
+        assert!(lru.get_mut("0").is_some());
+        lru.insert("new".to_owned(), PreviewState::Loading);
+        assert!(lru.contains("0"));
+        assert!(!lru.contains("1"));
+        assert_eq!(lru.entries.len(), CACHE_SIZE);
+        assert_eq!(lru.order.len(), CACHE_SIZE);
+    }
+}
@@ -0,0 +1,171 @@
+//! Reply threads. The chat has no notion of replies, so a message that
+//! `@`-mentions someone is linked to that user's most recent message, or to
+//! the one it quotes: `@bob "the start of bob's message…" my answer`.
+
+// How far back to look for the message being answered.
+const LOOKBACK: usize = 200;
+const QUOTE_LEN: usize = 40;
+
+/// A message that can be answered.
+pub struct Post<'a> {
+    pub uid: u64,
+    pub from: &'a str,
+    pub body: &'a str,
+}
+
+/// Users mentioned in the message, in order.
+pub fn mentions(body: &str) -> Vec<&str> {
+    body.split_whitespace()
+        .filter_map(|w| w.strip_prefix('@'))
+        .map(|w| w.trim_end_matches(|c: char| ",.:;!?)".contains(c)))
+        .filter(|w| !w.is_empty())
+        .collect()
+}
+
+// The quote following the first mention, without its ellipsis.
+fn quote(body: &str) -> Option<&str> {
+    let start = body.find('@')?;
+    let rest = body[start..]
+        .split_once(char::is_whitespace)?
+        .1
+        .trim_start();
+    let rest = rest.strip_prefix('"')?;
+    let quote = &rest[..rest.find('"')?];
+    let quote = quote.trim_end_matches('…').trim_end_matches("...").trim();
+    if quote.is_empty() {
+        None
+    } else {
+        Some(quote)
+    }
+}
+
+/// The message "body" from "from" answers, among the "older" ones (newest first).
+pub fn reply_to<'a, I: Iterator<Item = Post<'a>>>(from: &str, body: &str, older: I) -> Option<u64> {
+    let target = mentions(body).into_iter().find(|u| *u != from)?;
+    let quote = quote(body);
+    let mut latest = None;
+    for post in older.take(LOOKBACK) {
+        if post.from != target {
+            continue;
+        }
+        match quote {
+            Some(q) if post.body.starts_with(q) => return Some(post.uid),
+            Some(_) => latest = latest.or(Some(post.uid)),
+            None => return Some(post.uid),
+        }
+    }
+    latest
+}
+
+/// What to type to answer "body" from "user", with the start of it quoted.
+pub fn reply_text(user: &str, body: &str, with_quote: bool) -> String {
+    if !with_quote || body.trim().is_empty() {
+        return format!("@{} ", user);
+    }
+    let body = body.trim().replace('"', "'");
+    let quote = match body.char_indices().nth(QUOTE_LEN) {
+        None => body,
+        Some((end, _)) => {
+            // Cut on a word boundary when there is one
+            let cut = body[..end].rfind(' ').filter(|i| *i > 0).unwrap_or(end);
+            format!("{}…", body[..cut].trim_end())
+        }
+    };
+    format!("@{} \"{}\" ", user, quote)
+}
+
+/// The conversation around "uid": the messages it answers (oldest first) and
+/// the replies to it, depth first, with their depth. "links" are the messages
+/// newest first, with what they reply to.
+pub fn thread(uid: u64, links: &[(u64, Option<u64>)]) -> Vec<(usize, u64)> {
+    let parent = |uid: u64| links.iter().find(|(u, _)| *u == uid).and_then(|(_, p)| *p);
+    let mut ancestors = vec![];
+    let mut current = uid;
+    // A reply is always newer than what it answers, the limit is only a guard
+    while let Some(p) = parent(current) {
+        if ancestors.len() >= links.len() {
+            break;
+        }
+        ancestors.push(p);
+        current = p;
+    }
+    ancestors.reverse();
+    let mut out: Vec<(usize, u64)> = ancestors.into_iter().enumerate().collect();
+    let depth = out.len();
+    add_replies(uid, depth, links, &mut out);
+    out
+}
+
+fn add_replies(uid: u64, depth: usize, links: &[(u64, Option<u64>)], out: &mut Vec<(usize, u64)>) {
+    out.push((depth, uid));
+    // Oldest replies first
+    for (reply, _) in links.iter().rev().filter(|(_, p)| *p == Some(uid)) {
+        if out.iter().all(|(_, u)| u != reply) {
+            add_replies(*reply, depth + 1, links, out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn posts<'a>(list: &'a [(u64, &'a str, &'a str)]) -> impl Iterator<Item = Post<'a>> {
+        list.iter().map(|(uid, from, body)| Post {
+            uid: *uid,
+            from,
+            body,
+        })
+    }
+
+    #[test]
+    fn reply_to_test() {
+        // Newest first
+        let older = [
+            (3, "bob", "second thing"),
+            (2, "alice", "hi all"),
+            (1, "bob", "first thing to say"),
+        ];
+        assert_eq!(mentions("@bob, @alice: hi @"), vec!["bob", "alice"]);
+        assert_eq!(reply_to("carol", "@bob yes", posts(&older)), Some(3));
+        assert_eq!(
+            reply_to("carol", "@bob \"first thing…\" yes", posts(&older)),
+            Some(1)
+        );
+        assert_eq!(
+            reply_to("carol", "@bob \"nope\" yes", posts(&older)),
+            Some(3)
+        );
+        assert_eq!(reply_to("bob", "@bob @alice", posts(&older)), Some(2));
+        assert_eq!(reply_to("carol", "no mention", posts(&older)), None);
+        assert_eq!(reply_to("carol", "@dave hi", posts(&older)), None);
+    }
+
+    #[test]
+    fn reply_text_test() {
+        assert_eq!(reply_text("bob", "hi", false), "@bob ");
+        assert_eq!(reply_text("bob", "say \"hi\"", true), "@bob \"say 'hi'\" ");
+        let long = "a long message that goes on and on and on for a while";
+        let text = reply_text("bob", long, true);
+        assert_eq!(text, "@bob \"a long message that goes on and on and…\" ");
+        assert_eq!(
+            reply_to("carol", &text, posts(&[(1, "bob", long)])),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn thread_test() {
+        // 5 answers 3, 4 and 3 answer 1, 2 is unrelated
+        let links = [
+            (5, Some(3)),
+            (4, Some(1)),
+            (3, Some(1)),
+            (2, None),
+            (1, None),
+        ];
+        assert_eq!(thread(1, &links), vec![(0, 1), (1, 3), (2, 5), (1, 4)]);
+        assert_eq!(thread(5, &links), vec![(0, 1), (1, 3), (2, 5)]);
+        assert_eq!(thread(2, &links), vec![(0, 2)]);
+    }
+}
@@ -0,0 +1,704 @@
+use crate::upload::temp_file;
+use flate2::read::DeflateDecoder;
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::jpeg::JpegEncoder;
+use image::{AnimationDecoder, DynamicImage, ImageFormat};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::fs::{self, File};
+use std::io::{BufWriter, Cursor, Read};
+use std::path::{Path, PathBuf};
+
+const JPEG_QUALITY: u8 = 92;
+// Office metadata files are small, do not inflate more than this.
+const MAX_XML_SIZE: u64 = 1024 * 1024;
+
+lazy_static! {
+    static ref PDF_INFO_RGX: Regex = Regex::new(
+        r#"/(Author|Creator|Producer|Title|Subject|Keywords|CreationDate|ModDate)\s*\(([^)]{0,200})\)"#
+    )
+    .unwrap();
+    static ref XML_FIELD_RGX: Regex = Regex::new(
+        r#"<((?:dc|cp|meta):(?:creator|lastModifiedBy|title|subject|description|keywords|initial-creator)|Company|Manager|Application)(?:\s[^>]*)?>([^<]{1,200})</"#
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileKind {
+    Image(ImageFormat),
+    Pdf,
+    Office,
+    Other,
+}
+
+/// Metadata found in a file about to be uploaded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub kind: FileKind,
+    pub found: Vec<String>,
+}
+
+impl Report {
+    /// Either or not we can remove what was found, by re-encoding the image.
+    pub fn strippable(&self) -> bool {
+        matches!(self.kind, FileKind::Image(_))
+    }
+}
+
+/// Look for metadata in the file.
+pub fn inspect(path: &Path) -> anyhow::Result<Report> {
+    let data = fs::read(path)?;
+    let format = image::guess_format(&data).ok();
+    let (kind, found) = match format {
+        Some(f @ ImageFormat::Jpeg) => (FileKind::Image(f), jpeg_metadata(&data)),
+        Some(f @ ImageFormat::Png) => (FileKind::Image(f), png_metadata(&data)),
+        Some(f @ ImageFormat::Gif) => (FileKind::Image(f), gif_metadata(&data)),
+        Some(f @ ImageFormat::WebP) => (FileKind::Image(f), webp_metadata(&data)),
+        _ if data.starts_with(b"%PDF") => (FileKind::Pdf, pdf_metadata(&data)),
+        _ if data.starts_with(b"PK\x03\x04") => match office_metadata(&data) {
+            Some(found) => (FileKind::Office, found),
+            None => (FileKind::Other, vec![]),
+        },
+        _ => (FileKind::Other, vec![]),
+    };
+    Ok(Report { kind, found })
+}
+
+/// Re-encode an image into a temporary file, so only the pixels are kept,
+/// whatever metadata "inspect" found or not. Every frame of an animated gif
+/// is kept, and pixels are turned as the EXIF orientation says.
+pub fn strip_image(path: &Path, format: ImageFormat) -> anyhow::Result<PathBuf> {
+    let data = fs::read(path)?;
+    if format == ImageFormat::Gif {
+        return strip_gif(&data);
+    }
+    let img = image::load_from_memory(&data)?;
+    let img = match exif_payload(&data, format).and_then(exif_orientation) {
+        Some(orientation) => orient(img, orientation),
+        None => img,
+    };
+    // The webp encoder of "image" is lossless only, png keeps the quality
+    let (format, ext) = match format {
+        ImageFormat::WebP => (ImageFormat::Png, "png"),
+        ImageFormat::Jpeg => (ImageFormat::Jpeg, "jpg"),
+        ImageFormat::Gif => (ImageFormat::Gif, "gif"),
+        _ => (ImageFormat::Png, "png"),
+    };
+    let out = temp_file(ext);
+    if format == ImageFormat::Jpeg {
+        let w = BufWriter::new(File::create(&out)?);
+        // Alpha is not supported by jpeg
+        JpegEncoder::new_with_quality(w, JPEG_QUALITY).encode_image(&img.to_rgb8())?;
+    } else {
+        img.save_with_format(&out, format)?;
+    }
+    Ok(out)
+}
+
+fn strip_gif(data: &[u8]) -> anyhow::Result<PathBuf> {
+    let frames = GifDecoder::new(Cursor::new(data))?
+        .into_frames()
+        .collect_frames()?;
+    let out = temp_file("gif");
+    let mut encoder = GifEncoder::new(BufWriter::new(File::create(&out)?));
+    encoder.set_repeat(Repeat::Infinite)?;
+    encoder.encode_frames(frames)?;
+    Ok(out)
+}
+
+// The pixels as they are meant to be seen, the orientation tag is lost when
+// re-encoding.
+fn orient(img: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+fn u16_at(data: &[u8], pos: usize, le: bool) -> Option<u16> {
+    let b = data.get(pos..pos + 2)?;
+    Some(if le {
+        u16::from_le_bytes([b[0], b[1]])
+    } else {
+        u16::from_be_bytes([b[0], b[1]])
+    })
+}
+
+fn u32_at(data: &[u8], pos: usize, le: bool) -> Option<u32> {
+    let b = data.get(pos..pos + 4)?;
+    let b = [b[0], b[1], b[2], b[3]];
+    Some(if le {
+        u32::from_le_bytes(b)
+    } else {
+        u32::from_be_bytes(b)
+    })
+}
+
+fn exif_tag_name(tag: u16) -> Option<&'static str> {
+    Some(match tag {
+        0x010E => "description",
+        0x010F => "camera make",
+        0x0110 => "camera model",
+        0x0131 => "software",
+        0x0132 => "date",
+        0x013B => "artist",
+        0x8298 => "copyright",
+        0x8825 => "GPS location",
+        0x9003 => "date taken",
+        0xA420 => "unique id",
+        0xA430 => "owner name",
+        0xA431 => "camera serial number",
+        0xA434 => "lens model",
+        0xA435 => "lens serial number",
+        _ => return None,
+    })
+}
+
+// List the interesting tags of a TIFF structure (the EXIF payload).
+fn exif_tags(tiff: &[u8]) -> Vec<String> {
+    let le = match tiff.get(0..2) {
+        Some(b"II") => true,
+        Some(b"MM") => false,
+        _ => return vec![],
+    };
+    let mut out = vec![];
+    let mut next_ifd = u32_at(tiff, 4, le).map(|off| off as usize);
+    let mut exif_visited = false;
+    // Only IFD0 and the Exif sub IFD, that's where identifying tags are
+    while let Some(ifd) = next_ifd.take() {
+        let count = match u16_at(tiff, ifd, le) {
+            Some(c) => c as usize,
+            None => continue,
+        };
+        for i in 0..count {
+            let entry = ifd + 2 + i * 12;
+            let tag = match u16_at(tiff, entry, le) {
+                Some(t) => t,
+                None => break,
+            };
+            if tag == 0x8769 && !exif_visited {
+                exif_visited = true;
+                next_ifd = u32_at(tiff, entry + 8, le).map(|off| off as usize);
+            } else if let Some(name) = exif_tag_name(tag) {
+                if !out.iter().any(|n| n == name) {
+                    out.push(name.to_owned());
+                }
+            }
+        }
+    }
+    out
+}
+
+// The orientation tag of IFD0.
+fn exif_orientation(tiff: &[u8]) -> Option<u16> {
+    let le = match tiff.get(0..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let ifd = u32_at(tiff, 4, le)? as usize;
+    let count = u16_at(tiff, ifd, le)? as usize;
+    (0..count)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|entry| u16_at(tiff, *entry, le) == Some(0x0112))
+        .and_then(|entry| u16_at(tiff, entry + 8, le))
+}
+
+// The EXIF (TIFF) data of an image, where each format keeps it.
+fn exif_payload(data: &[u8], format: ImageFormat) -> Option<&[u8]> {
+    match format {
+        ImageFormat::Jpeg => {
+            let mut pos = 2;
+            while pos + 4 <= data.len() && data[pos] == 0xFF && data[pos + 1] != 0xDA {
+                let len = u16_at(data, pos + 2, false)? as usize;
+                let payload = data.get(pos + 4..pos + 2 + len)?;
+                if data[pos + 1] == 0xE1 && payload.starts_with(b"Exif\0\0") {
+                    return Some(&payload[6..]);
+                }
+                pos += 2 + len;
+            }
+            None
+        }
+        ImageFormat::Png => {
+            let mut pos = 8;
+            while let Some(len) = u32_at(data, pos, false) {
+                let len = len as usize;
+                if data.get(pos + 4..pos + 8)? == b"eXIf" {
+                    return data.get(pos + 8..pos + 8 + len);
+                }
+                pos += 12 + len;
+            }
+            None
+        }
+        ImageFormat::WebP => {
+            let mut pos = 12;
+            while let (Some(typ), Some(len)) = (data.get(pos..pos + 4), u32_at(data, pos + 4, true))
+            {
+                let len = len as usize;
+                if typ == b"EXIF" {
+                    let payload = data.get(pos + 8..pos + 8 + len)?;
+                    return Some(payload.strip_prefix(b"Exif\0\0").unwrap_or(payload));
+                }
+                pos += 8 + len + (len & 1);
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+fn exif_summary(tiff: &[u8]) -> String {
+    let tags = exif_tags(tiff);
+    if tags.is_empty() {
+        "EXIF".to_owned()
+    } else {
+        format!("EXIF ({})", tags.join(", "))
+    }
+}
+
+fn jpeg_metadata(data: &[u8]) -> Vec<String> {
+    let mut out = vec![];
+    let mut pos = 2;
+    while pos + 4 <= data.len() && data[pos] == 0xFF {
+        let marker = data[pos + 1];
+        // Start of scan, the image data follows
+        if marker == 0xDA {
+            break;
+        }
+        let len = match u16_at(data, pos + 2, false) {
+            Some(l) => l as usize,
+            None => break,
+        };
+        let payload = data.get(pos + 4..pos + 2 + len).unwrap_or(&[]);
+        match marker {
+            0xE1 if payload.starts_with(b"Exif\0\0") => out.push(exif_summary(&payload[6..])),
+            0xE1 if payload.starts_with(b"http://ns.adobe.com/xap/") => out.push("XMP".to_owned()),
+            0xE2 if payload.starts_with(b"ICC_PROFILE") => out.push("ICC profile".to_owned()),
+            0xED => out.push("IPTC/Photoshop".to_owned()),
+            0xFE => out.push("comment".to_owned()),
+            _ => {}
+        }
+        pos += 2 + len;
+    }
+    out.dedup();
+    out
+}
+
+fn png_metadata(data: &[u8]) -> Vec<String> {
+    let mut out = vec![];
+    let mut pos = 8;
+    while let Some(len) = u32_at(data, pos, false) {
+        let len = len as usize;
+        let typ = match data.get(pos + 4..pos + 8) {
+            Some(t) => t,
+            None => break,
+        };
+        let payload = data.get(pos + 8..pos + 8 + len).unwrap_or(&[]);
+        match typ {
+            b"eXIf" => out.push(exif_summary(payload)),
+            b"iCCP" => out.push("ICC profile".to_owned()),
+            b"tIME" => out.push("modification time".to_owned()),
+            b"tEXt" | b"zTXt" | b"iTXt" => {
+                let keyword = payload.split(|b| *b == 0).next().unwrap_or(&[]);
+                let keyword = String::from_utf8_lossy(keyword);
+                if keyword == "XML:com.adobe.xmp" {
+                    out.push("XMP".to_owned());
+                } else {
+                    out.push(format!("text ({})", keyword));
+                }
+            }
+            b"IEND" => break,
+            _ => {}
+        }
+        pos += 12 + len;
+    }
+    out
+}
+
+// Skip a sequence of gif data sub-blocks, returns the position after the
+// block terminator.
+fn skip_sub_blocks(data: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *data.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            return Some(pos);
+        }
+        pos += len;
+    }
+}
+
+fn color_table_size(packed: u8) -> usize {
+    if packed & 0x80 == 0 {
+        0
+    } else {
+        3 << ((packed & 0x07) + 1)
+    }
+}
+
+// Walk the gif blocks, looking for the comment & XMP extensions.
+fn gif_metadata(data: &[u8]) -> Vec<String> {
+    let mut out = vec![];
+    let mut pos = match data.get(10) {
+        Some(packed) => 13 + color_table_size(*packed),
+        None => return out,
+    };
+    while let Some(block) = data.get(pos) {
+        let next = match block {
+            // Extension
+            0x21 => {
+                let label = data.get(pos + 1);
+                let name = match label {
+                    Some(0xFE) => Some("comment"),
+                    Some(0xFF) if data.get(pos + 2..pos + 14) == Some(b"\x0bXMP DataXMP") => {
+                        Some("XMP")
+                    }
+                    _ => None,
+                };
+                if let Some(name) = name {
+                    if !out.iter().any(|n| n == name) {
+                        out.push(name.to_owned());
+                    }
+                }
+                skip_sub_blocks(data, pos + 2)
+            }
+            // Image descriptor, then the LZW code size and the image data
+            0x2C => match data.get(pos + 9) {
+                Some(packed) => skip_sub_blocks(data, pos + 11 + color_table_size(*packed)),
+                None => None,
+            },
+            // Trailer, or not a gif block
+            _ => None,
+        };
+        match next {
+            Some(next) => pos = next,
+            None => break,
+        }
+    }
+    out
+}
+
+fn webp_metadata(data: &[u8]) -> Vec<String> {
+    let mut out = vec![];
+    let mut pos = 12;
+    while let (Some(typ), Some(len)) = (data.get(pos..pos + 4), u32_at(data, pos + 4, true)) {
+        let len = len as usize;
+        let payload = data.get(pos + 8..pos + 8 + len).unwrap_or(&[]);
+        match typ {
+            b"EXIF" => {
+                // Some writers keep the jpeg "Exif\0\0" header
+                let tiff = payload.strip_prefix(b"Exif\0\0").unwrap_or(payload);
+                out.push(exif_summary(tiff));
+            }
+            b"XMP " => out.push("XMP".to_owned()),
+            b"ICCP" => out.push("ICC profile".to_owned()),
+            _ => {}
+        }
+        // Chunks are padded to an even size
+        pos += 8 + len + (len & 1);
+    }
+    out
+}
+
+fn pdf_metadata(data: &[u8]) -> Vec<String> {
+    let text = String::from_utf8_lossy(data);
+    let mut out: Vec<String> = PDF_INFO_RGX
+        .captures_iter(&text)
+        .map(|c| format!("{}: {}", &c[1], &c[2]))
+        .collect();
+    if text.contains("<x:xmpmeta") {
+        out.push("XMP".to_owned());
+    }
+    out.dedup();
+    out
+}
+
+// Metadata of OOXML (docx, xlsx...) & OpenDocument files, which are zip archives.
+// Returns None if the zip is not an office document. Entries are listed from the
+// central directory, the sizes in the local headers can be left to a data
+// descriptor after the content.
+fn office_metadata(data: &[u8]) -> Option<Vec<String>> {
+    // The end of central directory record, followed by a comment of up to 64 KiB
+    let min = data.len().saturating_sub(22 + 0xFFFF);
+    let eocd = (min..=data.len().checked_sub(22)?)
+        .rev()
+        .find(|pos| data.get(*pos..*pos + 4) == Some(b"PK\x05\x06"))?;
+    let entries = u16_at(data, eocd + 10, true)?;
+    let mut pos = u32_at(data, eocd + 16, true)? as usize;
+    let mut is_office = false;
+    let mut out = vec![];
+    for _ in 0..entries {
+        if data.get(pos..pos + 4) != Some(b"PK\x01\x02") {
+            break;
+        }
+        let method = u16_at(data, pos + 10, true)?;
+        let compressed_size = u32_at(data, pos + 20, true)? as usize;
+        let name_len = u16_at(data, pos + 28, true)? as usize;
+        let extra_len = u16_at(data, pos + 30, true)? as usize;
+        let comment_len = u16_at(data, pos + 32, true)? as usize;
+        let local = u32_at(data, pos + 42, true)? as usize;
+        let name = data.get(pos + 46..pos + 46 + name_len)?;
+        pos += 46 + name_len + extra_len + comment_len;
+        match name {
+            b"[Content_Types].xml" | b"mimetype" => is_office = true,
+            b"docProps/core.xml" | b"docProps/app.xml" | b"meta.xml" => {
+                if data.get(local..local + 4) != Some(b"PK\x03\x04") {
+                    continue;
+                }
+                let local_name_len = u16_at(data, local + 26, true)? as usize;
+                let local_extra_len = u16_at(data, local + 28, true)? as usize;
+                let start = local + 30 + local_name_len + local_extra_len;
+                let content = data.get(start..start + compressed_size)?;
+                let xml = match method {
+                    0 => String::from_utf8_lossy(content).into_owned(),
+                    8 => {
+                        let mut s = String::new();
+                        DeflateDecoder::new(content)
+                            .take(MAX_XML_SIZE)
+                            .read_to_string(&mut s)
+                            .ok()?;
+                        s
+                    }
+                    _ => String::new(),
+                };
+                for c in XML_FIELD_RGX.captures_iter(&xml) {
+                    out.push(format!("{}: {}", &c[1], &c[2]));
+                }
+            }
+            _ => {}
+        }
+    }
+    if is_office {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Minimal big endian TIFF with a "Make" and a GPS pointer in IFD0.
+    fn tiff() -> Vec<u8> {
+        let mut t = b"MM\0\x2a\0\0\0\x08".to_vec();
+        t.extend([0, 2]);
+        t.extend([0x01, 0x0F, 0, 2, 0, 0, 0, 4, b'A', b'B', b'C', 0]);
+        t.extend([0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 0]);
+        t.extend([0, 0, 0, 0]);
+        t
+    }
+
+    #[test]
+    fn jpeg_exif_test() {
+        let mut payload = b"Exif\0\0".to_vec();
+        payload.extend(tiff());
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        jpeg.extend(((payload.len() + 2) as u16).to_be_bytes());
+        jpeg.extend(payload);
+        jpeg.extend([0xFF, 0xFE, 0, 4, b'h', b'i']);
+        jpeg.extend([0xFF, 0xDA, 0, 2]);
+        assert_eq!(
+            jpeg_metadata(&jpeg),
+            vec![
+                "EXIF (camera make, GPS location)".to_owned(),
+                "comment".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn png_text_test() {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        let text = b"Author\0me";
+        png.extend((text.len() as u32).to_be_bytes());
+        png.extend(b"tEXt");
+        png.extend(text);
+        png.extend([0, 0, 0, 0]);
+        png.extend([0, 0, 0, 0]);
+        png.extend(b"IEND");
+        assert_eq!(png_metadata(&png), vec!["text (Author)".to_owned()]);
+    }
+
+    #[test]
+    fn pdf_info_test() {
+        let pdf = b"%PDF-1.4\n1 0 obj << /Author (John Doe) /Producer (Word) >> endobj";
+        assert_eq!(
+            pdf_metadata(pdf),
+            vec!["Author: John Doe".to_owned(), "Producer: Word".to_owned()]
+        );
+    }
+
+    #[test]
+    fn gif_blocks_test() {
+        let mut gif = b"GIF89a\x01\0\x01\0\x80\0\0".to_vec();
+        // Global color table of 2 entries
+        gif.extend([0, 0, 0, 255, 255, 255]);
+        // Image whose LZW data contains the comment extension bytes
+        gif.extend([0x2C, 0, 0, 0, 0, 1, 0, 1, 0, 0, 2]);
+        gif.extend([4, 0x21, 0xFE, 0x21, 0xFE, 0]);
+        assert!(gif_metadata(&gif).is_empty());
+
+        gif.extend([0x21, 0xFF, 11]);
+        gif.extend(b"XMP DataXMP");
+        gif.extend([3, b'<', b'x', b'>', 0]);
+        gif.extend([0x21, 0xFE, 2, b'h', b'i', 0]);
+        gif.push(0x3B);
+        assert_eq!(
+            gif_metadata(&gif),
+            vec!["XMP".to_owned(), "comment".to_owned()]
+        );
+    }
+
+    #[test]
+    fn strip_unknown_metadata_test() {
+        let mut jpeg = vec![];
+        let img = image::RgbImage::from_pixel(4, 4, image::Rgb([200, 10, 10]));
+        JpegEncoder::new(&mut jpeg).encode_image(&img).unwrap();
+        // APP12 "Ducky" segment, not reported but still removed
+        let mut ducky = vec![0xFF, 0xEC, 0, 9];
+        ducky.extend(b"Ducky\0\x01");
+        jpeg.splice(2..2, ducky);
+        assert!(jpeg_metadata(&jpeg).is_empty());
+
+        let path = temp_file("jpg");
+        fs::write(&path, &jpeg).unwrap();
+        let out = strip_image(&path, ImageFormat::Jpeg).unwrap();
+        let stripped = fs::read(&out).unwrap();
+        assert!(!stripped.windows(5).any(|w| w == b"Ducky"));
+        assert!(image::load_from_memory(&stripped).is_ok());
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&out);
+    }
+
+    #[test]
+    fn strip_orientation_test() {
+        let mut tiff = b"MM\0\x2a\0\0\0\x08".to_vec();
+        tiff.extend([0, 1]);
+        // Orientation, a short: 6, turned 90 degrees
+        tiff.extend([0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0]);
+        tiff.extend([0, 0, 0, 0]);
+        assert_eq!(exif_orientation(&tiff), Some(6));
+
+        let mut jpeg = vec![];
+        let img = image::RgbImage::from_pixel(8, 4, image::Rgb([200, 10, 10]));
+        JpegEncoder::new(&mut jpeg).encode_image(&img).unwrap();
+        let mut app1 = vec![0xFF, 0xE1];
+        app1.extend(((tiff.len() + 8) as u16).to_be_bytes());
+        app1.extend(b"Exif\0\0");
+        app1.extend(tiff);
+        jpeg.splice(2..2, app1);
+
+        let path = temp_file("jpg");
+        fs::write(&path, &jpeg).unwrap();
+        let out = strip_image(&path, ImageFormat::Jpeg).unwrap();
+        let stripped = fs::read(&out).unwrap();
+        assert!(exif_payload(&stripped, ImageFormat::Jpeg).is_none());
+        let img = image::load_from_memory(&stripped).unwrap();
+        assert_eq!((img.width(), img.height()), (4, 8));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&out);
+    }
+
+    #[test]
+    fn strip_animated_gif_test() {
+        let mut gif = vec![];
+        {
+            let mut encoder = GifEncoder::new(&mut gif);
+            for color in [[255, 0, 0, 255], [0, 0, 255, 255]] {
+                let frame = image::RgbaImage::from_pixel(2, 2, image::Rgba(color));
+                encoder.encode_frame(image::Frame::new(frame)).unwrap();
+            }
+        }
+        let path = temp_file("gif");
+        fs::write(&path, &gif).unwrap();
+        let out = strip_image(&path, ImageFormat::Gif).unwrap();
+        let frames = GifDecoder::new(File::open(&out).unwrap())
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        assert_eq!(frames.len(), 2);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&out);
+    }
+
+    // A zip archive, the entries are (name, content, compressed with sizes
+    // in a data descriptor).
+    fn zip(entries: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        use flate2::write::DeflateEncoder;
+        use std::io::Write;
+        let mut out = vec![];
+        let mut central = vec![];
+        for (name, content, descriptor) in entries {
+            let (method, flags, data) = if *descriptor {
+                let mut e = DeflateEncoder::new(vec![], flate2::Compression::default());
+                e.write_all(content).unwrap();
+                (8u16, 8u16, e.finish().unwrap())
+            } else {
+                (0, 0, content.to_vec())
+            };
+            let sizes = [(data.len() as u32), (content.len() as u32)];
+            let offset = out.len() as u32;
+            out.extend(b"PK\x03\x04\x14\0");
+            out.extend(flags.to_le_bytes());
+            out.extend(method.to_le_bytes());
+            out.extend([0; 8]);
+            for size in sizes {
+                let size = if *descriptor { 0 } else { size };
+                out.extend(size.to_le_bytes());
+            }
+            out.extend((name.len() as u16).to_le_bytes());
+            out.extend([0, 0]);
+            out.extend(name.as_bytes());
+            out.extend(&data);
+            if *descriptor {
+                out.extend(b"PK\x07\x08\0\0\0\0");
+                out.extend(sizes[0].to_le_bytes());
+                out.extend(sizes[1].to_le_bytes());
+            }
+            central.extend(b"PK\x01\x02\x14\0\x14\0");
+            central.extend(flags.to_le_bytes());
+            central.extend(method.to_le_bytes());
+            central.extend([0; 8]);
+            central.extend(sizes[0].to_le_bytes());
+            central.extend(sizes[1].to_le_bytes());
+            central.extend((name.len() as u16).to_le_bytes());
+            central.extend([0; 12]);
+            central.extend(offset.to_le_bytes());
+            central.extend(name.as_bytes());
+        }
+        let central_offset = out.len() as u32;
+        out.extend(&central);
+        out.extend(b"PK\x05\x06\0\0\0\0");
+        out.extend((entries.len() as u16).to_le_bytes());
+        out.extend((entries.len() as u16).to_le_bytes());
+        out.extend((central.len() as u32).to_le_bytes());
+        out.extend(central_offset.to_le_bytes());
+        out.extend([0, 0]);
+        out
+    }
+
+    #[test]
+    fn office_data_descriptor_test() {
+        let core = b"<cp:coreProperties><dc:creator>John Doe</dc:creator></cp:coreProperties>";
+        let docx = zip(&[
+            ("[Content_Types].xml", b"<Types/>", false),
+            ("word/document.xml", b"<w:document/>", true),
+            ("docProps/core.xml", core, true),
+        ]);
+        assert_eq!(
+            office_metadata(&docx),
+            Some(vec!["dc:creator: John Doe".to_owned()])
+        );
+        assert_eq!(office_metadata(&zip(&[("a.txt", b"hi", true)])), None);
+        assert_eq!(office_metadata(b"PK\x03\x04"), None);
+    }
+}
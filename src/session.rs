@@ -0,0 +1,169 @@
+use crate::config_dir_file;
+use crate::crypto::{self, SealedFile, StorageKey};
+use anyhow::Context;
+use reqwest::cookie::{CookieStore, Jar};
+use reqwest::Url;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+
+const SESSIONS_FILE: &str = "sessions.bin";
+// Where the key was kept before it moved to the vault
+const LEGACY_KEY_FILE: &str = "sessions.key";
+
+/// A chat session saved on disk so we can resume it without going through
+/// the login/captcha again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredSession {
+    pub url: String,
+    pub username: String,
+    pub session: String,
+    pub cookies: Vec<String>,
+    pub saved_at: String,
+}
+
+impl StoredSession {
+    pub fn new(url: &str, username: &str, session: &str, jar: &Jar) -> Self {
+        let cookies = Url::parse(url)
+            .ok()
+            .and_then(|u| jar.cookies(&u))
+            .and_then(|v| v.to_str().ok().map(|s| s.to_owned()))
+            .map(|s| s.split("; ").map(|c| c.to_owned()).collect())
+            .unwrap_or_default();
+        Self {
+            url: url.to_owned(),
+            username: username.to_owned(),
+            session: session.to_owned(),
+            cookies,
+            saved_at: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+
+    /// Put the saved cookies back in the client cookie jar.
+    pub fn restore_cookies(&self, jar: &Jar) {
+        if let Ok(url) = Url::parse(&self.url) {
+            for cookie in &self.cookies {
+                jar.add_cookie_str(cookie, &url);
+            }
+        }
+    }
+}
+
+fn sessions_file(key: &StorageKey) -> anyhow::Result<SealedFile> {
+    let path = config_dir_file(SESSIONS_FILE).context("no config directory")?;
+    Ok(key.file(path))
+}
+
+/// Sessions used to be sealed with a key stored next to them, they are sealed
+/// again with the storage key and the old key is removed.
+pub fn migrate_legacy_key(key: &StorageKey) -> anyhow::Result<()> {
+    let legacy_path = config_dir_file(LEGACY_KEY_FILE).context("no config directory")?;
+    let legacy_key = match fs::read(&legacy_path) {
+        Ok(data) => data,
+        Err(_) => return Ok(()),
+    };
+    let file = sessions_file(key)?;
+    let sessions = match (
+        <[u8; crypto::KEY_LEN]>::try_from(legacy_key),
+        fs::read(file.path()),
+    ) {
+        (Ok(legacy_key), Ok(data)) => crypto::open(&legacy_key, &data).ok(),
+        _ => None,
+    };
+    match sessions {
+        Some(plaintext) => file.write(&plaintext)?,
+        None => {
+            log::error!("stored sessions can't be read with the old key, dropping them");
+            let _ = fs::remove_file(file.path());
+        }
+    }
+    fs::remove_file(&legacy_path)?;
+    Ok(())
+}
+
+fn read_sessions(file: &SealedFile) -> anyhow::Result<HashMap<String, StoredSession>> {
+    match file.read()? {
+        Some(plaintext) => Ok(serde_json::from_slice(&plaintext)?),
+        None => Ok(HashMap::new()),
+    }
+}
+
+fn write_sessions(
+    file: &SealedFile,
+    sessions: &HashMap<String, StoredSession>,
+) -> anyhow::Result<()> {
+    file.write(&serde_json::to_vec(sessions)?)
+}
+
+/// Load all stored sessions, indexed by profile name.
+pub fn load_all(key: &StorageKey) -> anyhow::Result<HashMap<String, StoredSession>> {
+    read_sessions(&sessions_file(key)?)
+}
+
+pub fn load(key: &StorageKey, profile: &str) -> Option<StoredSession> {
+    match load_all(key) {
+        Ok(mut sessions) => sessions.remove(profile),
+        Err(e) => {
+            log::error!("failed to load stored sessions: {}", e);
+            None
+        }
+    }
+}
+
+// The other profiles sessions are kept, so a file that can't be read is an
+// error, not an empty list.
+fn save_in(file: &SealedFile, profile: &str, session: StoredSession) -> anyhow::Result<()> {
+    let mut sessions = read_sessions(file)?;
+    sessions.insert(profile.to_owned(), session);
+    write_sessions(file, &sessions)
+}
+
+pub fn save(key: &StorageKey, profile: &str, session: StoredSession) -> anyhow::Result<()> {
+    save_in(&sessions_file(key)?, profile, session)
+}
+
+/// Remove the stored session of a profile. Returns either or not there was one.
+pub fn remove(key: &StorageKey, profile: &str) -> anyhow::Result<bool> {
+    let file = sessions_file(key)?;
+    let mut sessions = read_sessions(&file)?;
+    let removed = sessions.remove(profile).is_some();
+    if removed {
+        write_sessions(&file, &sessions)?;
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(username: &str) -> StoredSession {
+        StoredSession {
+            url: "http://chat.onion/index.php".to_owned(),
+            username: username.to_owned(),
+            session: "abc".to_owned(),
+            cookies: vec![],
+            saved_at: String::new(),
+        }
+    }
+
+    #[test]
+    fn save_test() {
+        let dir = std::env::temp_dir().join(format!("bhcli-sessions-{}", std::process::id()));
+        let key = StorageKey::generate();
+        let file = key.file(dir.join(SESSIONS_FILE));
+        save_in(&file, "a", session("alice")).unwrap();
+        save_in(&file, "b", session("bob")).unwrap();
+        let sessions = read_sessions(&file).unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions["b"].username, "bob");
+
+        // With another key nothing can be read, and nothing is overwritten
+        let other = StorageKey::generate().file(dir.join(SESSIONS_FILE));
+        assert!(read_sessions(&other).is_err());
+        assert!(save_in(&other, "c", session("carol")).is_err());
+        assert_eq!(read_sessions(&file).unwrap().len(), 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
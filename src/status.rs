@@ -0,0 +1,105 @@
+use std::time::{Duration, Instant};
+
+// How long a status message stays displayed.
+const STATUS_TTL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Info,
+    Error,
+}
+
+/// Progress of a long running operation (eg: an upload).
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pub label: String,
+    pub done: u64,
+    pub total: u64,
+}
+
+impl Progress {
+    pub fn percent(&self) -> u64 {
+        if self.total == 0 {
+            return 100;
+        }
+        // The file can grow while it is being sent
+        (self.done * 100 / self.total).min(100)
+    }
+}
+
+/// Short feedback displayed in the help bar, shared between the UI and the
+/// background threads.
+#[derive(Default)]
+pub struct StatusLine {
+    message: Option<(String, Level, Instant)>,
+    progress: Option<Progress>,
+}
+
+impl StatusLine {
+    pub fn info<S: Into<String>>(&mut self, msg: S) {
+        self.message = Some((msg.into(), Level::Info, Instant::now()));
+    }
+
+    pub fn error<S: Into<String>>(&mut self, msg: S) {
+        self.message = Some((msg.into(), Level::Error, Instant::now()));
+    }
+
+    /// Current message, if it did not expire yet.
+    pub fn message(&self) -> Option<(&str, Level)> {
+        match &self.message {
+            Some((msg, level, at)) if at.elapsed() < STATUS_TTL => Some((msg, *level)),
+            _ => None,
+        }
+    }
+
+    pub fn set_progress(&mut self, progress: Option<Progress>) {
+        self.progress = progress;
+    }
+
+    pub fn progress(&self) -> Option<&Progress> {
+        self.progress.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_test() {
+        let mut p = Progress {
+            label: "f".to_owned(),
+            done: 0,
+            total: 0,
+        };
+        assert_eq!(p.percent(), 100);
+        p.total = 200;
+        assert_eq!(p.percent(), 0);
+        p.done = 50;
+        assert_eq!(p.percent(), 25);
+        p.done = 500;
+        assert_eq!(p.percent(), 100);
+    }
+
+    #[test]
+    fn status_line_test() {
+        let mut status = StatusLine::default();
+        assert!(status.message().is_none());
+        status.info("sent");
+        assert_eq!(status.message(), Some(("sent", Level::Info)));
+        status.error("failed");
+        assert_eq!(status.message(), Some(("failed", Level::Error)));
+        status.message.as_mut().unwrap().2 -= STATUS_TTL;
+        assert!(status.message().is_none());
+
+        assert!(status.progress().is_none());
+        status.set_progress(Some(Progress {
+            label: "f".to_owned(),
+            done: 1,
+            total: 2,
+        }));
+        assert_eq!(status.progress().unwrap().percent(), 50);
+        status.set_progress(None);
+        assert!(status.progress().is_none());
+    }
+}
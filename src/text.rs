@@ -0,0 +1,265 @@
+//! Unicode helpers: cursor movements by grapheme cluster, and layout by
+//! display width (emoji & CJK take two columns, combining marks none).
+use std::ops::Range;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+pub fn width(s: &str) -> usize {
+    s.width()
+}
+
+/// Byte index of the grapheme cluster before "idx".
+pub fn prev_grapheme(s: &str, idx: usize) -> usize {
+    s[..idx]
+        .grapheme_indices(true)
+        .next_back()
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+/// Byte index of the grapheme cluster after "idx".
+pub fn next_grapheme(s: &str, idx: usize) -> usize {
+    s[idx..]
+        .graphemes(true)
+        .next()
+        .map(|g| idx + g.len())
+        .unwrap_or(idx)
+}
+
+/// Byte index of the start of the next word.
+pub fn next_word(s: &str, idx: usize) -> usize {
+    let rest = &s[idx..];
+    match rest.find(char::is_whitespace) {
+        Some(pos) => match rest[pos..].find(|c: char| !c.is_whitespace()) {
+            Some(word) => idx + pos + word,
+            None => s.len(),
+        },
+        None => s.len(),
+    }
+}
+
+/// Byte index of the start of the word before "idx".
+pub fn prev_word(s: &str, idx: usize) -> usize {
+    let before = s[..idx].trim_end();
+    match before.rfind(char::is_whitespace) {
+        Some(pos) => pos + before[pos..].chars().next().map_or(1, |c| c.len_utf8()),
+        None => 0,
+    }
+}
+
+/// Wrap "s" in lines of at most "max_width" columns. Lines are returned as
+/// byte ranges of "s", they break on "\n" and between words; the spaces where
+/// a line is broken are dropped. Words longer than a line are split between
+/// grapheme clusters.
+pub fn wrap(s: &str, max_width: usize) -> Vec<Range<usize>> {
+    let max_width = max_width.max(1);
+    let mut lines = vec![];
+    let mut offset = 0;
+    for hard_line in s.split('\n') {
+        wrap_line(hard_line, offset, max_width, &mut lines);
+        offset += hard_line.len() + 1;
+    }
+    lines
+}
+
+fn wrap_line(s: &str, offset: usize, max_width: usize, lines: &mut Vec<Range<usize>>) {
+    let mut start = 0;
+    let mut end = 0;
+    let mut line_width = 0;
+    // Zero width characters are content too, so we can't rely on line_width.
+    let mut empty = true;
+    // Spaces after the last word of the line, only kept if a word follows.
+    let mut pending_width = 0;
+    let mut wrapped = false;
+    for (tok_start, tok) in tokens(s) {
+        let tok_width = width(tok);
+        if tok.chars().all(char::is_whitespace) && (!empty || wrapped) {
+            if !empty {
+                pending_width += tok_width;
+            }
+            continue;
+        }
+        if !empty && line_width + pending_width + tok_width > max_width {
+            lines.push(offset + start..offset + end);
+            wrapped = true;
+            line_width = 0;
+            empty = true;
+        }
+        if empty {
+            start = tok_start;
+            pending_width = 0;
+        }
+        empty = false;
+        if line_width + pending_width + tok_width <= max_width {
+            line_width += pending_width + tok_width;
+            end = tok_start + tok.len();
+            pending_width = 0;
+            continue;
+        }
+        // The word doesn't fit on a line of its own.
+        for (g_idx, g) in tok.grapheme_indices(true) {
+            let g_width = width(g);
+            if line_width > 0 && line_width + g_width > max_width {
+                lines.push(offset + start..offset + end);
+                wrapped = true;
+                start = tok_start + g_idx;
+                line_width = 0;
+            }
+            line_width += g_width;
+            end = tok_start + g_idx + g.len();
+        }
+    }
+    if empty {
+        end = start;
+    }
+    lines.push(offset + start..offset + end);
+}
+
+// Split "s" in runs of whitespace and of non whitespace grapheme clusters.
+fn tokens(s: &str) -> Vec<(usize, &str)> {
+    let mut out = vec![];
+    let mut start = 0;
+    let mut prev_space: Option<bool> = None;
+    for (idx, g) in s.grapheme_indices(true) {
+        let is_space = g.chars().all(char::is_whitespace);
+        if prev_space.is_some() && prev_space != Some(is_space) {
+            out.push((start, &s[start..idx]));
+            start = idx;
+        }
+        prev_space = Some(is_space);
+    }
+    if start < s.len() {
+        out.push((start, &s[start..]));
+    }
+    out
+}
+
+/// What the input box displays: the visible part of each line, and where the
+/// cursor is (column, row) relative to the box content.
+pub struct InputView {
+    pub lines: Vec<String>,
+    pub cursor: (u16, u16),
+}
+
+/// Lay out a multi-line input in a "cols" x "rows" box. Lines are scrolled
+/// horizontally and vertically so the cursor ("idx", in bytes) stays visible.
+pub fn input_view(input: &str, idx: usize, cols: usize, rows: usize) -> InputView {
+    let cols = cols.max(1);
+    let rows = rows.max(1);
+    let cursor_row = input[..idx].matches('\n').count();
+    let row_start = input[..idx].rfind('\n').map_or(0, |i| i + 1);
+    let cursor_col = width(&input[row_start..idx]);
+    // Keep one column for the cursor past the end of the line.
+    let h_scroll = (cursor_col + 1).saturating_sub(cols);
+    let v_scroll = (cursor_row + 1).saturating_sub(rows);
+    let lines = input
+        .split('\n')
+        .skip(v_scroll)
+        .take(rows)
+        .map(|line| visible_part(line, h_scroll, cols))
+        .collect();
+    InputView {
+        lines,
+        cursor: (
+            (cursor_col - h_scroll) as u16,
+            (cursor_row - v_scroll) as u16,
+        ),
+    }
+}
+
+// Columns "from" to "from + cols" of the line. Wide characters cut by the
+// edges are replaced by spaces.
+fn visible_part(line: &str, from: usize, cols: usize) -> String {
+    let mut out = String::new();
+    let mut col = 0;
+    for g in line.graphemes(true) {
+        let g_width = width(g);
+        if col >= from && col + g_width <= from + cols {
+            out.push_str(g);
+        } else if col + g_width > from && col < from + cols {
+            let visible = (col + g_width).min(from + cols) - col.max(from);
+            out.push_str(&" ".repeat(visible));
+        }
+        col += g_width;
+        if col >= from + cols {
+            break;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn wrapped(s: &str, w: usize) -> Vec<&str> {
+        wrap(s, w).into_iter().map(|r| &s[r]).collect()
+    }
+
+    #[test]
+    fn wrap_test() {
+        assert_eq!(wrapped("hello world", 5), vec!["hello", "world"]);
+        assert_eq!(wrapped("a\nb c", 10), vec!["a", "b c"]);
+        assert_eq!(wrapped("abcdef", 4), vec!["abcd", "ef"]);
+        assert_eq!(wrapped("💓💓💓", 4), vec!["💓💓", "💓"]);
+        assert_eq!(
+            wrapped("e\u{301}e\u{301}e\u{301}", 2),
+            vec!["e\u{301}e\u{301}", "e\u{301}"]
+        );
+        assert_eq!(wrapped("", 4), vec![""]);
+    }
+
+    #[test]
+    fn cursor_test() {
+        let s = "a 💓e\u{301} bc";
+        assert_eq!(next_grapheme(s, 2), 6);
+        assert_eq!(next_grapheme(s, 6), 9);
+        assert_eq!(prev_grapheme(s, 9), 6);
+        assert_eq!(next_word(s, 0), 2);
+        assert_eq!(prev_word(s, s.len()), 10);
+        assert_eq!(prev_word(s, 10), 2);
+    }
+
+    #[test]
+    fn input_view_test() {
+        let view = input_view("abcdef\n💓💓💓", 19, 4, 1);
+        assert_eq!(view.lines, vec![" 💓"]);
+        assert_eq!(view.cursor, (3, 0));
+        let view = input_view("abcdef\nx", 3, 4, 2);
+        assert_eq!(view.lines, vec!["abcd", "x"]);
+        assert_eq!(view.cursor, (3, 0));
+    }
+
+    proptest! {
+        #[test]
+        fn wrap_fits_width(s in "\\PC*", w in 1usize..40) {
+            for line in wrapped(&s, w) {
+                // Only a single grapheme wider than the line can overflow it
+                let visible = line.graphemes(true).filter(|g| width(g) > 0).count();
+                prop_assert!(width(line) <= w || visible == 1);
+            }
+        }
+
+        #[test]
+        fn wrap_keeps_text(s in "\\PC*", w in 1usize..40) {
+            let kept: String = wrapped(&s, w).concat().split_whitespace().collect();
+            let all: String = s.split_whitespace().collect();
+            prop_assert_eq!(kept, all);
+        }
+
+        #[test]
+        fn input_view_fits(s in "\\PC{0,60}", cols in 1usize..30, rows in 1usize..4, pos in 0usize..60) {
+            let mut idx = pos.min(s.len());
+            while !s.is_char_boundary(idx) {
+                idx -= 1;
+            }
+            let view = input_view(&s, idx, cols, rows);
+            prop_assert!((view.cursor.0 as usize) < cols);
+            prop_assert!((view.cursor.1 as usize) < rows);
+            for line in view.lines {
+                prop_assert!(width(&line) <= cols);
+            }
+        }
+    }
+}
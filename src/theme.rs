@@ -0,0 +1,447 @@
+//! Colours of the interface. A preset (dark, light, high-contrast) gives every
+//! colour, the config can override them one by one. Colours picked by people
+//! in the chat are lightened or darkened until they are readable on the
+//! background, and everything is brought down to 256 or 16 colours when the
+//! terminal can't do better.
+use anyhow::{anyhow, bail};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tui::style::{Color, Style};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThemeConfig {
+    /// "dark", "light" or "high-contrast"
+    pub preset: String,
+    /// "auto", "truecolor", "256" or "16"
+    pub colors: String,
+    /// Minimum contrast ratio of chat colours against the background, from 1
+    /// (no adjustment) to 21. The preset decides when it's not set.
+    pub min_contrast: Option<f64>,
+    /// Colour name => "#rrggbb", a colour name or a 0-255 index.
+    pub palette: HashMap<String, String>,
+}
+
+impl Default for ThemeConfig {
+    fn default() -> Self {
+        Self {
+            preset: "dark".to_owned(),
+            colors: "auto".to_owned(),
+            min_contrast: None,
+            palette: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorLevel {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+}
+
+impl ColorLevel {
+    // What the terminal claims to support.
+    fn detect() -> Self {
+        let colorterm = std::env::var("COLORTERM").unwrap_or_default();
+        let term = std::env::var("TERM").unwrap_or_default();
+        if colorterm.contains("truecolor") || colorterm.contains("24bit") {
+            ColorLevel::TrueColor
+        } else if term.contains("256color") {
+            ColorLevel::Ansi256
+        } else {
+            ColorLevel::Ansi16
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Theme {
+    /// What the terminal background is assumed to be, chat colours are
+    /// adjusted against it.
+    pub background: Color,
+    /// Text without a colour of its own
+    pub text: Color,
+    /// Links without a colour of their own
+    pub link: Color,
+    /// Dates, sent markers & other secondary text
+    pub dim: Color,
+    /// Dates of hidden messages, toggles that are off
+    pub inactive: Color,
+    pub error: Color,
+    /// Status messages, toggles that are on
+    pub info: Color,
+    /// Progress & items being sent
+    pub pending: Color,
+    /// The input box while typing
+    pub editing: Color,
+    pub directory: Color,
+    pub highlight_bg: Color,
+    pub deleted_bg: Color,
+    pub hidden_bg: Color,
+    /// Messages that tag us & PMs to us
+    pub mention_bg: Color,
+    level: ColorLevel,
+    min_contrast: f64,
+}
+
+impl Theme {
+    fn dark() -> Self {
+        Self {
+            background: Color::Rgb(0, 0, 0),
+            text: Color::White,
+            link: Color::LightCyan,
+            dim: Color::DarkGray,
+            inactive: Color::Gray,
+            error: Color::Red,
+            info: Color::LightGreen,
+            pending: Color::Yellow,
+            editing: Color::Yellow,
+            directory: Color::LightBlue,
+            highlight_bg: Color::Rgb(50, 50, 50),
+            deleted_bg: Color::Rgb(30, 0, 0),
+            hidden_bg: Color::Rgb(20, 20, 20),
+            mention_bg: Color::Rgb(45, 35, 0),
+            level: ColorLevel::TrueColor,
+            min_contrast: 3.0,
+        }
+    }
+
+    fn light() -> Self {
+        Self {
+            background: Color::Rgb(255, 255, 255),
+            text: Color::Black,
+            link: Color::Blue,
+            dim: Color::Rgb(110, 110, 110),
+            inactive: Color::Rgb(150, 150, 150),
+            error: Color::Rgb(190, 0, 0),
+            info: Color::Rgb(0, 130, 0),
+            pending: Color::Rgb(160, 110, 0),
+            editing: Color::Rgb(160, 110, 0),
+            directory: Color::Blue,
+            highlight_bg: Color::Rgb(215, 215, 215),
+            deleted_bg: Color::Rgb(255, 220, 220),
+            hidden_bg: Color::Rgb(235, 235, 235),
+            mention_bg: Color::Rgb(255, 240, 190),
+            level: ColorLevel::TrueColor,
+            min_contrast: 3.0,
+        }
+    }
+
+    fn high_contrast() -> Self {
+        Self {
+            background: Color::Rgb(0, 0, 0),
+            text: Color::White,
+            link: Color::LightCyan,
+            dim: Color::Rgb(200, 200, 200),
+            inactive: Color::Rgb(160, 160, 160),
+            error: Color::LightRed,
+            info: Color::LightGreen,
+            pending: Color::LightYellow,
+            editing: Color::LightYellow,
+            directory: Color::LightCyan,
+            highlight_bg: Color::Blue,
+            deleted_bg: Color::Rgb(90, 0, 0),
+            hidden_bg: Color::Rgb(40, 40, 40),
+            mention_bg: Color::Rgb(90, 70, 0),
+            level: ColorLevel::TrueColor,
+            min_contrast: 7.0,
+        }
+    }
+
+    pub fn from_config(cfg: &ThemeConfig) -> anyhow::Result<Self> {
+        let mut theme = match cfg.preset.as_str() {
+            "dark" => Self::dark(),
+            "light" => Self::light(),
+            "high-contrast" => Self::high_contrast(),
+            preset => bail!("unknown theme preset \"{}\"", preset),
+        };
+        theme.level = match cfg.colors.as_str() {
+            "auto" => ColorLevel::detect(),
+            "truecolor" => ColorLevel::TrueColor,
+            "256" => ColorLevel::Ansi256,
+            "16" => ColorLevel::Ansi16,
+            colors => bail!("unknown colors \"{}\"", colors),
+        };
+        if let Some(min_contrast) = cfg.min_contrast {
+            theme.min_contrast = min_contrast.clamp(1.0, 21.0);
+        }
+        for (name, value) in &cfg.palette {
+            let color = parse_color(value)?;
+            let slot = match name.as_str() {
+                "background" => &mut theme.background,
+                "text" => &mut theme.text,
+                "link" => &mut theme.link,
+                "dim" => &mut theme.dim,
+                "inactive" => &mut theme.inactive,
+                "error" => &mut theme.error,
+                "info" => &mut theme.info,
+                "pending" => &mut theme.pending,
+                "editing" => &mut theme.editing,
+                "directory" => &mut theme.directory,
+                "highlight_bg" => &mut theme.highlight_bg,
+                "deleted_bg" => &mut theme.deleted_bg,
+                "hidden_bg" => &mut theme.hidden_bg,
+                "mention_bg" => &mut theme.mention_bg,
+                _ => bail!("unknown theme colour \"{}\"", name),
+            };
+            *slot = color;
+        }
+        // The theme's own colours only need to fit the terminal.
+        for c in [
+            &mut theme.text,
+            &mut theme.link,
+            &mut theme.dim,
+            &mut theme.inactive,
+            &mut theme.error,
+            &mut theme.info,
+            &mut theme.pending,
+            &mut theme.editing,
+            &mut theme.directory,
+            &mut theme.highlight_bg,
+            &mut theme.deleted_bg,
+            &mut theme.hidden_bg,
+            &mut theme.mention_bg,
+        ] {
+            *c = downsample(*c, theme.level);
+        }
+        Ok(theme)
+    }
+
+    /// The colour as the terminal can display it.
+    pub fn color(&self, c: Color) -> Color {
+        downsample(c, self.level)
+    }
+
+    /// The colours of the style as the terminal can display them.
+    pub fn style(&self, mut s: Style) -> Style {
+        s.fg = s.fg.map(|c| self.color(c));
+        s.bg = s.bg.map(|c| self.color(c));
+        s
+    }
+
+    /// A colour picked by someone in the chat, readable on the background.
+    pub fn chat_color(&self, c: Color) -> Color {
+        match (rgb(c), rgb(self.background)) {
+            (Some(fg), Some(bg)) => self.color(readable(fg, bg, self.min_contrast)),
+            _ => self.color(c),
+        }
+    }
+}
+
+fn parse_color(s: &str) -> anyhow::Result<Color> {
+    if let Some(hex) = s.strip_prefix('#') {
+        let n = u32::from_str_radix(hex, 16)
+            .ok()
+            .filter(|_| hex.len() == 6)
+            .ok_or_else(|| anyhow!("bad colour \"{}\"", s))?;
+        return Ok(Color::Rgb((n >> 16) as u8, (n >> 8) as u8, n as u8));
+    }
+    if let Ok(idx) = s.parse::<u8>() {
+        return Ok(Color::Indexed(idx));
+    }
+    let name = s.to_lowercase().replace(['-', '_', ' '], "");
+    NAMED
+        .iter()
+        .find(|(n, _, _)| *n == name)
+        .map(|(_, c, _)| *c)
+        .ok_or_else(|| anyhow!("bad colour \"{}\"", s))
+}
+
+// The 16 terminal colours, with the usual xterm values.
+const NAMED: &[(&str, Color, (u8, u8, u8))] = &[
+    ("black", Color::Black, (0, 0, 0)),
+    ("red", Color::Red, (205, 0, 0)),
+    ("green", Color::Green, (0, 205, 0)),
+    ("yellow", Color::Yellow, (205, 205, 0)),
+    ("blue", Color::Blue, (0, 0, 238)),
+    ("magenta", Color::Magenta, (205, 0, 205)),
+    ("cyan", Color::Cyan, (0, 205, 205)),
+    ("gray", Color::Gray, (229, 229, 229)),
+    ("darkgray", Color::DarkGray, (127, 127, 127)),
+    ("lightred", Color::LightRed, (255, 0, 0)),
+    ("lightgreen", Color::LightGreen, (0, 255, 0)),
+    ("lightyellow", Color::LightYellow, (255, 255, 0)),
+    ("lightblue", Color::LightBlue, (92, 92, 255)),
+    ("lightmagenta", Color::LightMagenta, (255, 0, 255)),
+    ("lightcyan", Color::LightCyan, (0, 255, 255)),
+    ("white", Color::White, (255, 255, 255)),
+];
+
+fn rgb(c: Color) -> Option<(u8, u8, u8)> {
+    match c {
+        Color::Rgb(r, g, b) => Some((r, g, b)),
+        Color::Indexed(i) if i < 16 => Some(NAMED[i as usize].2),
+        Color::Indexed(i) if i < 232 => {
+            let i = i - 16;
+            let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+            Some((level(i / 36), level(i / 6 % 6), level(i % 6)))
+        }
+        Color::Indexed(i) => {
+            let v = 8 + (i - 232) * 10;
+            Some((v, v, v))
+        }
+        Color::Reset => None,
+        named => NAMED
+            .iter()
+            .find(|(_, c, _)| *c == named)
+            .map(|(_, _, rgb)| *rgb),
+    }
+}
+
+fn downsample(c: Color, level: ColorLevel) -> Color {
+    match (c, level) {
+        (Color::Rgb(r, g, b), ColorLevel::Ansi256) => Color::Indexed(to_256(r, g, b)),
+        (Color::Rgb(..), ColorLevel::Ansi16) | (Color::Indexed(_), ColorLevel::Ansi16) => {
+            match rgb(c) {
+                Some(rgb) => to_16(rgb),
+                None => c,
+            }
+        }
+        _ => c,
+    }
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2) as u32;
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
+
+// Closest colour of the 6x6x6 cube or of the grey ramp.
+fn to_256(r: u8, g: u8, b: u8) -> u8 {
+    let cube_idx = |v: u8| match v {
+        0..=47 => 0,
+        48..=114 => 1,
+        v => (v - 35) / 40,
+    };
+    let cube = 16 + 36 * cube_idx(r) + 6 * cube_idx(g) + cube_idx(b);
+    let avg = ((r as u32 + g as u32 + b as u32) / 3) as u8;
+    let grey = 232 + (avg.saturating_sub(3) / 10).min(23);
+    let to_rgb = |i: u8| rgb(Color::Indexed(i)).unwrap();
+    if distance(to_rgb(grey), (r, g, b)) < distance(to_rgb(cube), (r, g, b)) {
+        grey
+    } else {
+        cube
+    }
+}
+
+fn to_16(c: (u8, u8, u8)) -> Color {
+    NAMED
+        .iter()
+        .min_by_key(|(_, _, rgb)| distance(*rgb, c))
+        .map(|(_, color, _)| *color)
+        .unwrap()
+}
+
+// Relative luminance, as defined by WCAG.
+fn luminance((r, g, b): (u8, u8, u8)) -> f64 {
+    let channel = |v: u8| {
+        let v = v as f64 / 255.0;
+        if v <= 0.03928 {
+            v / 12.92
+        } else {
+            ((v + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    0.2126 * channel(r) + 0.7152 * channel(g) + 0.0722 * channel(b)
+}
+
+fn contrast(a: (u8, u8, u8), b: (u8, u8, u8)) -> f64 {
+    let (la, lb) = (luminance(a), luminance(b));
+    (la.max(lb) + 0.05) / (la.min(lb) + 0.05)
+}
+
+// Move "fg" towards white (or black on a light background) until it has
+// enough contrast with "bg".
+fn readable(fg: (u8, u8, u8), bg: (u8, u8, u8), min_contrast: f64) -> Color {
+    let target = if luminance(bg) < 0.5 {
+        (255, 255, 255)
+    } else {
+        (0, 0, 0)
+    };
+    let mix = |a: u8, b: u8, t: f64| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+    for step in 0..=20 {
+        let t = step as f64 / 20.0;
+        let c = (
+            mix(fg.0, target.0, t),
+            mix(fg.1, target.1, t),
+            mix(fg.2, target.2, t),
+        );
+        if contrast(c, bg) >= min_contrast {
+            return Color::Rgb(c.0, c.1, c.2);
+        }
+    }
+    Color::Rgb(target.0, target.1, target.2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contrast_test() {
+        let dark = Theme::dark();
+        // Dark blue is lightened on a black background, keeping its hue
+        match dark.chat_color(Color::Rgb(0, 0, 80)) {
+            Color::Rgb(r, g, b) => {
+                assert!(contrast((r, g, b), (0, 0, 0)) >= 3.0);
+                assert!(b > r && b > g);
+            }
+            c => panic!("{:?}", c),
+        }
+        // Readable colours are left alone
+        assert_eq!(
+            dark.chat_color(Color::Rgb(255, 200, 0)),
+            Color::Rgb(255, 200, 0)
+        );
+        // and light ones darkened on a light background
+        let light = Theme::light();
+        match light.chat_color(Color::Rgb(255, 255, 150)) {
+            Color::Rgb(r, g, b) => assert!(contrast((r, g, b), (255, 255, 255)) >= 3.0),
+            c => panic!("{:?}", c),
+        }
+    }
+
+    #[test]
+    fn downsample_test() {
+        assert_eq!(
+            downsample(Color::Rgb(255, 0, 0), ColorLevel::Ansi256),
+            Color::Indexed(196)
+        );
+        assert_eq!(
+            downsample(Color::Rgb(50, 50, 50), ColorLevel::Ansi256),
+            Color::Indexed(236)
+        );
+        assert_eq!(
+            downsample(Color::Rgb(250, 10, 5), ColorLevel::Ansi16),
+            Color::LightRed
+        );
+        assert_eq!(
+            downsample(Color::Indexed(21), ColorLevel::Ansi16),
+            Color::Blue
+        );
+        assert_eq!(downsample(Color::Red, ColorLevel::Ansi16), Color::Red);
+    }
+
+    #[test]
+    fn config_test() {
+        let mut cfg = ThemeConfig {
+            preset: "light".to_owned(),
+            colors: "16".to_owned(),
+            ..Default::default()
+        };
+        cfg.palette.insert("dim".to_owned(), "#00ff00".to_owned());
+        cfg.palette
+            .insert("error".to_owned(), "light-red".to_owned());
+        let theme = Theme::from_config(&cfg).unwrap();
+        assert_eq!(theme.dim, Color::LightGreen);
+        assert_eq!(theme.error, Color::LightRed);
+        assert_eq!(theme.text, Color::Black);
+
+        cfg.palette.insert("nope".to_owned(), "red".to_owned());
+        assert!(Theme::from_config(&cfg).is_err());
+        cfg.palette.clear();
+        cfg.palette.insert("dim".to_owned(), "#12345".to_owned());
+        assert!(Theme::from_config(&cfg).is_err());
+    }
+}
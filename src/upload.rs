@@ -0,0 +1,286 @@
+use crate::random_string;
+use crate::sanitize::Report;
+use crate::status::{Progress, StatusLine};
+use crate::util::StatefulList;
+use anyhow::{anyhow, Context};
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UploadConfig {
+    /// Biggest file the server accepts, in KiB.
+    pub max_size_kb: u64,
+    /// Look for metadata before sending files, and strip it from images.
+    /// Can be overridden per upload with "-s"/"-r".
+    pub strip_metadata: bool,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            max_size_kb: 1024,
+            strip_metadata: true,
+        }
+    }
+}
+
+/// Refuse files the server would reject anyway, before sending them over Tor.
+pub fn check_size(path: &Path, max_size_kb: u64) -> anyhow::Result<u64> {
+    let meta = fs::metadata(path).with_context(|| format!("cannot read {}", path.display()))?;
+    if !meta.is_file() {
+        return Err(anyhow!("{} is not a file", path.display()));
+    }
+    let size = meta.len();
+    if size > max_size_kb * 1024 {
+        return Err(anyhow!(
+            "{} is {} KiB, the limit is {} KiB",
+            path.display(),
+            size / 1024,
+            max_size_kb
+        ));
+    }
+    Ok(size)
+}
+
+const TEMP_PREFIX: &str = "bhcli-";
+
+pub fn temp_file(ext: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("{}{}.{}", TEMP_PREFIX, random_string(12), ext))
+}
+
+fn is_temp_file(path: &Path) -> bool {
+    let ours = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with(TEMP_PREFIX));
+    ours && path.parent() == Some(std::env::temp_dir().as_path())
+}
+
+/// Delete "path" if it is a temporary file we created (clipboard image,
+/// stripped copy), files picked by the user are left alone.
+pub fn remove_temp_file(path: &Path) {
+    if is_temp_file(path) {
+        match fs::remove_file(path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::error!("failed to remove {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Save the image currently in the clipboard into a temporary png file.
+/// Uses wl-paste on wayland, xclip otherwise.
+pub fn clipboard_image() -> anyhow::Result<PathBuf> {
+    let output = if std::env::var("WAYLAND_DISPLAY").is_ok() {
+        Command::new("wl-paste")
+            .args(["--no-newline", "--type", "image/png"])
+            .output()
+            .context("failed to run wl-paste")?
+    } else {
+        Command::new("xclip")
+            .args(["-selection", "clipboard", "-target", "image/png", "-out"])
+            .output()
+            .context("failed to run xclip")?
+    };
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(anyhow!("no image in the clipboard"));
+    }
+    let out = temp_file("png");
+    fs::write(&out, output.stdout)?;
+    Ok(out)
+}
+
+/// Mime type of the file, from its extension.
+pub fn mime_type(path: &str) -> Option<&'static str> {
+    let ext = Path::new(path).extension()?.to_str()?.to_lowercase();
+    Some(match ext.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        "zip" => "application/zip",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => return None,
+    })
+}
+
+/// Reader that reports how much of the file was sent in the status line.
+pub struct ProgressReader<R> {
+    inner: R,
+    progress: Progress,
+    status: Arc<Mutex<StatusLine>>,
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub fn new(inner: R, label: String, total: u64, status: Arc<Mutex<StatusLine>>) -> Self {
+        let progress = Progress {
+            label,
+            done: 0,
+            total,
+        };
+        status.lock().unwrap().set_progress(Some(progress.clone()));
+        Self {
+            inner,
+            progress,
+            status,
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.done += n as u64;
+        let mut status = self.status.lock().unwrap();
+        if n == 0 {
+            status.set_progress(None);
+        } else {
+            status.set_progress(Some(self.progress.clone()));
+        }
+        Ok(n)
+    }
+}
+
+/// Upload waiting for the user to review the metadata that was found.
+pub struct PendingUpload {
+    pub path: PathBuf,
+    pub send_to: String,
+    pub msg: String,
+    pub report: Report,
+}
+
+pub struct Entry {
+    pub name: String,
+    pub path: PathBuf,
+    pub is_dir: bool,
+}
+
+/// Minimal file browser used to pick the file to upload.
+pub struct FileBrowser {
+    pub dir: PathBuf,
+    pub entries: StatefulList<Entry>,
+}
+
+impl FileBrowser {
+    pub fn new(dir: PathBuf) -> Self {
+        let mut fb = Self {
+            dir,
+            entries: StatefulList::new(),
+        };
+        fb.refresh();
+        fb
+    }
+
+    fn refresh(&mut self) {
+        let mut entries: Vec<Entry> = fs::read_dir(&self.dir)
+            .map(|rd| {
+                rd.filter_map(|e| e.ok())
+                    .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+                    .map(|e| Entry {
+                        name: e.file_name().to_string_lossy().into_owned(),
+                        is_dir: e.path().is_dir(),
+                        path: e.path(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        // Directories first, then files, by name
+        entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then(a.name.cmp(&b.name)));
+        if let Some(parent) = self.dir.parent() {
+            entries.insert(
+                0,
+                Entry {
+                    name: "..".to_owned(),
+                    path: parent.to_owned(),
+                    is_dir: true,
+                },
+            );
+        }
+        self.entries.items = entries;
+        self.entries.state.select(None);
+        self.entries.select_top();
+    }
+
+    /// Enter the selected directory, or return the selected file.
+    pub fn open_selected(&mut self) -> Option<PathBuf> {
+        let idx = self.entries.state.selected()?;
+        let entry = self.entries.items.get(idx)?;
+        if entry.is_dir {
+            self.dir = entry.path.clone();
+            self.refresh();
+            None
+        } else {
+            Some(entry.path.clone())
+        }
+    }
+
+    pub fn parent(&mut self) {
+        if let Some(parent) = self.dir.parent() {
+            self.dir = parent.to_owned();
+            self.refresh();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_size_test() {
+        let path = temp_file("txt");
+        fs::write(&path, vec![0u8; 2048]).unwrap();
+        assert_eq!(check_size(&path, 2).unwrap(), 2048);
+        assert!(check_size(&path, 1).is_err());
+        assert!(check_size(&std::env::temp_dir(), 1).is_err());
+        remove_temp_file(&path);
+        assert!(!path.exists());
+        assert!(check_size(&path, 2).is_err());
+    }
+
+    #[test]
+    fn remove_temp_file_test() {
+        let dir = std::env::temp_dir().join(format!("bhcli-upload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // Not directly in the temp dir, so not ours
+        let path = dir.join("bhcli-picked.png");
+        fs::write(&path, b"png").unwrap();
+        remove_temp_file(&path);
+        assert!(path.exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn mime_type_test() {
+        assert_eq!(mime_type("a/b/photo.JPG"), Some("image/jpeg"));
+        assert_eq!(mime_type("clip.png"), Some("image/png"));
+        assert_eq!(mime_type("archive.tar.gz"), None);
+        assert_eq!(mime_type("noext"), None);
+    }
+
+    #[test]
+    fn progress_reader_test() {
+        let status = Arc::new(Mutex::new(StatusLine::default()));
+        let data = [1u8; 100];
+        let mut reader =
+            ProgressReader::new(&data[..], "f.bin".to_owned(), 100, Arc::clone(&status));
+        assert_eq!(status.lock().unwrap().progress().unwrap().percent(), 0);
+        let mut buf = [0u8; 40];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(status.lock().unwrap().progress().unwrap().percent(), 40);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest.len(), 60);
+        // Done once the end of the file is reached
+        assert!(status.lock().unwrap().progress().is_none());
+    }
+}
@@ -60,3 +60,43 @@ impl<T> StatefulList<T> {
         self.state.select(Some(0));
     }
 }
+
+/// First item to display so that the selected one is visible, scrolling as
+/// little as possible from "top". Items are "heights" lines high.
+pub fn scroll_to(
+    heights: &[usize],
+    top: usize,
+    selected: Option<usize>,
+    max_height: usize,
+) -> usize {
+    let mut top = top.min(heights.len().saturating_sub(1));
+    let selected = match selected {
+        Some(idx) if idx < heights.len() => idx,
+        _ => return top,
+    };
+    if selected < top {
+        return selected;
+    }
+    while top < selected && heights[top..=selected].iter().sum::<usize>() > max_height {
+        top += 1;
+    }
+    top
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scroll_to_test() {
+        let heights = [1, 2, 1, 3, 1];
+        assert_eq!(scroll_to(&heights, 0, None, 4), 0);
+        assert_eq!(scroll_to(&heights, 3, None, 4), 3);
+        assert_eq!(scroll_to(&heights, 9, None, 4), 4);
+        assert_eq!(scroll_to(&heights, 0, Some(2), 4), 0);
+        assert_eq!(scroll_to(&heights, 0, Some(3), 4), 2);
+        assert_eq!(scroll_to(&heights, 3, Some(1), 4), 1);
+        // Taller than the screen, the item is displayed from its top
+        assert_eq!(scroll_to(&heights, 0, Some(3), 2), 3);
+    }
+}
@@ -0,0 +1,220 @@
+use crate::config_dir_file;
+use crate::crypto::{self, StorageKey};
+use anyhow::{anyhow, Context};
+use argon2::Argon2;
+use base64::{engine::general_purpose, Engine as _};
+use rand::{thread_rng, RngCore};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::PathBuf;
+
+const VAULT_FILE: &str = "vault.bin";
+const SALT_LEN: usize = 16;
+// Secret holding the key of the sessions, outbox & history files
+const STORAGE_KEY: &str = "storage_key";
+
+/// Encrypted store for passwords & API keys, unlocked by a master passphrase.
+/// The file is made of the KDF salt followed by the sealed secrets.
+pub struct Vault {
+    path: PathBuf,
+    salt: [u8; SALT_LEN],
+    key: [u8; crypto::KEY_LEN],
+    secrets: BTreeMap<String, String>,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> anyhow::Result<[u8; crypto::KEY_LEN]> {
+    let mut key = [0u8; crypto::KEY_LEN];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("failed to derive vault key: {}", e))?;
+    Ok(key)
+}
+
+fn vault_path() -> anyhow::Result<PathBuf> {
+    config_dir_file(VAULT_FILE).context("no config directory")
+}
+
+pub fn exists() -> bool {
+    vault_path().map(|p| p.exists()).unwrap_or(false)
+}
+
+// Passphrase from the environment, or prompted on the terminal.
+fn ask_passphrase(prompt: &str) -> anyhow::Result<String> {
+    if let Ok(passphrase) = std::env::var("BHC_VAULT_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    Ok(rpassword::prompt_password(prompt)?)
+}
+
+fn ask_new_passphrase() -> anyhow::Result<String> {
+    if let Ok(passphrase) = std::env::var("BHC_VAULT_PASSPHRASE") {
+        check_new_passphrase(&passphrase).context("BHC_VAULT_PASSPHRASE")?;
+        return Ok(passphrase);
+    }
+    prompt_new_passphrase()
+}
+
+fn prompt_new_passphrase() -> anyhow::Result<String> {
+    let passphrase = rpassword::prompt_password("New vault passphrase: ")?;
+    let confirm = rpassword::prompt_password("Confirm vault passphrase: ")?;
+    if passphrase != confirm {
+        return Err(anyhow!("passphrases do not match"));
+    }
+    check_new_passphrase(&passphrase)?;
+    Ok(passphrase)
+}
+
+fn check_new_passphrase(passphrase: &str) -> anyhow::Result<()> {
+    if passphrase.is_empty() {
+        return Err(anyhow!("passphrase cannot be empty"));
+    }
+    Ok(())
+}
+
+impl Vault {
+    pub fn create(passphrase: &str) -> anyhow::Result<Self> {
+        Self::create_at(vault_path()?, passphrase)
+    }
+
+    fn create_at(path: PathBuf, passphrase: &str) -> anyhow::Result<Self> {
+        check_new_passphrase(passphrase)?;
+        let mut salt = [0u8; SALT_LEN];
+        thread_rng().fill_bytes(&mut salt);
+        let vault = Self {
+            path,
+            salt,
+            key: derive_key(passphrase, &salt)?,
+            secrets: BTreeMap::new(),
+        };
+        vault.save()?;
+        Ok(vault)
+    }
+
+    pub fn unlock(passphrase: &str) -> anyhow::Result<Self> {
+        Self::unlock_at(vault_path()?, passphrase)
+    }
+
+    fn unlock_at(path: PathBuf, passphrase: &str) -> anyhow::Result<Self> {
+        let data = fs::read(&path).context("failed to read vault")?;
+        if data.len() < SALT_LEN {
+            return Err(anyhow!("vault file is corrupted"));
+        }
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(&data[..SALT_LEN]);
+        let key = derive_key(passphrase, &salt)?;
+        let plaintext =
+            crypto::open(&key, &data[SALT_LEN..]).map_err(|_| anyhow!("wrong vault passphrase"))?;
+        let secrets = serde_json::from_slice(&plaintext)?;
+        Ok(Self {
+            path,
+            salt,
+            key,
+            secrets,
+        })
+    }
+
+    /// Unlock the existing vault, prompting for the passphrase.
+    pub fn prompt_unlock() -> anyhow::Result<Self> {
+        Self::unlock(&ask_passphrase("Vault passphrase: ")?)
+    }
+
+    /// Unlock the vault, or create it when there is none yet.
+    pub fn prompt_open_or_create() -> anyhow::Result<Self> {
+        if exists() {
+            Self::prompt_unlock()
+        } else {
+            Self::create(&ask_new_passphrase()?)
+        }
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let plaintext = serde_json::to_vec(&self.secrets)?;
+        let mut data = self.salt.to_vec();
+        data.extend(crypto::seal(&self.key, &plaintext)?);
+        crypto::write_private(&self.path, &data)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.secrets.get(name)
+    }
+
+    pub fn set(&mut self, name: &str, secret: String) {
+        self.secrets.insert(name.to_owned(), secret);
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.secrets.remove(name).is_some()
+    }
+
+    /// Key the local state is sealed with, created the first time.
+    pub fn storage_key(&mut self) -> anyhow::Result<StorageKey> {
+        let stored = self
+            .get(STORAGE_KEY)
+            .and_then(|k| general_purpose::STANDARD.decode(k).ok())
+            .and_then(|k| <[u8; crypto::KEY_LEN]>::try_from(k).ok());
+        if let Some(key) = stored {
+            return Ok(StorageKey::new(key));
+        }
+        let key = StorageKey::generate();
+        self.set(STORAGE_KEY, general_purpose::STANDARD.encode(key.bytes()));
+        self.save()?;
+        Ok(key)
+    }
+
+    pub fn names(&self) -> Vec<&String> {
+        self.secrets.keys().collect()
+    }
+
+    /// Re-encrypt the vault with a new passphrase (and a new salt).
+    pub fn rekey(&mut self, passphrase: &str) -> anyhow::Result<()> {
+        thread_rng().fill_bytes(&mut self.salt);
+        self.key = derive_key(passphrase, &self.salt)?;
+        self.save()
+    }
+
+    pub fn prompt_rekey(&mut self) -> anyhow::Result<()> {
+        self.rekey(&prompt_new_passphrase()?)
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        Self {
+            path: PathBuf::new(),
+            salt: [0u8; SALT_LEN],
+            key: [0u8; crypto::KEY_LEN],
+            secrets: BTreeMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vault_test() {
+        let dir = std::env::temp_dir().join(format!("bhcli-vault-{}", std::process::id()));
+        let path = dir.join(VAULT_FILE);
+        assert!(Vault::create_at(path.clone(), "").is_err());
+        let mut vault = Vault::create_at(path.clone(), "correct horse").unwrap();
+        vault.set("dkf_api_key", "key".to_owned());
+        vault.save().unwrap();
+        let data = fs::read(&path).unwrap();
+        assert!(!data.windows(3).any(|w| w == b"key"));
+
+        let mut vault = Vault::unlock_at(path.clone(), "correct horse").unwrap();
+        assert_eq!(vault.get("dkf_api_key").map(|s| s.as_str()), Some("key"));
+        let err = Vault::unlock_at(path.clone(), "wrong horse").err().unwrap();
+        assert_eq!(err.to_string(), "wrong vault passphrase");
+
+        let storage_key = vault.storage_key().unwrap();
+        vault.rekey("battery staple").unwrap();
+        assert!(Vault::unlock_at(path.clone(), "correct horse").is_err());
+        let mut vault = Vault::unlock_at(path.clone(), "battery staple").unwrap();
+        assert_eq!(vault.get("dkf_api_key").map(|s| s.as_str()), Some("key"));
+        // Files sealed before the rekey can still be read
+        assert_eq!(vault.storage_key().unwrap().bytes(), storage_key.bytes());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
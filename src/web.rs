@@ -0,0 +1,440 @@
+//! A read only dashboard of the session, served on a local address for a
+//! browser on the same machine. The page reloads itself, it has no script and
+//! every request must carry the token, as `?token=` or a bearer token.
+use crate::crypto::same;
+use serde_derive::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// How many messages the page shows.
+const MESSAGES: usize = 200;
+// Threads answering requests, and how many connections can wait for them.
+const WORKERS: usize = 4;
+const BACKLOG: usize = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebConfig {
+    pub enabled: bool,
+    /// Must be a loopback address
+    pub listen: String,
+    pub token: Option<String>,
+    /// Seconds between page reloads
+    pub refresh: u64,
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "127.0.0.1:8080".to_owned(),
+            token: None,
+            refresh: 5,
+        }
+    }
+}
+
+impl WebConfig {
+    pub fn check(&self) -> Result<(), String> {
+        match self.listen.parse::<SocketAddr>() {
+            Ok(addr) if addr.ip().is_loopback() => {}
+            Ok(_) => return Err(format!("\"{}\" is not a local address", self.listen)),
+            Err(e) => return Err(format!("invalid address \"{}\": {}", self.listen, e)),
+        }
+        match &self.token {
+            _ if !self.enabled => Ok(()),
+            Some(token) if token.len() >= 16 => Ok(()),
+            Some(_) => Err("the token must have at least 16 characters".to_owned()),
+            None => Err("a token is needed".to_owned()),
+        }
+    }
+}
+
+/// A piece of message with its style, colours are CSS ones.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Span {
+    pub text: String,
+    pub color: Option<String>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strike: bool,
+    pub link: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Line {
+    pub date: String,
+    pub spans: Vec<Span>,
+    pub deleted: bool,
+}
+
+/// A title, then the (colour, name) of the users.
+pub type UserGroup = (&'static str, Vec<(Option<String>, String)>);
+
+/// What the page shows, taken from the client state on each request.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    pub username: String,
+    /// Newest first
+    pub messages: Vec<Line>,
+    pub users: Vec<UserGroup>,
+    pub filters: Vec<(&'static str, Vec<String>)>,
+    /// Newest first, (date, user, action, reason)
+    pub actions: Vec<(String, String, &'static str, String)>,
+}
+
+type Source = Box<dyn Fn() -> Snapshot + Send + Sync>;
+type SharedSource = Arc<Mutex<Option<Source>>>;
+
+pub struct Web {
+    source: SharedSource,
+}
+
+impl Web {
+    pub fn start(cfg: &WebConfig) -> Self {
+        let web = Self {
+            source: Arc::new(Mutex::new(None)),
+        };
+        if let (true, Some(token)) = (cfg.enabled, &cfg.token) {
+            match TcpListener::bind(&cfg.listen) {
+                Ok(listener) => web.serve(listener, token.clone(), cfg.refresh),
+                Err(e) => log::error!("web: failed to listen on {}: {}", cfg.listen, e),
+            }
+        }
+        web
+    }
+
+    /// The state of the current session, replaced on each login.
+    pub fn attach<F: Fn() -> Snapshot + Send + Sync + 'static>(&self, source: F) {
+        *self.source.lock().unwrap() = Some(Box::new(source));
+    }
+
+    fn serve(&self, listener: TcpListener, token: String, refresh: u64) {
+        let (tx, rx) = crossbeam_channel::bounded::<TcpStream>(BACKLOG);
+        let token = Arc::new(token);
+        for _ in 0..WORKERS {
+            let rx = rx.clone();
+            let source = Arc::clone(&self.source);
+            let token = Arc::clone(&token);
+            thread::spawn(move || {
+                for stream in rx {
+                    if let Err(e) = handle(stream, &source, &token, refresh) {
+                        log::error!("web: {}", e);
+                    }
+                }
+            });
+        }
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::error!("web: {}", e);
+                        continue;
+                    }
+                };
+                // Busy, the connection is dropped rather than queued forever
+                if tx.try_send(stream).is_err() {
+                    log::error!("web: too many connections");
+                }
+            }
+        });
+    }
+}
+
+fn handle(
+    stream: TcpStream,
+    source: &Mutex<Option<Source>>,
+    token: &str,
+    refresh: u64,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let mut out = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut bearer = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("authorization") {
+                bearer = value.trim().strip_prefix("Bearer ").map(|t| t.to_owned());
+            }
+        }
+    }
+    let mut words = request.split_whitespace();
+    let (method, target) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let given = bearer.or_else(|| query_param(query, "token"));
+    let (status, body) = if method != "GET" {
+        ("405 Method Not Allowed", "Only GET is supported".to_owned())
+    } else if !given.is_some_and(|t| same(&t, token)) {
+        ("401 Unauthorized", "A valid token is needed".to_owned())
+    } else if path != "/" {
+        ("404 Not Found", "Not found".to_owned())
+    } else {
+        match source.lock().unwrap().as_ref() {
+            Some(source) => ("200 OK", render(&source(), refresh)),
+            None => ("503 Service Unavailable", "Not logged in yet".to_owned()),
+        }
+    };
+    let content_type = if status.starts_with("200") {
+        "text/html; charset=utf-8"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+    write!(
+        out,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\n\
+         Content-Security-Policy: default-src 'none'; style-src 'unsafe-inline'\r\n\
+         Referrer-Policy: no-referrer\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = || u8::from_str_radix(s.get(i + 1..i + 3)?, 16).ok();
+        match (bytes[i], hex()) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b'+', _) => {
+                out.push(b' ');
+                i += 1;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn render_span(span: &Span) -> String {
+    let mut style = String::new();
+    if let Some(color) = &span.color {
+        let _ = write!(style, "color:{};", escape(color));
+    }
+    if span.bold {
+        style.push_str("font-weight:bold;");
+    }
+    if span.italic {
+        style.push_str("font-style:italic;");
+    }
+    match (span.underline, span.strike) {
+        (true, true) => style.push_str("text-decoration:underline line-through;"),
+        (true, false) => style.push_str("text-decoration:underline;"),
+        (false, true) => style.push_str("text-decoration:line-through;"),
+        (false, false) => {}
+    }
+    let text = escape(&span.text);
+    // Only web links, no "javascript:" & co
+    match &span.link {
+        Some(link) if link.starts_with("http://") || link.starts_with("https://") => format!(
+            "<a href=\"{}\" rel=\"noreferrer noopener\" style=\"{}\">{}</a>",
+            escape(link),
+            style,
+            text
+        ),
+        _ if style.is_empty() => text,
+        _ => format!("<span style=\"{}\">{}</span>", style, text),
+    }
+}
+
+fn render(snapshot: &Snapshot, refresh: u64) -> String {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta http-equiv=\"refresh\" content=\"{}\"><title>bhcli - {}</title><style>\
+         body{{background:#111;color:#ddd;font-family:monospace;margin:0;display:flex}}\
+         main{{flex:1;padding:0 1em;white-space:pre-wrap}}aside{{width:22em;padding:0 1em;\
+         border-left:1px solid #333}}h2{{font-size:1em;color:#888}}ul{{list-style:none;\
+         padding:0}}.date{{color:#666}}.deleted{{opacity:.5}}a{{color:#8af}}</style></head><body>",
+        refresh,
+        escape(&snapshot.username)
+    );
+    html.push_str("<main><h2>Messages</h2>");
+    for line in snapshot.messages.iter().take(MESSAGES) {
+        let class = if line.deleted {
+            " class=\"deleted\""
+        } else {
+            ""
+        };
+        let _ = write!(
+            html,
+            "<div{}><span class=\"date\">{}</span> ",
+            class,
+            escape(&line.date)
+        );
+        for span in &line.spans {
+            html.push_str(&render_span(span));
+        }
+        html.push_str("</div>");
+    }
+    html.push_str("</main><aside>");
+    for (title, users) in &snapshot.users {
+        let _ = write!(html, "<h2>{} ({})</h2><ul>", title, users.len());
+        for (color, name) in users {
+            let span = Span {
+                text: name.clone(),
+                color: color.clone(),
+                ..Span::default()
+            };
+            let _ = write!(html, "<li>{}</li>", render_span(&span));
+        }
+        html.push_str("</ul>");
+    }
+    for (title, filters) in &snapshot.filters {
+        let _ = write!(html, "<h2>{}</h2><ul>", title);
+        if filters.is_empty() {
+            html.push_str("<li class=\"date\">none</li>");
+        }
+        for filter in filters {
+            let _ = write!(html, "<li>{}</li>", escape(filter));
+        }
+        html.push_str("</ul>");
+    }
+    html.push_str("<h2>Automatic actions</h2><ul>");
+    if snapshot.actions.is_empty() {
+        html.push_str("<li class=\"date\">none</li>");
+    }
+    for (date, user, action, reason) in &snapshot.actions {
+        let _ = write!(
+            html,
+            "<li><span class=\"date\">{}</span> {} {}: {}</li>",
+            escape(date),
+            action,
+            escape(user),
+            escape(reason)
+        );
+    }
+    html.push_str("</ul></aside></body></html>");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn render_test() {
+        let span = Span {
+            text: "<b>hi</b>".to_owned(),
+            color: Some("#ff0000".to_owned()),
+            bold: true,
+            ..Span::default()
+        };
+        assert_eq!(
+            render_span(&span),
+            "<span style=\"color:#ff0000;font-weight:bold;\">&lt;b&gt;hi&lt;/b&gt;</span>"
+        );
+        let span = Span {
+            text: "click".to_owned(),
+            link: Some("javascript:alert(1)".to_owned()),
+            ..Span::default()
+        };
+        assert_eq!(render_span(&span), "click");
+        assert_eq!(percent_decode("a%2Bb+c%zz"), "a+b c%zz");
+    }
+
+    #[test]
+    fn config_test() {
+        let mut cfg = WebConfig::default();
+        assert!(cfg.check().is_ok());
+        cfg.enabled = true;
+        assert!(cfg.check().is_err());
+        cfg.token = Some("0123456789abcdef".to_owned());
+        assert!(cfg.check().is_ok());
+        cfg.listen = "0.0.0.0:8080".to_owned();
+        assert!(cfg.check().is_err());
+    }
+
+    #[test]
+    fn serve_test() {
+        let web = Web::start(&WebConfig::default());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        web.serve(listener, "0123456789abcdef".to_owned(), 5);
+        let get = |request: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "{}\r\n\r\n", request).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        assert!(get("GET / HTTP/1.1").starts_with("HTTP/1.1 401"));
+        assert!(get("GET /?token=nope HTTP/1.1").starts_with("HTTP/1.1 401"));
+        assert!(get("GET /?token=0123456789abcdef HTTP/1.1").starts_with("HTTP/1.1 503"));
+        web.attach(|| Snapshot {
+            username: "me".to_owned(),
+            messages: vec![Line {
+                date: "10-19 12:00:00".to_owned(),
+                spans: vec![Span {
+                    text: "bob - hi".to_owned(),
+                    ..Span::default()
+                }],
+                deleted: false,
+            }],
+            users: vec![("Guests", vec![(None, "bob".to_owned())])],
+            filters: vec![("Message filters", vec!["spam".to_owned()])],
+            actions: vec![(
+                "10-19 12:01:00".to_owned(),
+                "eve".to_owned(),
+                "kick",
+                "name filter".to_owned(),
+            )],
+        });
+        let page = get("GET / HTTP/1.1\r\nAuthorization: Bearer 0123456789abcdef");
+        assert!(page.starts_with("HTTP/1.1 200"));
+        assert!(page.contains("bob - hi"));
+        assert!(page.contains("<h2>Guests (1)</h2>"));
+        assert!(page.contains("<li>spam</li>"));
+        assert!(page.contains("kick eve: name filter"));
+        assert!(get("GET /x?token=0123456789abcdef HTTP/1.1").starts_with("HTTP/1.1 404"));
+        assert!(get("POST /?token=0123456789abcdef HTTP/1.1").starts_with("HTTP/1.1 405"));
+    }
+}